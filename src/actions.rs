@@ -5,9 +5,12 @@ use anyhow::{Context, Result};
 use crossterm::style::Stylize;
 use handlebars::Handlebars;
 
-use crate::config::{SymbolicTarget, TemplateTarget, Variables};
+use crate::args::Options;
+use crate::config::{FileMode, SymbolicTarget, TemplateTarget, UnixUser, Variables};
 use crate::difference::{self, diff_nonempty, generate_template_diff, print_diff};
+use crate::encoding;
 use crate::filesystem::{Filesystem, SymlinkComparison, TemplateComparison};
+use crate::handlebars_helpers::render_target;
 
 #[cfg_attr(test, mockall::automock)]
 pub trait ActionRunner {
@@ -29,21 +32,48 @@ pub trait ActionRunner {
     ) -> Result<bool>;
 }
 
+/// The safety checks of a deploy that are skipped. `--force` skips all of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Force {
+    /// Rewrite templates whose rendered contents didn't change since the last deploy
+    pub unchanged: bool,
+    /// Overwrite and delete targets that were modified outside of Dotter, and move aside
+    /// files that are in place of a target's parent directory
+    pub external: bool,
+    /// Deploy templates that render to nothing but whitespace over targets that had contents
+    pub empty: bool,
+}
+
+impl From<&Options> for Force {
+    fn from(opt: &Options) -> Self {
+        Force {
+            unchanged: opt.force || opt.force_unchanged,
+            external: opt.force || opt.force_external,
+            empty: opt.force || opt.force_empty,
+        }
+    }
+}
+
 pub struct RealActionRunner<'a> {
     fs: &'a mut dyn Filesystem,
-    handlebars: &'a Handlebars<'a>,
+    handlebars: &'a Handlebars<'static>,
     variables: &'a Variables,
-    force: bool,
+    force: Force,
     diff_context_lines: usize,
+    /// Print the diff of every template update, not only at `-v`
+    print_diffs: bool,
+    /// Asks whether to apply each hunk of a template update
+    review: Option<&'a dyn Fn(&str) -> bool>,
 }
 
 impl<'a> RealActionRunner<'a> {
     pub fn new(
         fs: &'a mut dyn Filesystem,
-        handlebars: &'a Handlebars,
+        handlebars: &'a Handlebars<'static>,
         variables: &'a Variables,
-        force: bool,
+        force: Force,
         diff_context_lines: usize,
+        print_diffs: bool,
     ) -> RealActionRunner<'a> {
         RealActionRunner {
             fs,
@@ -51,19 +81,27 @@ impl<'a> RealActionRunner<'a> {
             variables,
             force,
             diff_context_lines,
+            print_diffs,
+            review: None,
         }
     }
+
+    /// Lets `review` choose the hunks of template updates that are applied
+    pub fn with_hunk_review(mut self, review: &'a dyn Fn(&str) -> bool) -> RealActionRunner<'a> {
+        self.review = Some(review);
+        self
+    }
 }
 
 impl<'a> ActionRunner for RealActionRunner<'a> {
     fn delete_symlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
-        delete_symlink(source, target, self.fs, self.force)
+        delete_symlink(source, target, self.fs, self.force.external)
     }
     fn delete_template(&mut self, source: &Path, cache: &Path, target: &Path) -> Result<bool> {
-        delete_template(source, cache, target, self.fs, self.force)
+        delete_template(source, cache, target, self.fs, self.force.external)
     }
     fn create_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
-        create_symlink(source, target, self.fs, self.force)
+        create_symlink(source, target, self.fs, self.force.external)
     }
     fn create_template(
         &mut self,
@@ -78,11 +116,11 @@ impl<'a> ActionRunner for RealActionRunner<'a> {
             self.fs,
             self.handlebars,
             self.variables,
-            self.force,
+            self.force.external,
         )
     }
     fn update_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
-        update_symlink(source, target, self.fs, self.force)
+        update_symlink(source, target, self.fs, self.force.external)
     }
     fn update_template(
         &mut self,
@@ -99,6 +137,8 @@ impl<'a> ActionRunner for RealActionRunner<'a> {
             self.variables,
             self.force,
             self.diff_context_lines,
+            self.print_diffs,
+            self.review,
         )
     }
 }
@@ -256,14 +296,7 @@ pub fn create_symlink(
     match comparison {
         SymlinkComparison::OnlySourceExists => {
             debug!("Performing creation");
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
-                &target.owner,
-            )
-            .context("create parent for target file")?;
+            create_parent(fs, &target.target, &target.owner, target.dir_mode, force)?;
             fs.make_symlink(&target.target, source, &target.owner)
                 .context("create target symlink")?;
             Ok(true)
@@ -306,7 +339,7 @@ pub fn create_template(
     cache: &Path,
     target: &TemplateTarget,
     fs: &mut dyn Filesystem,
-    handlebars: &Handlebars<'_>,
+    handlebars: &Handlebars<'static>,
     variables: &Variables,
     force: bool,
 ) -> Result<bool> {
@@ -325,14 +358,7 @@ pub fn create_template(
     match comparison {
         TemplateComparison::BothMissing => {
             debug!("Performing creation");
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
-                &target.owner,
-            )
-            .context("create parent for target file")?;
+            create_parent(fs, &target.target, &target.owner, target.dir_mode, force)?;
             perform_template_deploy(source, cache, target, fs, handlebars, variables)
                 .context("perform template cache")?;
             Ok(true)
@@ -342,14 +368,16 @@ pub fn create_template(
                 "Creating template {:?} -> {:?} but cache file already exists. This is probably a result of an error in the last run.",
                 source, target.target
             );
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
-                &target.owner,
-            )
-            .context("create parent for target file")?;
+            create_parent(fs, &target.target, &target.owner, target.dir_mode, force)?;
+            perform_template_deploy(source, cache, target, fs, handlebars, variables)
+                .context("perform template cache")?;
+            Ok(true)
+        }
+        // Merging keeps what's in the target, so there's nothing to protect
+        TemplateComparison::Changed | TemplateComparison::OnlyTargetExists
+            if target.merge.is_some() =>
+        {
+            debug!("Merging into existing target");
             perform_template_deploy(source, cache, target, fs, handlebars, variables)
                 .context("perform template cache")?;
             Ok(true)
@@ -365,14 +393,7 @@ pub fn create_template(
             );
             fs.remove_file(&target.target)
                 .context("remove existing file while forcing")?;
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
-                &target.owner,
-            )
-            .context("create parent for target file")?;
+            create_parent(fs, &target.target, &target.owner, target.dir_mode, force)?;
             perform_template_deploy(source, cache, target, fs, handlebars, variables)
                 .context("perform template cache")?;
             Ok(true)
@@ -442,14 +463,7 @@ pub fn update_symlink(
                 "Updating symlink {:?} -> {:?} but {}. Creating it anyways.",
                 source, target.target, comparison
             );
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
-                &target.owner,
-            )
-            .context("create parent for target file")?;
+            create_parent(fs, &target.target, &target.owner, target.dir_mode, force)?;
             fs.make_symlink(&target.target, source, &target.owner)
                 .context("create target symlink")?;
             Ok(true)
@@ -464,10 +478,12 @@ pub fn update_template(
     cache: &Path,
     target: &TemplateTarget,
     fs: &mut dyn Filesystem,
-    handlebars: &Handlebars<'_>,
+    handlebars: &Handlebars<'static>,
     variables: &Variables,
-    force: bool,
+    force: Force,
     diff_context_lines: usize,
+    print_diffs: bool,
+    review: Option<&dyn Fn(&str) -> bool>,
 ) -> Result<bool> {
     debug!("Updating template {:?} -> {:?}...", source, target.target);
     let comparison = fs
@@ -477,17 +493,53 @@ pub fn update_template(
 
     match comparison {
         TemplateComparison::Identical => {
-            debug!("Performing update");
-            difference::print_template_diff(
-                source,
-                target,
-                handlebars,
-                variables,
-                diff_context_lines,
-            );
             fs.set_owner(&target.target, &target.owner)
                 .context("set target file owner")?;
-            perform_template_deploy(source, cache, target, fs, handlebars, variables)
+            let contents = render_template_contents(source, target, fs, handlebars, variables)?;
+            let previous = fs.read(cache).context("read cache file")?;
+            if contents == previous && !force.unchanged {
+                debug!("Rendered template is unchanged, not rewriting the target");
+                fs.set_mode(
+                    source,
+                    &target.target,
+                    target.mode,
+                    target.executable_from_source,
+                    &target.owner,
+                )
+                .context("set mode of target")?;
+                return Ok(true);
+            }
+            if erases_contents(&contents, &previous) && !force.empty {
+                error!(
+                    "Updating template {:?} -> {:?} but it rendered empty, which would erase the target. Skipping.",
+                    source, target.target
+                );
+                return Ok(false);
+            }
+            let contents = match review {
+                Some(review) => {
+                    let reviewed =
+                        review_hunks(target, &previous, contents, diff_context_lines, review);
+                    if reviewed == previous {
+                        info!("No hunks of {:?} were accepted", target.target);
+                        return Ok(true);
+                    }
+                    reviewed
+                }
+                None => {
+                    difference::print_template_diff(
+                        source,
+                        target,
+                        handlebars,
+                        variables,
+                        diff_context_lines,
+                        print_diffs,
+                    );
+                    contents
+                }
+            };
+            debug!("Performing update");
+            write_template(source, cache, target, fs, contents)
                 .context("perform template cache")?;
             Ok(true)
         }
@@ -496,14 +548,13 @@ pub fn update_template(
                 "Updating template {:?} -> {:?} but target is missing. Creating it anyways.",
                 source, target.target
             );
-            fs.create_dir_all(
-                target
-                    .target
-                    .parent()
-                    .context("get parent of target file")?,
+            create_parent(
+                fs,
+                &target.target,
                 &target.owner,
-            )
-            .context("create parent for target file")?;
+                target.dir_mode,
+                force.external,
+            )?;
             perform_template_deploy(source, cache, target, fs, handlebars, variables)
                 .context("perform template cache")?;
             Ok(true)
@@ -516,20 +567,48 @@ pub fn update_template(
             error!("This is probably a bug. Delete cache.toml and cache/ folder.");
             Ok(true)
         }
-        TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile if force => {
+        TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile
+            if force.external =>
+        {
             warn!(
                 "Updating template {:?} -> {:?} but {}. Forcing.",
                 source, target.target, comparison
             );
+            let contents = render_template_contents(source, target, fs, handlebars, variables)?;
+            let previous = fs.read(cache).context("read cache file")?;
+            if erases_contents(&contents, &previous) && !force.empty {
+                error!(
+                    "Updating template {:?} -> {:?} but it rendered empty, which would erase the target. Skipping.",
+                    source, target.target
+                );
+                return Ok(false);
+            }
             difference::print_template_diff(
                 source,
                 target,
                 handlebars,
                 variables,
                 diff_context_lines,
+                print_diffs,
             );
             fs.remove_file(&target.target)
                 .context("remove target while forcing")?;
+            write_template(source, cache, target, fs, contents)
+                .context("perform template cache")?;
+            Ok(true)
+        }
+        // The target's own changes are kept by merging, and it's re-serialized anyway, so it
+        // can't be compared to the last deploy's output byte by byte
+        TemplateComparison::Changed if target.merge.is_some() => {
+            debug!("Performing update by merging into the changed target");
+            difference::print_template_diff(
+                source,
+                target,
+                handlebars,
+                variables,
+                diff_context_lines,
+                print_diffs,
+            );
             perform_template_deploy(source, cache, target, fs, handlebars, variables)
                 .context("perform template cache")?;
             Ok(true)
@@ -571,28 +650,149 @@ pub(crate) fn perform_template_deploy(
     cache: &Path,
     target: &TemplateTarget,
     fs: &mut dyn Filesystem,
-    handlebars: &Handlebars<'_>,
+    handlebars: &Handlebars<'static>,
     variables: &Variables,
 ) -> Result<()> {
+    let contents = render_template_contents(source, target, fs, handlebars, variables)?;
+    write_template(source, cache, target, fs, contents)
+}
+
+/// Renders the template into the contents of its target
+fn render_template_contents(
+    source: &Path,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'static>,
+    variables: &Variables,
+) -> Result<Vec<u8>> {
     let file_contents = fs
         .read_to_string(source)
         .context("read template source file")?;
-    let file_contents = target.apply_actions(file_contents);
-    let rendered = handlebars
-        .render_template(&file_contents, variables)
-        .context("render template")?;
+    let rendered = render_target(handlebars, source, target, file_contents, variables)?;
+    encoding::encode(rendered, target.encoding)
+}
 
+/// Asks `review` about every hunk of the update from `previous` to `contents`, and returns the
+/// contents with only the accepted hunks applied. The cache gets these contents too, so the
+/// rejected hunks are offered again by the next deploy. Contents that aren't UTF-8 can't be
+/// split into hunks, so they're applied as a whole
+fn review_hunks(
+    target: &TemplateTarget,
+    previous: &[u8],
+    contents: Vec<u8>,
+    diff_context_lines: usize,
+    review: &dyn Fn(&str) -> bool,
+) -> Vec<u8> {
+    let (Ok(old), Ok(new)) = (
+        std::str::from_utf8(previous),
+        std::str::from_utf8(&contents),
+    ) else {
+        warn!(
+            "Can't review the changes to {:?} hunk by hunk, since they aren't UTF-8. Applying all of them.",
+            target.target
+        );
+        return contents;
+    };
+
+    let palette = difference::Palette::current();
+    difference::apply_selected_hunks(
+        old,
+        new,
+        target.diff_algorithm,
+        diff_context_lines,
+        |hunk| {
+            review(&format!(
+                "{}Apply this hunk to {:?}? [y/N] ",
+                difference::format_hunk(hunk, &palette),
+                target.target
+            ))
+        },
+    )
+    .into_bytes()
+}
+
+/// Writes rendered contents to the cache, and from there to the target
+fn write_template(
+    source: &Path,
+    cache: &Path,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    contents: Vec<u8>,
+) -> Result<()> {
     // Cache
-    fs.create_dir_all(cache.parent().context("get parent of cache file")?, &None)
-        .context("create parent for cache file")?;
-    fs.write(cache, rendered)
+    fs.create_dir_all(
+        cache.parent().context("get parent of cache file")?,
+        &None,
+        None,
+    )
+    .context("create parent for cache file")?;
+    fs.write(cache, contents)
         .context("write rendered template to cache")?;
 
     // Target
     fs.copy_file(cache, &target.target, &target.owner)
         .context("copy template from cache to target")?;
-    fs.copy_permissions(source, &target.target, &target.owner)
-        .context("copy permissions from source to target")?;
+    fs.set_mode(
+        source,
+        &target.target,
+        target.mode,
+        target.executable_from_source,
+        &target.owner,
+    )
+    .context("set mode of target")?;
 
     Ok(())
 }
+
+/// Creates the parent directories of a target. A file that's in the way is an error, unless
+/// `force` is set: then it's moved aside to a `.bak` file
+fn create_parent(
+    fs: &mut dyn Filesystem,
+    target: &Path,
+    owner: &Option<UnixUser>,
+    dir_mode: Option<FileMode>,
+    force: bool,
+) -> Result<()> {
+    let parent = target.parent().context("get parent of target file")?;
+
+    // Otherwise creating the directories fails with a confusing "Not a directory"
+    let file = parent
+        .ancestors()
+        .find(|ancestor| std::fs::metadata(ancestor).is_ok_and(|metadata| !metadata.is_dir()));
+    if let Some(file) = file {
+        anyhow::ensure!(
+            force,
+            "cannot create {:?}: parent {:?} is a file, not a directory",
+            target,
+            file
+        );
+        let mut backup = file.as_os_str().to_owned();
+        backup.push(".bak");
+        let backup = Path::new(&backup);
+        anyhow::ensure!(
+            std::fs::symlink_metadata(backup).is_err(),
+            "cannot create {:?}: parent {:?} is a file, and its backup {:?} already exists",
+            target,
+            file,
+            backup
+        );
+        warn!(
+            "Creating {:?} but parent {:?} is a file. Forcing, it's backed up to {:?}.",
+            target, file, backup
+        );
+        fs.copy_file(file, backup, &None)
+            .context("back up file in place of parent directory")?;
+        fs.remove_file(file)
+            .context("remove file in place of parent directory")?;
+    }
+
+    fs.create_dir_all(parent, owner, dir_mode)
+        .context("create parent for target file")
+}
+
+/// Whether replacing the last deploy's contents with these would leave nothing but whitespace,
+/// which usually means that a variable the template relies on went missing
+fn erases_contents(contents: &[u8], previous: &[u8]) -> bool {
+    let blank = |contents: &[u8]| contents.iter().all(u8::is_ascii_whitespace);
+    blank(contents) && !blank(previous)
+}
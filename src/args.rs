@@ -1,13 +1,16 @@
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 
 /// A small dotfile manager.
 #[derive(Debug, Parser, Default, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct Options {
-    /// Location of the global configuration
+    /// Location of the global configuration, or `-` to read it from stdin, which assumes
+    /// --noconfirm. Only one of `-`, --patch and --vars-stdin can be given
     #[clap(
         short,
         long,
@@ -17,6 +20,17 @@ pub struct Options {
     )]
     pub global_config: PathBuf,
 
+    /// Global configuration that's merged over the global configuration, like the overrides of
+    /// one machine over a shared configuration. Can be repeated, later files override earlier
+    /// ones. Tables are merged key by key, everything else (arrays too) is replaced
+    #[clap(long = "config", value_name = "FILE", value_parser, global = true)]
+    pub config_overlays: Vec<PathBuf>,
+
+    /// Directory that relative paths in the global configuration are resolved against.
+    /// Defaults to the current directory
+    #[clap(long, value_parser, global = true)]
+    pub config_dir: Option<PathBuf>,
+
     /// Location of the local configuration
     #[clap(
         short,
@@ -27,14 +41,31 @@ pub struct Options {
     )]
     pub local_config: PathBuf,
 
-    /// Location of cache file
-    #[clap(long, value_parser, default_value = ".dotter/cache.toml")]
+    /// Location of cache file. Takes precedence over `cache_file` in the settings
+    #[clap(
+        long,
+        value_parser,
+        default_value = ".dotter/cache.toml",
+        global = true
+    )]
     pub cache_file: PathBuf,
 
-    /// Directory to cache into.
-    #[clap(long, value_parser, default_value = ".dotter/cache")]
+    /// Directory to cache into. Takes precedence over `cache_directory` in the settings
+    #[clap(long, value_parser, default_value = ".dotter/cache", global = true)]
     pub cache_directory: PathBuf,
 
+    /// Whether `cache_file` was given on the command line, and overrides the settings
+    #[clap(skip)]
+    pub cache_file_given: bool,
+
+    /// Whether `cache_directory` was given on the command line, and overrides the settings
+    #[clap(skip)]
+    pub cache_directory_given: bool,
+
+    /// Directory that git sources of packages are checked out into.
+    #[clap(long, value_parser, default_value = ".dotter/sources")]
+    pub sources_directory: PathBuf,
+
     /// Location of optional pre-deploy hook
     #[clap(long, value_parser, default_value = ".dotter/pre_deploy.sh")]
     pub pre_deploy: PathBuf,
@@ -65,20 +96,93 @@ pub struct Options {
     #[clap(short, long, value_parser, global = true)]
     pub quiet: bool,
 
-    /// Force - instead of skipping, overwrite target files if their content is unexpected.
+    /// Force - skip all safety checks, like --force-unchanged, --force-external and
+    /// --force-empty together.
     /// Overrides --dry-run.
     #[clap(short, long, value_parser, global = true)]
     pub force: bool,
 
-    /// Assume "yes" instead of prompting when removing empty directories
-    #[clap(short = 'y', long = "noconfirm", global = true)]
+    /// Rewrite templates even if their rendered contents didn't change since the last deploy
+    #[clap(long, value_parser, global = true)]
+    pub force_unchanged: bool,
+
+    /// Instead of skipping, overwrite and delete target files that were modified outside of
+    /// Dotter, and move files that are in place of a target's parent directory to a `.bak` file
+    #[clap(long, value_parser, global = true)]
+    pub force_external: bool,
+
+    /// Deploy templates that render to only whitespace over targets that had contents,
+    /// which is otherwise refused since it usually means a variable went missing
+    #[clap(long, value_parser, global = true)]
+    pub force_empty: bool,
+
+    /// Strict - treat references to undefined variables as errors, even if `strict = false` is
+    /// set in the settings
+    #[clap(long, value_parser, global = true)]
+    pub strict: bool,
+
+    /// Only warn instead of failing when multiple files are deployed to the same target
+    #[clap(long, value_parser, global = true)]
+    pub allow_conflicts: bool,
+
+    /// Don't color diffs and log messages. Also enabled by the NO_COLOR environment variable
+    #[clap(long = "no-color", visible_alias = "plain", global = true)]
+    pub no_color: bool,
+
+    /// Assume "yes" instead of prompting when removing empty directories, and deploy without
+    /// asking to confirm a summary of the changes first. Prompts are only shown on a terminal
+    #[clap(short = 'y', long = "noconfirm", visible_alias = "yes", global = true)]
     pub noconfirm: bool,
 
+    /// Write targets that have `privileged_write` set through the configured privilege helper
+    /// (`sudo` by default) when writing them as the current user is denied
+    #[clap(long, value_parser, global = true)]
+    pub allow_sudo: bool,
+
     /// Take standard input as an additional files/variables patch, added after evaluating
     /// `local.toml`. Assumes --noconfirm flag because all of stdin is taken as the patch.
     #[clap(short, long, value_parser, global = true)]
     pub patch: bool,
 
+    /// Read a JSON object of variables from standard input, like `{"theme": "dark"}`, and
+    /// overlay it over the configuration's variables. A variable can't change its type this way.
+    /// Assumes --noconfirm flag because all of stdin is taken as the variables.
+    #[clap(long, value_parser, global = true)]
+    pub vars_stdin: bool,
+
+    /// Print the diff of every template before overwriting it during a deploy, even without -v.
+    /// Suppressed by --quiet
+    #[clap(long, value_parser, global = true)]
+    pub diff: bool,
+
+    /// Only deploy the templates whose source was modified since the last deploy, skipping the
+    /// others without rendering them or reading their targets. Faster on large or slow
+    /// repositories, but targets that were changed outside of Dotter aren't noticed, and
+    /// neither are changes to variables, partials or the configuration
+    #[clap(long, value_parser, global = true)]
+    pub changed_sources_only: bool,
+
+    /// Time that the `now` helper renders instead of the current time, in RFC 3339 like
+    /// `2024-05-01T12:00:00Z`, so that repeated renders are identical. Defaults to the
+    /// SOURCE_DATE_EPOCH environment variable (seconds since the epoch) if that's set
+    #[clap(long, value_name = "TIME", global = true)]
+    pub now: Option<String>,
+
+    /// Unix domain socket that a deploy writes its events to, one JSON line per file action and
+    /// a summary at the end. Deploys normally if nothing is listening on it
+    #[clap(long, value_name = "PATH", global = true)]
+    pub notify_socket: Option<PathBuf>,
+
+    /// Only deploy or diff the file that's deployed to this target, like `~/.config/app`.
+    /// The other files are left alone, and their cache entries are kept
+    #[clap(long = "target", value_name = "PATH", global = true)]
+    pub only_target: Option<PathBuf>,
+
+    /// Number of threads that render and diff templates in parallel. Defaults to one per CPU,
+    /// 1 does everything in order on a single thread
+    #[clap(short, long, value_name = "N", global = true)]
+    pub jobs: Option<NonZeroUsize>,
+
     /// Amount of lines that are printed before and after a diff hunk.
     #[clap(long, value_parser, default_value = "3")]
     pub diff_context_lines: usize,
@@ -87,16 +191,108 @@ pub struct Options {
     pub action: Option<Action>,
 }
 
-#[derive(Debug, Clone, Subcommand, Default)]
+impl Options {
+    /// How many of the inputs read standard input: a global configuration of `-`, --patch or
+    /// --vars-stdin. It can only be read by one of them
+    pub fn stdin_inputs(&self) -> usize {
+        self.global_configs()
+            .iter()
+            .filter(|config| *config == Path::new("-"))
+            .count()
+            + usize::from(self.patch)
+            + usize::from(self.vars_stdin)
+    }
+
+    /// The global configuration, followed by the ones that are merged over it
+    pub fn global_configs(&self) -> Vec<PathBuf> {
+        std::iter::once(self.global_config.clone())
+            .chain(self.config_overlays.iter().cloned())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
 pub enum Action {
     /// Deploy the files to their respective targets. This is the default subcommand.
-    #[default]
-    Deploy,
+    Deploy(DeployOptions),
 
     /// Delete all deployed files from their target locations.
     /// Note that this operates on all files that are currently in cache.
+    /// With --dry-run, only lists the targets that would be removed.
     Undeploy,
 
+    /// Show the differences between the rendered templates and their currently deployed targets,
+    /// without changing anything. Exits with a failure status if any differences were found.
+    Diff(DiffOptions),
+
+    /// Check that all templates are valid Handlebars, without rendering them or touching their
+    /// targets. Exits with a failure status if any template fails to parse.
+    Check,
+
+    /// Print the groups of templates that render to byte-identical output, which could share
+    /// a source. Exits with a failure status if any template fails to render.
+    Dedup,
+
+    /// Print the resolved variables, or with `--unused` the variables that aren't referenced
+    Variables(VariablesOptions),
+
+    /// List every target with its source, package, type and whether it's currently deployed.
+    /// Nothing is written, and git sources aren't fetched.
+    List {
+        /// Print a table, or a JSON array for other tools
+        #[clap(long, value_enum, default_value_t = ListFormat::Text)]
+        format: ListFormat,
+    },
+
+    /// Print the effective configuration: the global and local configurations with includes,
+    /// inheritance and overlays merged, the files whose condition is false removed and the
+    /// variables resolved. Nothing is written
+    Config {
+        /// Print TOML, or JSON for other tools
+        #[clap(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+
+    /// Save the changes a deploy would make to a JSON file, with the templates already rendered.
+    /// The plan can be applied later - possibly on another machine - with `apply-plan`.
+    /// Like `deploy`, targets that aren't managed by Dotter or were changed since the last
+    /// deploy are left out, unless --force is given.
+    Plan {
+        /// File to write the plan to
+        output: PathBuf,
+    },
+
+    /// Apply a plan saved by `plan`. Targets that changed since the plan was made are skipped.
+    /// Unlike `deploy`, this doesn't read the configuration, but it records the targets in the
+    /// cache like a deploy.
+    ApplyPlan {
+        /// Plan file to apply
+        plan: PathBuf,
+    },
+
+    /// Show when the last successful deploy ran, which packages were selected and how many
+    /// files it changed
+    Status,
+
+    /// Show the last changes deploys made to a target, newest first: when, the hash of its
+    /// contents before and after, and which packages were selected
+    Log {
+        /// Target file to show the history of
+        target: PathBuf,
+    },
+
+    /// Print a JSON report of the state of every target and a hash of its contents.
+    /// Exits with 1 if any target isn't in sync.
+    /// Nothing is written: not the cache, not temporary files, and git sources aren't fetched.
+    /// Helpers that run commands still run them, and on Windows symlinks are assumed to be
+    /// available.
+    Audit,
+
+    /// Add the targets that already are what a deploy would make them to the cache, without
+    /// writing them, like after restoring them from a backup. Targets that differ are reported
+    /// and left for a deploy.
+    SyncCache,
+
     /// Initialize global.toml with a single package containing all the files in the current
     /// directory pointing to a dummy value and a local.toml that selects that package.
     Init,
@@ -118,14 +314,149 @@ pub enum Action {
     },
 }
 
+impl Default for Action {
+    fn default() -> Self {
+        Action::Deploy(DeployOptions::default())
+    }
+}
+
+#[derive(Debug, Clone, Args, Default)]
+pub struct DeployOptions {
+    /// With `patch`, a dry run writes the changes to the templates' targets as a single patch
+    /// that `git apply` can apply from the root directory, instead of printing them.
+    /// Symlinks can't be part of the patch, and like in a deploy, targets that aren't managed
+    /// by Dotter or were changed since the last deploy are left out unless forced
+    #[clap(long, value_enum, default_value_t = DeployFormat::Text)]
+    pub format: DeployFormat,
+
+    /// File to write the patch to, instead of stdout
+    #[clap(long, value_name = "FILE")]
+    pub out: Option<PathBuf>,
+
+    /// Ask for every hunk of an updated template whether to apply it, like `git add -p`.
+    /// Rejected hunks are left out of the target and offered again by the next deploy.
+    /// Only in an interactive deploy
+    #[clap(long)]
+    pub review: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DeployFormat {
+    #[default]
+    Text,
+    Patch,
+}
+
+#[derive(Debug, Clone, Args, Default)]
+pub struct DiffOptions {
+    /// Override a variable for this diff only, e.g. `--set theme=dark`. Dotted keys address
+    /// nested tables, and values are parsed as TOML when possible (falling back to a string).
+    /// Can be specified multiple times.
+    #[clap(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Only print the amount of added and removed lines per file, and a total
+    #[clap(long)]
+    pub stat: bool,
+
+    /// Don't print anything for files without changes, not even the header that's printed
+    /// before running a `diff.command`. Doesn't affect the exit status
+    #[clap(long)]
+    pub only_changed: bool,
+
+    /// Diff against the output of the last deploy, which is kept in the cache, instead of the
+    /// targets. Shows what a deploy would change because of changes to the repository,
+    /// regardless of edits to the targets. `diff.command` isn't used in this mode
+    #[clap(long)]
+    pub since_last_deploy: bool,
+    /// Group the diffs by package, under a header with the number of changed files in the
+    /// package. Packages are in deploy order, followed by the files of the local configuration.
+    /// `diff.command` isn't used in this mode
+    #[clap(long)]
+    pub by_package: bool,
+
+    /// Show changes inline as running text, like `git diff --word-diff`: changed words are
+    /// colored, or marked as `[-removed-]` and `{+added+}` with `--no-color`.
+    /// `diff.command` isn't used in this mode
+    #[clap(long, conflicts_with = "stat")]
+    pub word_diff: bool,
+
+    /// Only show the files of these statuses, like `git diff --diff-filter`: `A` for new
+    /// targets, `M` for modified ones and `T` for ones that aren't a file anymore.
+    /// Can be combined, like `AM`. Files that are left out don't affect the exit status
+    #[clap(long, value_name = "STATUSES", value_parser = parse_diff_filter)]
+    pub diff_filter: Option<DiffFilter>,
+}
+
+/// The statuses of files that `dotter diff` shows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffFilter {
+    pub added: bool,
+    pub modified: bool,
+    pub type_changed: bool,
+}
+
+pub fn parse_diff_filter(letters: &str) -> Result<DiffFilter, String> {
+    let mut filter = DiffFilter::default();
+    for letter in letters.chars() {
+        match letter {
+            'A' => filter.added = true,
+            'M' => filter.modified = true,
+            'T' => filter.type_changed = true,
+            _ => {
+                return Err(format!(
+                    "unknown status {:?}, expected A (added), M (modified) or T (type changed)",
+                    letter
+                ))
+            }
+        }
+    }
+    if letters.is_empty() {
+        return Err("no status given".into());
+    }
+    Ok(filter)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Debug, Clone, Args, Default)]
+pub struct VariablesOptions {
+    /// List the variables that no active template, partial, hook or `if` condition references.
+    /// References are found by parsing the templates, so variables that are only accessed
+    /// dynamically (for example through `lookup`) are listed as well.
+    #[clap(long)]
+    pub unused: bool,
+
+    /// Never list this variable (or the variables below it) as unused. Can be repeated
+    #[clap(long, value_name = "VARIABLE")]
+    pub ignore: Vec<String>,
+}
+
 pub fn get_options() -> Options {
-    let mut opt = Options::parse();
+    let matches = Options::command().get_matches();
+    let mut opt = Options::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let given = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+    opt.cache_file_given = given("cache_file");
+    opt.cache_directory_given = given("cache_directory");
     if opt.dry_run {
         opt.verbosity = std::cmp::max(opt.verbosity, 1);
     }
     opt.verbosity = std::cmp::min(3, opt.verbosity);
-    if opt.patch {
+    if opt.stdin_inputs() > 0 {
         opt.noconfirm = true;
     }
+    if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        opt.no_color = true;
+    }
     opt
 }
@@ -0,0 +1,283 @@
+use anyhow::{Context, Result};
+use handlebars::Handlebars;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, Cache, SymbolicTarget, TemplateTarget, Variables};
+use crate::display_error;
+use crate::encoding;
+use crate::filesystem;
+use crate::handlebars_helpers::render_target;
+use crate::plan::{sha256, TargetState};
+
+/// The sync state of every target, determined without writing anything.
+/// `sha256` is a hash of the serialized entries, so that a stored report can be checked
+/// for modifications.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditReport {
+    pub entries: Vec<AuditEntry>,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub kind: AuditKind,
+    pub status: AuditStatus,
+    /// Hash of the target's contents, if it's a file
+    pub sha256: Option<String>,
+    /// Why the status couldn't be determined
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    Symlink,
+    Template,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditStatus {
+    /// The target is what a deploy would make it
+    InSync,
+    /// The target differs from what a deploy would make it
+    Changed,
+    /// The target doesn't exist
+    Missing,
+    /// The target was deployed before but isn't in the configuration anymore
+    Stale,
+    /// The state couldn't be determined, for example because the template failed to render
+    Error,
+}
+
+impl AuditReport {
+    pub fn new(
+        symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
+        templates: &BTreeMap<PathBuf, TemplateTarget>,
+        cache: &Cache,
+        handlebars: &Handlebars<'static>,
+        variables: &Variables,
+    ) -> Result<AuditReport> {
+        let mut entries = Vec::new();
+
+        for (source, target) in symlinks {
+            entries.push(AuditEntry::new(
+                source,
+                &target.target,
+                AuditKind::Symlink,
+                || {
+                    let source =
+                        filesystem::real_path(source).context("get real path of source")?;
+                    Ok(TargetState::Symlink { points_to: source })
+                },
+            ));
+        }
+
+        for (source, target) in templates {
+            entries.push(AuditEntry::new(
+                source,
+                &target.target,
+                AuditKind::Template,
+                || {
+                    Ok(TargetState::File {
+                        sha256: rendered_sha256(source, target, handlebars, variables)?,
+                    })
+                },
+            ));
+        }
+
+        // Targets of the last deploy that wouldn't be deployed anymore
+        let stale_symlinks = cache
+            .symlinks
+            .iter()
+            .filter(|(source, target)| {
+                symlinks.get(*source).map(|desired| &desired.target) != Some(target)
+            })
+            .map(|entry| (AuditKind::Symlink, entry));
+        let stale_templates = cache
+            .templates
+            .iter()
+            .filter(|(source, target)| {
+                templates.get(*source).map(|desired| &desired.target) != Some(target)
+            })
+            .map(|entry| (AuditKind::Template, entry));
+        for (kind, (source, target)) in stale_symlinks.chain(stale_templates) {
+            let mut entry = AuditEntry::new(source, target, kind, || Ok(TargetState::Missing));
+            entry.status = AuditStatus::Stale;
+            entries.push(entry);
+        }
+
+        let sha256 = sha256(
+            serde_json::to_string(&entries)
+                .context("serialize entries")?
+                .as_bytes(),
+        );
+        Ok(AuditReport { entries, sha256 })
+    }
+
+    /// Whether every target is in sync
+    pub fn in_sync(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.status == AuditStatus::InSync)
+    }
+}
+
+/// A target of the configuration, for `dotter list`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InventoryEntry {
+    pub target: PathBuf,
+    pub source: PathBuf,
+    /// The package the source comes from, or None for the local configuration's files
+    pub package: Option<String>,
+    pub kind: AuditKind,
+    /// Whether the last deploy deployed the source to the target, according to the cache
+    pub deployed: bool,
+}
+
+/// Every target of the configuration, sorted by target
+pub fn inventory(
+    symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
+    templates: &BTreeMap<PathBuf, TemplateTarget>,
+    file_packages: &BTreeMap<PathBuf, String>,
+    cache: &Cache,
+) -> Vec<InventoryEntry> {
+    let symlinks = symlinks
+        .iter()
+        .map(|(source, target)| (source, &target.target, AuditKind::Symlink, &cache.symlinks));
+    let templates = templates.iter().map(|(source, target)| {
+        (
+            source,
+            &target.target,
+            AuditKind::Template,
+            &cache.templates,
+        )
+    });
+    let mut entries = symlinks
+        .chain(templates)
+        .map(|(source, target, kind, cached)| InventoryEntry {
+            target: target.clone(),
+            source: source.clone(),
+            package: config::file_package(file_packages, source).cloned(),
+            kind,
+            deployed: cached.get(source) == Some(target),
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.target.cmp(&b.target));
+    entries
+}
+
+/// Hash of the contents a deploy would write to the template's target
+fn rendered_sha256(
+    source: &Path,
+    target: &TemplateTarget,
+    handlebars: &Handlebars<'static>,
+    variables: &Variables,
+) -> Result<String> {
+    let contents = fs::read_to_string(source).context("read template source file")?;
+    let rendered = render_target(handlebars, source, target, contents, variables)?;
+    Ok(sha256(&encoding::encode(rendered, target.encoding)?))
+}
+
+/// Templates whose rendered output is byte-identical, grouped by its hash.
+/// Only groups of more than one template are returned. Templates that fail to render are
+/// left out, and the returned bool is true if an error was printed
+pub fn identical_outputs(
+    templates: &BTreeMap<PathBuf, TemplateTarget>,
+    handlebars: &Handlebars<'static>,
+    variables: &Variables,
+) -> (BTreeMap<String, Vec<PathBuf>>, bool) {
+    let mut groups = BTreeMap::<String, Vec<PathBuf>>::new();
+    let mut error_occurred = false;
+    for (source, target) in templates {
+        match rendered_sha256(source, target, handlebars, variables) {
+            Ok(sha256) => groups.entry(sha256).or_default().push(source.clone()),
+            Err(e) => {
+                display_error(e.context(format!("render template {:?}", source)));
+                error_occurred = true;
+            }
+        }
+    }
+    groups.retain(|_, sources| sources.len() > 1);
+    (groups, error_occurred)
+}
+
+impl AuditEntry {
+    /// Compares the target's current state to the one `desired` returns
+    fn new(
+        source: &Path,
+        target: &Path,
+        kind: AuditKind,
+        desired: impl FnOnce() -> Result<TargetState>,
+    ) -> AuditEntry {
+        let mut entry = AuditEntry {
+            source: source.into(),
+            target: target.into(),
+            kind,
+            status: AuditStatus::Error,
+            sha256: None,
+            error: None,
+        };
+
+        let current = match TargetState::of(target).context("get state of target") {
+            Ok(current) => current,
+            Err(e) => {
+                entry.error = Some(format!("{:#}", e));
+                return entry;
+            }
+        };
+        if let TargetState::File { sha256 } = &current {
+            entry.sha256 = Some(sha256.clone());
+        }
+
+        entry.status = match desired() {
+            Ok(_) if current == TargetState::Missing => AuditStatus::Missing,
+            Ok(desired) if desired == current => AuditStatus::InSync,
+            Ok(_) => AuditStatus::Changed,
+            Err(e) => {
+                entry.error = Some(format!("{:#}", e));
+                AuditStatus::Error
+            }
+        };
+        entry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identical_outputs_are_grouped() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        let c = dir.path().join("c");
+        fs::write(&a, "theme = {{theme}}\n").unwrap();
+        fs::write(&b, "theme = dark\n").unwrap();
+        fs::write(&c, "theme = light\n").unwrap();
+
+        let templates = maplit::btreemap! {
+            a.clone() => TemplateTarget::from(dir.path().join("out/a")),
+            b.clone() => TemplateTarget::from(dir.path().join("out/b")),
+            c => TemplateTarget::from(dir.path().join("out/c")),
+        };
+        let mut variables = Variables::new();
+        variables.insert("theme".into(), "dark".into());
+
+        let (groups, error_occurred) =
+            identical_outputs(&templates, &Handlebars::new(), &variables);
+        assert!(!error_occurred);
+        assert_eq!(
+            groups.into_iter().collect::<Vec<_>>(),
+            vec![(sha256(b"theme = dark\n"), vec![a, b])]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+
+/// Comment syntax that can be stripped from rendered templates
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentStyle {
+    /// `// line` and `/* block */` comments, outside of strings
+    Json,
+    /// `# comments`, outside of quotes
+    Hash,
+    /// `; comments`, outside of quotes
+    Semicolon,
+}
+
+/// Removes comments of the given style.
+/// Lines that only contained a comment are removed entirely,
+/// trailing comments are removed along with the whitespace before them.
+pub fn strip_comments(text: &str, style: CommentStyle) -> String {
+    let mut in_block_comment = false;
+    let mut lines = Vec::new();
+
+    for (index, line) in text.split('\n').enumerate() {
+        // Keep shebangs, they're not comments
+        if style == CommentStyle::Hash && index == 0 && line.starts_with("#!") {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let (stripped, removed) = match style {
+            CommentStyle::Json => strip_json_line(line, &mut in_block_comment),
+            CommentStyle::Hash => strip_line(line, '#'),
+            CommentStyle::Semicolon => strip_line(line, ';'),
+        };
+
+        if !removed {
+            lines.push(stripped);
+        } else if !stripped.trim().is_empty() {
+            lines.push(stripped.trim_end().to_string());
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Returns the line up to the first marker that isn't quoted, and whether anything was cut
+fn strip_line(line: &str, marker: char) -> (String, bool) {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (position, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == marker => return (line[..position].to_string(), true),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => {}
+        }
+    }
+
+    (line.to_string(), false)
+}
+
+fn strip_json_line(line: &str, in_block_comment: &mut bool) -> (String, bool) {
+    let mut output = String::new();
+    let mut removed = false;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if *in_block_comment {
+            removed = true;
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                *in_block_comment = false;
+            }
+        } else if in_string {
+            output.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '/' && chars.peek() == Some(&'/') {
+            removed = true;
+            break;
+        } else if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            removed = true;
+            *in_block_comment = true;
+        } else {
+            if c == '"' {
+                in_string = true;
+            }
+            output.push(c);
+        }
+    }
+
+    (output, removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_comments() {
+        let input = "#!/bin/sh\n# whole line\nexport A=1 # trailing\nexport B=\"x # y\" # z\nexport C='#'\n\n";
+        assert_eq!(
+            strip_comments(input, CommentStyle::Hash),
+            "#!/bin/sh\nexport A=1\nexport B=\"x # y\"\nexport C='#'\n\n"
+        );
+    }
+
+    #[test]
+    fn semicolon_comments() {
+        let input = "; whole line\n[section]\nkey = value ; trailing\nquoted = \"a;b\"\n";
+        assert_eq!(
+            strip_comments(input, CommentStyle::Semicolon),
+            "[section]\nkey = value\nquoted = \"a;b\"\n"
+        );
+    }
+
+    #[test]
+    fn json_comments() {
+        let input = r#"{
+  // whole line
+  "url": "https://example.com", // trailing
+  /* block
+     over lines */
+  "a": /* inline */ 1,
+  "escaped": "quote \" // not a comment"
+}
+"#;
+        assert_eq!(
+            strip_comments(input, CommentStyle::Json),
+            r#"{
+  "url": "https://example.com",
+  "a":  1,
+  "escaped": "quote \" // not a comment"
+}
+"#
+        );
+    }
+}
@@ -1,11 +1,21 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::comments::{self, CommentStyle};
+use crate::difference::DiffAlgorithm;
+use crate::encoding::Encoding;
 use crate::filesystem;
+use crate::git;
+use crate::handlebars_helpers::RenderCache;
+use crate::merge::{self, MergeFormat};
+use crate::plan::TargetState;
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fs;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(untagged)]
@@ -14,6 +24,11 @@ pub enum UnixUser {
     Name(String),
 }
 
+/// Unix permission bits, written in octal like `"0700"`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileMode(pub u32);
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(deny_unknown_fields)]
 pub struct SymbolicTarget {
@@ -22,9 +37,15 @@ pub struct SymbolicTarget {
     pub recurse: Option<bool>,
     #[serde(rename = "if")]
     pub condition: Option<String>,
+    /// Mode of parent directories that are created for the target,
+    /// overriding `dir_mode` in the settings
+    pub dir_mode: Option<FileMode>,
+    /// Targets by package, see `FileTarget::choose_target`
+    #[serde(skip)]
+    pub target_choices: BTreeMap<String, PathBuf>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 #[serde(deny_unknown_fields)]
 pub struct TemplateTarget {
     pub target: PathBuf,
@@ -33,15 +54,75 @@ pub struct TemplateTarget {
     pub prepend: Option<String>,
     #[serde(rename = "if")]
     pub condition: Option<String>,
+    /// Mode of parent directories that are created for the target,
+    /// overriding `dir_mode` in the settings
+    pub dir_mode: Option<FileMode>,
+    /// Mode of the target, used as is. If unset, the target gets the source's mode with the
+    /// umask applied
+    pub mode: Option<FileMode>,
+    /// Give the target only the executable bits of the source, with the umask applied to
+    /// everything else. Ignored if `mode` is set
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub executable_from_source: bool,
+    /// Prepend a "managed by Dotter" comment, overriding `banner` in the settings
+    pub banner: Option<bool>,
+    /// Remove comments of this style from the rendered output
+    pub strip_comments: Option<CommentStyle>,
+    /// Deep-merge the rendered output into the target's current contents in this format,
+    /// instead of replacing them, so that keys that were added to the target are kept
+    pub merge: Option<MergeFormat>,
+    /// Command that formats the rendered output, like `shfmt -i 2`. It's split on whitespace,
+    /// gets the output on stdin, and its stdout is what's written and diffed instead
+    pub format_command: Option<String>,
+    /// External command that shows the diff, overriding `diff.command` in the settings
+    pub diff_command: Option<String>,
+    /// Regexes of lines that are never shown as changed in diffs, like a timestamp that's
+    /// different on every render
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diff_ignore_lines: Vec<String>,
+    /// Leave the file out of `dotter diff` and its exit status, like a lockfile that's expected
+    /// to differ. It's still deployed
+    pub diff_ignore: Option<bool>,
+    /// Write the target through the privilege helper if a direct write is denied,
+    /// overriding `privileged_write` in the settings
+    pub privileged_write: Option<bool>,
+    /// Encoding of the target, applied after rendering. For keeping binary files as text,
+    /// or writing text for tools that don't understand UTF-8
+    pub encoding: Option<Encoding>,
+    /// Variables that are merged over the configuration's variables when rendering this file
+    #[serde(default, skip_serializing_if = "Variables::is_empty")]
+    pub variables: Variables,
+    /// Opening and closing delimiters of expressions, like `["<%", "%>"]`,
+    /// overriding `delimiters` in the settings
+    pub delimiters: Option<Box<[String; 2]>>,
+    /// Targets by package, see `FileTarget::choose_target`
+    #[serde(skip)]
+    pub target_choices: BTreeMap<String, PathBuf>,
+    /// The banner comment, resolved from `banner` and the target's file type
+    #[serde(skip)]
+    pub banner_comment: Option<String>,
+    /// Largest file size in bytes that diffs are generated for, resolved from the settings
+    #[serde(skip)]
+    pub max_diff_size: Option<u64>,
+    /// How long rendering may take, resolved from the settings
+    #[serde(skip)]
+    pub render_timeout: Option<Duration>,
+    /// How diffs are computed, resolved from the settings
+    #[serde(skip)]
+    pub diff_algorithm: DiffAlgorithm,
+    /// The last render of this file, shared by its clones
+    #[serde(skip)]
+    pub render_cache: RenderCache,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
-#[serde(from = "FileTargetOuterRepr", into = "FileTargetOuterRepr")]
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(into = "FileTargetOuterRepr")]
 pub enum FileTarget {
     Automatic(PathBuf),
     Symbolic(SymbolicTarget),
+    /// Boxed, since a template target is much larger than the other variants
     #[serde(rename = "template")]
-    ComplexTemplate(TemplateTarget),
+    ComplexTemplate(Box<TemplateTarget>),
 }
 
 // Shims to allow Serde to represent FileTarget::Automatic as untagged while the
@@ -58,19 +139,305 @@ enum FileTargetOuterRepr {
 enum FileTargetInnerRepr {
     Symbolic(SymbolicTarget),
     #[serde(rename = "template")]
-    ComplexTemplate(TemplateTarget),
+    ComplexTemplate(Box<TemplateTarget>),
 }
 
 pub type Files = BTreeMap<PathBuf, FileTarget>;
+/// Tables are sorted by key, so `{{#each}}` over them renders in the same order on every run,
+/// whatever the order of the configuration
 pub type Variables = toml::value::Table;
 #[cfg(feature = "scripting")]
 pub type Helpers = BTreeMap<String, PathBuf>;
+pub type Partials = BTreeMap<String, Partial>;
+
+/// A Handlebars partial, usable from templates as `{{> name}}`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Partial {
+    File(PathBuf),
+    Inline { content: String },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Report references to undefined variables as errors instead of rendering them as empty.
+    /// Can be forced on from the command line with `--strict`.
+    pub strict: bool,
+    /// Prepend a "managed by Dotter" comment to every template, in the comment syntax of the
+    /// target's file type. Can be overridden per file.
+    pub banner: bool,
+    /// Comment syntax for banners, by file extension (or file name without the leading dot, for
+    /// files without an extension). `{}` is replaced by the banner text, otherwise it's appended.
+    /// Takes precedence over the built-in comment syntaxes.
+    pub banner_comments: BTreeMap<String, String>,
+    /// Mode of parent directories that are created for targets, used as is. Directories that
+    /// already exist are left alone. If unset, they get `0777` with the umask applied.
+    pub dir_mode: Option<FileMode>,
+    pub diff: DiffSettings,
+    /// Allow packages to take their files from a git repository with `source = { git = "..." }`.
+    /// Off by default, since deploying then runs git and may access the network.
+    pub git_sources: bool,
+    /// Only warn instead of failing when multiple files are deployed to the same target.
+    /// Can be forced on from the command line with `--allow-conflicts`.
+    pub allow_conflicts: bool,
+    /// Seconds after which rendering a template is aborted, so that a runaway template doesn't
+    /// hang Dotter. 0 disables the limit.
+    pub render_timeout: f64,
+    pub hooks: HookSettings,
+    pub commands: CommandSettings,
+    /// Shell that runs the commands of `command_success` and `command_output`, and hooks which
+    /// aren't executable, like `/bin/zsh`. It's split on whitespace and given the command
+    /// with `-c` (`/C` for cmd, `-Command` for PowerShell). Defaults to `$SHELL`, or `sh` if
+    /// that isn't set. On Windows, it defaults to `cmd`, and hooks are run as batch files
+    pub shell: Option<String>,
+    /// Directory of executables that are registered as helpers named after their file name
+    /// without extension. `{{name a b}}` runs the executable with `a` and `b` as arguments and
+    /// the hash arguments as a JSON object on stdin, and renders its stdout.
+    pub helpers_dir: Option<PathBuf>,
+    /// Allow registering the executables in `helpers_dir` as helpers.
+    /// Off by default, since rendering then runs them.
+    pub external_helpers: bool,
+    /// Create symlinks with the path to the source relative to the link's directory instead of
+    /// an absolute one, so that they keep working when both are moved together.
+    /// Existing links to the source are left as they are. `apply-plan` always creates absolute
+    /// links, since it doesn't read the settings.
+    pub relative_symlinks: bool,
+    /// What to do on Windows when there's no permission to create symlinks
+    /// (which needs Developer Mode)
+    pub windows_symlink_fallback: SymlinkFallback,
+    /// Location of the cache file, for example to keep it out of a read-only repository.
+    /// `~` and environment variables are expanded. `--cache-file` takes precedence.
+    pub cache_file: Option<PathBuf>,
+    /// Directory that deployed templates are cached into, expanded like `cache_file`.
+    /// `--cache-directory` takes precedence.
+    pub cache_directory: Option<PathBuf>,
+    /// Number of copies of the previous cache that a deploy keeps before saving the new one,
+    /// as a recovery point if the cache is lost or a deploy goes wrong. The latest is next to
+    /// the cache file with `.bak` appended, older ones get `.bak.1`, `.bak.2` and so on.
+    /// They're only rotated when the deployed files or their history change. Only the cache
+    /// file is backed up, not the rendered templates in the cache directory. 0 keeps none.
+    pub cache_backups: usize,
+    /// Directories that targets have to be inside of, like `["~", "/etc/nginx"]`, to catch
+    /// misconfigured targets before anything is written. `..` and symlinks are resolved before
+    /// checking, so they can't lead outside of them. If empty, targets can be anywhere.
+    pub allowed_target_roots: Vec<PathBuf>,
+    /// `.env` files whose `KEY=value` lines are added to the variables as strings, by their
+    /// key. `~` and environment variables are expanded in the paths. They override the
+    /// packages' variables, and are overridden by the ones of the local configuration.
+    pub env_files: Vec<PathBuf>,
+    /// Opening and closing delimiters of template expressions instead of `{{` and `}}`,
+    /// like `["<%", "%>"]`, for targets that use braces themselves. Handlebars doesn't support
+    /// other delimiters, so templates are translated before rendering: literal `{{` is escaped,
+    /// and the delimiters are replaced by braces. Blocks, partials and comments use them too:
+    /// `<%#if x%>`, `<%> name%>`. This also applies to `prepend` and `append`, but not to
+    /// partials, which keep the standard delimiters
+    pub delimiters: Option<[String; 2]>,
+    /// Put the variables of each package under the package's name, like `{{nvim.color}}`
+    /// instead of `{{color}}`, so that packages can use the same names without colliding.
+    /// Variables of the local configuration stay as they are, so they override a package's
+    /// variables in its table: `[variables.nvim]`
+    pub namespace_variables: bool,
+    /// Target directories that are deployed from a source directory, like `["~/.config/nvim"]`,
+    /// whose files that aren't in the source directory `dotter diff` warns about, like leftovers
+    /// of removed files. Each directory has to be listed, since other programs usually keep files
+    /// in the same directory too. `~` and environment variables are expanded. Directories that
+    /// contain the home directory aren't checked
+    pub report_untracked: Vec<PathBuf>,
+    /// Write templates whose targets can't be written as the current user, like files in
+    /// `/etc`, through `privilege_helper` instead. Only with `--allow-sudo`, and only after a
+    /// direct write was denied. Can be set per file too
+    pub privileged_write: bool,
+    /// Command that runs a command as root for `privileged_write`, like `sudo` or `doas`.
+    /// It's split on whitespace
+    pub privilege_helper: String,
+    /// Constraints on the values of variables, checked once all variables are merged, like
+    /// `dpi = { type = "integer", min = 72, max = 400 }`. Nested variables are addressed by
+    /// their dotted path, like `"nvim.theme"`
+    pub variable_schema: BTreeMap<String, VariableConstraint>,
+    /// Type of files that are given without one, by the target's file extension, like
+    /// `sh = "template"` to copy shell scripts instead of symlinking them. Files without a
+    /// matching extension are templates if they contain template syntax, like before
+    pub default_type_by_extension: BTreeMap<String, DefaultFileType>,
+}
+
+/// What a variable's value has to be
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VariableConstraint {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<VariableType>,
+    /// Smallest allowed value of a number
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    /// Largest allowed value of a number
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    /// The allowed values, if not empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub one_of: Vec<toml::Value>,
+    /// Whether the variable has to be defined. Constraints of undefined variables are only
+    /// checked if it's set
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VariableType {
+    String,
+    Integer,
+    Float,
+    Boolean,
+    Array,
+    Table,
+}
+
+impl VariableType {
+    fn name(self) -> &'static str {
+        match self {
+            VariableType::String => "string",
+            VariableType::Integer => "integer",
+            VariableType::Float => "float",
+            VariableType::Boolean => "boolean",
+            VariableType::Array => "array",
+            VariableType::Table => "table",
+        }
+    }
+}
+
+impl VariableConstraint {
+    /// Why `value` violates the constraint, or None if it doesn't
+    fn violation(&self, value: Option<&toml::Value>) -> Option<String> {
+        let value = match value {
+            Some(value) => value,
+            None if self.required => return Some("is required but isn't defined".into()),
+            None => return None,
+        };
+
+        if let Some(kind) = self.kind {
+            if value.type_str() != kind.name() {
+                return Some(format!(
+                    "must be of type {}, but {} is of type {}",
+                    kind.name(),
+                    value,
+                    value.type_str()
+                ));
+            }
+        }
+
+        if self.min.is_some() || self.max.is_some() {
+            let number = match value {
+                toml::Value::Integer(i) => *i as f64,
+                toml::Value::Float(f) => *f,
+                _ => return Some(format!("must be a number, but is {}", value)),
+            };
+            if let Some(min) = self.min.filter(|min| number < *min) {
+                return Some(format!("must be at least {}, but is {}", min, value));
+            }
+            if let Some(max) = self.max.filter(|max| number > *max) {
+                return Some(format!("must be at most {}, but is {}", max, value));
+            }
+        }
+
+        if !self.one_of.is_empty() && !self.one_of.contains(value) {
+            let allowed = self
+                .one_of
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Some(format!("must be one of {}, but is {}", allowed, value));
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiffSettings {
+    /// Templates whose source or target is larger than this many bytes aren't diffed,
+    /// so a huge file doesn't have to be loaded into memory twice
+    pub max_file_size: u64,
+    /// External command to show diffs with instead of the built-in line diff, like
+    /// `git diff --no-index`. It's split on whitespace, and the target and the rendered
+    /// template are appended as paths. Exit status 0 means no differences, 1 means differences.
+    pub command: Option<String>,
+    /// Algorithm for the built-in line diff. `patience` aligns moved blocks and repeated lines
+    /// better than the default `lcs`
+    pub algorithm: DiffAlgorithm,
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSettings {
+    pub on_failure: HookFailure,
+    /// How often a failing hook is run again before `on_failure` applies, for hooks that call
+    /// flaky things like the network
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry, doubled before each further one
+    pub retry_delay_ms: u64,
+    /// Shell that runs hooks which aren't executable, instead of `shell` in the settings
+    pub shell: Option<String>,
+}
+
+/// Retries of the commands that the `command_output` helper runs
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandSettings {
+    /// How often a command that exits with an error is run again. Once they're exhausted, the
+    /// output of the last run is rendered as usual
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry, doubled before each further one
+    pub retry_delay_ms: u64,
+}
+
+/// What to do when a hook exits with an error
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    /// Stop immediately and exit with an error
+    #[default]
+    Abort,
+    /// Log the error and keep going, but exit with an error at the end
+    Warn,
+    /// Log the error and keep going
+    Continue,
+}
+
+/// Type of a file that's given as just a target, see `default_type_by_extension`
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultFileType {
+    Symbolic,
+    Template,
+}
+
+/// How symlinks are replaced when they can't be created
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkFallback {
+    /// Copy the sources instead, like templates
+    #[default]
+    Copy,
+    /// Link directories with junctions, which don't need any permission, and copy files
+    Junction,
+    /// Exit with an error
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Configuration {
     pub files: Files,
+    /// The package each file comes from, for error messages.
+    /// Files added by the local configuration aren't included.
+    pub file_packages: BTreeMap<PathBuf, String>,
+    /// The stage each enabled package is deployed in, see `Package::after`
+    pub package_stages: BTreeMap<String, usize>,
     pub variables: Variables,
     pub packages: BTreeMap<String, bool>,
+    pub settings: Settings,
+    pub partials: Partials,
 
     #[cfg(feature = "scripting")]
     pub helpers: Helpers,
@@ -80,21 +447,164 @@ pub struct Configuration {
     /// turned into a list of all the files inside the structure that
     /// are readable.
     pub recurse: bool,
+    /// Target directories whose files are all deployed from a source directory that's recursed,
+    /// with that directory
+    pub owned_directories: BTreeMap<PathBuf, PathBuf>,
+}
+
+/// A problem that keeps a configuration from being deployed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A package that doesn't exist is selected by the local configuration, or referenced by
+    /// another package with a relation like "extends"
+    UnknownPackage {
+        name: String,
+        referenced_by: Option<(String, &'static str)>,
+    },
+    /// A file has a target for a package that doesn't exist in its table of targets by package
+    UnknownTargetPackage { name: String, source: PathBuf },
+    /// Packages that extend each other, from the first one back to itself
+    InheritanceCycle(Vec<String>),
+    /// Packages that are deployed after each other, from the first one back to itself
+    DeployOrderCycle(Vec<String>),
+    /// The source of a file doesn't exist. `package` is None for the local configuration
+    MissingSource {
+        source: PathBuf,
+        package: Option<String>,
+    },
+    /// More than one source is deployed to the same target
+    CollidingTarget {
+        target: PathBuf,
+        sources: Vec<PathBuf>,
+    },
+    /// A variable violates its constraint in `variable_schema`
+    InvalidVariable { name: String, violation: String },
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::UnknownPackage {
+                name,
+                referenced_by: None,
+            } => write!(f, "local configuration selects unknown package {:?}", name),
+            ValidationError::UnknownPackage {
+                name,
+                referenced_by: Some((package, relation)),
+            } => write!(
+                f,
+                "package {:?} {} unknown package {:?}",
+                package, relation, name
+            ),
+            ValidationError::UnknownTargetPackage { name, source } => write!(
+                f,
+                "file {:?} has a target for unknown package {:?}",
+                source, name
+            ),
+            ValidationError::InheritanceCycle(cycle) => {
+                write!(f, "inheritance cycle: {}", cycle.join(" -> "))
+            }
+            ValidationError::DeployOrderCycle(cycle) => {
+                write!(f, "deploy order cycle: {}", cycle.join(" -> "))
+            }
+            ValidationError::MissingSource {
+                source,
+                package: Some(package),
+            } => write!(
+                f,
+                "source file {:?} not found (referenced by package {:?})",
+                source, package
+            ),
+            ValidationError::MissingSource {
+                source,
+                package: None,
+            } => write!(
+                f,
+                "source file {:?} not found (referenced by the local configuration)",
+                source
+            ),
+            ValidationError::CollidingTarget { target, sources } => write!(
+                f,
+                "multiple files are deployed to {:?}: {:?}",
+                target, sources
+            ),
+            ValidationError::InvalidVariable { name, violation } => {
+                write!(f, "variable {:?} {}", name, violation)
+            }
+        }
+    }
 }
 
+impl std::error::Error for ValidationError {}
+
 #[derive(Debug, Deserialize, Serialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Package {
+    /// Take the package's files from a git repository instead of the dotfiles directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<GitSource>,
+    /// Set to false to ignore the package, even if it's selected or depended on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enabled: Option<bool>,
     #[serde(default)]
     depends: Vec<String>,
+    /// Packages whose files are deployed before this package's files.
+    /// Unlike `depends`, this doesn't enable them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    after: Vec<String>,
+    /// Packages whose files and variables this package inherits and can override
+    #[serde(default, deserialize_with = "one_or_many")]
+    extends: Vec<String>,
+    /// Directory that the package's relative targets are resolved against, instead of the
+    /// current directory. Applies to the files the package inherits too, but not to absolute
+    /// targets or ones starting with `~`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_root: Option<PathBuf>,
     #[serde(default)]
     files: Files,
     #[serde(default)]
     variables: Variables,
+    /// The ancestor each file that's inherited through `extends` comes from
+    #[serde(skip)]
+    inherited_files: BTreeMap<PathBuf, String>,
+    /// The ancestor each variable that's inherited through `extends` comes from
+    #[serde(skip)]
+    inherited_variables: BTreeMap<String, String>,
+}
+
+/// Accepts either a single string or a list of them
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GitSource {
+    /// URL (or path) of the repository
+    pub git: String,
+    /// Branch, tag or commit to check out. Defaults to the remote's default branch
+    pub rev: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct GlobalConfig {
+    #[serde(default)]
+    settings: Settings,
+    #[serde(default)]
+    partials: Partials,
     #[serde(default)]
     #[cfg(feature = "scripting")]
     helpers: Helpers,
@@ -118,12 +628,15 @@ struct LocalConfig {
 
 pub fn load_configuration(
     local_config: &Path,
-    global_config: &Path,
+    global_configs: &[PathBuf],
+    config_dir: Option<&Path>,
+    sources_directory: &Path,
     patch: Option<Package>,
 ) -> Result<Configuration> {
-    let global: GlobalConfig = filesystem::load_file(global_config)
-        .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
-        .with_context(|| format!("load global config {:?}", global_config))?;
+    let mut global = load_global_config(global_configs)?;
+    if let Some(config_dir) = config_dir {
+        global.resolve_paths(config_dir);
+    }
     trace!("Global config: {:#?}", global);
 
     // If local.toml can't be found, look for a file named <hostname>.toml instead
@@ -137,7 +650,7 @@ pub fn load_configuration(
             "{:?} not found, using {}.toml instead (based on hostname)",
             local_config, hostname
         );
-        local_config_buf.set_file_name(&format!("{}.toml", hostname));
+        local_config_buf.set_file_name(format!("{}.toml", hostname));
     }
 
     let local: LocalConfig = filesystem::load_file(local_config_buf.as_path())
@@ -145,11 +658,17 @@ pub fn load_configuration(
         .with_context(|| format!("load local config {:?}", local_config))?;
     trace!("Local config: {:#?}", local);
 
-    let mut merged_config =
-        merge_configuration_files(global, local, patch).context("merge configuration files")?;
+    let mut merged_config = merge_configuration_files(global, local, patch, sources_directory)
+        .context("merge configuration files")?;
     trace!("Merged config: {:#?}", merged_config);
 
     debug!("Expanding files which are directories...");
+    merged_config.owned_directories = merged_config
+        .files
+        .iter()
+        .filter(|(source, target)| recurses(target, &merged_config) && source.is_dir())
+        .map(|(source, target)| Ok((expand_path(target.path())?, source.clone())))
+        .collect::<Result<_>>()?;
     merged_config.files =
         expand_directories(&merged_config).context("expand files that are directories")?;
 
@@ -158,10 +677,7 @@ pub fn load_configuration(
         .files
         .into_iter()
         .map(|(k, mut v)| -> Result<_, anyhow::Error> {
-            let path = v.path();
-            let path = shellexpand::full(&path.to_string_lossy())
-                .context("failed to expand file path")?
-                .to_string();
+            let path = expand_path(v.path())?;
             v.set_path(path);
             Ok((k, v))
         })
@@ -169,17 +685,149 @@ pub fn load_configuration(
 
     trace!("Final files: {:#?}", merged_config.files);
     trace!("Final variables: {:#?}", merged_config.variables);
+    trace!("Final partials: {:?}", merged_config.partials);
     #[cfg(feature = "scripting")]
     trace!("Final helpers: {:?}", merged_config.helpers);
 
     Ok(merged_config)
 }
 
+/// Expands `~` and environment variables in a target path
+fn expand_path(path: &Path) -> Result<PathBuf> {
+    Ok(shellexpand::full(&path.to_string_lossy())
+        .context("failed to expand file path")?
+        .to_string()
+        .into())
+}
+
+/// Loads the global configuration from a file, or from stdin if the path is `-`. The files
+/// after the first one are merged over it in order: tables are merged key by key, everything
+/// else - arrays too - is replaced
+fn load_global_config(global_configs: &[PathBuf]) -> Result<GlobalConfig> {
+    let (global_config, overlays) = global_configs
+        .split_first()
+        .context("no global config given")?;
+    if overlays.is_empty() {
+        if global_config == Path::new("-") {
+            return read_global_config(io::stdin().lock()).context("load global config from stdin");
+        }
+        return filesystem::load_file(global_config)
+            .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
+            .with_context(|| format!("load global config {:?}", global_config));
+    }
+
+    let mut tables = Vec::new();
+    for path in global_configs {
+        let table: toml::value::Table = if path == Path::new("-") {
+            read_global_config(io::stdin().lock()).context("load global config from stdin")?
+        } else {
+            filesystem::load_file(path)
+                .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
+                .with_context(|| format!("load global config {:?}", path))?
+        };
+        tables.push((path, table));
+    }
+    let mut merged = BTreeMap::new();
+    for (_, table) in &tables {
+        recursive_extend_map(&mut merged, table.clone());
+    }
+    toml::Value::Table(merged)
+        .try_into::<GlobalConfig>()
+        .or_else(|merged_error| {
+            // Point at the file that's invalid on its own, like without overlays
+            for (path, table) in tables {
+                if let Err(e) = toml::Value::Table(table).try_into::<GlobalConfig>() {
+                    return Err(e).with_context(|| format!("load global config {:?}", path));
+                }
+            }
+            Err(merged_error).context("deserialize merged global configs")
+        })
+}
+
+/// Loads only the settings of the global configuration, for commands that don't need the rest
+pub fn load_settings(global_configs: &[PathBuf]) -> Result<Settings> {
+    Ok(load_global_config(global_configs)?.settings)
+}
+
+fn read_global_config<T: serde::de::DeserializeOwned>(mut reader: impl Read) -> Result<T> {
+    let mut buf = String::new();
+    reader.read_to_string(&mut buf).context("read config")?;
+    filesystem::parse_toml(&buf).context("deserialize config")
+}
+
+impl GlobalConfig {
+    /// Resolve relative paths against `dir` instead of the current directory
+    fn resolve_paths(&mut self, dir: &Path) {
+        for package in self.packages.values_mut() {
+            package.files = std::mem::take(&mut package.files)
+                .into_iter()
+                .map(|(source, target)| (dir.join(source), target))
+                .collect();
+        }
+        for partial in self.partials.values_mut() {
+            if let Partial::File(path) = partial {
+                *path = dir.join(&path);
+            }
+        }
+        #[cfg(feature = "scripting")]
+        for helper in self.helpers.values_mut() {
+            *helper = dir.join(&helper);
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Cache {
     pub symlinks: BTreeMap<PathBuf, PathBuf>,
     pub templates: BTreeMap<PathBuf, PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_deploy: Option<LastDeploy>,
+    /// The last changes deploys made to each target, oldest first
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub history: BTreeMap<PathBuf, Vec<HistoryEntry>>,
+}
+
+/// How many changes are kept in the history of each target
+pub const HISTORY_LENGTH: usize = 10;
+
+/// A change a deploy made to a target, shown by `dotter log`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The packages that were selected
+    pub packages: Vec<String>,
+    /// None when the deploy created the target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<TargetState>,
+    /// None when the deploy removed the target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<TargetState>,
+}
+
+impl Cache {
+    /// Appends a change to the target's history, forgetting the oldest ones beyond
+    /// `HISTORY_LENGTH`
+    pub fn record_change(&mut self, target: &Path, entry: HistoryEntry) {
+        let history = self.history.entry(target.into()).or_default();
+        history.push(entry);
+        if history.len() > HISTORY_LENGTH {
+            history.drain(..history.len() - HISTORY_LENGTH);
+        }
+    }
+}
+
+/// What the last successful deploy did, shown by `dotter status`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LastDeploy {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub packages: Vec<String>,
+    /// Number of targets that were created, changed or removed
+    pub changed_files: usize,
 }
 
 pub fn save_dummy_config(
@@ -192,12 +840,21 @@ pub fn save_dummy_config(
         files: files.into_iter().map(|f| (f.into(), "".into())).collect(),
         variables: Variables::new(),
         depends: vec![],
+        after: vec![],
+        extends: vec![],
+        enabled: None,
+        source: None,
+        target_root: None,
+        inherited_files: BTreeMap::new(),
+        inherited_variables: BTreeMap::new(),
     };
     trace!("Default package: {:#?}", package);
 
     let mut packages = BTreeMap::new();
     packages.insert("default".into(), package);
     let global_config = GlobalConfig {
+        settings: Settings::default(),
+        partials: Partials::new(),
         #[cfg(feature = "scripting")]
         helpers: Helpers::new(),
         packages,
@@ -224,6 +881,203 @@ pub fn save_dummy_config(
     Ok(())
 }
 
+/// Overlays an ad-hoc `KEY=VALUE` assignment on top of the variables.
+/// Dotted keys address nested tables. The value is parsed as TOML when possible,
+/// so `true` and `3` become a boolean and an integer, falling back to a plain string.
+pub fn apply_variable_override(variables: &mut Variables, assignment: &str) -> Result<()> {
+    let (key, value) = assignment
+        .split_once('=')
+        .context("expected an assignment of the form KEY=VALUE")?;
+    let key = key.trim();
+    let value = value.trim();
+
+    anyhow::ensure!(
+        key.split('.').all(|segment| !segment.is_empty()),
+        "invalid variable name {:?}",
+        key
+    );
+
+    let parsed_value = toml::from_str::<toml::Value>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut parsed| parsed.as_table_mut()?.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.into()));
+
+    // Build the nested table from the innermost key outwards
+    let mut segments = key.rsplit('.');
+    let mut overlay = Variables::new();
+    overlay.insert(
+        segments.next().expect("split has at least one item").into(),
+        parsed_value,
+    );
+    for segment in segments {
+        let mut parent = Variables::new();
+        parent.insert(segment.into(), overlay.into());
+        overlay = parent;
+    }
+
+    recursive_extend_map(variables, overlay);
+
+    Ok(())
+}
+
+/// Reads variables from a JSON object, like `{"theme": "dark"}`
+pub fn read_json_variables(reader: impl Read) -> Result<Variables> {
+    serde_json::from_reader(reader).context("parse variables as a JSON object")
+}
+
+fn load_env_file(path: &Path) -> Result<Variables> {
+    let path = shellexpand::full(&path.to_string_lossy())
+        .context("expand path")?
+        .to_string();
+    parse_env_file(&fs::read_to_string(path).context("read file")?)
+}
+
+/// Parses the `KEY=value` lines of a `.env` file, optionally prefixed with `export`.
+/// Values can be in single quotes (taken literally) or double quotes (with `\n`, `\"` and `\\`
+/// escapes). Unquoted values are trimmed and end at a ` #` comment
+fn parse_env_file(contents: &str) -> Result<Variables> {
+    let mut variables = Variables::new();
+    for (index, line) in contents.lines().enumerate() {
+        || -> Result<()> {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return Ok(());
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=').context("expected KEY=value")?;
+            let key = key.trim();
+            anyhow::ensure!(
+                !key.is_empty()
+                    && key
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+                "invalid key {:?}",
+                key
+            );
+            variables.insert(key.into(), parse_env_value(value.trim())?.into());
+            Ok(())
+        }()
+        .with_context(|| format!("parse line {}", index + 1))?;
+    }
+    Ok(variables)
+}
+
+fn parse_env_value(value: &str) -> Result<String> {
+    if let Some(quoted) = value.strip_prefix('\'') {
+        let (value, rest) = quoted
+            .split_once('\'')
+            .context("unterminated single quote")?;
+        ensure_only_comment(rest)?;
+        return Ok(value.into());
+    }
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut unescaped = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next().context("unterminated double quote")? {
+                '"' => break,
+                '\\' => match chars.next().context("unterminated double quote")? {
+                    'n' => unescaped.push('\n'),
+                    c => unescaped.push(c),
+                },
+                c => unescaped.push(c),
+            }
+        }
+        ensure_only_comment(chars.as_str())?;
+        return Ok(unescaped);
+    }
+    let value = match value.find(" #") {
+        Some(comment) => &value[..comment],
+        None => value,
+    };
+    Ok(value.trim_end().into())
+}
+
+fn ensure_only_comment(rest: &str) -> Result<()> {
+    let rest = rest.trim();
+    anyhow::ensure!(
+        rest.is_empty() || rest.starts_with('#'),
+        "unexpected {:?} after the quoted value",
+        rest
+    );
+    Ok(())
+}
+
+/// Overlays variables on top of the configuration's, merging tables.
+/// A variable can't change its type, since templates written for one type
+/// would silently misbehave with another
+pub fn overlay_variables(variables: &mut Variables, overlay: Variables) -> Result<()> {
+    fn overlay_at(prefix: &str, variables: &mut Variables, overlay: Variables) -> Result<()> {
+        for (key, new_value) in overlay {
+            let name = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            match (variables.get_mut(&key), new_value) {
+                (Some(toml::Value::Table(table)), toml::Value::Table(new_table)) => {
+                    overlay_at(&name, table, new_table)?;
+                }
+                (Some(value), new_value) => {
+                    anyhow::ensure!(
+                        value.type_str() == new_value.type_str(),
+                        "variable {:?} is {} {} in the configuration, but the override is {} {}",
+                        name,
+                        article(value.type_str()),
+                        value.type_str(),
+                        article(new_value.type_str()),
+                        new_value.type_str()
+                    );
+                    *value = new_value;
+                }
+                (None, new_value) => {
+                    variables.insert(key, new_value);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn article(type_str: &str) -> &'static str {
+        if type_str.starts_with(|c| "aeiou".contains(c)) {
+            "an"
+        } else {
+            "a"
+        }
+    }
+
+    overlay_at("", variables, overlay)
+}
+
+/// Variables with others merged over them, like `recursive_extend_map` does, for rendering a
+/// file with its own variables. It's serialized as the merged table without copying either
+pub struct LayeredVariables<'a> {
+    pub base: &'a Variables,
+    pub overlay: &'a Variables,
+}
+
+impl Serialize for LayeredVariables<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let keys = self
+            .base
+            .keys()
+            .chain(self.overlay.keys())
+            .collect::<BTreeSet<_>>();
+        let mut map = serializer.serialize_map(Some(keys.len()))?;
+        for key in keys {
+            match (self.base.get(key), self.overlay.get(key)) {
+                (Some(toml::Value::Table(base)), Some(toml::Value::Table(overlay))) => {
+                    map.serialize_entry(key, &LayeredVariables { base, overlay })?
+                }
+                (_, Some(value)) | (Some(value), None) => map.serialize_entry(key, value)?,
+                (None, None) => unreachable!("keys are taken from both tables"),
+            }
+        }
+        map.end()
+    }
+}
+
 fn recursive_extend_map(
     original: &mut BTreeMap<String, toml::Value>,
     new: BTreeMap<String, toml::Value>,
@@ -247,12 +1101,215 @@ fn recursive_extend_map(
     }
 }
 
+/// Merges the files, variables, dependencies and deploy order of the packages each package
+/// `extends` into it. Ancestors are applied depth-first, each of them once, and later ones
+/// override earlier ones. For example if `d` extends `["b", "c"]`, which both extend `a`, the
+/// order is a, b, c, d. Files override the files of earlier packages that have the same target.
+fn resolve_inheritance(packages: &mut BTreeMap<String, Package>) -> Result<()> {
+    let mut resolved = Vec::new();
+
+    for name in packages.keys() {
+        let mut order = Vec::new();
+        linearize_ancestors(name, packages, &mut Vec::new(), &mut order)?;
+        if order.len() == 1 {
+            continue;
+        }
+        if let Some(from_git) = order.iter().find(|p| packages[*p].source.is_some()) {
+            anyhow::bail!(
+                "package {:?} takes its files from git and can't be combined with `extends`",
+                from_git
+            );
+        }
+        trace!("Inheritance order of {:?}: {:?}", name, order);
+
+        let mut files = Files::new();
+        let mut variables = Variables::new();
+        let mut inherited_files = BTreeMap::new();
+        let mut inherited_variables = BTreeMap::new();
+        let mut depends = BTreeSet::new();
+        let mut after = BTreeSet::new();
+        for ancestor in &order {
+            let package = &packages[ancestor];
+            let own = ancestor == name;
+            for (source, target) in &package.files {
+                files.retain(|_, existing| existing.path() != target.path());
+                files.insert(source.clone(), target.clone());
+                if own {
+                    inherited_files.remove(source);
+                } else {
+                    inherited_files.insert(source.clone(), ancestor.clone());
+                }
+            }
+            for variable in package.variables.keys() {
+                if own {
+                    inherited_variables.remove(variable);
+                } else {
+                    inherited_variables.insert(variable.clone(), ancestor.clone());
+                }
+            }
+            recursive_extend_map(&mut variables, package.variables.clone());
+            depends.extend(package.depends.iter().cloned());
+            after.extend(package.after.iter().cloned());
+        }
+        inherited_files.retain(|source, _| files.contains_key(source));
+        resolved.push((
+            name.clone(),
+            files,
+            variables,
+            inherited_files,
+            inherited_variables,
+            depends,
+            after,
+        ));
+    }
+
+    for (name, files, variables, inherited_files, inherited_variables, depends, after) in resolved {
+        let package = packages.get_mut(&name).expect("package exists");
+        package.files = files;
+        package.variables = variables;
+        package.inherited_files = inherited_files;
+        package.inherited_variables = inherited_variables;
+        package.depends = depends.into_iter().collect();
+        package.after = after.into_iter().collect();
+    }
+    Ok(())
+}
+
+fn linearize_ancestors(
+    name: &str,
+    packages: &BTreeMap<String, Package>,
+    stack: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<()> {
+    if order.iter().any(|p| p == name) {
+        return Ok(());
+    }
+    if stack.iter().any(|p| p == name) {
+        let cycle = stack.iter().cloned().chain([name.to_string()]).collect();
+        return Err(ValidationError::InheritanceCycle(cycle).into());
+    }
+
+    let package = packages
+        .get(name)
+        .with_context(|| format!("get info of package {}", name))?;
+    stack.push(name.to_string());
+    for parent in &package.extends {
+        linearize_ancestors(parent, packages, stack, order)?;
+    }
+    stack.pop();
+    order.push(name.to_string());
+    Ok(())
+}
+
+/// Assigns every package the stage it's deployed in. Packages that aren't `after` any other
+/// package are in stage 0, the others in the stage following the latest of the packages they're
+/// `after`. So without any `after`, everything is in the same stage and deployed as before.
+fn deploy_stages(packages: &BTreeMap<String, Package>) -> Result<BTreeMap<String, usize>> {
+    let mut stages = BTreeMap::new();
+    for name in packages.keys() {
+        deploy_stage(name, packages, &mut Vec::new(), &mut stages)?;
+    }
+    Ok(stages)
+}
+
+fn deploy_stage(
+    name: &str,
+    packages: &BTreeMap<String, Package>,
+    stack: &mut Vec<String>,
+    stages: &mut BTreeMap<String, usize>,
+) -> Result<usize> {
+    if let Some(stage) = stages.get(name) {
+        return Ok(*stage);
+    }
+    if stack.iter().any(|p| p == name) {
+        let cycle = stack.iter().cloned().chain([name.to_string()]).collect();
+        return Err(ValidationError::DeployOrderCycle(cycle).into());
+    }
+
+    let package = packages
+        .get(name)
+        .with_context(|| format!("get info of package {}", name))?;
+    stack.push(name.to_string());
+    let mut stage = 0;
+    for earlier in &package.after {
+        stage = stage.max(deploy_stage(earlier, packages, stack, stages)? + 1);
+    }
+    stack.pop();
+    stages.insert(name.to_string(), stage);
+    Ok(stage)
+}
+
+/// The packages that are selected, depended on, extended, deployed after or have a file target
+/// but don't exist, so that a typo is reported before anything is resolved
+fn unknown_package_references(global: &GlobalConfig, local: &LocalConfig) -> Vec<ValidationError> {
+    let known = |name: &String| global.packages.contains_key(name);
+    let mut errors = local
+        .packages
+        .iter()
+        .filter(|name| !known(name))
+        .map(|name| ValidationError::UnknownPackage {
+            name: name.clone(),
+            referenced_by: None,
+        })
+        .collect::<Vec<_>>();
+    for (name, package) in &global.packages {
+        let references = [
+            ("depends on", &package.depends),
+            ("extends", &package.extends),
+            ("is deployed after", &package.after),
+        ];
+        for (relation, referenced) in references.iter() {
+            for unknown in referenced.iter().filter(|name| !known(name)) {
+                errors.push(ValidationError::UnknownPackage {
+                    name: unknown.clone(),
+                    referenced_by: Some((name.clone(), relation)),
+                });
+            }
+        }
+    }
+    let files = global
+        .packages
+        .values()
+        .flat_map(|package| &package.files)
+        .chain(&local.files);
+    for (source, target) in files {
+        let choices = match target {
+            FileTarget::Automatic(_) => continue,
+            FileTarget::Symbolic(SymbolicTarget { target_choices, .. }) => target_choices,
+            FileTarget::ComplexTemplate(target) => &target.target_choices,
+        };
+        for unknown in choices
+            .keys()
+            .filter(|name| *name != "default" && !known(name))
+        {
+            errors.push(ValidationError::UnknownTargetPackage {
+                name: unknown.clone(),
+                source: source.clone(),
+            });
+        }
+    }
+    errors
+}
+
 #[allow(clippy::map_entry)]
 fn merge_configuration_files(
     mut global: GlobalConfig,
-    local: LocalConfig,
-    patch: Option<Package>,
+    mut local: LocalConfig,
+    mut patch: Option<Package>,
+    sources_directory: &Path,
 ) -> Result<Configuration> {
+    // All unknown references are reported at once
+    let unknown = unknown_package_references(&global, &local);
+    anyhow::ensure!(
+        unknown.is_empty(),
+        "{}",
+        unknown
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+
     // Patch each package with included.toml's
     for included_path in &local.includes {
         || -> Result<()> {
@@ -283,6 +1340,9 @@ fn merge_configuration_files(
         .with_context(|| format!("including file {:?}", included_path))?;
     }
 
+    resolve_inheritance(&mut global.packages).context("resolve package inheritance")?;
+    let mut package_stages = deploy_stages(&global.packages).context("order packages")?;
+
     // Enable depended packages
     let mut enabled_packages = local.packages.clone().into_iter().collect::<BTreeSet<_>>();
     let mut package_count = 0;
@@ -291,19 +1351,26 @@ fn merge_configuration_files(
     while enabled_packages.len() > package_count {
         let mut new_packages = BTreeSet::new();
         for package in &enabled_packages {
-            new_packages.extend(
-                global
-                    .packages
-                    .get(package)
-                    .with_context(|| format!("get info of package {}", package))?
-                    .depends
-                    .clone(),
-            );
+            let package = global
+                .packages
+                .get(package)
+                .with_context(|| format!("get info of package {}", package))?;
+            if package.enabled != Some(false) {
+                new_packages.extend(package.depends.clone());
+            }
         }
         package_count = enabled_packages.len();
         enabled_packages.extend(new_packages);
     }
 
+    enabled_packages.retain(|package| {
+        let disabled = global.packages[package].enabled == Some(false);
+        if disabled {
+            info!("Skipping package {:?} because it is disabled", package);
+        }
+        !disabled
+    });
+
     let packages_map = global
         .packages
         .keys()
@@ -312,16 +1379,86 @@ fn merge_configuration_files(
 
     // Apply packages filter
     global.packages.retain(|k, _| enabled_packages.contains(k));
+    package_stages.retain(|k, _| enabled_packages.contains(k));
+
+    // Check out packages whose files come from a git repository
+    for (package_name, package) in &mut global.packages {
+        if let Some(source) = &package.source {
+            anyhow::ensure!(
+                global.settings.git_sources,
+                "package {:?} takes its files from git, but `git_sources` isn't enabled in the settings",
+                package_name
+            );
+            let checkout = sources_directory.join(package_name);
+            git::sync(source, &checkout).with_context(|| {
+                format!("check out {:?} for package {:?}", source.git, package_name)
+            })?;
+            package.files = std::mem::take(&mut package.files)
+                .into_iter()
+                .map(|(source, target)| (checkout.join(source), target))
+                .collect();
+        }
+    }
+
+    // Targets by package are chosen before they're resolved against the target root
+    let choose_targets = |files: &mut Files| -> Result<()> {
+        for (source, target) in files.iter_mut() {
+            target
+                .choose_target(&enabled_packages)
+                .with_context(|| format!("choose target of {:?}", source))?;
+        }
+        Ok(())
+    };
+    for (package_name, package) in &mut global.packages {
+        choose_targets(&mut package.files)
+            .with_context(|| format!("choose targets of package {:?}", package_name))?;
+    }
+    choose_targets(&mut local.files).context("choose targets of local configuration")?;
+    if let Some(patch) = &mut patch {
+        choose_targets(&mut patch.files).context("choose targets of patch")?;
+    }
+
+    for (package_name, package) in &mut global.packages {
+        if let Some(root) = &package.target_root {
+            resolve_targets(&mut package.files, root)
+                .with_context(|| format!("resolve targets of package {:?}", package_name))?;
+        }
+    }
 
     let mut output = Configuration {
         #[cfg(feature = "scripting")]
         helpers: global.helpers,
         files: Files::default(),
+        file_packages: global
+            .packages
+            .iter()
+            .flat_map(|(name, package)| {
+                package
+                    .files
+                    .keys()
+                    .map(move |file| (file.clone(), name.clone()))
+            })
+            .collect(),
+        package_stages,
         variables: Variables::default(),
         packages: packages_map,
+        settings: global.settings,
+        partials: global.partials,
         recurse: true,
+        owned_directories: BTreeMap::new(),
     };
 
+    if output.settings.namespace_variables {
+        for (package_name, package) in &mut global.packages {
+            if !package.variables.is_empty() {
+                let variables = std::mem::take(&mut package.variables);
+                package
+                    .variables
+                    .insert(package_name.clone(), variables.into());
+            }
+        }
+    }
+
     // Merge all the packages
     let mut configuration_packages = global.packages.into_iter();
     let mut first_package = configuration_packages
@@ -331,14 +1468,28 @@ fn merge_configuration_files(
     for (package_name, package) in configuration_packages {
         || -> Result<()> {
             for (file_name, file_target) in package.files {
-                if first_package.files.contains_key(&file_name) {
-                    anyhow::bail!("file {:?} already encountered", file_name);
+                let origin = package.inherited_files.get(&file_name);
+                if let Some(first_target) = first_package.files.get(&file_name) {
+                    // Packages that extend the same package share its files
+                    let shared = origin.is_some()
+                        && first_package.inherited_files.get(&file_name) == origin
+                        && *first_target == file_target;
+                    if !shared {
+                        anyhow::bail!("file {:?} already encountered", file_name);
+                    }
                 } else {
+                    if let Some(origin) = origin {
+                        first_package
+                            .inherited_files
+                            .insert(file_name.clone(), origin.clone());
+                    }
                     first_package.files.insert(file_name, file_target);
                 }
             }
 
             for (variable_name, variable_value) in package.variables {
+                let origin = package.inherited_variables.get(&variable_name);
+                let first_origin = first_package.inherited_variables.get(&variable_name);
                 if let Some(first_value) = first_package.variables.get_mut(&variable_name).as_mut()
                 {
                     match (first_value, variable_value) {
@@ -346,11 +1497,21 @@ fn merge_configuration_files(
                             trace!("Merging {:?} tables", variable_name);
                             recursive_extend_map(first_value, variable_value);
                         }
+                        // Packages that extend the same package share its variables
+                        (first_value, variable_value)
+                            if origin.is_some()
+                                && first_origin == origin
+                                && **first_value == variable_value => {}
                         _ => {
                             anyhow::bail!("variable {:?} already encountered", variable_name);
                         }
                     }
                 } else {
+                    if let Some(origin) = origin {
+                        first_package
+                            .inherited_variables
+                            .insert(variable_name.clone(), origin.clone());
+                    }
                     first_package
                         .variables
                         .insert(variable_name, variable_value);
@@ -364,6 +1525,13 @@ fn merge_configuration_files(
     output.files = first_package.files;
     output.variables = first_package.variables;
 
+    // Add the .env files
+    for env_file in &output.settings.env_files {
+        let variables =
+            load_env_file(env_file).with_context(|| format!("load env file {:?}", env_file))?;
+        recursive_extend_map(&mut output.variables, variables);
+    }
+
     // Add local.toml's patches
     output.files.extend(local.files);
     recursive_extend_map(&mut output.variables, local.variables);
@@ -380,30 +1548,279 @@ fn merge_configuration_files(
     Ok(output)
 }
 
+/// Joins `root` with the targets that are still relative after expanding them
+fn resolve_targets(files: &mut Files, root: &Path) -> Result<()> {
+    let expand = |path: &Path| -> Result<PathBuf> {
+        Ok(shellexpand::full(&path.to_string_lossy())
+            .context("failed to expand file path")?
+            .to_string()
+            .into())
+    };
+    let root = expand(root).context("expand target root")?;
+    for target in files.values_mut() {
+        let path = expand(target.path())?;
+        // Empty targets remove the file, so they stay empty
+        if path.is_relative() && path != Path::new("") {
+            target.set_path(root.join(path));
+        }
+    }
+    Ok(())
+}
+
+impl Configuration {
+    /// Checks that every source exists and that no two sources are deployed to the same target,
+    /// unless `allow_conflicts` is set. Unknown packages and cycles are reported while loading
+    /// already, as they keep the configuration from being resolved
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = self
+            .files
+            .keys()
+            .filter(|source| !source.exists())
+            .map(|source| ValidationError::MissingSource {
+                source: source.clone(),
+                package: file_package(&self.file_packages, source).cloned(),
+            })
+            .collect::<Vec<_>>();
+        if !self.settings.allow_conflicts {
+            errors.extend(
+                find_conflicting_targets(&self.files)
+                    .into_iter()
+                    .map(|(target, sources)| ValidationError::CollidingTarget { target, sources }),
+            );
+        }
+        errors.extend(self.invalid_variables());
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The variables that violate their constraint in `variable_schema` of the settings
+    pub fn invalid_variables(&self) -> Vec<ValidationError> {
+        self.settings
+            .variable_schema
+            .iter()
+            .filter_map(|(name, constraint)| {
+                let mut keys = name.split('.');
+                let mut value = keys.next().and_then(|key| self.variables.get(key));
+                for key in keys {
+                    value = value.and_then(|value| value.get(key));
+                }
+                constraint
+                    .violation(value)
+                    .map(|violation| ValidationError::InvalidVariable {
+                        name: name.clone(),
+                        violation,
+                    })
+            })
+            .collect()
+    }
+
+    /// Fails with every violation of `variable_schema`. Call it once all overlays of the
+    /// variables (`--vars-stdin`, `diff --set`) have been applied
+    pub fn check_variables(&self) -> Result<()> {
+        let invalid = self.invalid_variables();
+        anyhow::ensure!(
+            invalid.is_empty(),
+            "{}",
+            invalid
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        Ok(())
+    }
+
+    /// The stage each file is deployed in, which is the stage of its package.
+    /// Files that don't belong to a package, like those added by the local configuration,
+    /// are in stage 0
+    pub fn file_stages(&self) -> BTreeMap<PathBuf, usize> {
+        self.files
+            .keys()
+            .map(|source| {
+                let stage = file_package(&self.file_packages, source)
+                    .and_then(|package| self.package_stages.get(package))
+                    .copied()
+                    .unwrap_or(0);
+                (source.clone(), stage)
+            })
+            .collect()
+    }
+}
+
+/// The package a file comes from, according to `Configuration::file_packages`.
+/// Files expanded from a directory come from the directory's package
+pub fn file_package<'a>(
+    file_packages: &'a BTreeMap<PathBuf, String>,
+    source: &Path,
+) -> Option<&'a String> {
+    source
+        .ancestors()
+        .find_map(|ancestor| file_packages.get(ancestor))
+}
+
+/// Pipes `rendered` through `command` and returns its stdout
+fn run_format_command(command: &str, rendered: String) -> Result<String> {
+    let mut words = command.split_whitespace();
+    let program = words.next().context("format command is empty")?;
+    let mut child = std::process::Command::new(program)
+        .args(words)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("run format command")?;
+
+    // Written on another thread, so that a formatter that writes before it read everything
+    // doesn't block on a full pipe
+    let mut stdin = child.stdin.take().context("open stdin")?;
+    let writer = std::thread::spawn(move || stdin.write_all(rendered.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .context("wait for format command")?;
+    let written = writer
+        .join()
+        .expect("writing to the format command doesn't panic");
+
+    anyhow::ensure!(
+        output.status.success(),
+        "format command failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    written.context("write to stdin")?;
+    String::from_utf8(output.stdout).context("decode output as UTF-8")
+}
+
+impl TryFrom<String> for FileMode {
+    type Error = String;
+
+    fn try_from(mode: String) -> Result<Self, Self::Error> {
+        let digits = mode.trim_start_matches("0o");
+        match u32::from_str_radix(digits, 8) {
+            Ok(bits) if bits <= 0o7777 => Ok(FileMode(bits)),
+            _ => Err(format!(
+                "invalid mode {:?}, expected octal digits like \"0755\"",
+                mode
+            )),
+        }
+    }
+}
+
+impl From<FileMode> for String {
+    fn from(mode: FileMode) -> Self {
+        format!("{:04o}", mode.0)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            strict: true,
+            banner: false,
+            banner_comments: BTreeMap::new(),
+            dir_mode: None,
+            diff: DiffSettings::default(),
+            git_sources: false,
+            allow_conflicts: false,
+            render_timeout: 5.0,
+            hooks: HookSettings::default(),
+            commands: CommandSettings::default(),
+            shell: None,
+            helpers_dir: None,
+            external_helpers: false,
+            relative_symlinks: false,
+            windows_symlink_fallback: SymlinkFallback::default(),
+            cache_file: None,
+            cache_directory: None,
+            cache_backups: 0,
+            allowed_target_roots: Vec::new(),
+            env_files: Vec::new(),
+            delimiters: None,
+            namespace_variables: false,
+            report_untracked: Vec::new(),
+            privileged_write: false,
+            privilege_helper: "sudo".into(),
+            variable_schema: BTreeMap::new(),
+            default_type_by_extension: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for DiffSettings {
+    fn default() -> Self {
+        DiffSettings {
+            max_file_size: 10 * 1024 * 1024,
+            command: None,
+            algorithm: DiffAlgorithm::default(),
+        }
+    }
+}
+
 impl FileTarget {
     pub fn path(&self) -> &Path {
         match self {
             FileTarget::Automatic(path) => path,
-            FileTarget::Symbolic(SymbolicTarget { target, .. })
-            | FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => target,
+            FileTarget::Symbolic(SymbolicTarget { target, .. }) => target,
+            FileTarget::ComplexTemplate(template) => &template.target,
         }
     }
 
     pub fn set_path(&mut self, new_path: impl Into<PathBuf>) {
         match self {
             FileTarget::Automatic(ref mut path) => *path = new_path.into(),
-            FileTarget::Symbolic(SymbolicTarget { target, .. })
-            | FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => {
-                *target = new_path.into()
+            FileTarget::Symbolic(SymbolicTarget { target, .. }) => *target = new_path.into(),
+            FileTarget::ComplexTemplate(template) => template.target = new_path.into(),
+        }
+    }
+
+    /// Sets the target to the one of the enabled package in the file's table of targets by
+    /// package, or to its `default` if none of them is enabled. Without a `default`, the target
+    /// is left empty, which removes the file. Files with a single target are left alone
+    pub fn choose_target(&mut self, enabled_packages: &BTreeSet<String>) -> Result<()> {
+        let (target, choices) = match self {
+            FileTarget::Automatic(_) => return Ok(()),
+            FileTarget::Symbolic(SymbolicTarget {
+                target,
+                target_choices,
+                ..
+            }) => (target, std::mem::take(target_choices)),
+            FileTarget::ComplexTemplate(template) => {
+                let TemplateTarget {
+                    target,
+                    target_choices,
+                    ..
+                } = &mut **template;
+                (target, std::mem::take(target_choices))
             }
+        };
+        if choices.is_empty() {
+            return Ok(());
         }
+
+        let mut enabled = choices
+            .iter()
+            .filter(|(package, _)| *package != "default" && enabled_packages.contains(*package));
+        *target = match (enabled.next(), enabled.next()) {
+            (Some((_, chosen)), None) => chosen.clone(),
+            (Some((first, _)), Some((second, _))) => anyhow::bail!(
+                "packages {:?} and {:?} are both enabled and have a target for the file",
+                first,
+                second
+            ),
+            (None, _) => choices.get("default").cloned().unwrap_or_default(),
+        };
+        Ok(())
     }
 
     pub fn condition(&self) -> Option<&String> {
         match self {
             FileTarget::Automatic(_) => None,
             FileTarget::Symbolic(SymbolicTarget { condition, .. }) => condition.as_ref(),
-            FileTarget::ComplexTemplate(TemplateTarget { condition, .. }) => condition.as_ref(),
+            FileTarget::ComplexTemplate(template) => template.condition.as_ref(),
         }
     }
 }
@@ -414,14 +1831,54 @@ impl<T: Into<PathBuf>> From<T> for FileTarget {
     }
 }
 
+/// Symbolic and template files can have a table of targets by package instead of a single one,
+/// like `target = { work = "~/.config/app/work.conf", default = "~/.config/app/config" }`.
+/// It's taken out before the rest is deserialized, since the target is chosen from it only
+/// once the enabled packages are known
+impl<'de> Deserialize<'de> for FileTarget {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let mut input = toml::Value::deserialize(deserializer)?;
+        let choices = match input.get_mut("target") {
+            Some(target @ toml::Value::Table(_)) => Some(std::mem::replace(
+                target,
+                toml::Value::String(String::new()),
+            )),
+            _ => None,
+        };
+        let mut file_target = FileTarget::from(
+            input
+                .try_into::<FileTargetOuterRepr>()
+                .map_err(D::Error::custom)?,
+        );
+        if let Some(choices) = choices {
+            let choices = choices.try_into().map_err(D::Error::custom)?;
+            match &mut file_target {
+                FileTarget::Automatic(_) => unreachable!("automatic targets are strings"),
+                FileTarget::Symbolic(SymbolicTarget { target_choices, .. }) => {
+                    *target_choices = choices
+                }
+                FileTarget::ComplexTemplate(template) => template.target_choices = choices,
+            }
+        }
+        Ok(file_target)
+    }
+}
+
 impl From<FileTargetOuterRepr> for FileTarget {
     fn from(input: FileTargetOuterRepr) -> Self {
         use FileTargetInnerRepr as IR;
         use FileTargetOuterRepr as OR;
         match input {
             OR::Simple(x) => Self::Automatic(x),
-            OR::Complex(IR::Symbolic(x)) => Self::Symbolic(x),
-            OR::Complex(IR::ComplexTemplate(x)) => Self::ComplexTemplate(x),
+            OR::Complex(x) => match x {
+                IR::Symbolic(x) => Self::Symbolic(x),
+                IR::ComplexTemplate(x) => Self::ComplexTemplate(x),
+            },
         }
     }
 }
@@ -444,6 +1901,8 @@ impl<T: Into<PathBuf>> From<T> for SymbolicTarget {
             owner: None,
             condition: None,
             recurse: None,
+            dir_mode: None,
+            target_choices: BTreeMap::new(),
         }
     }
 }
@@ -452,10 +1911,7 @@ impl<T: Into<PathBuf>> From<T> for TemplateTarget {
     fn from(input: T) -> Self {
         TemplateTarget {
             target: input.into(),
-            owner: None,
-            append: None,
-            prepend: None,
-            condition: None,
+            ..TemplateTarget::default()
         }
     }
 }
@@ -466,23 +1922,250 @@ impl SymbolicTarget {
             target: self.target,
             owner: self.owner,
             condition: self.condition,
-            prepend: None,
-            append: None,
+            dir_mode: self.dir_mode,
+            ..TemplateTarget::default()
+        }
+    }
+}
+
+const BANNER_TEXT: &str =
+    "This file is managed by Dotter. Do not edit, changes will be overwritten.";
+
+/// Comment syntax by file extension (or name, for files without one).
+/// `{}` is replaced by the banner text.
+const BUILTIN_COMMENT_STYLES: &[(&str, &str)] = &[
+    ("bash", "# {}"),
+    ("bashrc", "# {}"),
+    ("c", "// {}"),
+    ("cfg", "# {}"),
+    ("conf", "# {}"),
+    ("cpp", "// {}"),
+    ("css", "/* {} */"),
+    ("fish", "# {}"),
+    ("gitconfig", "# {}"),
+    ("go", "// {}"),
+    ("h", "// {}"),
+    ("htm", "<!-- {} -->"),
+    ("html", "<!-- {} -->"),
+    ("ini", "; {}"),
+    ("java", "// {}"),
+    ("js", "// {}"),
+    ("jsonc", "// {}"),
+    ("lua", "-- {}"),
+    ("md", "<!-- {} -->"),
+    ("plist", "<!-- {} -->"),
+    ("profile", "# {}"),
+    ("ps1", "# {}"),
+    ("py", "# {}"),
+    ("rb", "# {}"),
+    ("rs", "// {}"),
+    ("scss", "// {}"),
+    ("sh", "# {}"),
+    ("sql", "-- {}"),
+    ("svg", "<!-- {} -->"),
+    ("tex", "% {}"),
+    ("toml", "# {}"),
+    ("ts", "// {}"),
+    ("vim", "\" {}"),
+    ("vimrc", "\" {}"),
+    ("xml", "<!-- {} -->"),
+    ("yaml", "# {}"),
+    ("yml", "# {}"),
+    ("zsh", "# {}"),
+    ("zshrc", "# {}"),
+];
+
+/// Turns a template with the expression delimiters `open` and `close` into a Handlebars one:
+/// `{{` is escaped so that it's kept literally, then each expression's delimiters become `{{` and
+/// `}}`. A `close` that doesn't end an expression opened by `open` is kept as it is
+fn translate_delimiters(text: &str, open: &str, close: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find("{{") {
+        // A whole run of braces is escaped at once, `\{{{` keeps all three
+        let braces = rest[index..].len() - rest[index..].trim_start_matches('{').len();
+        escaped.push_str(&rest[..index]);
+        escaped.push('\\');
+        escaped.push_str(&rest[index..index + braces]);
+        rest = &rest[index + braces..];
+    }
+    escaped.push_str(rest);
+
+    let mut translated = String::with_capacity(escaped.len());
+    let mut rest = escaped.as_str();
+    while let Some(start) = rest.find(open) {
+        let inner = &rest[start + open.len()..];
+        let end = match inner.find(close) {
+            Some(end) => end,
+            None => break,
+        };
+        translated.push_str(&rest[..start]);
+        translated.push_str("{{");
+        translated.push_str(&inner[..end]);
+        translated.push_str("}}");
+        rest = &inner[end + close.len()..];
+    }
+    translated.push_str(rest);
+    translated
+}
+
+/// Where the source of a template is in its contents after `apply_actions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    /// Byte offset of the source, before the banner was inserted
+    start: usize,
+    len: usize,
+    /// Lines that were prepended before the source
+    prepended_lines: usize,
+    /// Byte offset and length of the banner
+    banner: Option<(usize, usize)>,
+}
+
+impl SourceSpan {
+    /// The line of the source that line `line` of `contents` (the result of `apply_actions`)
+    /// comes from, or None if an action added it. Both are counted from 1
+    pub fn source_line(&self, contents: &str, line: usize) -> Option<usize> {
+        let mut offset = if line <= 1 {
+            0
+        } else {
+            contents.match_indices('\n').nth(line - 2)?.0 + 1
+        };
+        let mut banner_lines = 0;
+        if let Some((at, len)) = self.banner {
+            if offset >= at + len {
+                offset -= len;
+                banner_lines = contents[at..at + len].matches('\n').count();
+            } else if offset >= at {
+                return None;
+            }
         }
+        let offset = offset.checked_sub(self.start)?;
+        if offset >= self.len {
+            return None;
+        }
+        Some(line - banner_lines - self.prepended_lines)
     }
 }
 
 impl TemplateTarget {
-    pub fn apply_actions(&self, mut file: String) -> String {
+    pub fn apply_actions(&self, file: String) -> String {
+        self.apply_actions_mapped(file).0
+    }
+
+    /// Like `apply_actions`, but also returns where the source ended up in the result,
+    /// so that lines can be mapped back to it with `source_line`
+    pub fn apply_actions_mapped(&self, file: String) -> (String, SourceSpan) {
+        let translate = |text: &str| match self.delimiters.as_deref() {
+            Some([open, close]) => translate_delimiters(text, open, close),
+            None => text.to_string(),
+        };
+
+        let mut file = translate(&file);
+        let mut span = SourceSpan {
+            start: 0,
+            len: file.len(),
+            prepended_lines: 0,
+            banner: None,
+        };
         if let Some(ref append) = self.append {
-            file += append.as_str();
+            file += translate(append).as_str();
         }
         if let Some(ref prepend) = self.prepend {
-            file = prepend.to_string() + file.as_str();
+            let prepend = translate(prepend);
+            file = prepend.clone() + file.as_str();
+            span.start = prepend.len();
+            span.prepended_lines = prepend.matches('\n').count();
+        }
+        if let Some(ref banner) = self.banner_comment {
+            // Keep the shebang as the first line
+            let split = if file.starts_with("#!") {
+                file.find('\n').map_or(file.len(), |i| i + 1)
+            } else {
+                0
+            };
+            let mut banner = banner.clone() + "\n";
+            if split == file.len() && !file.is_empty() && !file.ends_with('\n') {
+                banner.insert(0, '\n');
+            }
+            file.insert_str(split, &banner);
+            span.banner = Some((split, banner.len()));
+        }
+
+        (file, span)
+    }
+
+    /// Applies the actions that work on the rendered output. Merging reads the current target
+    pub fn apply_post_render_actions(&self, rendered: String) -> Result<String> {
+        let rendered = match self.strip_comments {
+            Some(style) => comments::strip_comments(&rendered, style),
+            None => rendered,
+        };
+        let rendered = match self.merge {
+            Some(format) => merge::merge_into_target(format, &rendered, &self.target)
+                .with_context(|| format!("merge into target as {:?}", format))?,
+            None => rendered,
+        };
+        match &self.format_command {
+            Some(command) => run_format_command(command, rendered)
+                .with_context(|| format!("format with {:?}", command)),
+            None => Ok(rendered),
+        }
+    }
+
+    /// Resolves whether this target gets a banner, and in which comment syntax
+    pub fn resolve_banner(&mut self, settings: &Settings) {
+        self.banner_comment = None;
+        // A comment would corrupt binary contents
+        let binary = self.encoding.is_some_and(|encoding| !encoding.is_text());
+        if !self.banner.unwrap_or(settings.banner) || binary {
+            return;
         }
 
-        file
+        let file_type = self
+            .target
+            .extension()
+            .or_else(|| self.target.file_name())
+            .map(|t| t.to_string_lossy().trim_start_matches('.').to_string())
+            .unwrap_or_default();
+        let style = settings
+            .banner_comments
+            .get(&file_type)
+            .map(String::as_str)
+            .or_else(|| {
+                BUILTIN_COMMENT_STYLES
+                    .iter()
+                    .find(|(t, _)| *t == file_type)
+                    .map(|(_, style)| *style)
+            });
+
+        match style {
+            Some(style) if style.contains("{}") => {
+                self.banner_comment = Some(style.replace("{}", BANNER_TEXT));
+            }
+            Some(style) => {
+                self.banner_comment = Some(format!("{} {}", style, BANNER_TEXT));
+            }
+            None => {
+                warn!(
+                    "Unknown comment syntax for {:?}, not adding a banner. Add {:?} to `banner_comments` in the settings to fix this.",
+                    self.target, file_type
+                );
+            }
+        }
+    }
+}
+
+/// Returns the targets that more than one source is deployed to, with those sources
+pub fn find_conflicting_targets(files: &Files) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+    let mut sources_by_target = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    for (source, target) in files {
+        sources_by_target
+            .entry(target.path().to_path_buf())
+            .or_default()
+            .push(source.clone());
     }
+    sources_by_target.retain(|_, sources| sources.len() > 1);
+    sources_by_target
 }
 
 fn expand_directories(config: &Configuration) -> Result<Files> {
@@ -500,20 +2183,18 @@ fn expand_directories(config: &Configuration) -> Result<Files> {
 /// Otherwise, returns recursively all the children and their targets
 /// in relation to parent target
 fn expand_directory(source: &Path, target: &FileTarget, config: &Configuration) -> Result<Files> {
-    let metadata = fs::metadata(source).context("read file metadata")?;
-
-    // if a target explicitly specifies a recurse option, this takes
-    // precedence over the global default
-    let recurse = match target {
-        FileTarget::Symbolic(SymbolicTarget {
-            target: _,
-            owner: _,
-            condition: _,
-            recurse: Some(rec),
-        }) => *rec,
-        _ => config.recurse,
+    let metadata = match fs::metadata(source) {
+        Ok(metadata) => metadata,
+        // Not a directory, the commands that use the file report that it's missing
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut map = Files::new();
+            map.insert(source.into(), target.clone());
+            return Ok(map);
+        }
+        Err(e) => return Err(e).context("read file metadata"),
     };
 
+    let recurse = recurses(target, config);
     trace!("expanding '{source:?}', recurse: {recurse}");
 
     if !recurse || !metadata.is_dir() {
@@ -536,6 +2217,17 @@ fn expand_directory(source: &Path, target: &FileTarget, config: &Configuration)
     }
 }
 
+/// Whether a source directory is expanded to its files. If a target explicitly specifies a
+/// recurse option, this takes precedence over the global default
+fn recurses(target: &FileTarget, config: &Configuration) -> bool {
+    match target {
+        FileTarget::Symbolic(SymbolicTarget {
+            recurse: Some(rec), ..
+        }) => *rec,
+        _ => config.recurse,
+    }
+}
+
 #[cfg(unix)]
 impl UnixUser {
     pub fn as_sudo_arg(&self) -> String {
@@ -598,7 +2290,7 @@ mod tests {
             )
             .unwrap()
             .file,
-            FileTarget::ComplexTemplate(PathBuf::from("~/.QuarticCat").into()),
+            FileTarget::ComplexTemplate(Box::new(PathBuf::from("~/.QuarticCat").into())),
         );
         assert_ne!(
             parse(
@@ -611,19 +2303,869 @@ mod tests {
             )
             .unwrap()
             .file,
-            FileTarget::ComplexTemplate(PathBuf::from("~/.QuarticCat").into()),
+            FileTarget::ComplexTemplate(Box::new(PathBuf::from("~/.QuarticCat").into())),
         );
-        assert_eq!(
-            parse(
-                r#"
+        assert!(parse(
+            r#"
                     [file]
                     target = '~/.QuarticCat'
                     type = 'symbolic'
                     append = 'whatever'
                 "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn variable_overrides() {
+        let mut variables: Variables = toml::from_str(
+            r#"
+                theme = "light"
+                [font]
+                family = "mono"
+                size = 10
+            "#,
+        )
+        .unwrap();
+
+        apply_variable_override(&mut variables, "theme=dark").unwrap();
+        apply_variable_override(&mut variables, "font.size = 12").unwrap();
+        apply_variable_override(&mut variables, "compact=true").unwrap();
+        apply_variable_override(&mut variables, "greeting=hello world").unwrap();
+
+        assert_eq!(variables["theme"], toml::Value::String("dark".into()));
+        assert_eq!(variables["font"]["size"], toml::Value::Integer(12));
+        assert_eq!(
+            variables["font"]["family"],
+            toml::Value::String("mono".into())
+        );
+        assert_eq!(variables["compact"], toml::Value::Boolean(true));
+        assert_eq!(
+            variables["greeting"],
+            toml::Value::String("hello world".into())
+        );
+
+        assert!(apply_variable_override(&mut variables, "theme").is_err());
+        assert!(apply_variable_override(&mut variables, "font..size=1").is_err());
+    }
+
+    #[test]
+    fn alternate_delimiters() {
+        let mut target: TemplateTarget = "~/.config/app/template.mustache".into();
+        target.delimiters = Some(Box::new(["<%".into(), "%>".into()]));
+        target.append = Some("<%! a comment %>by <%user%>\n".into());
+
+        let mut variables = Variables::new();
+        variables.insert("user".into(), "alice".into());
+        variables.insert("items".into(), vec!["a", "b"].into());
+        let source = "Hello {{name}} and {{{raw}}}!\n<%#each items%><%this%> <%/each%>\n";
+        let rendered = handlebars::Handlebars::new()
+            .render_template(&target.apply_actions(source.into()), &variables)
+            .unwrap();
+        assert_eq!(rendered, "Hello {{name}} and {{{raw}}}!\na b \nby alice\n");
+    }
+
+    #[test]
+    fn stray_close_delimiters_are_kept() {
+        assert_eq!(
+            translate_delimiters("x = a[b[0]] + [[ a ]]\n", "[[", "]]"),
+            "x = a[b[0]] + {{ a }}\n"
+        );
+        assert_eq!(
+            translate_delimiters("%> <%user%> %> <%unclosed\n", "<%", "%>"),
+            "%> {{user}} %> <%unclosed\n"
+        );
+    }
+
+    #[test]
+    fn template_banner() {
+        let mut settings = Settings {
+            banner: true,
+            ..Settings::default()
+        };
+
+        let mut script: TemplateTarget = "~/.local/bin/script.sh".into();
+        script.resolve_banner(&settings);
+        assert_eq!(
+            script.apply_actions("#!/bin/sh\necho hi\n".into()),
+            format!("#!/bin/sh\n# {}\necho hi\n", BANNER_TEXT)
+        );
+
+        let mut page: TemplateTarget = "~/index.html".into();
+        page.resolve_banner(&settings);
+        assert_eq!(
+            page.apply_actions("<p>hi</p>\n".into()),
+            format!("<!-- {} -->\n<p>hi</p>\n", BANNER_TEXT)
+        );
+
+        // Rendering twice from the same source doesn't stack banners
+        assert_eq!(
+            page.apply_actions("<p>hi</p>\n".into()),
+            page.apply_actions("<p>hi</p>\n".into())
+        );
+
+        let mut unknown: TemplateTarget = "~/.config/app/settings.json".into();
+        unknown.resolve_banner(&settings);
+        assert_eq!(unknown.banner_comment, None);
+
+        settings.banner_comments.insert("json".into(), "//".into());
+        unknown.resolve_banner(&settings);
+        assert_eq!(unknown.banner_comment, Some(format!("// {}", BANNER_TEXT)));
+
+        unknown.banner = Some(false);
+        unknown.resolve_banner(&settings);
+        assert_eq!(unknown.banner_comment, None);
+    }
+
+    #[test]
+    fn deserialize_file_mode() {
+        #[derive(Deserialize)]
+        struct Helper {
+            dir_mode: FileMode,
+        }
+
+        let parse = |s| toml::from_str::<Helper>(s).map(|h| h.dir_mode);
+
+        assert_eq!(parse("dir_mode = '0700'").unwrap(), FileMode(0o700));
+        assert_eq!(parse("dir_mode = '755'").unwrap(), FileMode(0o755));
+        assert_eq!(parse("dir_mode = '0o750'").unwrap(), FileMode(0o750));
+        assert!(parse("dir_mode = '0800'").is_err());
+        assert!(parse("dir_mode = '17777'").is_err());
+        assert_eq!(String::from(FileMode(0o700)), "0700");
+    }
+
+    #[test]
+    fn global_config_from_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("vimrc"), "set number").unwrap();
+
+        let input = "[vim.files]\nvimrc = '~/.vimrc'\n\n[vim.variables]\ntheme = 'dark'\n";
+        let mut global: GlobalConfig = read_global_config(input.as_bytes()).unwrap();
+        global.resolve_paths(dir.path());
+
+        let local = LocalConfig {
+            includes: Vec::new(),
+            packages: vec!["vim".into()],
+            files: Files::new(),
+            variables: Variables::new(),
+        };
+        let config =
+            merge_configuration_files(global, local, None, Path::new(".dotter/sources")).unwrap();
+        let files = expand_directories(&config).unwrap();
+
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            vec![&dir.path().join("vimrc")]
+        );
+        assert_eq!(config.variables["theme"], "dark".into());
+    }
+
+    #[test]
+    fn disabled_package() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+            [shell]
+            depends = ["git"]
+            files = { bashrc = "~/.bashrc" }
+            variables = { editor = "vim" }
+
+            [git]
+            enabled = false
+            files = { gitconfig = "~/.gitconfig" }
+            variables = { email = "me@example.com" }
+            "#,
+        )
+        .unwrap();
+        let local = LocalConfig {
+            includes: Vec::new(),
+            packages: vec!["shell".into(), "git".into()],
+            files: Files::new(),
+            variables: Variables::new(),
+        };
+
+        let config =
+            merge_configuration_files(global, local, None, Path::new(".dotter/sources")).unwrap();
+
+        assert_eq!(
+            config.files.keys().collect::<Vec<_>>(),
+            vec![&PathBuf::from("bashrc")]
+        );
+        assert!(config.variables.contains_key("editor"));
+        assert!(!config.variables.contains_key("email"));
+        assert!(!config.packages["git"]);
+    }
+
+    #[test]
+    fn unknown_package_references() {
+        let check = |global: &str, selected: &[&str]| {
+            let global: GlobalConfig = toml::from_str(global).unwrap();
+            let local = LocalConfig {
+                includes: Vec::new(),
+                packages: selected.iter().map(|name| name.to_string()).collect(),
+                files: Files::new(),
+                variables: Variables::new(),
+            };
+            merge_configuration_files(global, local, None, Path::new(".dotter/sources"))
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(
+            check("[work]\n", &["work", "nvm"]),
+            Err("local configuration selects unknown package \"nvm\"".into())
+        );
+        assert_eq!(
+            check("[work]\nextends = \"base\"\n", &["work"]),
+            Err("package \"work\" extends unknown package \"base\"".into())
+        );
+        assert_eq!(
+            check("[work]\nafter = [\"shell\"]\n", &["work"]),
+            Err("package \"work\" is deployed after unknown package \"shell\"".into())
+        );
+
+        // Everything is reported at once
+        assert_eq!(
+            check(
+                "[work]\ndepends = [\"git\"]\nafter = [\"shell\"]\n[home]\n",
+                &["nvm", "home"]
+            ),
+            Err("local configuration selects unknown package \"nvm\"\n\
+                 package \"work\" depends on unknown package \"git\"\n\
+                 package \"work\" is deployed after unknown package \"shell\""
+                .into())
+        );
+        assert_eq!(check("[work]\n[base]\n", &["work"]), Ok(()));
+
+        // Also the packages in a file's table of targets, except for its `default`
+        assert_eq!(
+            check(
+                "[work.files]\napp = { type = \"symbolic\", target = { wrk = \"~/app\", default = \"~/.app\" } }\n",
+                &["work"]
+            ),
+            Err("file \"app\" has a target for unknown package \"wrk\"".into())
+        );
+    }
+
+    #[test]
+    fn env_file_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join(".env.local");
+        fs::write(
+            &env_file,
+            r#"
+# Machine-local settings
+export EMAIL=alice@example.com
+NAME = "Alice \"Al\" Smith" # shown in commits
+SIGNING_KEY='$not{{expanded}}'
+EDITOR=nvim # the best one
+EMPTY=
+"#,
+        )
+        .unwrap();
+
+        let global: GlobalConfig = toml::from_str(&format!(
+            "[settings]\nenv_files = [{:?}]\n[git.variables]\nEDITOR = \"vim\"\nEMAIL = \"old\"\n",
+            env_file
+        ))
+        .unwrap();
+        let local = LocalConfig {
+            includes: Vec::new(),
+            packages: vec!["git".into()],
+            files: Files::new(),
+            variables: maplit::btreemap! { "EMAIL".into() => "local@example.com".into() },
+        };
+        let config =
+            merge_configuration_files(global, local, None, Path::new(".dotter/sources")).unwrap();
+
+        let rendered = handlebars::Handlebars::new()
+            .render_template(
+                "{{NAME}} <{{EMAIL}}> {{SIGNING_KEY}} {{EDITOR}} [{{EMPTY}}]",
+                &config.variables,
+            )
+            .unwrap();
+        assert_eq!(
+            rendered,
+            "Alice &quot;Al&quot; Smith <local@example.com> $not{{expanded}} nvim []"
+        );
+
+        assert_eq!(
+            format!("{:#}", parse_env_file("A=1\nB\n").unwrap_err()),
+            "parse line 2: expected KEY=value"
+        );
+        assert!(parse_env_file("A=\"open\n").is_err());
+    }
+
+    #[test]
+    fn namespaced_variables() {
+        let merge = |settings: &str| {
+            let global: GlobalConfig = toml::from_str(&format!(
+                "{}[nvim.variables]\ncolor = \"green\"\n[tmux.variables]\ncolor = \"blue\"\n",
+                settings
+            ))
+            .unwrap();
+            let local = LocalConfig {
+                includes: Vec::new(),
+                packages: vec!["nvim".into(), "tmux".into()],
+                files: Files::new(),
+                variables: toml::from_str("[tmux]\ncolor = \"red\"\n").unwrap(),
+            };
+            merge_configuration_files(global, local, None, Path::new(".dotter/sources"))
+        };
+
+        let error = merge("").unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "merge package \"tmux\": variable \"color\" already encountered"
+        );
+
+        let config = merge("[settings]\nnamespace_variables = true\n").unwrap();
+        let rendered = handlebars::Handlebars::new()
+            .render_template("{{nvim.color}} {{tmux.color}}", &config.variables)
+            .unwrap();
+        assert_eq!(rendered, "green red");
+    }
+
+    #[test]
+    fn json_variables_override() {
+        let mut variables: Variables = toml::from_str(
+            r#"
+            theme = "light"
+            font = { family = "mono", size = 11 }
+            "#,
+        )
+        .unwrap();
+        let overlay = read_json_variables(
+            r#"{"theme": "dark", "font": {"size": 14}, "new": true}"#.as_bytes(),
+        )
+        .unwrap();
+        overlay_variables(&mut variables, overlay).unwrap();
+
+        let rendered = handlebars::Handlebars::new()
+            .render_template(
+                "{{theme}} {{font.family}} {{font.size}} {{new}}",
+                &variables,
+            )
+            .unwrap();
+        assert_eq!(rendered, "dark mono 14 true");
+
+        let overlay = read_json_variables(r#"{"font": {"size": "large"}}"#.as_bytes()).unwrap();
+        let error = overlay_variables(&mut variables, overlay).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "variable \"font.size\" is an integer in the configuration, but the override is a string"
+        );
+        assert!(read_json_variables("[1, 2]".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn package_target_root() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+            [work]
+            target_root = "/home/user/work-config"
+            files = { "work/gitconfig" = ".gitconfig", "work/vimrc" = "/etc/vimrc" }
+
+            [personal]
+            target_root = "/home/user"
+            files = { "personal/gitconfig" = ".gitconfig" }
+
+            [other]
+            files = { "other/gitconfig" = "other/.gitconfig" }
+            "#,
+        )
+        .unwrap();
+        let local = LocalConfig {
+            includes: Vec::new(),
+            packages: vec!["work".into(), "personal".into(), "other".into()],
+            files: Files::new(),
+            variables: Variables::new(),
+        };
+
+        let config =
+            merge_configuration_files(global, local, None, Path::new(".dotter/sources")).unwrap();
+        let targets = config
+            .files
+            .iter()
+            .map(|(source, target)| (source.to_str().unwrap(), target.path().to_str().unwrap()))
+            .collect::<BTreeMap<_, _>>();
+
+        assert_eq!(
+            targets,
+            maplit::btreemap! {
+                "other/gitconfig" => "other/.gitconfig",
+                "personal/gitconfig" => "/home/user/.gitconfig",
+                "work/gitconfig" => "/home/user/work-config/.gitconfig",
+                "work/vimrc" => "/etc/vimrc",
+            }
+        );
+    }
+
+    fn inherited_packages(config: &str) -> Result<BTreeMap<String, Package>> {
+        let mut global: GlobalConfig = toml::from_str(config).unwrap();
+        resolve_inheritance(&mut global.packages)?;
+        Ok(global.packages)
+    }
+
+    #[test]
+    fn package_inheritance() {
+        let packages = inherited_packages(
+            r#"
+            [base-vim.files]
+            vimrc = "~/.vimrc"
+            plugins = "~/.vim/plugins.vim"
+            [base-vim.variables]
+            font = { name = "mono", size = 11 }
+            colorscheme = "default"
+
+            [work-vim]
+            extends = "base-vim"
+            [work-vim.files]
+            work-plugins = "~/.vim/plugins.vim"
+            [work-vim.variables]
+            font = { size = 14 }
+            proxy = "http://proxy"
+            "#,
+        )
+        .unwrap();
+
+        let work = &packages["work-vim"];
+        assert_eq!(
+            work.files.keys().collect::<Vec<_>>(),
+            vec![&PathBuf::from("vimrc"), &PathBuf::from("work-plugins")]
+        );
+        assert_eq!(work.variables["font"]["name"], "mono".into());
+        assert_eq!(work.variables["font"]["size"], 14.into());
+        assert_eq!(work.variables["colorscheme"], "default".into());
+        assert_eq!(work.variables["proxy"], "http://proxy".into());
+
+        // The base package is unchanged
+        assert_eq!(packages["base-vim"].variables["font"]["size"], 11.into());
+        assert!(!packages["base-vim"].variables.contains_key("proxy"));
+    }
+
+    #[test]
+    fn only_inherited_duplicates_are_shared() {
+        let merge = |config: &str, packages: &[&str]| {
+            let local = LocalConfig {
+                includes: Vec::new(),
+                packages: packages.iter().map(|name| name.to_string()).collect(),
+                files: Files::new(),
+                variables: Variables::new(),
+            };
+            merge_configuration_files(
+                toml::from_str(config).unwrap(),
+                local,
+                None,
+                Path::new(".dotter/sources"),
+            )
+        };
+
+        let inherited = r#"
+            base = { files = { vimrc = "~/.vimrc" }, variables = { font = "mono" } }
+            work = { extends = "base" }
+            home = { extends = "base" }
+            "#;
+        let config = merge(inherited, &["work", "home"]).unwrap();
+        assert_eq!(config.files.len(), 1);
+        assert_eq!(config.variables["font"], "mono".into());
+
+        // The same entries in unrelated packages still conflict
+        let error = merge(
+            r#"
+            work = { files = { vimrc = "~/.vimrc" } }
+            home = { files = { vimrc = "~/.vimrc" } }
+            "#,
+            &["work", "home"],
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "merge package \"work\": file \"vimrc\" already encountered"
+        );
+        let error = merge(
+            r#"
+            work = { variables = { font = "mono" } }
+            home = { variables = { font = "mono" } }
+            "#,
+            &["work", "home"],
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "merge package \"work\": variable \"font\" already encountered"
+        );
+    }
+
+    #[test]
+    fn diamond_inheritance() {
+        let packages = inherited_packages(
+            r#"
+            a.variables = { x = "a", y = "a" }
+            b = { extends = "a", variables = { x = "b" } }
+            c = { extends = "a", variables = { y = "c" } }
+            d = { extends = ["b", "c"] }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(packages["d"].variables["x"], "b".into());
+        assert_eq!(packages["d"].variables["y"], "c".into());
+    }
+
+    #[test]
+    fn inheritance_cycle() {
+        let error = inherited_packages(
+            r#"
+            a = { extends = "c" }
+            b = { extends = "a" }
+            c = { extends = "b" }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "inheritance cycle: a -> c -> b -> a");
+    }
+
+    #[test]
+    fn deploy_order() {
+        let stages = |config: &str| {
+            let global: GlobalConfig = toml::from_str(config).unwrap();
+            deploy_stages(&global.packages)
+        };
+
+        assert_eq!(
+            stages(
+                r#"
+                tool = {}
+                app = { after = ["tool", "fonts"] }
+                fonts = { after = ["tool"] }
+                other = {}
+                "#
+            )
+            .unwrap(),
+            maplit::btreemap! {
+                "app".into() => 2,
+                "fonts".into() => 1,
+                "other".into() => 0,
+                "tool".into() => 0,
+            }
+        );
+
+        let error = stages(
+            r#"
+            a = { after = ["c"] }
+            b = { after = ["a"] }
+            c = { after = ["b"] }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "deploy order cycle: a -> c -> b -> a");
+    }
+
+    #[test]
+    fn validation_errors() {
+        let global: GlobalConfig = toml::from_str("[work]\nextends = \"base\"\n").unwrap();
+        let local = |packages: &[&str]| LocalConfig {
+            includes: Vec::new(),
+            packages: packages.iter().map(|name| name.to_string()).collect(),
+            files: Files::new(),
+            variables: Variables::new(),
+        };
+        assert_eq!(
+            super::unknown_package_references(&global, &local(&["nvm"])),
+            vec![
+                ValidationError::UnknownPackage {
+                    name: "nvm".into(),
+                    referenced_by: None,
+                },
+                ValidationError::UnknownPackage {
+                    name: "base".into(),
+                    referenced_by: Some(("work".into(), "extends")),
+                },
+            ]
+        );
+
+        let cycle = |relation: &str| {
+            let global: GlobalConfig = toml::from_str(&format!(
+                "a = {{ {0} = [\"b\"] }}\nb = {{ {0} = [\"a\"] }}\n",
+                relation
+            ))
+            .unwrap();
+            let error = merge_configuration_files(
+                global,
+                local(&["a"]),
+                None,
+                Path::new(".dotter/sources"),
+            )
+            .unwrap_err();
+            error.downcast_ref::<ValidationError>().cloned()
+        };
+        let packages = vec!["a".to_string(), "b".into(), "a".into()];
+        assert_eq!(
+            cycle("extends"),
+            Some(ValidationError::InheritanceCycle(packages.clone()))
+        );
+        assert_eq!(
+            cycle("after"),
+            Some(ValidationError::DeployOrderCycle(packages))
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let vimrc = dir.path().join("vimrc");
+        let nvimrc = dir.path().join("nvimrc");
+        let missing = dir.path().join("missing");
+        fs::write(&vimrc, "").unwrap();
+        fs::write(&nvimrc, "").unwrap();
+        let global: GlobalConfig = toml::from_str(&format!(
+            "[vim.files]\n{:?} = \"~/.vimrc\"\n{:?} = \"~/.vimrc\"\n{:?} = \"~/.missing\"\n",
+            vimrc, nvimrc, missing
+        ))
+        .unwrap();
+        let mut config =
+            merge_configuration_files(global, local(&["vim"]), None, Path::new(".dotter/sources"))
+                .unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ValidationError::MissingSource {
+                    source: missing.clone(),
+                    package: Some("vim".into()),
+                },
+                ValidationError::CollidingTarget {
+                    target: "~/.vimrc".into(),
+                    sources: vec![nvimrc, vimrc],
+                },
+            ])
+        );
+
+        config.settings.allow_conflicts = true;
+        config.files.remove(&missing);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn targets_are_chosen_by_package() {
+        let global = r#"
+            [work]
+
+            [app.files]
+            "app.conf" = { type = "template", target = { work = "~/.config/app/work.conf", default = "~/.config/app/config" } }
+            "theme.conf" = { type = "symbolic", target = { work = "~/.config/app/theme.conf" } }
+            "#;
+        let target = |packages: &[&str], source: &str| {
+            let local = LocalConfig {
+                includes: Vec::new(),
+                packages: packages.iter().map(|name| name.to_string()).collect(),
+                files: Files::new(),
+                variables: Variables::new(),
+            };
+            let config = merge_configuration_files(
+                toml::from_str(global).unwrap(),
+                local,
+                None,
+                Path::new(".dotter/sources"),
             )
-            .is_err(),
-            true
+            .unwrap();
+            config
+                .files
+                .get(Path::new(source))
+                .map(|target| target.path().to_path_buf())
+        };
+
+        assert_eq!(
+            target(&["app"], "app.conf"),
+            Some("~/.config/app/config".into())
+        );
+        assert_eq!(
+            target(&["app", "work"], "app.conf"),
+            Some("~/.config/app/work.conf".into())
+        );
+        assert_eq!(
+            target(&["app", "work"], "theme.conf"),
+            Some("~/.config/app/theme.conf".into())
+        );
+        // Without a `default`, the file is only deployed with the package
+        assert_eq!(target(&["app"], "theme.conf"), None);
+    }
+
+    #[test]
+    fn variable_schema_violations() {
+        let global = r#"
+            [settings.variable_schema]
+            dpi = { type = "integer", min = 72, max = 400 }
+            theme = { one_of = ["light", "dark"] }
+            "nvim.font_size" = { type = "integer" }
+            font = { type = "string", required = true }
+            [desktop.variables]
+            dpi = 96
+            theme = "light"
+            nvim = { font_size = 12 }
+            font = "Fira Code"
+            "#;
+        let local = |variables: &str| LocalConfig {
+            includes: Vec::new(),
+            packages: vec!["desktop".into()],
+            files: Files::new(),
+            variables: toml::from_str(variables).unwrap(),
+        };
+        let violations = |variables: &str| {
+            merge_configuration_files(
+                toml::from_str(global).unwrap(),
+                local(variables),
+                None,
+                Path::new(".dotter/sources"),
+            )
+            .unwrap()
+            .invalid_variables()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+        };
+
+        assert!(violations("").is_empty());
+        assert_eq!(
+            violations("nvim = { font_size = \"12\" }"),
+            vec![
+                "variable \"nvim.font_size\" must be of type integer, but \"12\" is of type string"
+            ]
+        );
+        assert_eq!(
+            violations("dpi = 960"),
+            vec!["variable \"dpi\" must be at most 400, but is 960"]
+        );
+        assert_eq!(
+            violations("theme = \"drak\""),
+            vec!["variable \"theme\" must be one of \"light\", \"dark\", but is \"drak\""]
+        );
+
+        // Overrides applied after loading are checked too, and so is `validate`
+        let mut config = merge_configuration_files(
+            toml::from_str(global).unwrap(),
+            local(""),
+            None,
+            Path::new(".dotter/sources"),
+        )
+        .unwrap();
+        assert!(config.check_variables().is_ok());
+        apply_variable_override(&mut config.variables, "dpi=960").unwrap();
+        assert!(config.check_variables().is_err());
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .contains(&ValidationError::InvalidVariable {
+                name: "dpi".into(),
+                violation: "must be at most 400, but is 960".into(),
+            }));
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_file_and_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = dir.path().join("global.toml");
+        let local = dir.path().join("local.toml");
+        let included = dir.path().join("included.toml");
+        fs::write(&global, "[shell.files]\nbashrc = \"~/.bashrc\"\n").unwrap();
+        fs::write(
+            &local,
+            format!("packages = [\"shell\"]\nincludes = [{:?}]\n", included),
+        )
+        .unwrap();
+        fs::write(
+            &included,
+            "[shell.variables]\neditor = \"vim\"\ntheme = = dark\n",
+        )
+        .unwrap();
+
+        let error = load_configuration(&local, &[global], None, dir.path(), None).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(
+            message.contains(&format!("including file {:?}", included)),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains(
+                "at line 3, column 9:\n        3 | theme = = dark\n          |         ^"
+            ),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn global_config_overlays() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        let overlay = dir.path().join("machine.toml");
+        fs::write(
+            &base,
+            r#"
+            [shell.files]
+            bashrc = "~/.bashrc"
+            [shell.variables]
+            editor = "vim"
+            prompt = { color = "blue", symbol = "$" }
+            paths = ["/usr/bin", "/bin"]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            &overlay,
+            r#"
+            [shell.variables]
+            prompt = { color = "red" }
+            paths = ["/opt/bin"]
+            "#,
+        )
+        .unwrap();
+
+        let global = load_global_config(&[base.clone(), overlay.clone()]).unwrap();
+        let shell = &global.packages["shell"];
+        assert_eq!(shell.files.len(), 1);
+        assert_eq!(shell.variables["editor"], "vim".into());
+        assert_eq!(
+            shell.variables["prompt"],
+            toml::from_str::<toml::Value>("color = \"red\"\nsymbol = \"$\"").unwrap()
+        );
+        assert_eq!(
+            shell.variables["paths"],
+            toml::Value::Array(vec!["/opt/bin".into()])
+        );
+
+        // An invalid file is named in the error
+        fs::write(&overlay, "[settings]\nallow_conflicts = \"yes\"\n").unwrap();
+        let error = load_global_config(&[base, overlay.clone()]).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains(&format!("load global config {:?}", overlay)),
+            "{:#}",
+            error
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rendered_output_is_formatted() {
+        let mut target = TemplateTarget {
+            format_command: Some("tr a-z A-Z".into()),
+            ..TemplateTarget::from(PathBuf::from("/home/user/.config/app"))
+        };
+        let rendered = crate::handlebars_helpers::render_target(
+            &handlebars::Handlebars::new(),
+            Path::new("app"),
+            &target,
+            "name = {{name}}\n".into(),
+            &maplit::btreemap! { "name".into() => "alice".into() },
+        )
+        .unwrap();
+        assert_eq!(rendered, "NAME = ALICE\n");
+
+        target.format_command = Some("ls /nonexistent-dotter-dir".into());
+        let error = target
+            .apply_post_render_actions("name = alice\n".into())
+            .unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("nonexistent-dotter-dir"),
+            "{:#}",
+            error
         );
     }
 }
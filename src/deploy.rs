@@ -1,34 +1,60 @@
 use anyhow::{Context, Result};
+use handlebars::Handlebars;
 
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use crate::actions::{self, ActionRunner, RealActionRunner};
-use crate::args::Options;
-use crate::config::{self, Cache, FileTarget, SymbolicTarget, TemplateTarget};
+use crate::actions::{self, ActionRunner, Force, RealActionRunner};
+use crate::args::{ConfigFormat, DiffOptions, ListFormat, Options, VariablesOptions};
+use crate::audit::{self, AuditReport};
+use crate::config::{
+    self, Cache, FileTarget, HistoryEntry, LastDeploy, SymbolicTarget, SymlinkFallback,
+    TemplateTarget, Variables,
+};
+use crate::difference;
 use crate::display_error;
+use crate::encoding::Encoding;
 use crate::filesystem::{self, load_file, Filesystem};
-use crate::handlebars_helpers::create_new_handlebars;
+use crate::handlebars_helpers::{create_new_handlebars, render_target, RenderCache};
 use crate::hooks;
+use crate::notify::{Notifier, Outcome};
+use crate::parallel;
+use crate::plan::{self, DeployPlan, TargetState};
+use crate::progress::{self, Progress};
+use crate::variables;
 
-/// Returns true if an error was printed
-pub fn deploy(opt: &Options) -> Result<bool> {
-    // === Load configuration ===
-    let mut patch = None;
-    if opt.patch {
-        debug!("Reading manual patch from stdin...");
-        let mut patch_str = String::new();
-        io::stdin()
-            .read_to_string(&mut patch_str)
-            .context("read patch from stdin")?;
-        patch = Some(toml::from_str(&patch_str).context("parse patch into package")?);
+/// Returns true if an error was printed.
+/// On a terminal, asks for confirmation before changing anything, unless `--noconfirm`.
+/// With `review`, it also asks for every hunk of a template update whether to apply it
+pub fn deploy(opt: &Options, review: bool) -> Result<bool> {
+    let interactive =
+        !opt.noconfirm && !opt.dry_run && io::stdin().is_terminal() && io::stdout().is_terminal();
+    if interactive {
+        let review = review.then_some(&filesystem::ask_boolean as &dyn Fn(&str) -> bool);
+        deploy_with_confirmation(opt, Some(&filesystem::ask_boolean), review)
+    } else {
+        if review {
+            warn!("Not reviewing hunks, since the deploy isn't interactive. Applying all of them.");
+        }
+        deploy_with_confirmation(opt, None, None)
     }
-    trace!("Manual patch: {:#?}", patch);
+}
 
-    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
-        .context("get a configuration")?;
+/// Deploys after `confirm` accepted a summary of the changes, or without asking if it's None.
+/// `review` chooses the hunks of template updates that are applied, if it's set.
+/// Returns true if an error was printed
+pub fn deploy_with_confirmation(
+    opt: &Options,
+    confirm: Option<&dyn Fn(&str) -> bool>,
+    review: Option<&dyn Fn(&str) -> bool>,
+) -> Result<bool> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+    let opt = &with_cache_settings(opt, &config.settings)?;
 
     let mut cache = if let Some(cache) = load_file(&opt.cache_file)? {
         cache
@@ -41,96 +67,114 @@ pub fn deploy(opt: &Options) -> Result<bool> {
 
     let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
 
-    debug!("Running pre-deploy hook");
-    if !opt.dry_run {
-        hooks::run_hook(
-            &opt.pre_deploy,
-            &opt.cache_directory,
+    // === Re-structure configuration ===
+
+    let stages = config.file_stages();
+    let (mut desired_symlinks, mut desired_templates) =
+        split_files(config.files, &config.settings, &config.variables)?;
+
+    mark_first_deploys(&mut desired_templates, &cache);
+
+    let mut skipped_templates = if opt.changed_sources_only {
+        skip_unchanged_sources(&mut desired_templates, &mut cache)
+            .context("skip templates whose source didn't change")?
+    } else {
+        BTreeMap::new()
+    };
+    let mut skipped_symlinks = BTreeMap::new();
+    if let Some(target) = &opt.only_target {
+        let selected = select_target(target, &mut desired_symlinks, &mut desired_templates)?;
+        skipped_symlinks = take_other_targets(&mut cache.symlinks, &selected);
+        skipped_templates.extend(take_other_targets(&mut cache.templates, &selected));
+    }
+
+    if let Some(confirm) = confirm {
+        let report = AuditReport::new(
+            &desired_symlinks,
+            &desired_templates,
+            &cache,
             &handlebars,
             &config.variables,
         )
-        .context("run pre-deploy hook")?;
+        .context("summarize changes")?;
+        if let Some(summary) = deploy_summary(&report) {
+            if !confirm(&format!("{}. Proceed? [y/N] ", summary)) {
+                info!("Deploy cancelled");
+                return Ok(false);
+            }
+        }
+    }
+
+    let mut error_occurred = false;
+
+    debug!("Running pre-deploy hook");
+    if !opt.dry_run {
+        error_occurred |= hooks::handle_failure(
+            hooks::run_hook(
+                &opt.pre_deploy,
+                &opt.cache_directory,
+                &handlebars,
+                &config.variables,
+                &config.settings,
+            ),
+            config.settings.hooks.on_failure,
+            "run pre-deploy hook",
+        )?;
     }
 
     let (mut real_fs, mut dry_run_fs);
     let fs: &mut dyn Filesystem = if !opt.dry_run {
-        real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm);
+        real_fs = crate::filesystem::RealFilesystem::new(
+            opt.noconfirm,
+            config.settings.relative_symlinks,
+        )
+        .with_privileged_writes(privileged_writes(
+            opt,
+            &desired_templates,
+            &config.settings,
+        ));
         &mut real_fs
     } else {
         dry_run_fs = crate::filesystem::DryRunFilesystem::new();
         &mut dry_run_fs
     };
 
-    // === Re-structure configuration ===
-
-    // On Windows, you need developer mode to create symlinks.
-    let symlinks_enabled = if filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
-        .context("check whether symlinks are enabled")?
-    {
-        true
-    } else {
-        warn!(
-            "No permission to create symbolic links.\n
-On Windows, in order to create symbolic links you need to enable Developer Mode.\n
-Proceeding by copying instead of symlinking."
-        );
-        false
-    };
-
-    let mut desired_symlinks = BTreeMap::<PathBuf, SymbolicTarget>::new();
-    let mut desired_templates = BTreeMap::<PathBuf, TemplateTarget>::new();
-
-    for (source, target) in config.files {
-        if symlinks_enabled {
-            match target {
-                FileTarget::Automatic(target) => {
-                    if filesystem::is_template(&source)
-                        .context(format!("check whether {:?} is a template", source))?
-                    {
-                        desired_templates.insert(source, target.into());
-                    } else {
-                        desired_symlinks.insert(source, target.into());
-                    }
-                }
-                FileTarget::Symbolic(target) => {
-                    desired_symlinks.insert(source, target);
-                }
-                FileTarget::ComplexTemplate(target) => {
-                    desired_templates.insert(source, target);
-                }
-            }
-        } else {
-            match target {
-                FileTarget::Automatic(target) => {
-                    desired_templates.insert(source, target.into());
-                }
-                FileTarget::Symbolic(target) => {
-                    desired_templates.insert(source, target.into_template());
-                }
-                FileTarget::ComplexTemplate(target) => {
-                    desired_templates.insert(source, target);
-                }
-            }
-        }
-    }
-
     // === Perform deployment ===
 
     let mut runner = RealActionRunner::new(
         fs,
         &handlebars,
         &config.variables,
-        opt.force,
+        opt.into(),
         opt.diff_context_lines,
+        opt.diff && !opt.quiet,
+    );
+    if let Some(review) = review {
+        runner = runner.with_hunk_review(review);
+    }
+
+    let targets_before = target_states(
+        cache
+            .symlinks
+            .values()
+            .chain(cache.templates.values())
+            .chain(desired_symlinks.values().map(|t| &t.target))
+            .chain(desired_templates.values().map(|t| &t.target)),
     );
 
-    let (suggest_force, mut error_occurred) = run_deploy(
+    let mut notifier = Notifier::connect(opt.notify_socket.as_deref());
+    let (suggest_force, deploy_error_occurred) = run_deploy(
         &mut runner,
         &desired_symlinks,
         &desired_templates,
+        &stages,
         &mut cache,
         opt,
+        &mut notifier,
     );
+    error_occurred |= deploy_error_occurred;
+    cache.symlinks.extend(skipped_symlinks);
+    cache.templates.extend(skipped_templates);
 
     // === Post-deploy ===
 
@@ -140,27 +184,157 @@ Proceeding by copying instead of symlinking."
     }
 
     if !opt.dry_run {
-        filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .context("get current time")?
+            .as_secs();
+        let packages = config
+            .packages
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .map(|(package, _)| package.clone())
+            .collect::<Vec<_>>();
+
+        let mut changed_files = 0;
+        for (target, before) in targets_before {
+            let after = TargetState::of(&target)
+                .ok()
+                .filter(|state| *state != TargetState::Missing);
+            if after == before {
+                continue;
+            }
+            changed_files += 1;
+            cache.record_change(
+                &target,
+                HistoryEntry {
+                    timestamp,
+                    packages: packages.clone(),
+                    before,
+                    after,
+                },
+            );
+        }
+
+        // A deploy of a single target doesn't bring the others up to date
+        if !error_occurred && opt.only_target.is_none() {
+            cache.last_deploy = Some(LastDeploy {
+                timestamp,
+                packages,
+                changed_files,
+            });
+        }
+    }
+
+    if !opt.dry_run {
+        back_up_cache(&opt.cache_file, &cache, config.settings.cache_backups)?;
+        save_cache(&opt.cache_file, cache)?;
     }
 
     debug!("Running post-deploy hook");
     if !opt.dry_run {
-        hooks::run_hook(
-            &opt.post_deploy,
-            &opt.cache_directory,
-            &handlebars,
-            &config.variables,
-        )
-        .context("run post-deploy hook")?;
+        error_occurred |= hooks::handle_failure(
+            hooks::run_hook(
+                &opt.post_deploy,
+                &opt.cache_directory,
+                &handlebars,
+                &config.variables,
+                &config.settings,
+            ),
+            config.settings.hooks.on_failure,
+            "run post-deploy hook",
+        )?;
     }
 
+    notifier.summary(error_occurred);
     Ok(error_occurred)
 }
 
+/// The targets that may be written through the privilege helper. That's none without
+/// `--allow-sudo`, which is warned about if there are any with `privileged_write`
+fn privileged_writes(
+    opt: &Options,
+    templates: &BTreeMap<PathBuf, TemplateTarget>,
+    settings: &config::Settings,
+) -> filesystem::PrivilegedWrites {
+    let targets = templates
+        .values()
+        .filter(|target| target.privileged_write.unwrap_or_default())
+        .map(|target| target.target.clone())
+        .collect::<BTreeSet<_>>();
+    if targets.is_empty() || opt.allow_sudo {
+        return filesystem::PrivilegedWrites {
+            helper: settings.privilege_helper.clone(),
+            targets,
+        };
+    }
+
+    warn!(
+        "{} files have `privileged_write` set, but --allow-sudo wasn't given, so they're only written as the current user",
+        targets.len()
+    );
+    filesystem::PrivilegedWrites::default()
+}
+
+/// Describes what a deploy would change, like `2 files changed, 1 new, 1 orphan to remove`.
+/// None if everything is in sync
+fn deploy_summary(report: &AuditReport) -> Option<String> {
+    let count = |status| {
+        report
+            .entries
+            .iter()
+            .filter(|entry| entry.status == status)
+            .count()
+    };
+    let parts = vec![
+        match count(audit::AuditStatus::Changed) {
+            1 => "1 file changed".to_string(),
+            changed => format!("{} files changed", changed),
+        },
+        format!("{} new", count(audit::AuditStatus::Missing)),
+        match count(audit::AuditStatus::Stale) {
+            1 => "1 orphan to remove".to_string(),
+            stale => format!("{} orphans to remove", stale),
+        },
+    ];
+    let parts = parts
+        .into_iter()
+        .filter(|part| !part.starts_with("0 "))
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", "))
+    }
+}
+
+/// Returns true if an error was printed. A dry run lists the targets that would be removed
 pub fn undeploy(opt: Options) -> Result<bool> {
+    let dry_run = opt.dry_run;
+    let (error_occurred, removed) = undeploy_targets(opt)?;
+    if dry_run {
+        for target in removed {
+            println!("would remove {:?}", target);
+        }
+    }
+    Ok(error_occurred)
+}
+
+/// Undeploys, and returns whether an error was printed and the targets that were removed,
+/// or that would be removed in a dry run
+fn undeploy_targets(opt: Options) -> Result<(bool, Vec<PathBuf>)> {
     // === Load configuration ===
-    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, None)
-        .context("get a configuration")?;
+    let mut config = config::load_configuration(
+        &opt.local_config,
+        &opt.global_configs(),
+        opt.config_dir.as_deref(),
+        &opt.sources_directory,
+        None,
+    )
+    .context("get a configuration")?;
+    config.settings.strict |= opt.strict;
+    config.settings.allow_conflicts |= opt.allow_conflicts;
+
+    let opt = with_cache_settings(&opt, &config.settings)?;
 
     let mut cache: config::Cache = filesystem::load_file(&opt.cache_file)?
         .context("load cache: Cannot undeploy without a cache.")?;
@@ -169,23 +343,30 @@ pub fn undeploy(opt: Options) -> Result<bool> {
 
     // === Pre-undeploy ===
 
+    let mut suggest_force = false;
+    let mut error_occurred = false;
+
     debug!("Running pre-undeploy hook");
     if !opt.dry_run {
-        hooks::run_hook(
-            &opt.pre_undeploy,
-            &opt.cache_directory,
-            &handlebars,
-            &config.variables,
-        )
-        .context("run pre-undeploy hook")?;
+        error_occurred |= hooks::handle_failure(
+            hooks::run_hook(
+                &opt.pre_undeploy,
+                &opt.cache_directory,
+                &handlebars,
+                &config.variables,
+                &config.settings,
+            ),
+            config.settings.hooks.on_failure,
+            "run pre-undeploy hook",
+        )?;
     }
 
-    let mut suggest_force = false;
-    let mut error_occurred = false;
-
     let (mut real_fs, mut dry_run_fs);
     let fs: &mut dyn Filesystem = if !opt.dry_run {
-        real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm);
+        real_fs = crate::filesystem::RealFilesystem::new(
+            opt.noconfirm,
+            config.settings.relative_symlinks,
+        );
         &mut real_fs
     } else {
         dry_run_fs = crate::filesystem::DryRunFilesystem::new();
@@ -194,32 +375,51 @@ pub fn undeploy(opt: Options) -> Result<bool> {
 
     // === Perform undeployment ===
 
+    let mut failures = Vec::new();
+    let mut removed = Vec::new();
+
     for (deleted_symlink, target) in cache.symlinks.clone() {
+        // Targets that are already gone are only removed from the cache
+        let exists = target.symlink_metadata().is_ok();
         execute_action(
-            actions::delete_symlink(&deleted_symlink, &target, fs, opt.force),
-            || cache.symlinks.remove(&deleted_symlink),
+            actions::delete_symlink(&deleted_symlink, &target, fs, Force::from(&opt).external),
+            || {
+                cache.symlinks.remove(&deleted_symlink);
+                if exists {
+                    removed.push(target.clone());
+                }
+            },
             || format!("delete symlink {:?} -> {:?}", deleted_symlink, target),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failures,
         );
     }
 
     for (deleted_template, target) in cache.templates.clone() {
+        let exists = target.symlink_metadata().is_ok();
         execute_action(
             actions::delete_template(
                 &deleted_template,
-                &opt.cache_directory.join(&deleted_template),
+                &snapshot_path(&opt.cache_directory, &deleted_template),
                 &target,
                 fs,
-                opt.force,
+                Force::from(&opt).external,
             ),
-            || cache.templates.remove(&deleted_template),
+            || {
+                cache.templates.remove(&deleted_template);
+                if exists {
+                    removed.push(target.clone());
+                }
+            },
             || format!("delete template {:?} -> {:?}", deleted_template, target),
             &mut suggest_force,
-            &mut error_occurred,
+            &mut failures,
         );
     }
 
+    report_failures(&failures);
+    error_occurred |= !failures.is_empty();
+
     // === Post-undeploy ===
 
     if suggest_force {
@@ -230,615 +430,4313 @@ pub fn undeploy(opt: Options) -> Result<bool> {
     if !opt.dry_run {
         // Should be empty if everything went well, but if some things were skipped this contains
         // them.
-        filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+        save_cache(&opt.cache_file, cache)?;
     }
 
     debug!("Running post-undeploy hook");
     if !opt.dry_run {
-        hooks::run_hook(
-            &opt.post_undeploy,
-            &opt.cache_directory,
-            &handlebars,
-            &config.variables,
-        )
-        .context("run post-undeploy hook")?;
+        error_occurred |= hooks::handle_failure(
+            hooks::run_hook(
+                &opt.post_undeploy,
+                &opt.cache_directory,
+                &handlebars,
+                &config.variables,
+                &config.settings,
+            ),
+            config.settings.hooks.on_failure,
+            "run post-undeploy hook",
+        )?;
     }
 
-    Ok(error_occurred)
+    Ok((error_occurred, removed))
 }
 
-fn run_deploy<A: ActionRunner>(
-    runner: &mut A,
-    desired_symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
-    desired_templates: &BTreeMap<PathBuf, TemplateTarget>,
-    cache: &mut Cache,
-    opt: &Options,
-) -> (bool, bool) {
-    let mut suggest_force = false;
-    let mut error_occurred = false;
+/// Removes the files whose source doesn't exist, so that the other files can still be processed.
+/// Returns true if an error was printed
+fn remove_missing_sources(
+    files: &mut config::Files,
+    file_packages: &BTreeMap<PathBuf, String>,
+) -> bool {
+    let mut missing = false;
+    files.retain(|source, _| {
+        if source.exists() {
+            return true;
+        }
+        match file_packages.get(source) {
+            Some(package) => error!(
+                "Source file not found: {:?} (referenced by package {:?})",
+                source, package
+            ),
+            None => error!(
+                "Source file not found: {:?} (referenced by the local configuration)",
+                source
+            ),
+        }
+        missing = true;
+        false
+    });
+    missing
+}
 
-    // Index by both source and target location
-    let existing_symlinks: BTreeSet<(PathBuf, PathBuf)> = cache
-        .symlinks
-        .iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect();
-    let existing_templates: BTreeSet<(PathBuf, PathBuf)> = cache
-        .templates
-        .iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect();
+/// Records the state of every target, to count how many a deploy changed. None if nothing a
+/// deploy manages is there
+fn target_states<'a>(
+    targets: impl Iterator<Item = &'a PathBuf>,
+) -> BTreeMap<PathBuf, Option<TargetState>> {
+    targets
+        .map(|target| {
+            let state = TargetState::of(target).ok();
+            (
+                target.clone(),
+                state.filter(|state| *state != TargetState::Missing),
+            )
+        })
+        .collect()
+}
 
-    let desired_symlinks: BTreeMap<(PathBuf, PathBuf), _> = desired_symlinks
-        .iter()
-        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
-        .collect();
-    let desired_templates: BTreeMap<(PathBuf, PathBuf), _> = desired_templates
+/// For `--target`: keeps only the file that's deployed to `target`, by comparing resolved paths,
+/// and returns its target as it's configured. Suggests the closest target if there's no match
+fn select_target(
+    target: &Path,
+    symlinks: &mut BTreeMap<PathBuf, SymbolicTarget>,
+    templates: &mut BTreeMap<PathBuf, TemplateTarget>,
+) -> Result<PathBuf> {
+    let resolve = |path: &Path| filesystem::resolve_target_path(path).unwrap_or(path.into());
+    let expanded = PathBuf::from(shellexpand::tilde(&target.to_string_lossy()).as_ref());
+    let wanted = resolve(&expanded);
+    let targets = symlinks
+        .values()
+        .map(|symlink| &symlink.target)
+        .chain(templates.values().map(|template| &template.target))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let selected = match targets
         .iter()
-        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
-        .collect();
+        .find(|&candidate| resolve(candidate) == wanted)
+    {
+        Some(selected) => selected.clone(),
+        None => {
+            let candidates = targets
+                .iter()
+                .map(|candidate| candidate.to_string_lossy())
+                .collect::<Vec<_>>();
+            let closest = similar::get_close_matches(
+                wanted.to_string_lossy().as_ref(),
+                &candidates.iter().map(AsRef::as_ref).collect::<Vec<_>>(),
+                1,
+                0.6,
+            );
+            match closest.first() {
+                Some(closest) => anyhow::bail!(
+                    "no file is deployed to {:?}, did you mean {:?}?",
+                    expanded,
+                    closest
+                ),
+                None => anyhow::bail!("no file is deployed to {:?}", expanded),
+            }
+        }
+    };
+    symlinks.retain(|_, symlink| symlink.target == selected);
+    templates.retain(|_, template| template.target == selected);
+    Ok(selected)
+}
 
-    // Avoid modifying cache while iterating over it
-    let mut resulting_cache = cache.clone();
+/// Takes the cache entries out of `cached` that aren't deployed to `selected`, so that a deploy
+/// of only that target doesn't remove them. They're returned to be put back after the deploy
+fn take_other_targets(
+    cached: &mut BTreeMap<PathBuf, PathBuf>,
+    selected: &Path,
+) -> BTreeMap<PathBuf, PathBuf> {
+    let (kept, others) = std::mem::take(cached)
+        .into_iter()
+        .partition(|(_, target)| target == selected);
+    *cached = kept;
+    others
+}
 
-    for (source, target) in
-        existing_symlinks.difference(&desired_symlinks.keys().cloned().collect())
-    {
-        execute_action(
-            runner.delete_symlink(source, target),
-            || resulting_cache.symlinks.remove(source),
-            || format!("delete symlink {:?} -> {:?}", source, target),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
-    }
+/// For `--changed-sources-only`: takes the templates out of `templates` and the cache that the
+/// last deploy deployed to the same target and whose source wasn't modified since, so that
+/// they're left alone. Returns their cache entries, to be put back after the deploy.
+/// Only the source's modification time is compared, so a template whose variables, partials
+/// or configuration changed is skipped as well
+fn skip_unchanged_sources(
+    templates: &mut BTreeMap<PathBuf, TemplateTarget>,
+    cache: &mut Cache,
+) -> Result<BTreeMap<PathBuf, PathBuf>> {
+    let last_deploy = match &cache.last_deploy {
+        Some(last_deploy) => SystemTime::UNIX_EPOCH + Duration::from_secs(last_deploy.timestamp),
+        None => {
+            info!("No successful deploy has been recorded yet, deploying all templates");
+            return Ok(BTreeMap::new());
+        }
+    };
 
-    for (source, target) in
-        existing_templates.difference(&desired_templates.keys().cloned().collect())
-    {
-        execute_action(
-            runner.delete_template(source, &opt.cache_directory.join(source), target),
-            || resulting_cache.templates.remove(source),
-            || format!("delete template {:?} -> {:?}", source, target),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
+    let mut skipped = BTreeMap::new();
+    for (source, target) in templates.iter() {
+        if cache.templates.get(source) != Some(&target.target) {
+            continue;
+        }
+        let modified = std::fs::metadata(source)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("get modification time of {:?}", source))?;
+        // Timestamps only have seconds, so a source modified in the same second counts as changed
+        if modified < last_deploy {
+            debug!("Source {:?} is unchanged since the last deploy", source);
+            skipped.insert(source.clone(), target.target.clone());
+        }
     }
-
-    for (source, target_path) in desired_symlinks
-        .keys()
-        .cloned()
-        .collect::<BTreeSet<_>>()
-        .difference(&existing_symlinks)
-    {
-        let target = desired_symlinks
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.create_symlink(source, target),
-            || {
-                resulting_cache
-                    .symlinks
-                    .insert(source.clone(), target_path.clone())
-            },
-            || format!("create symlink {:?} -> {:?}", source, target_path),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
+    for source in skipped.keys() {
+        templates.remove(source);
+        cache.templates.remove(source);
     }
+    Ok(skipped)
+}
 
-    for (source, target_path) in desired_templates
-        .keys()
-        .cloned()
-        .collect::<BTreeSet<_>>()
-        .difference(&existing_templates)
-    {
-        let target = desired_templates
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.create_template(source, &opt.cache_directory.join(source), target),
-            || {
-                resulting_cache
-                    .templates
-                    .insert(source.clone(), target_path.clone())
-            },
-            || format!("create template {:?} -> {:?}", source, target_path),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
-    }
+/// Takes the locations of the cache from the settings, unless they were given on the command line
+fn with_cache_settings(opt: &Options, settings: &config::Settings) -> Result<Options> {
+    let expand = |path: &Path| -> Result<PathBuf> {
+        Ok(shellexpand::full(&path.to_string_lossy())?
+            .to_string()
+            .into())
+    };
 
-    for (source, target_path) in
-        existing_symlinks.intersection(&desired_symlinks.keys().cloned().collect())
-    {
-        let target = desired_symlinks
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.update_symlink(source, target),
-            || (),
-            || format!("update symlink {:?} -> {:?}", source, target_path),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
+    let mut opt = opt.clone();
+    if let Some(cache_file) = &settings.cache_file {
+        if !opt.cache_file_given {
+            opt.cache_file = expand(cache_file).context("expand `cache_file`")?;
+        }
     }
-
-    for (source, target_path) in
-        existing_templates.intersection(&desired_templates.keys().cloned().collect())
-    {
-        let target = desired_templates
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.update_template(source, &opt.cache_directory.join(source), target),
-            || (),
-            || format!("update template {:?} -> {:?}", source, target_path),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
+    if let Some(cache_directory) = &settings.cache_directory {
+        if !opt.cache_directory_given {
+            opt.cache_directory = expand(cache_directory).context("expand `cache_directory`")?;
+        }
     }
+    Ok(opt)
+}
 
-    *cache = resulting_cache;
+/// Loads the configuration with everything the command line changes about it: the patch, the
+/// variables of `--vars-stdin`, `--strict` and `--allow-conflicts`
+fn load_config(opt: &Options) -> Result<config::Configuration> {
+    load_config_with_overrides(opt, &[])
+}
 
-    (suggest_force, error_occurred)
+/// Like `load_config`, with variable overrides like `diff --set theme=dark` applied last
+fn load_config_with_overrides(
+    opt: &Options,
+    overrides: &[String],
+) -> Result<config::Configuration> {
+    let patch = read_patch(opt)?;
+
+    let mut config = config::load_configuration(
+        &opt.local_config,
+        &opt.global_configs(),
+        opt.config_dir.as_deref(),
+        &opt.sources_directory,
+        patch,
+    )
+    .context("get a configuration")?;
+    apply_stdin_variables(opt, &mut config)?;
+    for assignment in overrides {
+        config::apply_variable_override(&mut config.variables, assignment)
+            .with_context(|| format!("apply variable override {:?}", assignment))?;
+    }
+    if !overrides.is_empty() {
+        trace!("Variables after overrides: {:#?}", config.variables);
+    }
+    config.check_variables()?;
+    config.settings.strict |= opt.strict;
+    config.settings.allow_conflicts |= opt.allow_conflicts;
+    Ok(config)
 }
 
-/// Used to remove duplication
-fn execute_action<T, S: FnOnce() -> T, E: FnOnce() -> String>(
-    result: Result<bool>,
-    success: S,
-    context: E,
+/// `with_cache_settings` for the commands that only need the cache. Without a global
+/// configuration, like on a machine that only applies plans, the locations of the flags are used
+fn cache_options(opt: &Options) -> Result<Options> {
+    let global_config = &opt.global_config;
+    let settings = if global_config != Path::new("-") && !global_config.exists() {
+        config::Settings::default()
+    } else {
+        config::load_settings(&opt.global_configs()).context("load settings")?
+    };
+    with_cache_settings(opt, &settings)
+}
+
+/// Saves the cache, creating the directory it's in if needed
+fn save_cache(cache_file: &Path, cache: Cache) -> Result<()> {
+    if let Some(parent) = cache_file.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).context("create directory of cache file")?;
+    }
+    filesystem::save_file(cache_file, cache).context("save cache")
+}
+
+/// Copies the current cache file to `<cache file>.bak`, moving the `keep - 1` backups before it
+/// to `.bak.1`, `.bak.2` and so on, and dropping the oldest.
+/// Does nothing if `keep` is 0, if there's no cache file yet, or if `cache` has the same files
+/// and history as it, so that deploys that change nothing don't rotate the older backups away.
+/// `last_deploy` isn't compared, since every deploy updates it
+fn back_up_cache(cache_file: &Path, cache: &Cache, keep: usize) -> Result<()> {
+    if keep == 0 || !cache_file.exists() {
+        return Ok(());
+    }
+    let current = std::fs::read_to_string(cache_file)
+        .ok()
+        .and_then(|current| toml::from_str::<Cache>(&current).ok());
+    if let Some(current) = current {
+        if current.symlinks == cache.symlinks
+            && current.templates == cache.templates
+            && current.history == cache.history
+        {
+            return Ok(());
+        }
+    }
+    let backup = |index: usize| {
+        let mut name = cache_file.as_os_str().to_owned();
+        name.push(".bak");
+        if index > 0 {
+            name.push(format!(".{}", index));
+        }
+        PathBuf::from(name)
+    };
+
+    for index in (0..keep - 1).rev() {
+        let from = backup(index);
+        if from.exists() {
+            std::fs::rename(&from, backup(index + 1))
+                .with_context(|| format!("rotate cache backup {:?}", from))?;
+        }
+    }
+    std::fs::copy(cache_file, backup(0)).context("back up cache file")?;
+    Ok(())
+}
+
+/// Sets the `dotter.first_deploy` variable of every template: true if the cache doesn't have the
+/// template deployed to its target, for content that's only wanted on a fresh machine
+fn mark_first_deploys(templates: &mut BTreeMap<PathBuf, TemplateTarget>, cache: &Cache) {
+    for (source, target) in templates.iter_mut() {
+        let first_deploy = cache.templates.get(source) != Some(&target.target);
+        let dotter = target
+            .variables
+            .entry(String::from("dotter"))
+            .or_insert_with(|| toml::value::Table::new().into());
+        if let toml::Value::Table(dotter) = dotter {
+            dotter.insert("first_deploy".into(), first_deploy.into());
+        }
+    }
+}
+
+/// Where the rendered output of a template is kept after deploying it, to detect changes to
+/// the target. Absolute sources are kept inside the cache directory too, instead of replacing it
+pub(crate) fn snapshot_path(cache_directory: &Path, source: &Path) -> PathBuf {
+    let relative = source
+        .components()
+        .filter(|component| {
+            !matches!(
+                component,
+                std::path::Component::Prefix(_) | std::path::Component::RootDir
+            )
+        })
+        .collect::<PathBuf>();
+    cache_directory.join(relative)
+}
+
+/// Prints what the last successful deploy did
+pub fn status(opt: &Options) -> Result<()> {
+    let opt = &cache_options(opt)?;
+    let cache: Option<Cache> = load_file(&opt.cache_file)?;
+    match cache.and_then(|cache| cache.last_deploy) {
+        Some(last_deploy) => print!("{}", format_last_deploy(&last_deploy)?),
+        None => println!("No successful deploy has been recorded yet."),
+    }
+    Ok(())
+}
+
+/// Prints the audit report. Returns true if a target isn't in sync
+pub fn audit(opt: &Options) -> Result<bool> {
+    let (report, _) = audit_report(opt)?;
+    let json = serde_json::to_string_pretty(&report).context("serialize report")?;
+    println!("{}", json);
+    Ok(!report.in_sync())
+}
+
+/// The audit report, and the settings of the configuration it was made with
+fn audit_report(opt: &Options) -> Result<(AuditReport, config::Settings)> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+    let opt = &with_cache_settings(opt, &config.settings)?;
+
+    let cache = if let Some(cache) = load_file(&opt.cache_file)? {
+        cache
+    } else {
+        warn!("Cache file not found. Assuming cache is empty.");
+        config::Cache::default()
+    };
+
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    let (desired_symlinks, mut desired_templates) =
+        split_files_with(config.files, &config.settings, &config.variables, true)?;
+    mark_first_deploys(&mut desired_templates, &cache);
+
+    // === Compare targets ===
+
+    let report = AuditReport::new(
+        &desired_symlinks,
+        &desired_templates,
+        &cache,
+        &handlebars,
+        &config.variables,
+    )
+    .context("create report")?;
+    Ok((report, config.settings))
+}
+
+/// Adds the targets that already are what a deploy would make them to the cache, without
+/// writing them, for example after restoring them from a backup.
+/// Returns true if an error was printed
+pub fn sync_cache(opt: &Options) -> Result<bool> {
+    let (report, settings) = audit_report(opt)?;
+    let opt = &with_cache_settings(opt, &settings)?;
+    let mut cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+
+    let (mut real_fs, mut dry_run_fs);
+    let fs: &mut dyn Filesystem = if !opt.dry_run {
+        real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm, false);
+        &mut real_fs
+    } else {
+        dry_run_fs = crate::filesystem::DryRunFilesystem::new();
+        &mut dry_run_fs
+    };
+
+    let mut error_occurred = false;
+    let (mut cached, mut mismatched) = (0, 0);
+    for entry in &report.entries {
+        match entry.status {
+            audit::AuditStatus::InSync => {
+                let result = || -> Result<()> {
+                    match entry.kind {
+                        audit::AuditKind::Symlink => {
+                            cache
+                                .symlinks
+                                .insert(entry.source.clone(), entry.target.clone());
+                        }
+                        audit::AuditKind::Template => {
+                            let snapshot = snapshot_path(&opt.cache_directory, &entry.source);
+                            fs.create_dir_all(
+                                snapshot.parent().context("get parent of snapshot")?,
+                                &None,
+                                None,
+                            )
+                            .context("create snapshot directory")?;
+                            fs.copy_file(&entry.target, &snapshot, &None)
+                                .context("copy target to snapshot")?;
+                            cache
+                                .templates
+                                .insert(entry.source.clone(), entry.target.clone());
+                        }
+                    }
+                    Ok(())
+                }();
+                match result {
+                    Ok(()) => {
+                        info!("{:?} -> {:?} is up to date", entry.source, entry.target);
+                        cached += 1;
+                    }
+                    Err(e) => {
+                        display_error(
+                            e.context(format!("cache {:?} -> {:?}", entry.source, entry.target)),
+                        );
+                        error_occurred = true;
+                    }
+                }
+            }
+            audit::AuditStatus::Changed => {
+                warn!(
+                    "{:?} -> {:?} differs from what a deploy would make it, leaving it for a deploy",
+                    entry.source, entry.target
+                );
+                mismatched += 1;
+            }
+            audit::AuditStatus::Error => {
+                error!(
+                    "Failed to compare {:?} -> {:?}: {}",
+                    entry.source,
+                    entry.target,
+                    entry.error.as_deref().unwrap_or("unknown error")
+                );
+                error_occurred = true;
+            }
+            audit::AuditStatus::Missing | audit::AuditStatus::Stale => {}
+        }
+    }
+
+    if !opt.dry_run {
+        save_cache(&opt.cache_file, cache)?;
+    }
+    println!(
+        "Cached {} up to date targets, {} differ and are left for a deploy.",
+        cached, mismatched
+    );
+    Ok(error_occurred)
+}
+
+/// Prints every target of the configuration
+pub fn list(opt: &Options, format: ListFormat) -> Result<()> {
+    let entries = inventory(opt)?;
+    match format {
+        ListFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&entries).context("serialize targets")?
+        ),
+        ListFormat::Text => {
+            for entry in entries {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    entry.target.display(),
+                    match entry.kind {
+                        audit::AuditKind::Symlink => "symlink",
+                        audit::AuditKind::Template => "template",
+                    },
+                    entry.package.as_deref().unwrap_or("-"),
+                    if entry.deployed {
+                        "deployed"
+                    } else {
+                        "not deployed"
+                    }
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn inventory(opt: &Options) -> Result<Vec<audit::InventoryEntry>> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+    let opt = &with_cache_settings(opt, &config.settings)?;
+
+    let cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+
+    // Only needed to filter out files whose condition is false
+    create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    let (desired_symlinks, desired_templates) =
+        split_files_with(config.files, &config.settings, &config.variables, true)?;
+    Ok(audit::inventory(
+        &desired_symlinks,
+        &desired_templates,
+        &config.file_packages,
+        &cache,
+    ))
+}
+
+pub fn print_config(opt: &Options, format: ConfigFormat) -> Result<()> {
+    let config = effective_config(opt)?;
+    let printed = match format {
+        ConfigFormat::Toml => toml::to_string(
+            &toml::Value::try_from(&config).context("convert configuration to TOML")?,
+        )
+        .context("serialize configuration")?,
+        ConfigFormat::Json => {
+            serde_json::to_string_pretty(&config).context("serialize configuration")?
+        }
+    };
+    println!("{}", printed.trim_end());
+    Ok(())
+}
+
+fn effective_config(opt: &Options) -> Result<config::Configuration> {
+    let mut config = load_config(opt)?;
+
+    // Removes the files whose condition is false and adds the `dotter` variable
+    create_new_handlebars(&mut config).context("initialize handlebars")?;
+    Ok(config)
+}
+
+/// Prints the changes deploys made to a target, newest first
+pub fn log(opt: &Options, target: &Path) -> Result<()> {
+    let opt = &cache_options(opt)?;
+    let cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+
+    let target = PathBuf::from(
+        shellexpand::full(&target.to_string_lossy())
+            .context("expand target path")?
+            .to_string(),
+    );
+    let absolute = std::env::current_dir()
+        .context("get current directory")?
+        .join(&target);
+    match cache
+        .history
+        .get(&target)
+        .or_else(|| cache.history.get(&absolute))
+    {
+        Some(history) => {
+            for entry in history.iter().rev() {
+                println!("{}", format_history_entry(entry)?);
+            }
+        }
+        None => println!("No deploy has changed {:?} yet.", target),
+    }
+    Ok(())
+}
+
+fn format_history_entry(entry: &HistoryEntry) -> Result<String> {
+    fn describe(state: &Option<TargetState>) -> String {
+        match state {
+            None | Some(TargetState::Missing) => "missing".into(),
+            Some(TargetState::File { sha256 }) => sha256.chars().take(12).collect(),
+            Some(TargetState::Symlink { points_to }) => format!("symlink to {:?}", points_to),
+        }
+    }
+
+    Ok(format!(
+        "{}  {} -> {}  (packages: {})",
+        format_timestamp(entry.timestamp)?,
+        describe(&entry.before),
+        describe(&entry.after),
+        entry.packages.join(", ")
+    ))
+}
+
+fn format_timestamp(timestamp: u64) -> Result<String> {
+    time::OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .context("convert timestamp")?
+        .format(&time::format_description::well_known::Rfc3339)
+        .context("format timestamp")
+}
+
+fn format_last_deploy(last_deploy: &LastDeploy) -> Result<String> {
+    let time = format_timestamp(last_deploy.timestamp)?;
+    Ok(format!(
+        "Last deploy: {}\nPackages: {}\nChanged files: {}\n",
+        time,
+        last_deploy.packages.join(", "),
+        last_deploy.changed_files
+    ))
+}
+
+/// With `--vars-stdin`, overlays the JSON object on stdin over the configuration's variables
+fn apply_stdin_variables(opt: &Options, config: &mut config::Configuration) -> Result<()> {
+    if opt.vars_stdin {
+        debug!("Reading variables from stdin...");
+        let overlay =
+            config::read_json_variables(io::stdin().lock()).context("read variables from stdin")?;
+        trace!("Variables from stdin: {:#?}", overlay);
+        config::overlay_variables(&mut config.variables, overlay)
+            .context("apply variables from stdin")?;
+    }
+    Ok(())
+}
+
+fn read_patch(opt: &Options) -> Result<Option<config::Package>> {
+    let mut patch = None;
+    if opt.patch {
+        debug!("Reading manual patch from stdin...");
+        let mut patch_str = String::new();
+        io::stdin()
+            .read_to_string(&mut patch_str)
+            .context("read patch from stdin")?;
+        patch = Some(toml::from_str(&patch_str).context("parse patch into package")?);
+    }
+    trace!("Manual patch: {:#?}", patch);
+    Ok(patch)
+}
+
+/// Splits the configured files into the symlinks and templates that should be deployed.
+/// The templates only reuse their renders with `variables`, the final ones of the command
+fn split_files(
+    files: config::Files,
+    settings: &config::Settings,
+    variables: &Variables,
+) -> Result<(
+    BTreeMap<PathBuf, SymbolicTarget>,
+    BTreeMap<PathBuf, TemplateTarget>,
+)> {
+    // On Windows, you need developer mode to create symlinks.
+    let symlinks_enabled = if filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
+        .context("check whether symlinks are enabled")?
+    {
+        true
+    } else {
+        match settings.windows_symlink_fallback {
+            SymlinkFallback::Copy => warn!(
+                "No permission to create symbolic links.\n
+On Windows, in order to create symbolic links you need to enable Developer Mode.\n
+Proceeding by copying instead of symlinking."
+            ),
+            SymlinkFallback::Junction => warn!(
+                "No permission to create symbolic links.\n
+On Windows, in order to create symbolic links you need to enable Developer Mode.\n
+Proceeding by linking directories with junctions and copying files."
+            ),
+            SymlinkFallback::Error => anyhow::bail!(
+                "no permission to create symbolic links (on Windows, enable Developer Mode, \
+                 or set `windows_symlink_fallback` to copy instead)"
+            ),
+        }
+        false
+    };
+
+    split_files_with(files, settings, variables, symlinks_enabled)
+}
+
+/// Refuses targets that resolve to a path outside of all `roots`, unless there are none
+fn check_target_roots(files: &config::Files, roots: &[PathBuf]) -> Result<()> {
+    if roots.is_empty() {
+        return Ok(());
+    }
+    let roots = roots
+        .iter()
+        .map(|root| -> Result<PathBuf> {
+            let expanded = shellexpand::full(&root.to_string_lossy())
+                .context("expand path")?
+                .to_string();
+            filesystem::resolve_path(Path::new(&expanded))
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("resolve allowed target roots")?;
+
+    let mut escaping = Vec::new();
+    for target in files.values() {
+        let resolved = filesystem::resolve_target_path(target.path())
+            .with_context(|| format!("resolve target {:?}", target.path()))?;
+        if !roots.iter().any(|root| resolved.starts_with(root)) {
+            escaping.push(format!(
+                "    {:?} (resolves to {:?})",
+                target.path(),
+                resolved
+            ));
+        }
+    }
+    anyhow::ensure!(
+        escaping.is_empty(),
+        "refusing targets outside of `allowed_target_roots` {:?}:\n{}",
+        roots,
+        escaping.join("\n")
+    );
+    Ok(())
+}
+
+/// Refuses template targets that are existing directories or end in a path separator,
+/// which are usually a copy-paste mistake in the configuration
+fn check_template_targets(templates: &BTreeMap<PathBuf, TemplateTarget>) -> Result<()> {
+    for target in templates.values() {
+        let looks_like_directory = target
+            .target
+            .to_string_lossy()
+            .ends_with(std::path::is_separator);
+        let is_directory = std::fs::symlink_metadata(&target.target)
+            .map(|metadata| metadata.is_dir())
+            .unwrap_or(false);
+        anyhow::ensure!(
+            !looks_like_directory && !is_directory,
+            "target {:?} is a directory, expected a file",
+            target.target
+        );
+    }
+    Ok(())
+}
+
+/// Like `split_files`, but without checking whether symlinks can be created,
+/// which creates a test file on Windows
+fn split_files_with(
+    files: config::Files,
+    settings: &config::Settings,
+    variables: &Variables,
+    symlinks_enabled: bool,
+) -> Result<(
+    BTreeMap<PathBuf, SymbolicTarget>,
+    BTreeMap<PathBuf, TemplateTarget>,
+)> {
+    let conflicts = config::find_conflicting_targets(&files);
+    if !conflicts.is_empty() {
+        let description = conflicts
+            .iter()
+            .map(|(target, sources)| format!("    {:?} <- {:?}", target, sources))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if settings.allow_conflicts {
+            warn!(
+                "Multiple files are deployed to the same target, the last one wins:\n{}",
+                description
+            );
+        } else {
+            anyhow::bail!(
+                "multiple files are deployed to the same target (use --allow-conflicts to deploy anyway):\n{}",
+                description
+            );
+        }
+    }
+
+    check_target_roots(&files, &settings.allowed_target_roots)?;
+
+    let mut desired_symlinks = BTreeMap::<PathBuf, SymbolicTarget>::new();
+    let mut desired_templates = BTreeMap::<PathBuf, TemplateTarget>::new();
+
+    for (source, target) in files {
+        if symlinks_enabled {
+            match target {
+                FileTarget::Automatic(target) => {
+                    if is_automatic_template(&source, &target, settings)? {
+                        let mut target: TemplateTarget = target.into();
+                        target.resolve_banner(settings);
+                        desired_templates.insert(source, target);
+                    } else {
+                        desired_symlinks.insert(source, target.into());
+                    }
+                }
+                FileTarget::Symbolic(target) => {
+                    desired_symlinks.insert(source, target);
+                }
+                FileTarget::ComplexTemplate(mut target) => {
+                    target.resolve_banner(settings);
+                    desired_templates.insert(source, *target);
+                }
+            }
+        } else {
+            let junction =
+                settings.windows_symlink_fallback == SymlinkFallback::Junction && source.is_dir();
+            match target {
+                FileTarget::Automatic(target) if junction => {
+                    desired_symlinks.insert(source, target.into());
+                }
+                FileTarget::Symbolic(target) if junction => {
+                    desired_symlinks.insert(source, target);
+                }
+                FileTarget::Automatic(target) => {
+                    let automatic_template = is_automatic_template(&source, &target, settings)?;
+                    let mut target: TemplateTarget = target.into();
+                    if automatic_template {
+                        target.resolve_banner(settings);
+                    }
+                    desired_templates.insert(source, target);
+                }
+                FileTarget::Symbolic(target) => {
+                    desired_templates.insert(source, target.into_template());
+                }
+                FileTarget::ComplexTemplate(mut target) => {
+                    target.resolve_banner(settings);
+                    desired_templates.insert(source, *target);
+                }
+            }
+        }
+    }
+
+    check_template_targets(&desired_templates)?;
+
+    let render_timeout = if settings.render_timeout > 0.0 {
+        Some(Duration::from_secs_f64(settings.render_timeout))
+    } else {
+        None
+    };
+    let variables_hash = RenderCache::hash_variables(variables);
+    for target in desired_symlinks.values_mut() {
+        target.dir_mode = target.dir_mode.or(settings.dir_mode);
+    }
+    for target in desired_templates.values_mut() {
+        target.dir_mode = target.dir_mode.or(settings.dir_mode);
+        target.privileged_write = target
+            .privileged_write
+            .or(settings.privileged_write.then_some(true));
+        target.max_diff_size = Some(settings.diff.max_file_size);
+        target.diff_algorithm = settings.diff.algorithm;
+        if target.delimiters.is_none() {
+            target.delimiters = settings.delimiters.clone().map(Box::new);
+        }
+        target.render_timeout = render_timeout;
+        if target.diff_command.is_none() {
+            target.diff_command = settings.diff.command.clone();
+        }
+        target.render_cache.set_variables(variables_hash.as_deref());
+    }
+
+    Ok((desired_symlinks, desired_templates))
+}
+
+/// Whether a file that's given as just a target is a template: by the type configured for the
+/// target's extension, otherwise by whether the source contains template syntax
+fn is_automatic_template(
+    source: &Path,
+    target: &Path,
+    settings: &config::Settings,
+) -> Result<bool> {
+    let configured = target.extension().and_then(|extension| {
+        settings
+            .default_type_by_extension
+            .get(extension.to_string_lossy().as_ref())
+    });
+    match configured {
+        Some(file_type) => Ok(*file_type == config::DefaultFileType::Template),
+        None => filesystem::is_template(source)
+            .context(format!("check whether {:?} is a template", source)),
+    }
+}
+
+/// Returns true if differences were found or an error was printed
+pub fn diff(opt: &Options, diff_opt: &DiffOptions) -> Result<bool> {
+    // === Load configuration ===
+    let mut config = load_config_with_overrides(opt, &diff_opt.overrides)?;
+    let opt = &with_cache_settings(opt, &config.settings)?;
+
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    let untracked = untracked_files(&config).context("find untracked files")?;
+    let error_occurred = remove_missing_sources(&mut config.files, &config.file_packages);
+    let (mut desired_symlinks, mut desired_templates) = split_files(
+        std::mem::take(&mut config.files),
+        &config.settings,
+        &config.variables,
+    )?;
+    if let Some(target) = &opt.only_target {
+        select_target(target, &mut desired_symlinks, &mut desired_templates)?;
+    }
+    let cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+    mark_first_deploys(&mut desired_templates, &cache);
+    desired_templates.retain(|_, target| !target.diff_ignore.unwrap_or_default());
+
+    // === Diff templates against their targets ===
+
+    let stdout = io::stdout();
+    let progress = Progress::new(opt, desired_templates.len());
+    let snapshots = diff_opt
+        .since_last_deploy
+        .then_some(opt.cache_directory.as_path());
+    let palette = difference::Palette::current();
+    let (changed_files, diff_error_occurred) = if diff_opt.by_package {
+        diff_packages(
+            &mut stdout.lock(),
+            &progress,
+            snapshots,
+            &cache.templates,
+            group_by_package(
+                desired_templates,
+                &config.file_packages,
+                &config.package_stages,
+            ),
+            &handlebars,
+            &config.variables,
+            diff_opt,
+            opt.diff_context_lines,
+            opt.jobs,
+            &palette,
+        )
+    } else {
+        diff_templates(
+            &mut stdout.lock(),
+            &progress,
+            snapshots,
+            &cache.templates,
+            &desired_templates,
+            &handlebars,
+            &config.variables,
+            diff_opt,
+            opt.diff_context_lines,
+            opt.jobs,
+            &palette,
+        )
+    }
+    .context("print diffs")?;
+
+    for (file, source) in untracked {
+        warn!(
+            "Untracked file {:?}: it's in a directory that's deployed from {:?}, but isn't part of it",
+            file, source
+        );
+    }
+
+    Ok(changed_files > 0 || error_occurred || diff_error_occurred)
+}
+
+/// The files in the directories of `report_untracked` that nothing is deployed to, with the
+/// source directory of their directory. Directories that contain the home directory are skipped,
+/// so the whole home directory is never listed
+fn untracked_files(config: &config::Configuration) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let home = PathBuf::from(shellexpand::tilde("~").as_ref());
+    let targets = config
+        .files
+        .values()
+        .map(FileTarget::path)
+        .collect::<BTreeSet<_>>();
+
+    let mut untracked = Vec::new();
+    for dir in &config.settings.report_untracked {
+        let dir = PathBuf::from(
+            shellexpand::full(&dir.to_string_lossy())
+                .context("expand directory of `report_untracked`")?
+                .as_ref(),
+        );
+        let source = match config.owned_directories.get(&dir) {
+            Some(source) => source,
+            None => {
+                warn!(
+                    "Not looking for untracked files in {:?}, it isn't deployed from a source directory",
+                    dir
+                );
+                continue;
+            }
+        };
+        if home.starts_with(&dir) {
+            debug!(
+                "Not looking for untracked files in {:?}, it contains the home directory",
+                dir
+            );
+            continue;
+        }
+        let mut files = Vec::new();
+        collect_untracked(&dir, &targets, &mut files)?;
+        files.sort();
+        untracked.extend(files.into_iter().map(|file| (file, source.clone())));
+    }
+    Ok(untracked)
+}
+
+/// Adds the files below `dir` that aren't in `targets` to `untracked`
+fn collect_untracked(
+    dir: &Path,
+    targets: &BTreeSet<&Path>,
+    untracked: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("read directory {:?}", dir)),
+    };
+    for entry in entries {
+        let entry = entry.with_context(|| format!("read directory {:?}", dir))?;
+        let path = entry.path();
+        if targets.contains(path.as_path()) {
+            continue;
+        }
+        // Symlinks to directories aren't followed
+        if entry.file_type().context("get file type")?.is_dir() {
+            collect_untracked(&path, targets, untracked)?;
+        } else {
+            untracked.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Splits the templates by the package they come from. Packages are in deploy order,
+/// and the templates that don't come from a package, like those of the local configuration,
+/// are last, under no name
+fn group_by_package(
+    templates: BTreeMap<PathBuf, TemplateTarget>,
+    file_packages: &BTreeMap<PathBuf, String>,
+    package_stages: &BTreeMap<String, usize>,
+) -> Vec<(Option<String>, BTreeMap<PathBuf, TemplateTarget>)> {
+    let mut groups = BTreeMap::<_, BTreeMap<_, _>>::new();
+    for (source, target) in templates {
+        let key = match config::file_package(file_packages, &source) {
+            Some(package) => (
+                false,
+                package_stages.get(package).copied().unwrap_or(0),
+                Some(package.clone()),
+            ),
+            None => (true, 0, None),
+        };
+        groups.entry(key).or_default().insert(source, target);
+    }
+    groups
+        .into_iter()
+        .map(|((_, _, package), templates)| (package, templates))
+        .collect()
+}
+
+/// Like `diff_templates`, but under a header for each package with the number of its changed
+/// files. Packages without changes are omitted with `--only-changed`.
+/// Returns the number of changed files and whether an error was printed
+#[allow(clippy::too_many_arguments)]
+fn diff_packages(
+    out: &mut dyn Write,
+    progress: &Progress,
+    snapshots: Option<&Path>,
+    cached: &BTreeMap<PathBuf, PathBuf>,
+    packages: Vec<(Option<String>, BTreeMap<PathBuf, TemplateTarget>)>,
+    handlebars: &Handlebars<'static>,
+    variables: &config::Variables,
+    diff_opt: &DiffOptions,
+    context_lines: usize,
+    jobs: Option<NonZeroUsize>,
+    palette: &difference::Palette,
+) -> io::Result<(usize, bool)> {
+    let mut total_changed = 0;
+    let mut error_occurred = false;
+    let mut printed_packages = 0;
+
+    for (package, templates) in packages {
+        // The header needs the count, so the diffs are collected first
+        let mut diffs = Vec::new();
+        let (changed, package_error_occurred) = diff_templates(
+            &mut diffs,
+            progress,
+            snapshots,
+            cached,
+            &templates,
+            handlebars,
+            variables,
+            diff_opt,
+            context_lines,
+            jobs,
+            palette,
+        )?;
+        total_changed += changed;
+        error_occurred |= package_error_occurred;
+        if changed == 0 && diff_opt.only_changed {
+            continue;
+        }
+
+        let name = match &package {
+            Some(package) => format!("package {}", package),
+            None => "local configuration".into(),
+        };
+        let status = match changed {
+            0 => "in sync".into(),
+            1 => "1 changed file".into(),
+            changed => format!("{} changed files", changed),
+        };
+        progress::suspend(|| -> io::Result<()> {
+            if printed_packages > 0 {
+                writeln!(out)?;
+            }
+            writeln!(
+                out,
+                "{}",
+                palette
+                    .header
+                    .apply(format!("═══ {}: {} ═══", name, status))
+            )?;
+            out.write_all(&diffs)?;
+            out.flush()
+        })?;
+        printed_packages += 1;
+    }
+
+    Ok((total_changed, error_occurred))
+}
+
+/// Writes the diff of every template to `out`, or lets the template's diff command print it.
+/// With `snapshots`, templates are compared to the output of the last deploy in that cache
+/// directory instead of their targets.
+/// Returns the number of files with differences and whether an error was printed
+#[allow(clippy::too_many_arguments)]
+fn diff_templates(
+    out: &mut dyn Write,
+    progress: &Progress,
+    snapshots: Option<&Path>,
+    cached: &BTreeMap<PathBuf, PathBuf>,
+    templates: &BTreeMap<PathBuf, TemplateTarget>,
+    handlebars: &Handlebars<'static>,
+    variables: &config::Variables,
+    diff_opt: &DiffOptions,
+    context_lines: usize,
+    jobs: Option<NonZeroUsize>,
+    palette: &difference::Palette,
+) -> io::Result<(usize, bool)> {
+    let mut changed_files = 0;
+    let mut error_occurred = false;
+    let mut stats = Vec::new();
+    let mut printed_files = 0;
+
+    // Templates are rendered and diffed in parallel, but printed in order
+    let templates = templates.iter().collect::<Vec<_>>();
+    let prepared = parallel::map(&templates, jobs, |(source, target)| {
+        let compared = match snapshots {
+            Some(snapshots) => snapshot_path(snapshots, source),
+            None => target.target.clone(),
+        };
+        let target_exists = compared.exists();
+        // External commands print their diffs themselves, so the header comes first.
+        // Their output goes straight to stdout, so it can't be grouped by package.
+        // Targets of the wrong type are left to the built-in diff, which reports them
+        let regular_file = compared.is_file() && !compared.is_symlink();
+        let command = match (
+            &target.diff_command,
+            regular_file,
+            diff_opt.stat,
+            snapshots,
+            diff_opt.by_package,
+            diff_opt.word_diff,
+        ) {
+            (Some(command), true, false, None, false, false) => Some(command),
+            _ => None,
+        };
+        // The built-in diff is only needed to skip unchanged files for external commands
+        let diff = (command.is_none() || diff_opt.only_changed).then(|| {
+            difference::check_diff_size(source, target, &compared).and_then(|()| {
+                difference::generate_diff_against(
+                    source, target, &compared, handlebars, variables, true,
+                )
+            })
+        });
+        (target_exists, command, diff)
+    });
+
+    for ((source, target), (target_exists, command, diff)) in templates.into_iter().zip(prepared) {
+        progress.step(&target.target);
+        let context = || format!("diff template {:?} -> {:?}", source, target.target);
+
+        let type_change = match &diff {
+            Some(Err(e)) => e.downcast_ref::<difference::TypeChange>(),
+            _ => None,
+        };
+        if let Some(filter) = diff_opt.diff_filter {
+            let shown = match (type_change, target_exists) {
+                (Some(_), _) => filter.type_changed,
+                (None, true) => filter.modified,
+                (None, false) => filter.added,
+            };
+            if !shown {
+                continue;
+            }
+        }
+        if let Some(change) = type_change {
+            changed_files += 1;
+            progress::suspend(|| -> io::Result<()> {
+                if printed_files > 0 {
+                    writeln!(out)?;
+                }
+                write!(
+                    out,
+                    "{}",
+                    difference::format_type_change_header(&target.target, change, palette)
+                )?;
+                out.flush()
+            })?;
+            printed_files += 1;
+            continue;
+        }
+
+        if let Some(command) = command {
+            match diff {
+                Some(Ok(diff)) if !difference::diff_nonempty(&diff) => continue,
+                Some(Err(e)) => {
+                    display_error(e.context(context()));
+                    error_occurred = true;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let result = progress::suspend(|| -> io::Result<_> {
+                if printed_files > 0 {
+                    writeln!(out)?;
+                }
+                write!(
+                    out,
+                    "{}",
+                    difference::format_file_header(&target.target, true, palette)
+                )?;
+                out.flush()?;
+                Ok(difference::run_diff_command(
+                    command, source, target, handlebars, variables,
+                ))
+            })?;
+            printed_files += 1;
+            match result {
+                Ok(found) => changed_files += usize::from(found),
+                Err(e) => {
+                    display_error(e.context(context()));
+                    error_occurred = true;
+                }
+            }
+            continue;
+        }
+
+        let diff = match diff.expect("diffed without a command") {
+            Ok(diff) => diff,
+            Err(e) => {
+                display_error(e.context(context()));
+                error_occurred = true;
+                continue;
+            }
+        };
+
+        if target_exists && !difference::diff_nonempty(&diff) {
+            // A deploy would still write it, since it isn't known to be deployed
+            let unmanaged = snapshots.is_none() && cached.get(source) != Some(&target.target);
+            if unmanaged
+                && !diff_opt.only_changed
+                && !diff_opt.stat
+                && diff_opt.diff_filter.is_none()
+            {
+                progress::suspend(|| -> io::Result<()> {
+                    if printed_files > 0 {
+                        writeln!(out)?;
+                    }
+                    write!(
+                        out,
+                        "{}",
+                        difference::format_unmanaged_header(&target.target, palette)
+                    )?;
+                    out.flush()
+                })?;
+                printed_files += 1;
+            }
+            continue;
+        }
+        changed_files += 1;
+
+        if diff_opt.stat {
+            let (added, removed) = difference::diff_stats(&diff);
+            stats.push((target.target.clone(), added, removed));
+        } else {
+            let diffs = difference::format_file_diffs(
+                vec![difference::FileDiff {
+                    target: target.target.clone(),
+                    target_exists,
+                    diff,
+                }],
+                context_lines,
+                diff_opt.word_diff,
+                palette,
+            );
+            progress::suspend(|| -> io::Result<()> {
+                if printed_files > 0 {
+                    writeln!(out)?;
+                }
+                write!(out, "{}", diffs)?;
+                out.flush()
+            })?;
+            printed_files += 1;
+        }
+    }
+
+    if !stats.is_empty() {
+        progress::suspend(|| write!(out, "{}", difference::format_diff_stat(&stats)))?;
+    }
+
+    Ok((changed_files, error_occurred))
+}
+
+/// Returns true if a template failed to parse
+pub fn check(opt: &Options) -> Result<bool> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+
+    // Only needed to filter out files whose condition is false
+    create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    if let Err(problems) = config.validate() {
+        for problem in problems {
+            error!("Invalid configuration: {}", problem);
+        }
+        return Ok(true);
+    }
+
+    let (_, desired_templates) = split_files(config.files, &config.settings, &config.variables)?;
+
+    // === Parse every template ===
+
+    let mut error_occurred = false;
+    for (source, target) in &desired_templates {
+        if let Err(e) = check_template(source, target) {
+            display_error(e.context(format!("check template {:?}", source)));
+            error_occurred = true;
+        }
+    }
+
+    if !error_occurred {
+        info!("All {} templates are valid", desired_templates.len());
+    }
+
+    Ok(error_occurred)
+}
+
+/// Prints the groups of templates that render to identical output.
+/// Returns true if a template failed to render
+pub fn dedup(opt: &Options) -> Result<bool> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+
+    let opt = &with_cache_settings(opt, &config.settings)?;
+
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    let (_, mut desired_templates) =
+        split_files_with(config.files, &config.settings, &config.variables, true)?;
+    let cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+    mark_first_deploys(&mut desired_templates, &cache);
+
+    // === Group templates by their rendered output ===
+
+    let (groups, error_occurred) =
+        audit::identical_outputs(&desired_templates, &handlebars, &config.variables);
+
+    if groups.is_empty() {
+        info!("No templates render to identical output");
+    }
+    for (sha256, sources) in groups {
+        println!("{} templates render to {}:", sources.len(), &sha256[..12]);
+        for source in sources {
+            println!(
+                "    {:?} -> {:?}",
+                source, desired_templates[&source].target
+            );
+        }
+    }
+
+    Ok(error_occurred)
+}
+
+pub fn variables(opt: &Options, variables_opt: &VariablesOptions) -> Result<()> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+
+    create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    if !variables_opt.unused {
+        print!(
+            "{}",
+            // Serialized as a Value, which puts plain values before tables like TOML requires
+            toml::to_string_pretty(&toml::Value::Table(config.variables))
+                .context("serialize variables")?
+        );
+        return Ok(());
+    }
+
+    // === Collect references from everything that gets rendered ===
+
+    let mut referenced = BTreeSet::new();
+    let mut add_references = |name: &str, contents: &str| -> Result<()> {
+        let template = handlebars::Template::compile_with_name(contents, name.into())
+            .with_context(|| format!("parse {}", name))?;
+        variables::referenced_variables(&template, &mut referenced);
+        Ok(())
+    };
+
+    for (source, target) in &config.files {
+        if let Some(condition) = target.condition() {
+            add_references(
+                &format!("condition of {:?}", source),
+                &format!("{{{{#if {} }}}}{{{{/if}}}}", condition),
+            )?;
+        }
+    }
+
+    let (_, desired_templates) = split_files(config.files, &config.settings, &config.variables)?;
+    for (source, target) in &desired_templates {
+        let contents = std::fs::read_to_string(source)
+            .with_context(|| format!("read template {:?}", source))?;
+        add_references(
+            &format!("template {:?}", source),
+            &target.apply_actions(contents),
+        )?;
+    }
+
+    for (name, partial) in &config.partials {
+        let contents = match partial {
+            config::Partial::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("read partial {:?} from file {:?}", name, path))?,
+            config::Partial::Inline { content } => content.clone(),
+        };
+        add_references(&format!("partial {:?}", name), &contents)?;
+    }
+
+    for hook in [
+        &opt.pre_deploy,
+        &opt.post_deploy,
+        &opt.pre_undeploy,
+        &opt.post_undeploy,
+    ] {
+        if hook.exists() {
+            let contents =
+                std::fs::read_to_string(hook).with_context(|| format!("read hook {:?}", hook))?;
+            add_references(&format!("hook {:?}", hook), &contents)?;
+        }
+    }
+    trace!("Referenced variables: {:#?}", referenced);
+
+    // === Report the rest ===
+
+    let mut ignore = variables_opt.ignore.clone();
+    ignore.push("dotter".into());
+    for variable in variables::unused_variables(&config.variables, &referenced, &ignore) {
+        println!("{}", variable);
+    }
+
+    Ok(())
+}
+
+/// Returns true if an error was printed
+pub fn plan(opt: &Options, output: &Path) -> Result<bool> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    let opt = &with_cache_settings(opt, &config.settings)?;
+    let (desired_symlinks, mut desired_templates) =
+        split_files(config.files, &config.settings, &config.variables)?;
+    let cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+    mark_first_deploys(&mut desired_templates, &cache);
+
+    // === Record the changes ===
+
+    let (plan, skipped) = DeployPlan::new(
+        &desired_symlinks,
+        &desired_templates,
+        &cache,
+        &opt.cache_directory,
+        Force::from(opt).external,
+        &handlebars,
+        &config.variables,
+    )
+    .context("create plan")?;
+    info!("Planned {} changes", plan.actions.len());
+    if skipped {
+        error!("Some files were left out of the plan. To plan overwriting unexpected target files, use the --force flag.");
+    }
+
+    let json = serde_json::to_string_pretty(&plan).context("serialize plan")?;
+    std::fs::write(output, json).with_context(|| format!("write plan to {:?}", output))?;
+
+    Ok(skipped)
+}
+
+/// Returns true if an error was printed
+pub fn apply_plan(opt: &Options, plan: &Path) -> Result<bool> {
+    let json = std::fs::read_to_string(plan).with_context(|| format!("read plan {:?}", plan))?;
+    let plan: DeployPlan = serde_json::from_str(&json).context("deserialize plan")?;
+    let opt = &cache_options(opt)?;
+    let mut cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+
+    let (mut real_fs, mut dry_run_fs);
+    let fs: &mut dyn Filesystem = if !opt.dry_run {
+        real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm, false);
+        &mut real_fs
+    } else {
+        dry_run_fs = crate::filesystem::DryRunFilesystem::new();
+        &mut dry_run_fs
+    };
+
+    let error_occurred = plan.apply(fs, &mut cache, &opt.cache_directory);
+    if !opt.dry_run {
+        save_cache(&opt.cache_file, cache)?;
+    }
+    Ok(error_occurred)
+}
+
+/// Writes the changes a deploy would make to the templates' targets as a patch, to `out` or
+/// stdout. Returns true if an error was printed
+pub fn write_patch(opt: &Options, out: Option<&Path>) -> Result<bool> {
+    anyhow::ensure!(
+        opt.dry_run,
+        "`--format patch` can only be used with --dry-run"
+    );
+    let (patch, error_occurred) = deploy_patch(opt)?;
+    match out {
+        Some(out) => {
+            std::fs::write(out, patch).with_context(|| format!("write patch to {:?}", out))?
+        }
+        None => print!("{}", patch),
+    }
+    Ok(error_occurred)
+}
+
+fn deploy_patch(opt: &Options) -> Result<(String, bool)> {
+    // === Load configuration ===
+    let mut config = load_config(opt)?;
+    let opt = &with_cache_settings(opt, &config.settings)?;
+
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+
+    let (desired_symlinks, mut desired_templates) = split_files(
+        std::mem::take(&mut config.files),
+        &config.settings,
+        &config.variables,
+    )?;
+    let cache: Cache = load_file(&opt.cache_file)?.unwrap_or_default();
+    mark_first_deploys(&mut desired_templates, &cache);
+
+    // === Diff the templates against their targets ===
+
+    let mut error_occurred = false;
+    for (source, target) in &desired_symlinks {
+        let in_sync = filesystem::real_path(source).ok().map(|points_to| {
+            TargetState::of(&target.target).ok() == Some(TargetState::Symlink { points_to })
+        });
+        if in_sync != Some(true) {
+            warn!(
+                "Symlink {:?} -> {:?} can't be part of a patch, leaving it out",
+                source, target.target
+            );
+        }
+    }
+
+    let mut patch = String::new();
+    for (source, target) in &desired_templates {
+        let file_patch = || -> Result<Option<String>> {
+            if let Some(encoding) = target.encoding.filter(|e| *e != Encoding::Utf8) {
+                warn!(
+                    "Template {:?} -> {:?} is encoded as {:?} and can't be part of a patch, leaving it out",
+                    source, target.target, encoding
+                );
+                return Ok(None);
+            }
+            // Like a deploy, leave out targets that Dotter mustn't overwrite
+            let state = TargetState::of(&target.target).context("get state of target")?;
+            if !Force::from(opt).external {
+                if let Some(problem) = plan::template_target_problem(
+                    source,
+                    target,
+                    &state,
+                    &cache,
+                    &opt.cache_directory,
+                ) {
+                    anyhow::bail!("{}, so it's left out", problem);
+                }
+            }
+
+            let contents = std::fs::read_to_string(source).context("read template source file")?;
+            let rendered = render_target(&handlebars, source, target, contents, &config.variables)?;
+            let current = match std::fs::read_to_string(&target.target) {
+                Ok(current) => Some(current),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).context("read template target file"),
+            };
+            let executable =
+                filesystem::template_mode(source, target.mode, target.executable_from_source)?
+                    .is_some_and(|mode| mode.0 & 0o111 != 0);
+            Ok(difference::to_unified_diff(
+                &std::path::absolute(&target.target).context("get absolute path of target")?,
+                current.as_deref(),
+                &rendered,
+                executable,
+                target.diff_algorithm,
+                opt.diff_context_lines,
+            ))
+        };
+        match file_patch() {
+            Ok(file_patch) => patch.extend(file_patch),
+            Err(e) => {
+                display_error(
+                    e.context(format!("diff template {:?} -> {:?}", source, target.target)),
+                );
+                error_occurred = true;
+            }
+        }
+    }
+
+    Ok((patch, error_occurred))
+}
+
+fn check_template(source: &Path, target: &TemplateTarget) -> Result<()> {
+    let contents = std::fs::read_to_string(source).context("read template source file")?;
+    let contents = target.apply_actions(contents);
+    handlebars::Template::compile_with_name(contents, source.to_string_lossy().into())
+        .context("parse template")?;
+    Ok(())
+}
+
+fn run_deploy<A: ActionRunner>(
+    runner: &mut A,
+    desired_symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
+    desired_templates: &BTreeMap<PathBuf, TemplateTarget>,
+    stages: &BTreeMap<PathBuf, usize>,
+    cache: &mut Cache,
+    opt: &Options,
+    notifier: &mut Notifier,
+) -> (bool, bool) {
+    let mut suggest_force = false;
+    let mut failures = Vec::new();
+
+    // Index by both source and target location
+    let existing_symlinks: BTreeSet<(PathBuf, PathBuf)> = cache
+        .symlinks
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let existing_templates: BTreeSet<(PathBuf, PathBuf)> = cache
+        .templates
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    let desired_symlinks: BTreeMap<(PathBuf, PathBuf), _> = desired_symlinks
+        .iter()
+        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
+        .collect();
+    let desired_templates: BTreeMap<(PathBuf, PathBuf), _> = desired_templates
+        .iter()
+        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
+        .collect();
+
+    // Avoid modifying cache while iterating over it
+    let mut resulting_cache = cache.clone();
+
+    let progress = Progress::new(
+        opt,
+        existing_symlinks
+            .union(&desired_symlinks.keys().cloned().collect())
+            .count()
+            + existing_templates
+                .union(&desired_templates.keys().cloned().collect())
+                .count(),
+    );
+
+    for (source, target) in
+        existing_symlinks.difference(&desired_symlinks.keys().cloned().collect())
+    {
+        progress.step(target);
+        let outcome = execute_action(
+            runner.delete_symlink(source, target),
+            || resulting_cache.symlinks.remove(source),
+            || format!("delete symlink {:?} -> {:?}", source, target),
+            &mut suggest_force,
+            &mut failures,
+        );
+        notifier.action("delete_symlink", source, target, outcome);
+    }
+
+    for (source, target) in
+        existing_templates.difference(&desired_templates.keys().cloned().collect())
+    {
+        progress.step(target);
+        let outcome = execute_action(
+            runner.delete_template(source, &snapshot_path(&opt.cache_directory, source), target),
+            || resulting_cache.templates.remove(source),
+            || format!("delete template {:?} -> {:?}", source, target),
+            &mut suggest_force,
+            &mut failures,
+        );
+        notifier.action("delete_template", source, target, outcome);
+    }
+
+    // Packages that are deployed `after` others come in later stages
+    let stage_of = |source: &Path| stages.get(source).copied().unwrap_or(0);
+    let last_stage = stages.values().copied().max().unwrap_or(0);
+    for stage in 0..=last_stage {
+        for (source, target_path) in desired_symlinks
+            .keys()
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .difference(&existing_symlinks)
+            .filter(|(source, _)| stage_of(source) == stage)
+        {
+            let target = desired_symlinks
+                .get(&(source.into(), target_path.into()))
+                .unwrap();
+            progress.step(target_path);
+            let outcome = execute_action(
+                runner.create_symlink(source, target),
+                || {
+                    resulting_cache
+                        .symlinks
+                        .insert(source.clone(), target_path.clone())
+                },
+                || format!("create symlink {:?} -> {:?}", source, target_path),
+                &mut suggest_force,
+                &mut failures,
+            );
+            notifier.action("create_symlink", source, target_path, outcome);
+        }
+
+        for (source, target_path) in desired_templates
+            .keys()
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .difference(&existing_templates)
+            .filter(|(source, _)| stage_of(source) == stage)
+        {
+            let target = desired_templates
+                .get(&(source.into(), target_path.into()))
+                .unwrap();
+            progress.step(target_path);
+            let outcome = execute_action(
+                runner.create_template(
+                    source,
+                    &snapshot_path(&opt.cache_directory, source),
+                    target,
+                ),
+                || {
+                    resulting_cache
+                        .templates
+                        .insert(source.clone(), target_path.clone())
+                },
+                || format!("create template {:?} -> {:?}", source, target_path),
+                &mut suggest_force,
+                &mut failures,
+            );
+            notifier.action("create_template", source, target_path, outcome);
+        }
+
+        for (source, target_path) in existing_symlinks
+            .intersection(&desired_symlinks.keys().cloned().collect())
+            .filter(|(source, _)| stage_of(source) == stage)
+        {
+            let target = desired_symlinks
+                .get(&(source.into(), target_path.into()))
+                .unwrap();
+            progress.step(target_path);
+            let outcome = execute_action(
+                runner.update_symlink(source, target),
+                || (),
+                || format!("update symlink {:?} -> {:?}", source, target_path),
+                &mut suggest_force,
+                &mut failures,
+            );
+            notifier.action("update_symlink", source, target_path, outcome);
+        }
+
+        for (source, target_path) in existing_templates
+            .intersection(&desired_templates.keys().cloned().collect())
+            .filter(|(source, _)| stage_of(source) == stage)
+        {
+            let target = desired_templates
+                .get(&(source.into(), target_path.into()))
+                .unwrap();
+            progress.step(target_path);
+            let outcome = execute_action(
+                runner.update_template(
+                    source,
+                    &snapshot_path(&opt.cache_directory, source),
+                    target,
+                ),
+                || (),
+                || format!("update template {:?} -> {:?}", source, target_path),
+                &mut suggest_force,
+                &mut failures,
+            );
+            notifier.action("update_template", source, target_path, outcome);
+        }
+    }
+
+    *cache = resulting_cache;
+
+    drop(progress);
+    report_failures(&failures);
+
+    (suggest_force, !failures.is_empty())
+}
+
+/// Used to remove duplication. Failed actions are recorded in `failures`
+fn execute_action<T, S: FnOnce() -> T, E: FnOnce() -> String>(
+    result: Result<bool>,
+    success: S,
+    context: E,
     suggest_force: &mut bool,
-    error_occurred: &mut bool,
-) {
+    failures: &mut Vec<String>,
+) -> Outcome {
     match result {
         Ok(true) => {
             success();
+            Outcome::Ok
+        }
+        Ok(false) => {
+            *suggest_force = true;
+            Outcome::Skipped
+        }
+        Err(e) => {
+            let context = context();
+            display_error(e.context(context.clone()));
+            failures.push(context);
+            Outcome::Failed
+        }
+    }
+}
+
+/// Lists the failed actions again, so that they aren't lost between the successful ones
+fn report_failures(failures: &[String]) {
+    if failures.is_empty() {
+        return;
+    }
+    error!(
+        "{} actions failed, all others were performed:\n{}",
+        failures.len(),
+        failures
+            .iter()
+            .map(|failure| format!("    {}", failure))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::args;
+    use crate::filesystem::{SymlinkComparison, TemplateComparison};
+
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    use mockall::predicate::*;
+
+    fn path_eq(expected: &str) -> impl Fn(&Path) -> bool {
+        let expected = PathBuf::from(expected);
+        move |actual| actual == expected
+    }
+
+    /// Writes the global configuration `global` and a local one that selects `packages` into
+    /// `dir`, and returns options that keep the cache and look for hooks there as well, so that
+    /// nothing depends on the current directory
+    pub(crate) fn test_options(dir: &Path, global: &str, packages: &[&str]) -> Options {
+        let global_config = dir.join("global.toml");
+        std::fs::write(&global_config, global).unwrap();
+        let local_config = dir.join("local.toml");
+        std::fs::write(&local_config, format!("packages = {:?}\n", packages)).unwrap();
+        Options {
+            global_config,
+            local_config,
+            cache_file: dir.join("cache.toml"),
+            cache_directory: dir.join("cache"),
+            sources_directory: dir.join("sources"),
+            pre_deploy: dir.join("pre_deploy.sh"),
+            post_deploy: dir.join("post_deploy.sh"),
+            pre_undeploy: dir.join("pre_undeploy.sh"),
+            post_undeploy: dir.join("post_undeploy.sh"),
+            noconfirm: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn high_level_simple() {
+        // State
+        let a_out: SymbolicTarget = "a_out".into();
+        let b_out: TemplateTarget = "b_out".into();
+
+        let desired_symlinks = maplit::btreemap! {
+            PathBuf::from("a_in") => a_out.clone()
+        };
+        let desired_templates = maplit::btreemap! {
+            PathBuf::from("b_in") => b_out.clone()
+        };
+
+        // Test high level
+        let mut runner = actions::MockActionRunner::new();
+        let mut seq = mockall::Sequence::new();
+        let mut cache = Cache::default();
+
+        runner
+            .expect_create_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(a_out))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+        runner
+            .expect_create_template()
+            .times(1)
+            .with(
+                function(path_eq("b_in")),
+                function(path_eq("cache/b_in")),
+                eq(b_out),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(true));
+
+        let (suggest_force, error_occurred) = run_deploy(
+            &mut runner,
+            &desired_symlinks,
+            &desired_templates,
+            &BTreeMap::new(),
+            &mut cache,
+            &Options {
+                cache_directory: "cache".into(),
+                force: false,
+                ..Options::default()
+            },
+            &mut Notifier::default(),
+        );
+
+        assert!(!suggest_force);
+        assert!(!error_occurred);
+
+        assert!(cache.symlinks.contains_key(&PathBuf::from("a_in")));
+        assert!(cache.templates.contains_key(&PathBuf::from("b_in")));
+        assert_eq!(cache.symlinks.len(), 1);
+        assert_eq!(cache.templates.len(), 1);
+    }
+
+    #[test]
+    fn high_level_skip() {
+        // Setup
+        let a_out: SymbolicTarget = "a_out".into();
+        let b_out: TemplateTarget = "b_out".into();
+
+        let desired_symlinks = maplit::btreemap! {
+            PathBuf::from("a_in") => a_out.clone()
+        };
+        let desired_templates = maplit::btreemap! {
+            PathBuf::from("b_in") => b_out.clone()
+        };
+
+        let mut runner = actions::MockActionRunner::new();
+        let mut seq = mockall::Sequence::new();
+        let mut cache = Cache::default();
+
+        // Expectation
+        runner
+            .expect_create_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(a_out))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Err(anyhow::anyhow!("oh no")));
+        runner
+            .expect_create_template()
+            .times(1)
+            .with(
+                function(path_eq("b_in")),
+                function(path_eq("cache/b_in")),
+                eq(b_out),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(false));
+
+        // Reality
+        let (suggest_force, error_occurred) = run_deploy(
+            &mut runner,
+            &desired_symlinks,
+            &desired_templates,
+            &BTreeMap::new(),
+            &mut cache,
+            &Options {
+                cache_directory: "cache".into(),
+                force: false,
+                ..Options::default()
+            },
+            &mut Notifier::default(),
+        );
+
+        assert!(suggest_force);
+        assert!(error_occurred);
+
+        assert_eq!(cache.symlinks.len(), 0);
+        assert_eq!(cache.templates.len(), 0);
+    }
+
+    #[test]
+    fn high_level_change_target() {
+        // Setup
+        let a_out_new: SymbolicTarget = "a_out_new".into();
+
+        let desired_symlinks = maplit::btreemap! {
+            PathBuf::from("a_in") => a_out_new.clone()
+        };
+
+        let mut runner = actions::MockActionRunner::new();
+        let mut seq = mockall::Sequence::new();
+        let mut cache = Cache {
+            symlinks: maplit::btreemap! {
+                PathBuf::from("a_in") => "a_out_old".into()
+            },
+            templates: BTreeMap::new(),
+            last_deploy: None,
+            history: BTreeMap::new(),
+        };
+
+        // Expectation
+        runner
+            .expect_delete_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("a_out_old")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+        runner
+            .expect_create_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(a_out_new))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+
+        // Reality
+        let (suggest_force, error_occurred) = run_deploy(
+            &mut runner,
+            &desired_symlinks,
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            &mut cache,
+            &Options {
+                cache_directory: "cache".into(),
+                force: false,
+                ..Options::default()
+            },
+            &mut Notifier::default(),
+        );
+
+        assert!(!suggest_force);
+        assert!(!error_occurred);
+
+        assert_eq!(cache.symlinks.len(), 1);
+        assert_eq!(cache.templates.len(), 0);
+    }
+
+    #[test]
+    fn high_level_change_type() {
+        // Setup
+        let a_out_new: SymbolicTarget = "a_out_new".into();
+
+        let desired_symlinks = maplit::btreemap! {
+            PathBuf::from("a_in") => a_out_new.clone()
+        };
+
+        let mut runner = actions::MockActionRunner::new();
+        let mut seq = mockall::Sequence::new();
+        let mut cache = Cache {
+            symlinks: BTreeMap::new(),
+            templates: maplit::btreemap! {
+                PathBuf::from("a_in") => "a_out_old".into()
+            },
+            last_deploy: None,
+            history: BTreeMap::new(),
+        };
+
+        // Expectation
+        runner
+            .expect_delete_template()
+            .times(1)
+            .with(
+                function(path_eq("a_in")),
+                function(path_eq("cache/a_in")),
+                function(path_eq("a_out_old")),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(true));
+        runner
+            .expect_create_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(a_out_new))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+
+        // Reality
+        let (suggest_force, error_occurred) = run_deploy(
+            &mut runner,
+            &desired_symlinks,
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            &mut cache,
+            &Options {
+                cache_directory: "cache".into(),
+                force: false,
+                ..Options::default()
+            },
+            &mut Notifier::default(),
+        );
+
+        assert!(!suggest_force);
+        assert!(!error_occurred);
+
+        assert_eq!(cache.symlinks.len(), 1);
+        assert_eq!(cache.templates.len(), 0);
+    }
+
+    #[test]
+    #[ignore] // This is desired, but not implemented: see issue #22
+    fn high_level_skip_change_type() {
+        // Setup
+        let desired_symlinks = maplit::btreemap! {
+            PathBuf::from("a_in") => "a_out_new".into()
+        };
+
+        let mut runner = actions::MockActionRunner::new();
+        let mut seq = mockall::Sequence::new();
+        let mut cache = Cache {
+            symlinks: BTreeMap::new(),
+            templates: maplit::btreemap! {
+                PathBuf::from("a_in") => "a_out_old".into()
+            },
+            last_deploy: None,
+            history: BTreeMap::new(),
+        };
+
+        // Expectation
+        runner
+            .expect_delete_template()
+            .times(1)
+            .with(
+                function(path_eq("a_in")),
+                function(path_eq("cache/a_in")),
+                function(path_eq("a_out_old")),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(false));
+
+        // Reality
+        let (suggest_force, error_occurred) = run_deploy(
+            &mut runner,
+            &desired_symlinks,
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            &mut cache,
+            &Options {
+                cache_directory: "cache".into(),
+                force: false,
+                ..Options::default()
+            },
+            &mut Notifier::default(),
+        );
+
+        assert!(!suggest_force);
+        assert!(!error_occurred);
+
+        assert_eq!(cache.symlinks.len(), 1);
+        assert_eq!(cache.templates.len(), 0);
+    }
+
+    #[test]
+    fn high_level_deploy_stages() {
+        // The symlink's package is deployed after the template's package
+        let a_out: SymbolicTarget = "a_out".into();
+        let b_out: TemplateTarget = "b_out".into();
+
+        let desired_symlinks = maplit::btreemap! {
+            PathBuf::from("a_in") => a_out.clone()
+        };
+        let desired_templates = maplit::btreemap! {
+            PathBuf::from("b_in") => b_out.clone()
+        };
+        let stages = maplit::btreemap! {
+            PathBuf::from("a_in") => 1,
+            PathBuf::from("b_in") => 0,
+        };
+
+        let mut runner = actions::MockActionRunner::new();
+        let mut seq = mockall::Sequence::new();
+        let mut cache = Cache::default();
+
+        runner
+            .expect_create_template()
+            .times(1)
+            .with(
+                function(path_eq("b_in")),
+                function(path_eq("cache/b_in")),
+                eq(b_out),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(true));
+        runner
+            .expect_create_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(a_out))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+
+        let (suggest_force, error_occurred) = run_deploy(
+            &mut runner,
+            &desired_symlinks,
+            &desired_templates,
+            &stages,
+            &mut cache,
+            &Options {
+                cache_directory: "cache".into(),
+                ..Options::default()
+            },
+            &mut Notifier::default(),
+        );
+
+        assert!(!suggest_force);
+        assert!(!error_occurred);
+    }
+
+    #[test]
+    fn low_level_simple() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = Default::default();
+
+        // Expectation:
+        // create_symlink
+        fs.expect_compare_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("a_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(SymlinkComparison::OnlySourceExists));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("")), eq(None), eq(None)) // parent of a_out
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_make_symlink()
+            .times(1)
+            .with(
+                function(path_eq("a_out")),
+                function(path_eq("a_in")),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+
+        // create_template
+        fs.expect_compare_template()
+            .times(1)
+            .with(
+                function(path_eq("b_out")),
+                function(path_eq("cache/b_cache")),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(TemplateComparison::BothMissing));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("")), eq(None), eq(None)) // parent of b_out
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_read_to_string()
+            .times(1)
+            .with(function(path_eq("b_in")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("Hello!".into()));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("cache")), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_write()
+            .times(1)
+            .with(function(path_eq("cache/b_cache")), eq(b"Hello!".to_vec()))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_copy_file()
+            .times(1)
+            .with(
+                function(path_eq("cache/b_cache")),
+                function(path_eq("b_out")),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(
+                function(path_eq("b_in")),
+                function(path_eq("b_out")),
+                eq(None),
+                eq(false),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _, _| Ok(()));
+
+        // Reality
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &handlebars,
+            &variables,
+            (&opt).into(),
+            opt.diff_context_lines,
+            false,
+        );
+        assert!(runner
+            .create_symlink(&PathBuf::from("a_in"), &PathBuf::from("a_out").into())
+            .unwrap());
+        assert!(runner
+            .create_template(
+                &PathBuf::from("b_in"),
+                &PathBuf::from("cache/b_cache"),
+                &PathBuf::from("b_out").into(),
+            )
+            .unwrap());
+    }
+
+    #[test]
+    fn low_level_skip() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = Default::default();
+
+        // Expectation:
+        // create_symlink
+        fs.expect_compare_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("a_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(SymlinkComparison::Changed));
+
+        // create_template
+        fs.expect_compare_template()
+            .times(1)
+            .with(
+                function(path_eq("b_out")),
+                function(path_eq("cache/b_cache")),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(TemplateComparison::Changed));
+
+        // Reality
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &handlebars,
+            &variables,
+            (&opt).into(),
+            opt.diff_context_lines,
+            false,
+        );
+
+        // Both should skip
+        assert!(!runner
+            .create_symlink(&PathBuf::from("a_in"), &PathBuf::from("a_out").into())
+            .unwrap());
+        assert!(!runner
+            .create_template(
+                &PathBuf::from("b_in"),
+                &PathBuf::from("cache/b_cache"),
+                &PathBuf::from("b_out").into(),
+            )
+            .unwrap());
+    }
+
+    #[test]
+    fn granular_force_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("greeting");
+        let cache = dir.path().join("cache/greeting");
+        let target = TemplateTarget::from(dir.path().join("greeting.out"));
+        std::fs::create_dir(dir.path().join("cache")).unwrap();
+        std::fs::write(&source, "{{greeting}}").unwrap();
+        let greeting = |greeting: Option<&str>| {
+            let mut variables = config::Variables::new();
+            if let Some(greeting) = greeting {
+                variables.insert("greeting".into(), greeting.into());
+            }
+            variables
+        };
+        // Deploys the template over a target that was last deployed as `previous`
+        // and now contains `current`
+        let update = |previous: &str, current: &str, variables, force| {
+            std::fs::write(&cache, previous).unwrap();
+            std::fs::write(&target.target, current).unwrap();
+            let old = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+            std::fs::File::options()
+                .write(true)
+                .open(&target.target)
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+            let result = actions::update_template(
+                &source,
+                &cache,
+                &target,
+                &mut crate::filesystem::RealFilesystem::new(true, false),
+                &Handlebars::new(),
+                &variables,
+                force,
+                0,
+                false,
+                None,
+            )
+            .unwrap();
+            let rewritten = std::fs::metadata(&target.target)
+                .unwrap()
+                .modified()
+                .unwrap()
+                != old;
+            (
+                result,
+                rewritten,
+                std::fs::read_to_string(&target.target).unwrap(),
+            )
+        };
+        let all_but = |relaxed: fn(&mut Force)| {
+            let mut force = Force {
+                unchanged: true,
+                external: true,
+                empty: true,
+            };
+            relaxed(&mut force);
+            force
+        };
+
+        // Unchanged templates aren't rewritten
+        let unchanged = |force| update("hi", "hi", greeting(Some("hi")), force);
+        assert_eq!(unchanged(Force::default()), (true, false, "hi".into()));
+        assert_eq!(
+            unchanged(all_but(|force| force.unchanged = false)),
+            (true, false, "hi".into())
+        );
+        let only_unchanged = Force {
+            unchanged: true,
+            ..Force::default()
+        };
+        assert_eq!(unchanged(only_unchanged), (true, true, "hi".into()));
+
+        // Targets that were modified outside of Dotter aren't overwritten
+        let external = |force| update("hi", "edited", greeting(Some("hello")), force);
+        assert_eq!(
+            external(all_but(|force| force.external = false)),
+            (false, false, "edited".into())
+        );
+        let only_external = Force {
+            external: true,
+            ..Force::default()
+        };
+        assert_eq!(external(only_external), (true, true, "hello".into()));
+
+        // Empty renders don't erase targets
+        let empty = |force| update("hi", "hi", greeting(None), force);
+        assert_eq!(
+            empty(all_but(|force| force.empty = false)),
+            (false, false, "hi".into())
+        );
+        let only_empty = Force {
+            empty: true,
+            ..Force::default()
+        };
+        assert_eq!(empty(only_empty), (true, true, "".into()));
+
+        // --force implies all of them
+        assert_eq!(
+            Force::from(&Options {
+                force: true,
+                ..Options::default()
+            }),
+            all_but(|_| {})
+        );
+        assert_eq!(
+            Force::from(&Options {
+                force_external: true,
+                ..Options::default()
+            }),
+            only_external
+        );
+    }
+
+    #[test]
+    fn parent_of_target_is_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("bar");
+        let parent = dir.path().join("config/foo");
+        let target = SymbolicTarget::from(parent.join("bar"));
+        std::fs::write(&source, "bar").unwrap();
+        std::fs::create_dir(dir.path().join("config")).unwrap();
+        std::fs::write(&parent, "in the way").unwrap();
+
+        let mut fs = crate::filesystem::RealFilesystem::new(true, false);
+        let error = actions::create_symlink(&source, &target, &mut fs, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "cannot create {:?}: parent {:?} is a file, not a directory",
+                target.target, parent
+            )
+        );
+        assert!(parent.is_file());
+
+        // With force, the file is backed up and replaced
+        assert!(actions::create_symlink(&source, &target, &mut fs, true).unwrap());
+        assert!(parent.is_dir());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("config/foo.bak")).unwrap(),
+            "in the way"
+        );
+        assert_eq!(std::fs::read_to_string(&target.target).unwrap(), "bar");
+    }
+
+    #[test]
+    fn diff_with_variable_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target: TemplateTarget = dir.path().join("target").into();
+        std::fs::write(&source, "theme = {{theme}}\n").unwrap();
+        std::fs::write(&target.target, "theme = light\n").unwrap();
+
+        let handlebars = handlebars::Handlebars::new();
+        let mut variables = config::Variables::new();
+        variables.insert("theme".into(), "light".into());
+
+        let diff =
+            difference::generate_template_diff(&source, &target, &handlebars, &variables, true)
+                .unwrap();
+        assert!(!difference::diff_nonempty(&diff));
+
+        config::apply_variable_override(&mut variables, "theme=dark").unwrap();
+        let diff =
+            difference::generate_template_diff(&source, &target, &handlebars, &variables, true)
+                .unwrap();
+        assert!(difference::diff_nonempty(&diff));
+    }
+
+    #[test]
+    fn check_template_syntax() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good");
+        let broken = dir.path().join("broken");
+        std::fs::write(&good, "{{#if theme}}theme = {{theme}}{{/if}}\n").unwrap();
+        std::fs::write(&broken, "{{#if theme}}theme = {{theme}}\n").unwrap();
+        let target: TemplateTarget = dir.path().join("target").into();
+
+        check_template(&good, &target).unwrap();
+        let error = check_template(&broken, &target).unwrap_err();
+        assert_eq!(error.to_string(), "parse template");
+        assert!(!target.target.exists());
+    }
+
+    #[test]
+    fn conflicting_targets() {
+        let files = maplit::btreemap! {
+            PathBuf::from("git/gitconfig") => FileTarget::Symbolic("/home/user/.gitconfig".into()),
+            PathBuf::from("work/gitconfig") => FileTarget::Symbolic("/home/user/.gitconfig".into()),
+            PathBuf::from("vimrc") => FileTarget::Symbolic("/home/user/.vimrc".into()),
+        };
+
+        let mut settings = config::Settings::default();
+        let error = split_files(files.clone(), &settings, &Variables::new()).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("\"/home/user/.gitconfig\""), "{}", message);
+        assert!(message.contains("\"git/gitconfig\""), "{}", message);
+        assert!(message.contains("\"work/gitconfig\""), "{}", message);
+        assert!(!message.contains("vimrc"), "{}", message);
+
+        settings.allow_conflicts = true;
+        split_files(files, &settings, &Variables::new()).unwrap();
+    }
+
+    #[test]
+    fn symlink_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let plugins = dir.path().join("plugins");
+        let vimrc = dir.path().join("vimrc");
+        std::fs::create_dir(&plugins).unwrap();
+        std::fs::write(&vimrc, "set number\n").unwrap();
+        let files = maplit::btreemap! {
+            plugins.clone() => FileTarget::Automatic("/home/user/.vim/plugins".into()),
+            vimrc.clone() => FileTarget::Symbolic("/home/user/.vimrc".into()),
+        };
+
+        let mut settings = config::Settings::default();
+        let (symlinks, templates) =
+            split_files_with(files.clone(), &settings, &Variables::new(), false).unwrap();
+        assert!(symlinks.is_empty());
+        assert_eq!(templates.len(), 2);
+
+        // Only directories can be junctions
+        settings.windows_symlink_fallback = SymlinkFallback::Junction;
+        let (symlinks, templates) =
+            split_files_with(files, &settings, &Variables::new(), false).unwrap();
+        assert_eq!(symlinks.keys().collect::<Vec<_>>(), vec![&plugins]);
+        assert_eq!(templates.keys().collect::<Vec<_>>(), vec![&vimrc]);
+    }
+
+    #[test]
+    fn only_target_selects_its_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("config")).unwrap();
+        let app = dir.path().join("config/app.toml");
+        let other = dir.path().join("config/other.toml");
+        let link = dir.path().join("config/link");
+        let mut symlinks = maplit::btreemap! { "link".into() => link.clone().into() };
+        let mut templates = maplit::btreemap! {
+            PathBuf::from("app.toml") => TemplateTarget::from(app.clone()),
+            PathBuf::from("other.toml") => TemplateTarget::from(other.clone()),
+        };
+
+        // Paths are compared after they're resolved
+        let selected = select_target(
+            &dir.path().join("config/../config/app.toml"),
+            &mut symlinks.clone(),
+            &mut templates.clone(),
+        )
+        .unwrap();
+        assert_eq!(selected, app);
+
+        let error = select_target(
+            &dir.path().join("config/app.tml"),
+            &mut symlinks,
+            &mut templates,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "no file is deployed to {:?}, did you mean {:?}?",
+                dir.path().join("config/app.tml"),
+                app
+            )
+        );
+
+        select_target(&app, &mut symlinks, &mut templates).unwrap();
+        assert!(symlinks.is_empty());
+        assert_eq!(
+            templates.keys().collect::<Vec<_>>(),
+            vec![Path::new("app.toml")]
+        );
+
+        let mut cached = maplit::btreemap! {
+            PathBuf::from("app.toml") => app.clone(),
+            PathBuf::from("other.toml") => other.clone(),
+        };
+        let others = take_other_targets(&mut cached, &app);
+        assert_eq!(cached.values().collect::<Vec<_>>(), vec![&app]);
+        assert_eq!(others.values().collect::<Vec<_>>(), vec![&other]);
+    }
+
+    #[test]
+    fn cache_is_backed_up_before_saving() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("cache.toml");
+        let deploys = std::cell::Cell::new(0);
+        let deploy = |target: &str| {
+            deploys.set(deploys.get() + 1);
+            let mut cache = Cache::default();
+            cache.symlinks.insert("vimrc".into(), target.into());
+            cache.last_deploy = Some(LastDeploy {
+                timestamp: deploys.get(),
+                packages: Vec::new(),
+                changed_files: 1,
+            });
+            back_up_cache(&cache_file, &cache, 2).unwrap();
+            save_cache(&cache_file, cache).unwrap();
+        };
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+
+        // The first deploy has nothing to back up
+        deploy("first");
+        assert!(!dir.path().join("cache.toml.bak").exists());
+        let first = read("cache.toml");
+
+        deploy("second");
+        assert_eq!(read("cache.toml.bak"), first);
+        let second = read("cache.toml");
+
+        deploy("third");
+        assert_eq!(read("cache.toml.bak"), second);
+        assert_eq!(read("cache.toml.bak.1"), first);
+
+        // Only two are kept
+        deploy("fourth");
+        assert_eq!(read("cache.toml.bak.1"), second);
+        assert!(!dir.path().join("cache.toml.bak.2").exists());
+        let third = read("cache.toml.bak");
+
+        // A deploy that doesn't change the files doesn't rotate the backups
+        deploy("fourth");
+        assert_eq!(read("cache.toml.bak"), third);
+        assert_eq!(read("cache.toml.bak.1"), second);
+    }
+
+    #[test]
+    fn default_type_by_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("setup.sh");
+        let conf = dir.path().join("app.conf");
+        let explicit = dir.path().join("other.sh");
+        std::fs::write(&script, "echo hello\n").unwrap();
+        std::fs::write(&conf, "name = {{name}}\n").unwrap();
+        std::fs::write(&explicit, "echo hello\n").unwrap();
+        let files = maplit::btreemap! {
+            script.clone() => FileTarget::Automatic("/home/user/bin/setup.sh".into()),
+            conf.clone() => FileTarget::Automatic("/home/user/.config/app.conf".into()),
+            explicit.clone() => FileTarget::Symbolic("/home/user/bin/other.sh".into()),
+        };
+
+        let mut settings = config::Settings::default();
+        settings
+            .default_type_by_extension
+            .insert("sh".into(), config::DefaultFileType::Template);
+        settings
+            .default_type_by_extension
+            .insert("conf".into(), config::DefaultFileType::Symbolic);
+        let (symlinks, templates) =
+            split_files_with(files, &settings, &Variables::new(), true).unwrap();
+        assert_eq!(templates.keys().collect::<Vec<_>>(), vec![&script]);
+        assert_eq!(
+            symlinks.keys().collect::<BTreeSet<_>>(),
+            maplit::btreeset! { &conf, &explicit }
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allowed_target_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(home.join(".config")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, home.join(".config/link")).unwrap();
+        let vimrc = dir.path().join("vimrc");
+        std::fs::write(&vimrc, "set number\n").unwrap();
+
+        let settings = config::Settings {
+            allowed_target_roots: vec![home.clone(), home.join(".config")],
+            ..config::Settings::default()
+        };
+        let split = |target: PathBuf| {
+            let files = maplit::btreemap! { vimrc.clone() => FileTarget::Automatic(target) };
+            split_files_with(files, &settings, &Variables::new(), true).map_err(|e| e.to_string())
+        };
+
+        assert!(split(home.join(".config/nvim/init.vim")).is_ok());
+        assert!(split(home.join(".config/nvim/../.vimrc")).is_ok());
+
+        let error = split(home.join(".config/../../etc/passwd")).unwrap_err();
+        assert!(error.starts_with("refusing targets outside of `allowed_target_roots`"));
+        assert!(
+            error.contains(&format!("{:?}", dir.path().join("etc/passwd"))),
+            "{}",
+            error
+        );
+        assert!(split(home.join(".config/link/file")).is_err());
+    }
+
+    #[test]
+    fn template_target_is_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("gitconfig");
+        std::fs::write(&source, "name = {{name}}\n").unwrap();
+        let config_dir = dir.path().join("config");
+        std::fs::create_dir(&config_dir).unwrap();
+
+        let split = |target: PathBuf| {
+            let files = maplit::btreemap! { source.clone() => FileTarget::Automatic(target) };
+            split_files_with(files, &config::Settings::default(), &Variables::new(), true)
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(
+            split(config_dir.clone()).unwrap_err(),
+            format!("target {:?} is a directory, expected a file", config_dir)
+        );
+        let trailing_slash = PathBuf::from(format!("{}/", dir.path().join("git").display()));
+        assert_eq!(
+            split(trailing_slash.clone()).unwrap_err(),
+            format!(
+                "target {:?} is a directory, expected a file",
+                trailing_slash
+            )
+        );
+        assert!(split(config_dir.join("gitconfig")).is_ok());
+    }
+
+    #[test]
+    fn deploy_records_last_deploy() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        std::fs::write(&source, "set number\n").unwrap();
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(
+            &global_config,
+            format!(
+                "[vim.files]\n{:?} = {:?}\n",
+                source,
+                dir.path().join("home/.vimrc")
+            ),
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"vim\"]\n").unwrap();
+
+        let opt = Options {
+            global_config,
+            local_config,
+            cache_file: dir.path().join("cache.toml"),
+            cache_directory: dir.path().join("cache"),
+            pre_deploy: dir.path().join("pre_deploy.sh"),
+            post_deploy: dir.path().join("post_deploy.sh"),
+            noconfirm: true,
+            ..Default::default()
+        };
+        assert!(!deploy(&opt, false).unwrap());
+
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        let last_deploy = cache.last_deploy.unwrap();
+        assert_eq!(last_deploy.packages, vec!["vim".to_string()]);
+        assert_eq!(last_deploy.changed_files, 1);
+        assert!(format_last_deploy(&last_deploy)
+            .unwrap()
+            .ends_with("Packages: vim\nChanged files: 1\n"));
+
+        // Nothing changes the second time
+        assert!(!deploy(&opt, false).unwrap());
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        assert_eq!(cache.last_deploy.unwrap().changed_files, 0);
+    }
+
+    #[test]
+    fn deploy_records_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        let target = dir.path().join("home/.vimrc");
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[vim.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source, target
+            ),
+            &["vim"],
+        );
+        std::fs::write(&source, "set number\n").unwrap();
+        assert!(!deploy(&opt, false).unwrap());
+        std::fs::write(&source, "set nonumber\n").unwrap();
+        assert!(!deploy(&opt, false).unwrap());
+
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        let file = |contents: &[u8]| TargetState::File {
+            sha256: crate::plan::sha256(contents),
+        };
+        let history = cache.history[&target].clone();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].before, Some(file(b"set number\n")));
+        assert_eq!(history[1].after, Some(file(b"set nonumber\n")));
+        assert_eq!(history[1].packages, vec!["vim".to_string()]);
+
+        // The history of each target is capped
+        let mut cache = cache;
+        for _ in 0..config::HISTORY_LENGTH {
+            cache.record_change(&target, history[1].clone());
         }
-        Ok(false) => {
-            *suggest_force = true;
+        assert_eq!(cache.history[&target].len(), config::HISTORY_LENGTH);
+        assert_eq!(cache.history[&target][0], history[1]);
+    }
+
+    #[test]
+    fn deploy_records_creations() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        let target = dir.path().join("home/.vimrc");
+        let opt = test_options(
+            dir.path(),
+            &format!("[vim.files]\n{:?} = {:?}\n", source, target),
+            &["vim"],
+        );
+        std::fs::write(&source, "set number\n").unwrap();
+        assert!(!deploy(&opt, false).unwrap());
+
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        let history = &cache.history[&target];
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].before, None);
+        assert_eq!(
+            history[0].after,
+            Some(TargetState::Symlink { points_to: source })
+        );
+        assert!(format_history_entry(&history[0])
+            .unwrap()
+            .contains("  missing -> symlink to "));
+    }
+
+    #[test]
+    fn deploy_records_removals() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        let target = dir.path().join("home/.vimrc");
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[vim.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source, target
+            ),
+            &["vim"],
+        );
+        std::fs::write(&source, "set number\n").unwrap();
+        assert!(!deploy(&opt, false).unwrap());
+
+        std::fs::write(&opt.local_config, "packages = []\n").unwrap();
+        assert!(!deploy(&opt, false).unwrap());
+        assert!(!target.exists());
+
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        let history = &cache.history[&target];
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            history[1].before,
+            Some(TargetState::File {
+                sha256: crate::plan::sha256(b"set number\n"),
+            })
+        );
+        assert_eq!(history[1].after, None);
+        assert!(format_history_entry(&history[1])
+            .unwrap()
+            .contains(" -> missing  (packages: )"));
+    }
+
+    #[test]
+    fn sync_cache_adds_matching_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let matching = dir.path().join("vimrc");
+        let changed = dir.path().join("bashrc");
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[shell.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n\
+                 {:?} = {{ target = {:?}, type = \"template\" }}\n",
+                matching,
+                dir.path().join("home/.vimrc"),
+                changed,
+                dir.path().join("home/.bashrc")
+            ),
+            &["shell"],
+        );
+        std::fs::write(&matching, "set number\n").unwrap();
+        std::fs::write(&changed, "alias ll='ls -l'\n").unwrap();
+
+        // Restored from a backup, but without the cache
+        std::fs::create_dir(dir.path().join("home")).unwrap();
+        std::fs::write(dir.path().join("home/.vimrc"), "set number\n").unwrap();
+        std::fs::write(dir.path().join("home/.bashrc"), "edited\n").unwrap();
+
+        // A dry run writes nothing
+        assert!(!sync_cache(&Options {
+            dry_run: true,
+            ..opt.clone()
+        })
+        .unwrap());
+        assert!(!opt.cache_file.exists());
+        assert!(!opt.cache_directory.exists());
+
+        assert!(!sync_cache(&opt).unwrap());
+
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        assert_eq!(
+            cache.templates,
+            maplit::btreemap! { matching.clone() => dir.path().join("home/.vimrc") }
+        );
+        assert_eq!(
+            std::fs::read_to_string(snapshot_path(&opt.cache_directory, &matching)).unwrap(),
+            "set number\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("home/.bashrc")).unwrap(),
+            "edited\n"
+        );
+    }
+
+    #[test]
+    fn changed_sources_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let untouched = dir.path().join("vimrc");
+        let touched = dir.path().join("bashrc");
+        let mut opt = test_options(
+            dir.path(),
+            &format!(
+                "[shell.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n\
+                 {:?} = {{ target = {:?}, type = \"template\" }}\n",
+                untouched,
+                dir.path().join("home/.vimrc"),
+                touched,
+                dir.path().join("home/.bashrc")
+            ),
+            &["shell"],
+        );
+        std::fs::write(&untouched, "set number\n").unwrap();
+        std::fs::write(&touched, "alias ll='ls -l'\n").unwrap();
+
+        assert!(!deploy(&opt, false).unwrap());
+
+        let set_modified = |path: &Path, time: SystemTime| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+        std::fs::write(&untouched, "set nonumber\n").unwrap();
+        set_modified(&untouched, SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+        std::fs::write(&touched, "alias la='ls -a'\n").unwrap();
+        set_modified(&touched, SystemTime::now() + Duration::from_secs(60));
+
+        opt.changed_sources_only = true;
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("home/.vimrc")).unwrap(),
+            "set number\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("home/.bashrc")).unwrap(),
+            "alias la='ls -a'\n"
+        );
+        // Skipped templates are still deployed as far as the cache is concerned
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        assert_eq!(cache.templates.len(), 2);
+
+        opt.changed_sources_only = false;
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("home/.vimrc")).unwrap(),
+            "set nonumber\n"
+        );
+    }
+
+    #[test]
+    fn changed_sources_only_after_a_deploy_of_one_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let selected = dir.path().join("vimrc");
+        let other = dir.path().join("bashrc");
+        let mut opt = test_options(
+            dir.path(),
+            &format!(
+                "[shell.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n\
+                 {:?} = {{ target = {:?}, type = \"template\" }}\n",
+                selected,
+                dir.path().join("home/.vimrc"),
+                other,
+                dir.path().join("home/.bashrc")
+            ),
+            &["shell"],
+        );
+        std::fs::write(&selected, "set number\n").unwrap();
+        std::fs::write(&other, "alias ll='ls -l'\n").unwrap();
+        assert!(!deploy(&opt, false).unwrap());
+
+        // Both sources changed after the last full deploy
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        cache.last_deploy.as_mut().unwrap().timestamp = now - 100;
+        save_cache(&opt.cache_file, cache).unwrap();
+        for (path, contents) in [
+            (&selected, "set nonumber\n"),
+            (&other, "alias la='ls -a'\n"),
+        ] {
+            std::fs::write(path, contents).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(now - 50))
+                .unwrap();
         }
-        Err(e) => {
-            display_error(e.context(context()));
-            *error_occurred = true;
+
+        opt.only_target = Some(dir.path().join("home/.vimrc"));
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("home/.bashrc")).unwrap(),
+            "alias ll='ls -l'\n"
+        );
+
+        opt.only_target = None;
+        opt.changed_sources_only = true;
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("home/.bashrc")).unwrap(),
+            "alias la='ls -a'\n"
+        );
+    }
+
+    #[test]
+    fn list_inventory() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        let vimrc = dir.path().join("vimrc");
+        let bashrc = dir.path().join("bashrc");
+        let gitconfig = dir.path().join("gitconfig");
+        for file in [&vimrc, &bashrc, &gitconfig] {
+            std::fs::write(file, "contents\n").unwrap();
         }
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(
+            &global_config,
+            format!(
+                "[vim.files]\n{:?} = {:?}\n\
+                 [shell.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                vimrc,
+                home.join(".vimrc"),
+                bashrc,
+                home.join(".bashrc")
+            ),
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(
+            &local_config,
+            format!(
+                "packages = [\"vim\", \"shell\"]\n[files]\n{:?} = {:?}\n",
+                gitconfig,
+                home.join(".gitconfig")
+            ),
+        )
+        .unwrap();
+        let cache_file = dir.path().join("cache.toml");
+        filesystem::save_file(
+            &cache_file,
+            Cache {
+                symlinks: maplit::btreemap! { vimrc.clone() => home.join(".vimrc") },
+                ..Cache::default()
+            },
+        )
+        .unwrap();
+
+        let opt = Options {
+            global_config,
+            local_config,
+            cache_file,
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(inventory(&opt).unwrap()).unwrap(),
+            serde_json::json!([
+                {
+                    "target": home.join(".bashrc"),
+                    "source": bashrc,
+                    "package": "shell",
+                    "kind": "template",
+                    "deployed": false,
+                },
+                {
+                    "target": home.join(".gitconfig"),
+                    "source": gitconfig,
+                    "package": null,
+                    "kind": "symlink",
+                    "deployed": false,
+                },
+                {
+                    "target": home.join(".vimrc"),
+                    "source": vimrc,
+                    "package": "vim",
+                    "kind": "symlink",
+                    "deployed": true,
+                },
+            ])
+        );
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::filesystem::{SymlinkComparison, TemplateComparison};
+    #[test]
+    fn first_deploy_variable() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("bashrc");
+        let target = dir.path().join("home/.bashrc");
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[shell.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source, target
+            ),
+            &["shell"],
+        );
+        std::fs::write(
+            &source,
+            "{{#if dotter.first_deploy}}echo welcome{{else}}echo hi{{/if}}\n",
+        )
+        .unwrap();
 
-    use std::path::{Path, PathBuf};
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "echo welcome\n");
 
-    use super::*;
+        // The diff of the next deploy is computed with the same value
+        let (report, _) = audit_report(&opt).unwrap();
+        assert_eq!(report.entries[0].status, crate::audit::AuditStatus::Changed);
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "echo hi\n");
+        let (report, _) = audit_report(&opt).unwrap();
+        assert!(report.in_sync());
+    }
 
-    use mockall::predicate::*;
+    #[test]
+    fn cache_location_from_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        std::fs::write(&source, "set number\n").unwrap();
+        let cache_file = dir.path().join("xdg/dotter/cache.toml");
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(
+            &global_config,
+            format!(
+                "[settings]\ncache_file = {:?}\n[vim.files]\n{:?} = {:?}\n",
+                cache_file,
+                source,
+                dir.path().join("home/.vimrc")
+            ),
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"vim\"]\n").unwrap();
 
-    fn path_eq(expected: &str) -> impl Fn(&Path) -> bool {
-        let expected = PathBuf::from(expected);
-        move |actual| actual == expected
+        let opt = Options {
+            global_config,
+            local_config,
+            cache_file: ".dotter/cache.toml".into(),
+            cache_directory: dir.path().join("cache"),
+            pre_deploy: dir.path().join("pre_deploy.sh"),
+            post_deploy: dir.path().join("post_deploy.sh"),
+            noconfirm: true,
+            ..Default::default()
+        };
+        assert!(!deploy(&opt, false).unwrap());
+
+        let cache: Cache = load_file(&cache_file).unwrap().unwrap();
+        assert_eq!(
+            cache.symlinks.get(&source),
+            Some(&dir.path().join("home/.vimrc"))
+        );
+
+        // The command line takes precedence
+        let settings = config::Settings {
+            cache_file: Some(cache_file),
+            ..Default::default()
+        };
+        let explicit = Options {
+            cache_file: "elsewhere.toml".into(),
+            cache_file_given: true,
+            ..opt
+        };
+        assert_eq!(
+            with_cache_settings(&explicit, &settings)
+                .unwrap()
+                .cache_file,
+            PathBuf::from("elsewhere.toml")
+        );
     }
 
     #[test]
-    fn high_level_simple() {
-        // State
-        let a_out: SymbolicTarget = "a_out".into();
-        let b_out: TemplateTarget = "b_out".into();
+    fn deploy_continues_after_broken_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = String::from("[shell.files]\n");
+        for name in ["bashrc", "broken", "profile", "zshrc"] {
+            let contents = if name == "broken" {
+                "{{#if}}unclosed\n".to_string()
+            } else {
+                format!("# {}\n", name)
+            };
+            let source = dir.path().join(name);
+            std::fs::write(&source, contents).unwrap();
+            files += &format!(
+                "{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source,
+                dir.path().join("home").join(name)
+            );
+        }
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(&global_config, files).unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"shell\"]\n").unwrap();
 
-        let desired_symlinks = maplit::btreemap! {
-            PathBuf::from("a_in") => a_out.clone()
+        let opt = Options {
+            global_config,
+            local_config,
+            cache_file: dir.path().join("cache.toml"),
+            cache_directory: dir.path().join("cache"),
+            pre_deploy: dir.path().join("pre_deploy.sh"),
+            post_deploy: dir.path().join("post_deploy.sh"),
+            noconfirm: true,
+            ..Default::default()
         };
-        let desired_templates = maplit::btreemap! {
-            PathBuf::from("b_in") => b_out.clone()
+        assert!(deploy(&opt, false).unwrap());
+
+        for name in ["bashrc", "profile", "zshrc"] {
+            assert_eq!(
+                std::fs::read_to_string(dir.path().join("home").join(name)).unwrap(),
+                format!("# {}\n", name)
+            );
+        }
+        assert!(!dir.path().join("home/broken").exists());
+        let cache: Cache = load_file(&opt.cache_file).unwrap().unwrap();
+        assert_eq!(cache.templates.len(), 3);
+        assert!(cache.last_deploy.is_none());
+    }
+
+    #[test]
+    fn deploy_asks_for_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("bashrc");
+        let target = dir.path().join("home/bashrc");
+        std::fs::write(&source, "# bashrc\n").unwrap();
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(
+            &global_config,
+            format!(
+                "[shell.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source, target
+            ),
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"shell\"]\n").unwrap();
+
+        let opt = Options {
+            global_config,
+            local_config,
+            cache_file: dir.path().join("cache.toml"),
+            cache_directory: dir.path().join("cache"),
+            pre_deploy: dir.path().join("pre_deploy.sh"),
+            post_deploy: dir.path().join("post_deploy.sh"),
+            ..Default::default()
+        };
+        let prompts = &std::cell::RefCell::new(Vec::new());
+        let answer = |answer| {
+            move |prompt: &str| {
+                prompts.borrow_mut().push(prompt.to_string());
+                answer
+            }
         };
 
-        // Test high level
-        let mut runner = actions::MockActionRunner::new();
-        let mut seq = mockall::Sequence::new();
-        let mut cache = Cache::default();
+        // Declining changes nothing
+        assert!(!deploy_with_confirmation(&opt, Some(&answer(false)), None).unwrap());
+        assert!(!target.exists());
+        assert!(!opt.cache_file.exists());
 
-        runner
-            .expect_create_symlink()
-            .times(1)
-            .with(function(path_eq("a_in")), eq(a_out))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(true));
-        runner
-            .expect_create_template()
-            .times(1)
-            .with(
-                function(path_eq("b_in")),
-                function(path_eq("cache/b_in")),
-                eq(b_out),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(true));
+        assert!(!deploy_with_confirmation(&opt, Some(&answer(true)), None).unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "# bashrc\n");
+        assert_eq!(*prompts.borrow(), vec!["1 new. Proceed? [y/N] "; 2]);
 
-        let (suggest_force, error_occurred) = run_deploy(
-            &mut runner,
-            &desired_symlinks,
-            &desired_templates,
-            &mut cache,
-            &Options {
-                cache_directory: "cache".into(),
-                force: false,
-                ..Options::default()
-            },
+        // Nothing to confirm when everything is in sync
+        assert!(!deploy_with_confirmation(&opt, Some(&answer(false)), None).unwrap());
+        assert_eq!(prompts.borrow().len(), 2);
+    }
+
+    #[test]
+    fn undeploy_dry_run_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("vimrc");
+        let symlinked = dir.path().join("bashrc");
+        let template_target = dir.path().join("home/.vimrc");
+        let symlink_target = dir.path().join("home/.bashrc");
+        let missing_target = dir.path().join("home/.profile");
+        std::fs::write(&template, "set number\n").unwrap();
+        std::fs::write(&symlinked, "# bashrc\n").unwrap();
+        std::fs::write(dir.path().join("profile"), "# profile\n").unwrap();
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[shell.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n{:?} = {{ target = {:?}, type = \"symbolic\" }}\n{:?} = {{ target = {:?}, type = \"symbolic\" }}\n",
+                template,
+                template_target,
+                symlinked,
+                symlink_target,
+                dir.path().join("profile"),
+                missing_target
+            ),
+            &["shell"],
         );
+        assert!(!deploy(&opt, false).unwrap());
+        std::fs::remove_file(&missing_target).unwrap();
+        let cache = std::fs::read_to_string(&opt.cache_file).unwrap();
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+        let (error_occurred, mut removed) = undeploy_targets(Options {
+            dry_run: true,
+            ..opt.clone()
+        })
+        .unwrap();
+        assert!(!error_occurred);
+        removed.sort();
+        // The target that's already gone isn't listed
+        assert_eq!(
+            removed,
+            vec![symlink_target.clone(), template_target.clone()]
+        );
+        assert!(symlink_target.exists());
+        assert_eq!(
+            std::fs::read_to_string(&template_target).unwrap(),
+            "set number\n"
+        );
+        assert_eq!(std::fs::read_to_string(&opt.cache_file).unwrap(), cache);
 
-        assert!(cache.symlinks.contains_key(&PathBuf::from("a_in")));
-        assert!(cache.templates.contains_key(&PathBuf::from("b_in")));
-        assert_eq!(cache.symlinks.len(), 1);
-        assert_eq!(cache.templates.len(), 1);
+        assert!(!undeploy(opt).unwrap());
+        assert!(!symlink_target.exists());
+        assert!(!template_target.exists());
     }
 
     #[test]
-    fn high_level_skip() {
-        // Setup
-        let a_out: SymbolicTarget = "a_out".into();
-        let b_out: TemplateTarget = "b_out".into();
+    fn review_applies_accepted_hunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("config");
+        let target = dir.path().join("home/config");
+        std::fs::write(&source, "a = 1\nb = 2\nc = 3\nd = 4\ne = 5\n").unwrap();
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[app.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source, target
+            ),
+            &["app"],
+        );
+        assert!(!deploy(&opt, false).unwrap());
 
-        let desired_symlinks = maplit::btreemap! {
-            PathBuf::from("a_in") => a_out.clone()
+        std::fs::write(&source, "a = 10\nb = 2\nc = 3\nd = 4\ne = 50\n").unwrap();
+        let prompts = std::cell::RefCell::new(Vec::new());
+        let review = |prompt: &str| {
+            prompts.borrow_mut().push(prompt.to_string());
+            prompt.contains("a = 10")
         };
-        let desired_templates = maplit::btreemap! {
-            PathBuf::from("b_in") => b_out.clone()
+        assert!(!deploy_with_confirmation(&opt, None, Some(&review)).unwrap());
+        assert_eq!(prompts.borrow().len(), 2);
+        assert_eq!(
+            std::fs::read_to_string(&target).unwrap(),
+            "a = 10\nb = 2\nc = 3\nd = 4\ne = 5\n"
+        );
+
+        // The rejected hunk is offered again, since the target is what was deployed
+        let review = |prompt: &str| prompt.contains("e = 50");
+        assert!(!deploy_with_confirmation(&opt, None, Some(&review)).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&target).unwrap(),
+            "a = 10\nb = 2\nc = 3\nd = 4\ne = 50\n"
+        );
+    }
+
+    #[test]
+    fn audit_leaves_everything_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let vimrc = dir.path().join("vimrc");
+        let zshrc = dir.path().join("zshrc");
+        std::fs::write(&vimrc, "set number\n").unwrap();
+        std::fs::write(&zshrc, "setopt autocd\n").unwrap();
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(
+            &global_config,
+            format!(
+                "[shell.files]\n{:?} = {:?}\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                vimrc,
+                dir.path().join("home/.vimrc"),
+                zshrc,
+                dir.path().join("home/.zshrc")
+            ),
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"shell\"]\n").unwrap();
+
+        let opt = Options {
+            global_config,
+            local_config,
+            cache_file: dir.path().join("cache.toml"),
+            cache_directory: dir.path().join("cache"),
+            pre_deploy: dir.path().join("pre_deploy.sh"),
+            post_deploy: dir.path().join("post_deploy.sh"),
+            noconfirm: true,
+            ..Default::default()
         };
+        assert!(!deploy(&opt, false).unwrap());
 
-        let mut runner = actions::MockActionRunner::new();
-        let mut seq = mockall::Sequence::new();
-        let mut cache = Cache::default();
+        fn list(dir: &Path, files: &mut Vec<PathBuf>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    list(&path, files);
+                }
+                files.push(path);
+            }
+            files.sort();
+        }
+        let listing = || {
+            let mut files = Vec::new();
+            list(dir.path(), &mut files);
+            files
+        };
+        let modified = || {
+            std::fs::metadata(&opt.cache_file)
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        let (files_before, modified_before) = (listing(), modified());
 
-        // Expectation
-        runner
-            .expect_create_symlink()
-            .times(1)
-            .with(function(path_eq("a_in")), eq(a_out))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Err(anyhow::anyhow!("oh no")));
-        runner
-            .expect_create_template()
-            .times(1)
-            .with(
-                function(path_eq("b_in")),
-                function(path_eq("cache/b_in")),
-                eq(b_out),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(false));
+        let (report, _) = audit_report(&opt).unwrap();
+        assert!(report.in_sync());
+        assert_eq!(report.entries.len(), 2);
 
-        // Reality
-        let (suggest_force, error_occurred) = run_deploy(
-            &mut runner,
-            &desired_symlinks,
-            &desired_templates,
-            &mut cache,
-            &Options {
-                cache_directory: "cache".into(),
-                force: false,
-                ..Options::default()
-            },
+        std::fs::write(dir.path().join("home/.zshrc"), "edited\n").unwrap();
+        let (report, _) = audit_report(&opt).unwrap();
+        assert_eq!(report.entries[1].status, crate::audit::AuditStatus::Changed);
+        assert_eq!(
+            report.entries[1].sha256.as_deref(),
+            Some(crate::plan::sha256(b"edited\n").as_str())
         );
 
-        assert_eq!(suggest_force, true);
-        assert_eq!(error_occurred, true);
+        assert_eq!(listing(), files_before);
+        assert_eq!(modified(), modified_before);
+    }
 
-        assert_eq!(cache.symlinks.len(), 0);
-        assert_eq!(cache.templates.len(), 0);
+    #[test]
+    fn diff_continues_after_missing_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let valid = dir.path().join("valid");
+        let missing = dir.path().join("renamed");
+        std::fs::write(&valid, "theme = {{theme}}\n").unwrap();
+        let mut files = maplit::btreemap! {
+            valid.clone() => FileTarget::Automatic(dir.path().join("valid_target")),
+            missing.clone() => FileTarget::Automatic(dir.path().join("missing_target")),
+        };
+        let file_packages = maplit::btreemap! {
+            valid.clone() => "shell".to_string(),
+            missing => "shell".to_string(),
+        };
+
+        assert!(remove_missing_sources(&mut files, &file_packages));
+        let (_, templates) =
+            split_files(files, &config::Settings::default(), &Variables::new()).unwrap();
+        assert_eq!(templates.keys().collect::<Vec<_>>(), vec![&valid]);
+
+        let mut variables = config::Variables::new();
+        variables.insert("theme".into(), "dark".into());
+        let diff = difference::generate_template_diff(
+            &valid,
+            &templates[&valid],
+            &handlebars::Handlebars::new(),
+            &variables,
+            true,
+        )
+        .unwrap();
+        assert!(difference::diff_nonempty(&diff));
     }
 
     #[test]
-    fn high_level_change_target() {
-        // Setup
-        let a_out_new: SymbolicTarget = "a_out_new".into();
+    fn only_changed_hides_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let handlebars = handlebars::Handlebars::new();
+        let mut variables = config::Variables::new();
+        variables.insert("theme".into(), "dark".into());
 
-        let desired_symlinks = maplit::btreemap! {
-            PathBuf::from("a_in") => a_out_new.clone()
+        let mut templates = BTreeMap::new();
+        for name in ["changed", "in-sync", "in-sync-external"] {
+            let source = dir.path().join(name);
+            std::fs::write(&source, "theme = {{theme}}\n").unwrap();
+            let mut target: TemplateTarget = dir.path().join(format!("{}.target", name)).into();
+            if name.ends_with("external") {
+                target.diff_command = Some("false".into());
+            }
+            templates.insert(source, target);
+        }
+        std::fs::write(dir.path().join("changed.target"), "theme = light\n").unwrap();
+        std::fs::write(dir.path().join("in-sync.target"), "theme = dark\n").unwrap();
+        std::fs::write(dir.path().join("in-sync-external.target"), "theme = dark\n").unwrap();
+
+        let diff_opt = DiffOptions {
+            only_changed: true,
+            ..Default::default()
         };
+        let mut out = Vec::new();
+        let (changed_files, error_occurred) = diff_templates(
+            &mut out,
+            &Progress::hidden(),
+            None,
+            &BTreeMap::new(),
+            &templates,
+            &handlebars,
+            &variables,
+            &diff_opt,
+            0,
+            None,
+            &difference::Palette::plain(),
+        )
+        .unwrap();
+        assert!(changed_files > 0);
+        assert!(!error_occurred);
 
-        let mut runner = actions::MockActionRunner::new();
-        let mut seq = mockall::Sequence::new();
-        let mut cache = Cache {
-            symlinks: maplit::btreemap! {
-                PathBuf::from("a_in") => "a_out_old".into()
-            },
-            templates: BTreeMap::new(),
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("changed.target (changed)"));
+        assert!(!out.contains("in-sync"));
+    }
+
+    #[test]
+    fn diff_output_is_in_config_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let handlebars = handlebars::Handlebars::new();
+        let mut variables = config::Variables::new();
+        variables.insert("theme".into(), "dark".into());
+
+        let mut templates = BTreeMap::new();
+        for index in 0..16 {
+            let source = dir.path().join(format!("{:02}", index));
+            // Larger templates take longer, so that threads don't finish in order
+            let lines = "theme = {{theme}}\n".repeat(if index % 2 == 0 { 200 } else { 1 });
+            std::fs::write(&source, lines).unwrap();
+            let target = dir.path().join(format!("{:02}.target", index));
+            std::fs::write(&target, "theme = light\n").unwrap();
+            templates.insert(source, TemplateTarget::from(target));
+        }
+
+        let run = |jobs: usize| {
+            let mut out = Vec::new();
+            let (changed_files, error_occurred) = diff_templates(
+                &mut out,
+                &Progress::hidden(),
+                None,
+                &BTreeMap::new(),
+                &templates,
+                &handlebars,
+                &variables,
+                &DiffOptions {
+                    stat: true,
+                    ..Default::default()
+                },
+                0,
+                NonZeroUsize::new(jobs),
+                &difference::Palette::plain(),
+            )
+            .unwrap();
+            assert_eq!((changed_files, error_occurred), (16, false));
+            String::from_utf8(out).unwrap()
         };
 
-        // Expectation
-        runner
-            .expect_delete_symlink()
-            .times(1)
-            .with(function(path_eq("a_in")), function(path_eq("a_out_old")))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(true));
-        runner
-            .expect_create_symlink()
-            .times(1)
-            .with(function(path_eq("a_in")), eq(a_out_new))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(true));
+        // Even on a single CPU
+        let first = run(4);
+        let order = first
+            .lines()
+            .filter_map(|line| line.split(".target").next()?.rsplit('/').next())
+            .take(16)
+            .collect::<Vec<_>>();
+        let expected = (0..16)
+            .map(|index| format!("{:02}", index))
+            .collect::<Vec<_>>();
+        assert_eq!(order, expected);
+        for _ in 0..5 {
+            assert_eq!(run(4), first);
+        }
 
-        // Reality
-        let (suggest_force, error_occurred) = run_deploy(
-            &mut runner,
-            &desired_symlinks,
+        // `--jobs 1` is the serial baseline
+        assert_eq!(run(1), first);
+    }
+
+    #[test]
+    fn diff_jobs_produce_the_same_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let handlebars = handlebars::Handlebars::new();
+        let mut variables = config::Variables::new();
+        variables.insert("theme".into(), "dark".into());
+
+        let mut templates = BTreeMap::new();
+        for index in 0..8 {
+            let source = dir.path().join(format!("app{}.toml", index));
+            let lines = format!("name = {}\ntheme = {{{{theme}}}}\n", index)
+                .repeat(if index % 2 == 0 { 100 } else { 1 });
+            std::fs::write(&source, lines).unwrap();
+            let target = dir.path().join(format!("app{}.toml.target", index));
+            std::fs::write(&target, format!("name = {}\ntheme = light\n", index)).unwrap();
+            templates.insert(source, TemplateTarget::from(target));
+        }
+
+        let run = |jobs: usize| {
+            let mut out = Vec::new();
+            let (changed_files, error_occurred) = diff_templates(
+                &mut out,
+                &Progress::hidden(),
+                None,
+                &BTreeMap::new(),
+                &templates,
+                &handlebars,
+                &variables,
+                &DiffOptions::default(),
+                3,
+                NonZeroUsize::new(jobs),
+                &difference::Palette::plain(),
+            )
+            .unwrap();
+            assert_eq!((changed_files, error_occurred), (8, false));
+            String::from_utf8(out).unwrap()
+        };
+
+        let serial = run(1);
+        let mut headers = (0..8).map(|index| format!("app{}.toml.target", index));
+        let mut next = headers.next();
+        for line in serial.lines() {
+            if matches!(&next, Some(header) if line.contains(header.as_str())) {
+                next = headers.next();
+            }
+        }
+        assert_eq!(next, None, "diffs aren't in order:\n{}", serial);
+        assert_eq!(serial.matches("theme = dark").count(), 8 / 2 * 100 + 8 / 2);
+        assert_eq!(run(4), serial);
+    }
+
+    #[test]
+    fn diff_filter_selects_statuses() {
+        let dir = tempfile::tempdir().unwrap();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = config::Variables::new();
+
+        let mut templates = BTreeMap::new();
+        for name in ["new", "modified"] {
+            let source = dir.path().join(name);
+            std::fs::write(&source, "theme = dark\n").unwrap();
+            templates.insert(
+                source,
+                TemplateTarget::from(dir.path().join(format!("{}.target", name))),
+            );
+        }
+        std::fs::write(dir.path().join("modified.target"), "theme = light\n").unwrap();
+
+        let run = |filter: &str| {
+            let diff_opt = DiffOptions {
+                diff_filter: Some(args::parse_diff_filter(filter).unwrap()),
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            let (changed_files, error_occurred) = diff_templates(
+                &mut out,
+                &Progress::hidden(),
+                None,
+                &BTreeMap::new(),
+                &templates,
+                &handlebars,
+                &variables,
+                &diff_opt,
+                0,
+                None,
+                &difference::Palette::plain(),
+            )
+            .unwrap();
+            assert!(!error_occurred);
+            (changed_files, String::from_utf8(out).unwrap())
+        };
+
+        let (changed_files, out) = run("A");
+        assert_eq!(changed_files, 1);
+        assert!(out.contains("new.target (new)"), "{}", out);
+        assert!(!out.contains("modified.target"), "{}", out);
+
+        let (changed_files, out) = run("M");
+        assert_eq!(changed_files, 1);
+        assert!(out.contains("modified.target (changed)"), "{}", out);
+        assert!(!out.contains("new.target"), "{}", out);
+
+        assert_eq!(run("AM").0, 2);
+        assert!(args::parse_diff_filter("D").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn type_change_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = config::Variables::new();
+
+        let source = dir.path().join("gitconfig");
+        std::fs::write(&source, "name = me\n").unwrap();
+        let elsewhere = dir.path().join("elsewhere");
+        std::fs::write(&elsewhere, "name = me\n").unwrap();
+        let target = dir.path().join("gitconfig.target");
+        std::os::unix::fs::symlink(&elsewhere, &target).unwrap();
+        let templates = maplit::btreemap! { source => TemplateTarget::from(target.clone()) };
+
+        let mut out = Vec::new();
+        let (changed_files, error_occurred) = diff_templates(
+            &mut out,
+            &Progress::hidden(),
+            None,
             &BTreeMap::new(),
-            &mut cache,
-            &Options {
-                cache_directory: "cache".into(),
-                force: false,
-                ..Options::default()
-            },
+            &templates,
+            &handlebars,
+            &variables,
+            &DiffOptions::default(),
+            0,
+            None,
+            &difference::Palette::plain(),
+        )
+        .unwrap();
+        assert_eq!(changed_files, 1);
+        assert!(!error_occurred);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "━━━ {} (target changed type: expected file, found symlink) ━━━\n",
+                target.display()
+            )
         );
+    }
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+    #[test]
+    fn diff_flags_targets_missing_from_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut templates = BTreeMap::new();
+        for name in ["deployed", "copied"] {
+            let source = dir.path().join(name);
+            std::fs::write(&source, "set number\n").unwrap();
+            let target = dir.path().join(format!("{}.target", name));
+            std::fs::write(&target, "set number\n").unwrap();
+            templates.insert(source, TemplateTarget::from(target));
+        }
+        let cached = maplit::btreemap! {
+            dir.path().join("deployed") => dir.path().join("deployed.target"),
+        };
 
-        assert_eq!(cache.symlinks.len(), 1);
-        assert_eq!(cache.templates.len(), 0);
+        let mut out = Vec::new();
+        let (changed_files, error_occurred) = diff_templates(
+            &mut out,
+            &Progress::hidden(),
+            None,
+            &cached,
+            &templates,
+            &handlebars::Handlebars::new(),
+            &config::Variables::new(),
+            &DiffOptions::default(),
+            3,
+            None,
+            &difference::Palette::plain(),
+        )
+        .unwrap();
+        assert_eq!((changed_files, error_occurred), (0, false));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "━━━ {} (in sync but not in the cache, run `dotter sync-cache`) ━━━\n",
+                dir.path().join("copied.target").display()
+            )
+        );
     }
 
     #[test]
-    fn high_level_change_type() {
-        // Setup
-        let a_out_new: SymbolicTarget = "a_out_new".into();
-
-        let desired_symlinks = maplit::btreemap! {
-            PathBuf::from("a_in") => a_out_new.clone()
+    fn diff_by_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut templates = BTreeMap::new();
+        for (name, target_contents) in [
+            ("bashrc", Some("old\n")),
+            ("gitconfig", Some("new\n")),
+            ("zshrc", Some("old\n")),
+            ("extra", None),
+        ] {
+            let source = dir.path().join(name);
+            std::fs::write(&source, "new\n").unwrap();
+            let target = dir.path().join(format!("{}.target", name));
+            if let Some(contents) = target_contents {
+                std::fs::write(&target, contents).unwrap();
+            }
+            templates.insert(source, TemplateTarget::from(target));
+        }
+        let file_packages = maplit::btreemap! {
+            dir.path().join("bashrc") => "shell".to_string(),
+            dir.path().join("zshrc") => "shell".to_string(),
+            dir.path().join("gitconfig") => "git".to_string(),
         };
-
-        let mut runner = actions::MockActionRunner::new();
-        let mut seq = mockall::Sequence::new();
-        let mut cache = Cache {
-            symlinks: BTreeMap::new(),
-            templates: maplit::btreemap! {
-                PathBuf::from("a_in") => "a_out_old".into()
-            },
+        // Shell is deployed after git
+        let package_stages = maplit::btreemap! {
+            "git".to_string() => 0,
+            "shell".to_string() => 1,
         };
 
-        // Expectation
-        runner
-            .expect_delete_template()
-            .times(1)
-            .with(
-                function(path_eq("a_in")),
-                function(path_eq("cache/a_in")),
-                function(path_eq("a_out_old")),
+        let diff = |diff_opt: DiffOptions| {
+            let mut out = Vec::new();
+            let (changed_files, error_occurred) = diff_packages(
+                &mut out,
+                &Progress::hidden(),
+                None,
+                &BTreeMap::new(),
+                group_by_package(templates.clone(), &file_packages, &package_stages),
+                &Handlebars::new(),
+                &config::Variables::new(),
+                &diff_opt,
+                0,
+                None,
+                &difference::Palette::plain(),
             )
-            .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(true));
-        runner
-            .expect_create_symlink()
-            .times(1)
-            .with(function(path_eq("a_in")), eq(a_out_new))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(true));
+            .unwrap();
+            assert_eq!(changed_files, 3);
+            assert!(!error_occurred);
+            String::from_utf8(out).unwrap()
+        };
 
-        // Reality
-        let (suggest_force, error_occurred) = run_deploy(
-            &mut runner,
-            &desired_symlinks,
-            &BTreeMap::new(),
-            &mut cache,
-            &Options {
-                cache_directory: "cache".into(),
-                force: false,
-                ..Options::default()
-            },
+        let out = diff(DiffOptions::default());
+        let headers = out
+            .lines()
+            .filter(|line| line.starts_with('═'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            vec![
+                "═══ package git: in sync ═══",
+                "═══ package shell: 2 changed files ═══",
+                "═══ local configuration: 1 changed file ═══",
+            ]
         );
+        let shell = out.find("package shell").unwrap();
+        let local = out.find("local configuration").unwrap();
+        for file in ["bashrc.target", "zshrc.target"] {
+            let position = out.find(file).unwrap();
+            assert!(shell < position && position < local, "{}", out);
+        }
+        assert!(out.find("extra.target").unwrap() > local);
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+        let out = diff(DiffOptions {
+            only_changed: true,
+            ..DiffOptions::default()
+        });
+        assert!(!out.contains("package git"));
+        assert!(out.contains("package shell"));
+    }
 
-        assert_eq!(cache.symlinks.len(), 1);
-        assert_eq!(cache.templates.len(), 0);
+    #[test]
+    fn diff_since_last_deploy() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("colors");
+        let target = dir.path().join("colors.target");
+        let cache_directory = dir.path().join("cache");
+        let snapshot = snapshot_path(&cache_directory, &source);
+        std::fs::create_dir_all(snapshot.parent().unwrap()).unwrap();
+        std::fs::write(&source, "theme = {{theme}}\n").unwrap();
+        std::fs::write(&snapshot, "theme = light\n").unwrap();
+        std::fs::write(&target, "theme = light\n# edited locally\n").unwrap();
+
+        let templates = maplit::btreemap! { source => TemplateTarget::from(target) };
+        let mut variables = config::Variables::new();
+        variables.insert("theme".into(), "dark".into());
+        let diff = |snapshots: Option<&Path>| {
+            let mut out = Vec::new();
+            let (changed_files, error_occurred) = diff_templates(
+                &mut out,
+                &Progress::hidden(),
+                snapshots,
+                &BTreeMap::new(),
+                &templates,
+                &Handlebars::new(),
+                &variables,
+                &DiffOptions::default(),
+                0,
+                None,
+                &difference::Palette::plain(),
+            )
+            .unwrap();
+            assert!(changed_files > 0);
+            assert!(!error_occurred);
+            String::from_utf8(out).unwrap()
+        };
+
+        let since_last_deploy = diff(Some(&cache_directory));
+        assert!(since_last_deploy.contains("theme = light"));
+        assert!(since_last_deploy.contains("theme = dark"));
+        assert!(!since_last_deploy.contains("edited locally"));
+
+        assert!(diff(None).contains("edited locally"));
     }
 
     #[test]
-    #[ignore] // This is desired, but not implemented: see issue #22
-    fn high_level_skip_change_type() {
-        // Setup
-        let desired_symlinks = maplit::btreemap! {
-            PathBuf::from("a_in") => "a_out_new".into()
+    fn patch_applies_to_current_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir_all(&home).unwrap();
+        let changed = dir.path().join("changed");
+        let unchanged = dir.path().join("unchanged");
+        let missing = dir.path().join("missing");
+        let unmanaged = dir.path().join("unmanaged");
+        std::fs::write(&changed, "a\ntheme = {{theme}}\nc\nd\ne\nf\ng\nh\nlast").unwrap();
+        std::fs::write(&unchanged, "theme = {{theme}}\n").unwrap();
+        std::fs::write(&missing, "new\nfile\n").unwrap();
+        std::fs::write(&unmanaged, "theme = {{theme}}\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&missing, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let deployed = "a\ntheme = light\nc\nd\ne\nf\ng\nh\nlast\n";
+        std::fs::write(home.join("changed"), deployed).unwrap();
+        std::fs::write(home.join("unchanged"), "theme = dark\n").unwrap();
+        std::fs::write(home.join("unmanaged"), "theme = light\n").unwrap();
+
+        // Only `changed` was deployed by Dotter before, and wasn't edited since
+        let cache_directory = dir.path().join("cache");
+        let snapshot = snapshot_path(&cache_directory, &changed);
+        std::fs::create_dir_all(snapshot.parent().unwrap()).unwrap();
+        std::fs::write(snapshot, deployed).unwrap();
+        let cache = Cache {
+            templates: [(changed.clone(), home.join("changed"))].into(),
+            ..Default::default()
         };
+        save_cache(&dir.path().join("cache.toml"), cache).unwrap();
 
-        let mut runner = actions::MockActionRunner::new();
-        let mut seq = mockall::Sequence::new();
-        let mut cache = Cache {
-            symlinks: BTreeMap::new(),
-            templates: maplit::btreemap! {
-                PathBuf::from("a_in") => "a_out_old".into()
-            },
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(
+            &global_config,
+            format!(
+                "[default.files]\n\
+                 {:?} = {{ target = {:?}, type = \"template\" }}\n\
+                 {:?} = {{ target = {:?}, type = \"template\" }}\n\
+                 {:?} = {{ target = {:?}, type = \"template\" }}\n\
+                 {:?} = {{ target = {:?}, type = \"template\" }}\n\
+                 [default.variables]\ntheme = \"dark\"\n",
+                changed,
+                home.join("changed"),
+                unchanged,
+                home.join("unchanged"),
+                missing,
+                home.join("new/missing"),
+                unmanaged,
+                home.join("unmanaged"),
+            ),
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"default\"]\n").unwrap();
+
+        let opt = Options {
+            global_config,
+            local_config,
+            cache_file: dir.path().join("cache.toml"),
+            cache_directory,
+            dry_run: true,
+            diff_context_lines: 3,
+            ..Default::default()
         };
+        let out = dir.path().join("changes.patch");
+        // The unmanaged target is left out with an error
+        assert!(write_patch(&opt, Some(&out)).unwrap());
 
-        // Expectation
-        runner
-            .expect_delete_template()
-            .times(1)
-            .with(
-                function(path_eq("a_in")),
-                function(path_eq("cache/a_in")),
-                function(path_eq("a_out_old")),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(false));
+        let patch = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(patch.matches("diff --git").count(), 2);
+        assert!(!patch.contains("unchanged"));
+        assert!(!patch.contains("unmanaged"));
+        #[cfg(unix)]
+        assert!(patch.contains("new file mode 100755"));
 
-        // Reality
-        let (suggest_force, error_occurred) = run_deploy(
-            &mut runner,
-            &desired_symlinks,
-            &BTreeMap::new(),
-            &mut cache,
-            &Options {
-                cache_directory: "cache".into(),
-                force: false,
-                ..Options::default()
-            },
+        let status = std::process::Command::new("git")
+            .arg("apply")
+            .arg(&out)
+            .current_dir("/")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read_to_string(home.join("changed")).unwrap(),
+            "a\ntheme = dark\nc\nd\ne\nf\ng\nh\nlast"
         );
+        assert_eq!(
+            std::fs::read_to_string(home.join("new/missing")).unwrap(),
+            "new\nfile\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(home.join("unchanged")).unwrap(),
+            "theme = dark\n"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(home.join("new/missing"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_ne!(mode & 0o111, 0);
+        }
+    }
 
-        assert_eq!(suggest_force, false);
-        assert_eq!(error_occurred, false);
+    #[test]
+    fn sources_with_variables_are_diffed_and_deployed() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app-{{version}}.conf");
+        let target = dir.path().join("home/app.conf");
+        std::fs::write(dir.path().join("app-2.conf"), "version = {{version}}\n").unwrap();
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[app.files]\n{:?} = {:?}\n[app.variables]\nversion = 2\n",
+                source, target
+            ),
+            &["app"],
+        );
 
-        assert_eq!(cache.symlinks.len(), 1);
-        assert_eq!(cache.templates.len(), 0);
+        // The rendered source is diffed against the missing target
+        assert!(diff(&opt, &DiffOptions::default()).unwrap());
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "version = 2\n");
+        assert!(!diff(&opt, &DiffOptions::default()).unwrap());
     }
 
     #[test]
-    fn low_level_simple() {
-        // Setup
-        let mut fs = crate::filesystem::MockFilesystem::new();
-        let mut seq = mockall::Sequence::new();
+    fn diff_ignored_files_are_still_deployed() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("lockfile");
+        let target = dir.path().join("home/lockfile");
+        std::fs::write(&source, "version = 2\n").unwrap();
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[default.files]\n\
+                 {:?} = {{ target = {:?}, type = \"template\", diff_ignore = true }}\n",
+                source, target
+            ),
+            &["default"],
+        );
+        // The missing target would be a difference
+        assert!(!diff(&opt, &DiffOptions::default()).unwrap());
 
-        let opt = Options::default();
-        let handlebars = handlebars::Handlebars::new();
-        let variables = Default::default();
+        assert!(!deploy(&opt, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "version = 2\n");
+    }
 
-        // Expectation:
-        // create_symlink
-        fs.expect_compare_symlink()
-            .times(1)
-            .with(function(path_eq("a_in")), function(path_eq("a_out")))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(SymlinkComparison::OnlySourceExists));
-        fs.expect_create_dir_all()
-            .times(1)
-            .with(function(path_eq("")), eq(None)) // parent of a_out
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
-        fs.expect_make_symlink()
-            .times(1)
-            .with(
-                function(path_eq("a_out")),
-                function(path_eq("a_in")),
-                eq(None),
+    #[test]
+    fn merge_targets_changed_outside_are_merged_into() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("settings.json");
+        let cache = dir.path().join("cache/settings.json");
+        let target_path = dir.path().join("settings.out.json");
+        std::fs::write(&source, r#"{"theme": "{{theme}}"}"#).unwrap();
+        std::fs::write(&target_path, r#"{"user": true}"#).unwrap();
+        let mut target = TemplateTarget::from(target_path.clone());
+        target.merge = Some(crate::merge::MergeFormat::Json);
+        let handlebars = Handlebars::new();
+        let mut variables = config::Variables::new();
+        variables.insert("theme".into(), "dark".into());
+        let mut fs = crate::filesystem::RealFilesystem::new(true, false);
+        let read = || {
+            serde_json::from_str::<serde_json::Value>(
+                &std::fs::read_to_string(&target_path).unwrap(),
             )
-            .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(()));
+            .unwrap()
+        };
 
-        // create_template
-        fs.expect_compare_template()
-            .times(1)
-            .with(
-                function(path_eq("b_out")),
-                function(path_eq("cache/b_cache")),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(TemplateComparison::BothMissing));
-        fs.expect_create_dir_all()
-            .times(1)
-            .with(function(path_eq("")), eq(None)) // parent of b_out
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
-        fs.expect_read_to_string()
-            .times(1)
-            .with(function(path_eq("b_in")))
-            .in_sequence(&mut seq)
-            .returning(|_| Ok("Hello!".into()));
-        fs.expect_create_dir_all()
-            .times(1)
-            .with(function(path_eq("cache")), eq(None))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
-        fs.expect_write()
-            .times(1)
-            .with(
-                function(path_eq("cache/b_cache")),
-                eq(String::from("Hello!")),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
-        fs.expect_copy_file()
-            .times(1)
-            .with(
-                function(path_eq("cache/b_cache")),
-                function(path_eq("b_out")),
-                eq(None),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(()));
-        fs.expect_copy_permissions()
-            .times(1)
-            .with(
-                function(path_eq("b_in")),
-                function(path_eq("b_out")),
-                eq(None),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(()));
+        // An existing target that isn't managed yet
+        assert!(actions::create_template(
+            &source,
+            &cache,
+            &target,
+            &mut fs,
+            &handlebars,
+            &variables,
+            false
+        )
+        .unwrap());
+        assert_eq!(read(), serde_json::json!({"theme": "dark", "user": true}));
 
-        // Reality
-        let mut runner = actions::RealActionRunner::new(
+        // Edits of the target since then are kept
+        std::fs::write(
+            &target_path,
+            r#"{"theme": "dark", "user": true, "edited": 1}"#,
+        )
+        .unwrap();
+        variables.insert("theme".into(), "light".into());
+        assert!(actions::update_template(
+            &source,
+            &cache,
+            &target,
             &mut fs,
             &handlebars,
             &variables,
-            opt.force,
-            opt.diff_context_lines,
+            Force::default(),
+            3,
+            false,
+            None,
+        )
+        .unwrap());
+        assert_eq!(
+            read(),
+            serde_json::json!({"theme": "light", "user": true, "edited": 1})
         );
-        assert!(runner
-            .create_symlink(&PathBuf::from("a_in"), &PathBuf::from("a_out").into())
-            .unwrap());
-        assert!(runner
-            .create_template(
-                &PathBuf::from("b_in"),
-                &PathBuf::from("cache/b_cache"),
-                &PathBuf::from("b_out").into(),
-            )
-            .unwrap());
     }
 
     #[test]
-    fn low_level_skip() {
-        // Setup
-        let mut fs = crate::filesystem::MockFilesystem::new();
-        let mut seq = mockall::Sequence::new();
+    fn untracked_files_in_owned_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("nvim");
+        let target = dir.path().join("home/.config/nvim");
+        std::fs::create_dir_all(source.join("lua")).unwrap();
+        std::fs::write(source.join("init.lua"), "").unwrap();
+        std::fs::write(source.join("lua/plugins.lua"), "").unwrap();
+        std::fs::create_dir_all(target.join("lua")).unwrap();
+        std::fs::write(target.join("init.lua"), "").unwrap();
+        std::fs::write(target.join("lua/plugins.lua"), "").unwrap();
+        std::fs::write(target.join("lua/removed.lua"), "").unwrap();
+        // Outside of the owned directory
+        std::fs::write(dir.path().join("home/.config/other"), "").unwrap();
+        // In a directory that's deployed from a source, but not listed
+        let config_source = dir.path().join("config");
+        std::fs::create_dir_all(&config_source).unwrap();
+        std::fs::write(config_source.join("app.conf"), "").unwrap();
+        let config_target = dir.path().join("home/.config");
 
-        let opt = Options::default();
-        let handlebars = handlebars::Handlebars::new();
-        let variables = Default::default();
+        let global_config = dir.path().join("global.toml");
+        std::fs::write(
+            &global_config,
+            format!(
+                "[settings]\nreport_untracked = [{:?}]\n[nvim.files]\n{:?} = {:?}\n{:?} = {:?}\n",
+                target, source, target, config_source, config_target
+            ),
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"nvim\"]\n").unwrap();
 
-        // Expectation:
-        // create_symlink
-        fs.expect_compare_symlink()
-            .times(1)
-            .with(function(path_eq("a_in")), function(path_eq("a_out")))
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(SymlinkComparison::Changed));
+        let config = config::load_configuration(
+            &local_config,
+            &[global_config],
+            None,
+            Path::new(".dotter/sources"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            untracked_files(&config).unwrap(),
+            vec![(target.join("lua/removed.lua"), source)]
+        );
+    }
 
-        // create_template
-        fs.expect_compare_template()
-            .times(1)
-            .with(
-                function(path_eq("b_out")),
-                function(path_eq("cache/b_cache")),
-            )
-            .in_sequence(&mut seq)
-            .returning(|_, _| Ok(TemplateComparison::Changed));
+    #[test]
+    fn effective_config_of_overlays() {
+        let dir = tempfile::tempdir().unwrap();
+        let global_config = dir.path().join("global.toml");
+        let overlay = dir.path().join("machine.toml");
+        std::fs::write(
+            &global_config,
+            "[shell.variables]\neditor = \"vim\"\nprompt = { color = \"blue\", symbol = \"$\" }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &overlay,
+            "[shell.variables]\nprompt = { color = \"red\" }\n",
+        )
+        .unwrap();
+        let local_config = dir.path().join("local.toml");
+        std::fs::write(&local_config, "packages = [\"shell\"]\n").unwrap();
 
-        // Reality
-        let mut runner = actions::RealActionRunner::new(
-            &mut fs,
-            &handlebars,
-            &variables,
-            opt.force,
-            opt.diff_context_lines,
+        let opt = Options {
+            global_config,
+            config_overlays: vec![overlay],
+            local_config,
+            ..Default::default()
+        };
+        let config = effective_config(&opt).unwrap();
+        let variables = toml::Value::Table(config.variables.clone());
+        assert_eq!(variables["editor"].as_str(), Some("vim"));
+        assert_eq!(variables["prompt"]["color"].as_str(), Some("red"));
+        assert_eq!(variables["prompt"]["symbol"].as_str(), Some("$"));
+
+        let printed = serde_json::to_value(&config).unwrap();
+        assert_eq!(printed["variables"]["prompt"]["color"], "red");
+        assert_eq!(printed["packages"]["shell"], true);
+    }
+
+    #[test]
+    fn plans_are_applied_without_the_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        let target = dir.path().join("home/.vimrc");
+        std::fs::write(&source, "set number\n").unwrap();
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[vim.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source, target
+            ),
+            &["vim"],
         );
+        let plan_file = dir.path().join("plan.json");
+        assert!(!plan(&opt, &plan_file).unwrap());
 
-        // Both should skip
-        assert!(!runner
-            .create_symlink(&PathBuf::from("a_in"), &PathBuf::from("a_out").into())
-            .unwrap());
-        assert!(!runner
-            .create_template(
-                &PathBuf::from("b_in"),
-                &PathBuf::from("cache/b_cache"),
-                &PathBuf::from("b_out").into(),
-            )
-            .unwrap());
+        // Like a machine that only got the plan
+        std::fs::remove_file(&opt.global_config).unwrap();
+        std::fs::remove_file(&opt.local_config).unwrap();
+        assert!(!apply_plan(&opt, &plan_file).unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "set number\n");
+        status(&opt).unwrap();
+        log(&opt, &target).unwrap();
+    }
+
+    #[test]
+    fn global_options_apply_to_every_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("home/.vimrc");
+        for name in ["vimrc", "vimrc.work"] {
+            std::fs::write(dir.path().join(name), "set number\n").unwrap();
+        }
+        let opt = test_options(
+            dir.path(),
+            &format!(
+                "[vim.files]\n{:?} = {:?}\n{:?} = {:?}\n",
+                dir.path().join("vimrc"),
+                target,
+                dir.path().join("vimrc.work"),
+                target
+            ),
+            &["vim"],
+        );
+        let unused = VariablesOptions {
+            unused: true,
+            ignore: Vec::new(),
+        };
+        assert!(variables(&opt, &unused).is_err());
+
+        let opt = Options {
+            allow_conflicts: true,
+            ..opt
+        };
+        variables(&opt, &unused).unwrap();
+        assert!(!dedup(&opt).unwrap());
     }
 }
@@ -0,0 +1,120 @@
+use std::sync::Mutex;
+
+/// Warnings of the current command, printed again at its end so that they aren't lost in
+/// long output
+pub static WARNINGS: Warnings = Warnings::new();
+
+pub struct Warnings {
+    messages: Mutex<Vec<String>>,
+}
+
+impl Warnings {
+    pub const fn new() -> Warnings {
+        Warnings {
+            messages: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn record(&self, message: String) {
+        self.messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(message);
+    }
+
+    /// The "Warnings (N):" list, or None if there were none. The warnings are forgotten, so
+    /// that the next summary only has the ones recorded after this one
+    pub fn take_summary(&self) -> Option<String> {
+        let messages =
+            std::mem::take(&mut *self.messages.lock().unwrap_or_else(|e| e.into_inner()));
+        if messages.is_empty() {
+            return None;
+        }
+        let mut summary = format!("Warnings ({}):", messages.len());
+        for message in messages.iter() {
+            summary.push_str("\n  - ");
+            summary.push_str(&message.replace('\n', "\n    "));
+        }
+        Some(summary)
+    }
+}
+
+/// Output that records every warning that's shown in `warnings`.
+/// Like simplelog's `add_filter_allow`, only the warnings of targets starting with one of
+/// `allow_targets` are recorded
+pub struct CollectingLogger {
+    pub inner: Box<dyn log::Log>,
+    pub warnings: &'static Warnings,
+    pub allow_targets: &'static [&'static str],
+}
+
+impl log::Log for CollectingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        let allowed = self
+            .allow_targets
+            .iter()
+            .any(|target| record.target().starts_with(target));
+        if record.level() == log::Level::Warn && allowed && self.inner.enabled(record.metadata()) {
+            self.warnings.record(record.args().to_string());
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use log::Log;
+
+    #[test]
+    fn warnings_are_summarized() {
+        static COLLECTED: Warnings = Warnings::new();
+        assert_eq!(COLLECTED.take_summary(), None);
+
+        let logger = CollectingLogger {
+            inner: Box::new(simplelog::SimpleLogger::new(
+                log::LevelFilter::Warn,
+                simplelog::Config::default(),
+            )),
+            warnings: &COLLECTED,
+            allow_targets: &["dotter"],
+        };
+        let log_from = |target, level, message: &str| {
+            logger.log(
+                &log::Record::builder()
+                    .target(target)
+                    .level(level)
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+        let log = |level, message: &str| log_from("dotter::config", level, message);
+        log(log::Level::Warn, "no package matched the os");
+        log(log::Level::Info, "not a warning");
+        log(log::Level::Error, "not a warning either");
+        log(log::Level::Warn, "skipping file\nsecond line");
+        log_from("handlebars::render", log::Level::Warn, "not from dotter");
+
+        assert_eq!(
+            COLLECTED.take_summary().unwrap(),
+            "Warnings (2):\n  - no package matched the os\n  - skipping file\n    second line"
+        );
+
+        // Each summary only has the warnings since the last one
+        log(log::Level::Warn, "redeployed");
+        assert_eq!(
+            COLLECTED.take_summary().unwrap(),
+            "Warnings (1):\n  - redeployed"
+        );
+        assert_eq!(COLLECTED.take_summary(), None);
+    }
+}
@@ -1,72 +1,548 @@
 use anyhow::{Context, Result};
-use crossterm::style::Stylize;
+use crossterm::style::{ContentStyle, Stylize};
 use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
 
 use std::cmp::{max, min};
+use std::fmt::Write;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::config::{TemplateTarget, Variables};
+use crate::encoding;
+use crate::handlebars_helpers::render_target;
+use crate::plan::sha256;
+use crate::progress;
 
 pub type Diff = Vec<diff::Result<String>>;
-pub type HunkDiff = Vec<(usize, usize, Diff)>;
+pub type HunkDiff = Vec<Hunk>;
 
+/// Kind of file that's at a target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Symlink,
+    Directory,
+    /// Like a socket or device
+    Other,
+}
+
+impl std::fmt::Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            FileKind::File => "file",
+            FileKind::Symlink => "symlink",
+            FileKind::Directory => "directory",
+            FileKind::Other => "special file",
+        })
+    }
+}
+
+/// The error of a diff whose target isn't the kind of file it should be, so its contents
+/// weren't compared. Reported as a difference of its own rather than as a failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeChange {
+    pub expected: FileKind,
+    pub found: FileKind,
+}
+
+impl std::fmt::Display for TypeChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "target changed type: expected {}, found {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for TypeChange {}
+
+/// The kind of file at `path`, without following symlinks, or None if there's nothing
+fn file_kind(path: &Path) -> io::Result<Option<FileKind>> {
+    let file_type = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_file() {
+        FileKind::File
+    } else if file_type.is_dir() {
+        FileKind::Directory
+    } else {
+        FileKind::Other
+    }))
+}
+
+/// A group of changed lines, with the surrounding context lines
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// Line number of the hunk's first line in the old file
+    pub old_start: usize,
+    /// Line number of the hunk's first line in the new file
+    pub new_start: usize,
+    pub lines: Diff,
+}
+
+impl Hunk {
+    fn new(old_start: usize, new_start: usize) -> Hunk {
+        Hunk {
+            old_start,
+            new_start,
+            lines: Diff::new(),
+        }
+    }
+}
+
+impl From<(usize, usize, Diff)> for Hunk {
+    fn from((old_start, new_start, lines): (usize, usize, Diff)) -> Self {
+        Hunk {
+            old_start,
+            new_start,
+            lines,
+        }
+    }
+}
+
+/// Prints the differences a template update is about to make to its target, at `-v` or with
+/// `always` (for `deploy --diff`)
 pub fn print_template_diff(
     source: &Path,
     target: &TemplateTarget,
-    handlebars: &Handlebars<'_>,
+    handlebars: &Handlebars<'static>,
     variables: &Variables,
     diff_context_lines: usize,
+    always: bool,
 ) {
-    if log_enabled!(log::Level::Info) {
-        match generate_template_diff(source, target, handlebars, variables, true) {
-            Ok(diff) => {
-                if diff_nonempty(&diff) {
-                    info!(
-                        "{} template {:?} -> {:?}",
-                        "[~]".yellow(),
-                        source,
-                        target.target
-                    );
-                    print_diff(diff, diff_context_lines);
-                }
-            }
-            Err(e) => {
+    if !always && !log_enabled!(log::Level::Info) {
+        return;
+    }
+
+    if let Some(command) = &target.diff_command {
+        if target.target.exists() {
+            print_update_header(source, target, always);
+            if let Err(e) = progress::suspend(|| {
+                run_diff_command(command, source, target, handlebars, variables)
+            }) {
                 warn!(
-                    "Failed to generate diff for template {:?} -> {:?} on step: {}",
+                    "Failed to run diff command for template {:?} -> {:?}: {:#}",
                     source, target.target, e
                 );
             }
+            return;
+        }
+    }
+
+    if always {
+        match format_template_update(
+            source,
+            target,
+            handlebars,
+            variables,
+            diff_context_lines,
+            &Palette::current(),
+        ) {
+            Ok(Some(output)) => progress::suspend(|| print!("{}", output)),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Failed to generate diff for template {:?} -> {:?}: {:#}",
+                source, target.target, e
+            ),
+        }
+        return;
+    }
+
+    let diff = check_diff_size(source, target, &target.target)
+        .and_then(|()| generate_template_diff(source, target, handlebars, variables, true));
+    match diff {
+        Ok(diff) => {
+            if diff_nonempty(&diff) {
+                print_update_header(source, target, always);
+                print_diff(diff, diff_context_lines);
+            }
+        }
+        Err(e) => {
+            warn!(
+                "Failed to generate diff for template {:?} -> {:?} on step: {}",
+                source, target.target, e
+            );
         }
     }
 }
 
+/// The `[~]` line at `-v`, or the file header of `dotter diff` if the diff is printed regardless
+/// of the log level
+fn print_update_header(source: &Path, target: &TemplateTarget, always: bool) {
+    if always {
+        let header = format_file_header(&target.target, true, &Palette::current());
+        progress::suspend(|| print!("{}", header));
+    } else {
+        info!(
+            "{} template {:?} -> {:?}",
+            "[~]".yellow(),
+            source,
+            target.target
+        );
+    }
+}
+
+/// The header and hunks of the changes updating the template's target would make,
+/// or None if it's already up to date
+pub fn format_template_update(
+    source: &Path,
+    target: &TemplateTarget,
+    handlebars: &Handlebars<'static>,
+    variables: &Variables,
+    diff_context_lines: usize,
+    palette: &Palette,
+) -> Result<Option<String>> {
+    check_diff_size(source, target, &target.target)?;
+    let diff = generate_template_diff(source, target, handlebars, variables, true)?;
+    if !diff_nonempty(&diff) {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "{}{}",
+        format_file_header(&target.target, true, palette),
+        format_diff(diff, diff_context_lines, palette)
+    )))
+}
+
 pub fn generate_template_diff(
     source: &Path,
     target: &TemplateTarget,
-    handlebars: &Handlebars<'_>,
+    handlebars: &Handlebars<'static>,
     variables: &Variables,
     source_to_target: bool,
 ) -> Result<Diff> {
-    let file_contents = fs::read_to_string(source).context("read template source file")?;
-    let file_contents = target.apply_actions(file_contents);
-    let rendered = handlebars
-        .render_template(&file_contents, variables)
-        .context("render template")?;
+    generate_diff_against(
+        source,
+        target,
+        &target.target,
+        handlebars,
+        variables,
+        source_to_target,
+    )
+}
+
+/// Fails if the source or `compared` is larger than the target's `max_diff_size`, so that huge
+/// files aren't diffed just to be shown. Diffs that decide whether a target is deployed aren't
+/// limited
+pub fn check_diff_size(source: &Path, target: &TemplateTarget, compared: &Path) -> Result<()> {
+    let max_size = match target.max_diff_size {
+        Some(max_size) => max_size,
+        None => return Ok(()),
+    };
+    // The target isn't followed, so that one of the wrong type is still reported as such
+    for (file, metadata) in [
+        (source, fs::metadata(source)),
+        (compared, fs::symlink_metadata(compared)),
+    ] {
+        let size = match metadata {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context(format!("read metadata of {:?}", file)),
+        };
+        anyhow::ensure!(
+            size <= max_size,
+            "file too large to diff ({:.1} MB)",
+            size as f64 / (1024.0 * 1024.0)
+        );
+    }
+    Ok(())
+}
+
+/// Like `generate_template_diff`, but compares the rendered template to the file `compared`
+/// instead of the target, like the snapshot of the last deploy
+pub fn generate_diff_against(
+    source: &Path,
+    target: &TemplateTarget,
+    compared: &Path,
+    handlebars: &Handlebars<'static>,
+    variables: &Variables,
+    source_to_target: bool,
+) -> Result<Diff> {
+    // Reading through a symlink or a directory would compare something else entirely
+    match file_kind(compared).context(format!("read metadata of {:?}", compared))? {
+        Some(found) if found != FileKind::File => {
+            return Err(TypeChange {
+                expected: FileKind::File,
+                found,
+            }
+            .into())
+        }
+        _ => {}
+    }
+
+    let rendered = render_template(source, target, handlebars, variables)?;
+    match target.encoding {
+        Some(encoding) if !encoding.is_text() => {
+            let rendered = encoding::encode(rendered, target.encoding)?;
+            return binary_diff(&rendered, compared, source_to_target);
+        }
+        // Report characters that the encoding can't represent, like a deploy would
+        Some(_) => {
+            encoding::encode(rendered.clone(), target.encoding)?;
+        }
+        None => {}
+    }
+
+    let target_contents = match fs::read(compared) {
+        Ok(contents) => {
+            encoding::read_text(contents, target.encoding).context("decode template target file")?
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // Everything in a missing target is new
+            return Ok(if source_to_target {
+                rendered
+                    .lines()
+                    .map(|l| diff::Result::Right(l.into()))
+                    .collect()
+            } else {
+                rendered
+                    .lines()
+                    .map(|l| diff::Result::Left(l.into()))
+                    .collect()
+            });
+        }
+        Err(e) => return Err(e).context("read template target file"),
+    };
+
+    let diff = if source_to_target {
+        target
+            .diff_algorithm
+            .diff_lines(&target_contents, &rendered)
+    } else {
+        target
+            .diff_algorithm
+            .diff_lines(&rendered, &target_contents)
+    };
+    ignore_lines(diff, &target.diff_ignore_lines)
+}
+
+/// Treats changed lines that match one of `patterns` as unchanged, so they never form a hunk.
+/// In each run of changed lines, the n-th removed and the n-th added line that match become one
+/// unchanged line, and the ones left over are kept as unchanged lines on their own, so that the
+/// line numbers of both sides stay right
+fn ignore_lines(diff: Diff, patterns: &[String]) -> Result<Diff> {
+    if patterns.is_empty() {
+        return Ok(diff);
+    }
+    let patterns = regex::RegexSet::new(patterns).context("compile diff_ignore_lines patterns")?;
+
+    let mut ignored = Diff::with_capacity(diff.len());
+    let mut changes = Diff::new();
+    for line in diff {
+        match line {
+            diff::Result::Both(..) => {
+                ignore_changes(&mut ignored, std::mem::take(&mut changes), &patterns);
+                ignored.push(line);
+            }
+            line => changes.push(line),
+        }
+    }
+    ignore_changes(&mut ignored, changes, &patterns);
+    Ok(ignored)
+}
+
+/// `ignore_lines` for one run of changed lines
+fn ignore_changes(ignored: &mut Diff, changes: Diff, patterns: &regex::RegexSet) {
+    let mut added = changes
+        .iter()
+        .filter_map(|line| match line {
+            diff::Result::Right(r) if patterns.is_match(r) => Some(r.clone()),
+            _ => None,
+        })
+        .collect::<std::collections::VecDeque<_>>();
+    let removed = changes
+        .iter()
+        .filter(|line| matches!(line, diff::Result::Left(l) if patterns.is_match(l)))
+        .count();
+    let mut paired_added = min(removed, added.len());
+
+    for line in changes {
+        ignored.push(match line {
+            diff::Result::Left(l) if patterns.is_match(&l) => match added.pop_front() {
+                Some(r) => diff::Result::Both(l, r),
+                None => diff::Result::Both(l.clone(), l),
+            },
+            diff::Result::Right(r) if patterns.is_match(&r) => {
+                if paired_added > 0 {
+                    paired_added -= 1;
+                    continue;
+                }
+                diff::Result::Both(r.clone(), r)
+            }
+            line => line,
+        });
+    }
+}
+
+/// Algorithm used to compute line diffs
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    /// Longest common subsequence, from the `diff` crate
+    #[default]
+    Lcs,
+    /// Myers' algorithm, which is also git's default
+    Myers,
+    /// Patience diff, which matches unique lines first so that moved blocks stay together
+    Patience,
+}
+
+impl DiffAlgorithm {
+    pub fn diff_lines(self, old: &str, new: &str) -> Diff {
+        match self {
+            DiffAlgorithm::Lcs => diff::lines(old, new)
+                .into_iter()
+                .map(to_owned_diff_result)
+                .collect(),
+            DiffAlgorithm::Myers | DiffAlgorithm::Patience => {
+                self.diff_slices(&split_lines(old), &split_lines(new))
+            }
+        }
+    }
+
+    /// Diffs lines that were already split
+    pub fn diff_slices(self, old_lines: &[&str], new_lines: &[&str]) -> Diff {
+        let algorithm = match self {
+            DiffAlgorithm::Lcs => {
+                return diff::slice(old_lines, new_lines)
+                    .into_iter()
+                    .map(to_owned_diff_result)
+                    .collect()
+            }
+            DiffAlgorithm::Myers => similar::Algorithm::Myers,
+            DiffAlgorithm::Patience => similar::Algorithm::Patience,
+        };
+
+        let mut diff = Diff::new();
+        for op in similar::capture_diff_slices(algorithm, old_lines, new_lines) {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            match tag {
+                similar::DiffTag::Equal => diff.extend(
+                    old_range
+                        .zip(new_range)
+                        .map(|(o, n)| diff::Result::Both(old_lines[o].into(), new_lines[n].into())),
+                ),
+                _ => {
+                    diff.extend(old_range.map(|o| diff::Result::Left(old_lines[o].into())));
+                    diff.extend(new_range.map(|n| diff::Result::Right(new_lines[n].into())));
+                }
+            }
+        }
+        diff
+    }
+}
 
-    let target_contents =
-        fs::read_to_string(&target.target).context("read template target file")?;
+/// Splits lines like `diff::lines` does, with an empty last line after a trailing newline
+fn split_lines(text: &str) -> Vec<&str> {
+    let mut lines = text.lines().collect::<Vec<_>>();
+    if text.ends_with('\n') {
+        lines.push("");
+    }
+    lines
+}
+
+/// Describes binary contents in one line, since they can't be diffed line by line
+fn describe_binary(contents: &[u8]) -> String {
+    format!(
+        "binary data, {} bytes, sha256 {}",
+        contents.len(),
+        &sha256(contents)[..16]
+    )
+}
 
-    let diff_result = if source_to_target {
-        diff::lines(&target_contents, &rendered)
+/// A diff of one line per side describing the contents, if they differ
+fn binary_diff(rendered: &[u8], target: &Path, source_to_target: bool) -> Result<Diff> {
+    let rendered_line = describe_binary(rendered);
+    let target_line = match fs::read(target) {
+        Ok(contents) if contents == rendered => {
+            return Ok(vec![diff::Result::Both(
+                rendered_line.clone(),
+                rendered_line,
+            )]);
+        }
+        Ok(contents) => Some(describe_binary(&contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("read template target file"),
+    };
+
+    let (old, new) = if source_to_target {
+        (target_line, Some(rendered_line))
     } else {
-        diff::lines(&rendered, &target_contents)
+        (Some(rendered_line), target_line)
     };
+    Ok(old
+        .map(diff::Result::Left)
+        .into_iter()
+        .chain(new.map(diff::Result::Right))
+        .collect())
+}
 
-    Ok(diff_result.into_iter().map(to_owned_diff_result).collect())
+fn render_template(
+    source: &Path,
+    target: &TemplateTarget,
+    handlebars: &Handlebars<'static>,
+    variables: &Variables,
+) -> Result<String> {
+    let file_contents = fs::read_to_string(source).context("read template source file")?;
+    render_target(handlebars, source, target, file_contents, variables)
+}
+
+/// Shows the diff between the target and the rendered template with an external command,
+/// whose output goes straight to the terminal.
+/// Returns true if the command reported differences
+pub fn run_diff_command(
+    command: &str,
+    source: &Path,
+    target: &TemplateTarget,
+    handlebars: &Handlebars<'static>,
+    variables: &Variables,
+) -> Result<bool> {
+    let rendered = render_template(source, target, handlebars, variables)?;
+
+    // Keep the file name, so the tool can tell the file type from the extension
+    let rendered_file = std::env::temp_dir().join(format!(
+        "dotter-{}-{}",
+        std::process::id(),
+        target
+            .target
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default()
+    ));
+    fs::write(&rendered_file, encoding::encode(rendered, target.encoding)?)
+        .context("write rendered template to temporary file")?;
+
+    let mut words = command.split_whitespace();
+    let status = words
+        .next()
+        .context("diff command is empty")
+        .and_then(|program| {
+            std::process::Command::new(program)
+                .args(words)
+                .arg(&target.target)
+                .arg(&rendered_file)
+                .status()
+                .with_context(|| format!("run diff command {:?}", command))
+        });
+    fs::remove_file(&rendered_file).context("remove temporary file")?;
+
+    match status?.code() {
+        Some(0) => Ok(false),
+        Some(1) => Ok(true),
+        Some(code) => anyhow::bail!("diff command exited with code {}", code),
+        None => anyhow::bail!("diff command was terminated by a signal"),
+    }
 }
 
-fn to_owned_diff_result(from: diff::Result<&str>) -> diff::Result<String> {
+fn to_owned_diff_result<S: ToString + ?Sized>(from: diff::Result<&S>) -> diff::Result<String> {
     match from {
         diff::Result::Left(s) => diff::Result::Left(s.to_string()),
         diff::Result::Right(s) => diff::Result::Right(s.to_string()),
@@ -86,7 +562,54 @@ pub fn diff_nonempty(diff: &[diff::Result<String>]) -> bool {
     false
 }
 
-fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
+/// Returns the amount of added and removed lines
+pub fn diff_stats(diff: &[diff::Result<String>]) -> (usize, usize) {
+    diff.iter()
+        .fold((0, 0), |(added, removed), line| match line {
+            diff::Result::Right(_) => (added + 1, removed),
+            diff::Result::Left(_) => (added, removed + 1),
+            diff::Result::Both(..) => (added, removed),
+        })
+}
+
+/// Formats a table of (file, added lines, removed lines), like `git diff --stat`
+pub fn format_diff_stat(stats: &[(PathBuf, usize, usize)]) -> String {
+    let names = stats
+        .iter()
+        .map(|(file, _, _)| file.to_string_lossy())
+        .collect::<Vec<_>>();
+    let name_width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+    let total_added = stats.iter().map(|(_, a, _)| a).sum::<usize>();
+    let total_removed = stats.iter().map(|(_, _, r)| r).sum::<usize>();
+    let added_width = (total_added.to_string().len()) + 1;
+    let removed_width = (total_removed.to_string().len()) + 1;
+
+    let mut table = String::new();
+    for (name, (_, added, removed)) in names.iter().zip(stats) {
+        writeln!(
+            table,
+            " {:<name_width$} | {:>added_width$} {:>removed_width$}",
+            name,
+            format!("+{}", added),
+            format!("-{}", removed),
+        )
+        .unwrap();
+    }
+    writeln!(
+        table,
+        " {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+        stats.len(),
+        if stats.len() == 1 { "" } else { "s" },
+        total_added,
+        if total_added == 1 { "" } else { "s" },
+        total_removed,
+        if total_removed == 1 { "" } else { "s" },
+    )
+    .unwrap();
+    table
+}
+
+pub fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
     let mut hunks = vec![];
 
     let mut left_line_number: usize = 1;
@@ -99,9 +622,9 @@ fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
             diff::Result::Left(_) | diff::Result::Right(_) => {
                 // The central part of a hunk
                 if current_hunk.is_none() {
-                    current_hunk = Some((left_line_number, right_line_number, vec![]));
+                    current_hunk = Some(Hunk::new(left_line_number, right_line_number));
                 }
-                current_hunk.as_mut().unwrap().2.push(line.clone());
+                current_hunk.as_mut().unwrap().lines.push(line.clone());
             }
             diff::Result::Both(_, _) => {
                 if diff[position..=min(position + extra_lines, diff.len() - 1)]
@@ -110,15 +633,15 @@ fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
                 {
                     // There's a hunk soon - but we might already be in a hunk
                     if current_hunk.is_none() {
-                        current_hunk = Some((left_line_number, right_line_number, vec![]));
+                        current_hunk = Some(Hunk::new(left_line_number, right_line_number));
                     }
-                    current_hunk.as_mut().unwrap().2.push(line.clone());
+                    current_hunk.as_mut().unwrap().lines.push(line.clone());
                 } else if diff[position.saturating_sub(extra_lines)..position]
                     .iter()
                     .any(is_different)
                 {
                     // We're just after a hunk
-                    current_hunk.as_mut().unwrap().2.push(line.clone());
+                    current_hunk.as_mut().unwrap().lines.push(line.clone());
                 } else if let Some(hunk) = current_hunk.take() {
                     // We're finished with the current hunk
                     hunks.push(hunk);
@@ -149,59 +672,1108 @@ fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
     hunks
 }
 
+/// `old` with only the hunks of its diff to `new` applied that `accept` accepts, like
+/// `git add -p`. The lines of rejected hunks and everything outside of hunks stay as they are
+pub fn apply_selected_hunks(
+    old: &str,
+    new: &str,
+    algorithm: DiffAlgorithm,
+    extra_lines: usize,
+    mut accept: impl FnMut(&Hunk) -> bool,
+) -> String {
+    let diff = algorithm.diff_lines(old, new);
+    let old_lines = diff
+        .iter()
+        .filter_map(|line| match line {
+            diff::Result::Left(l) | diff::Result::Both(l, _) => Some(l.clone()),
+            diff::Result::Right(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let mut lines = Vec::<&str>::new();
+    let mut old_line = 1;
+
+    let hunks = hunkify_diff(diff, extra_lines);
+    for hunk in &hunks {
+        lines.extend(
+            old_lines[old_line - 1..hunk.old_start - 1]
+                .iter()
+                .map(String::as_str),
+        );
+        old_line = hunk.old_start;
+
+        let accepted = accept(hunk);
+        for line in &hunk.lines {
+            match line {
+                diff::Result::Both(..) => {
+                    lines.push(&old_lines[old_line - 1]);
+                    old_line += 1;
+                }
+                diff::Result::Left(_) => {
+                    if !accepted {
+                        lines.push(&old_lines[old_line - 1]);
+                    }
+                    old_line += 1;
+                }
+                diff::Result::Right(r) => {
+                    if accepted {
+                        lines.push(r);
+                    }
+                }
+            }
+        }
+    }
+    lines.extend(old_lines[old_line - 1..].iter().map(String::as_str));
+
+    // A trailing newline is an empty last line
+    lines.join("\n")
+}
+
+/// Renders a single hunk, like one of `format_diff`
+pub fn format_hunk(hunk: &Hunk, palette: &Palette) -> String {
+    let max_possible_line = max(hunk.old_start, hunk.new_start) + hunk.lines.len();
+    let mut out = String::new();
+    write_hunk(
+        &mut out,
+        hunk.clone(),
+        max_possible_line.to_string().len(),
+        palette,
+    );
+    out
+}
+
 fn is_different(diff: &diff::Result<String>) -> bool {
     !matches!(diff, diff::Result::Both(..))
 }
 
-fn print_hunk(mut left_line: usize, mut right_line: usize, hunk: Diff, max_digits: usize) {
-    for line in hunk {
+/// Styles used when printing diffs
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub added: ContentStyle,
+    pub added_gutter: ContentStyle,
+    pub removed: ContentStyle,
+    pub removed_gutter: ContentStyle,
+    pub context_gutter: ContentStyle,
+    pub header: ContentStyle,
+    /// Mark the changed words of similar removed and added lines with a row of carets,
+    /// for output where colors can't show them
+    pub intra_line_markers: bool,
+}
+
+static COLORS_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Disables colors in diffs printed from now on, for `--no-color`
+pub fn disable_colors() {
+    COLORS_ENABLED.store(false, Ordering::Relaxed);
+}
+
+impl Palette {
+    /// The default palette, or the plain one if colors are disabled
+    pub fn current() -> Palette {
+        if COLORS_ENABLED.load(Ordering::Relaxed) {
+            Palette::default()
+        } else {
+            Palette::plain()
+        }
+    }
+
+    /// A palette without any styling
+    pub fn plain() -> Palette {
+        Palette {
+            added: ContentStyle::new(),
+            added_gutter: ContentStyle::new(),
+            removed: ContentStyle::new(),
+            removed_gutter: ContentStyle::new(),
+            context_gutter: ContentStyle::new(),
+            header: ContentStyle::new(),
+            intra_line_markers: true,
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            added: ContentStyle::new().green(),
+            added_gutter: ContentStyle::new().green().dim(),
+            removed: ContentStyle::new().red(),
+            removed_gutter: ContentStyle::new().red().dim(),
+            context_gutter: ContentStyle::new().dark_grey(),
+            header: ContentStyle::new().bold(),
+            intra_line_markers: false,
+        }
+    }
+}
+
+/// Splits a line into words, runs of whitespace and single punctuation characters
+fn words(line: &str) -> Vec<&str> {
+    fn class(c: char) -> u8 {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    }
+
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let continues = match chars.peek() {
+            Some((_, next)) => class(c) != 2 && class(*next) == class(c),
+            None => false,
+        };
+        if !continues {
+            let end = chars.peek().map_or(line.len(), |(index, _)| *index);
+            words.push(&line[start..end]);
+            start = end;
+        }
+    }
+    words
+}
+
+/// Appends characters that line up with `word`: carets if it changed, otherwise
+/// whitespace (keeping tabs, so the carets stay aligned after them)
+fn push_marker(marker: &mut String, word: &str, changed: bool) {
+    for c in word.chars() {
+        marker.push(match c {
+            _ if changed => '^',
+            '\t' => '\t',
+            _ => ' ',
+        });
+    }
+}
+
+/// Computes caret rows under the words that differ between a removed and an added line.
+/// Returns None if the lines have less than half of their characters in common,
+/// since marking nearly everything wouldn't help.
+fn changed_words(old: &str, new: &str) -> Option<(String, String)> {
+    let old_words = words(old);
+    let new_words = words(new);
+
+    let mut old_marker = String::new();
+    let mut new_marker = String::new();
+    let mut common = 0;
+    for word in diff::slice(&old_words, &new_words) {
+        match word {
+            diff::Result::Left(w) => push_marker(&mut old_marker, w, true),
+            diff::Result::Right(w) => push_marker(&mut new_marker, w, true),
+            diff::Result::Both(w, _) => {
+                common += w.chars().count();
+                push_marker(&mut old_marker, w, false);
+                push_marker(&mut new_marker, w, false);
+            }
+        }
+    }
+
+    let total = old.chars().count() + new.chars().count();
+    if common * 4 < total {
+        return None;
+    }
+    Some((
+        old_marker.trim_end().to_string(),
+        new_marker.trim_end().to_string(),
+    ))
+}
+
+/// Pairs up the removed and added lines of each change in the hunk and returns the
+/// caret row to print under each line, if any
+fn intra_line_markers(lines: &[diff::Result<String>]) -> Vec<Option<String>> {
+    let mut markers = vec![None; lines.len()];
+    let mut index = 0;
+    while index < lines.len() {
+        let removed_start = index;
+        while matches!(lines.get(index), Some(diff::Result::Left(_))) {
+            index += 1;
+        }
+        let added_start = index;
+        while matches!(lines.get(index), Some(diff::Result::Right(_))) {
+            index += 1;
+        }
+        if index == removed_start {
+            index += 1;
+            continue;
+        }
+
+        let pairs = min(added_start - removed_start, index - added_start);
+        for offset in 0..pairs {
+            let (old, new) = (removed_start + offset, added_start + offset);
+            if let (diff::Result::Left(old_line), diff::Result::Right(new_line)) =
+                (&lines[old], &lines[new])
+            {
+                if let Some((old_marker, new_marker)) = changed_words(old_line, new_line) {
+                    markers[old] = Some(old_marker);
+                    markers[new] = Some(new_marker);
+                }
+            }
+        }
+    }
+    markers
+}
+
+fn write_hunk(out: &mut String, hunk: Hunk, max_digits: usize, palette: &Palette) {
+    let markers = if palette.intra_line_markers {
+        intra_line_markers(&hunk.lines)
+    } else {
+        vec![None; hunk.lines.len()]
+    };
+
+    let mut left_line = hunk.old_start;
+    let mut right_line = hunk.new_start;
+    for (line, marker) in hunk.lines.into_iter().zip(markers) {
         match line {
             diff::Result::Left(l) => {
-                println!(
+                writeln!(
+                    out,
                     " {:>width$} | {:>width$} | {}",
-                    left_line.to_string().red(),
+                    palette.removed_gutter.apply(left_line),
                     "",
-                    l.red(),
+                    palette.removed.apply(l),
                     width = max_digits
-                );
+                )
+                .unwrap();
                 left_line += 1;
             }
             diff::Result::Both(l, _) => {
-                println!(
+                writeln!(
+                    out,
                     " {:>width$} | {:>width$} | {}",
-                    left_line.to_string().dark_grey(),
-                    right_line.to_string().dark_grey(),
+                    palette.context_gutter.apply(left_line),
+                    palette.context_gutter.apply(right_line),
                     l,
                     width = max_digits
-                );
+                )
+                .unwrap();
                 left_line += 1;
                 right_line += 1;
             }
             diff::Result::Right(r) => {
-                println!(
+                writeln!(
+                    out,
                     " {:>width$} | {:>width$} | {}",
                     "",
-                    right_line.to_string().green(),
-                    r.green(),
+                    palette.added_gutter.apply(right_line),
+                    palette.added.apply(r),
                     width = max_digits
-                );
+                )
+                .unwrap();
                 right_line += 1;
             }
         }
+        if let Some(marker) = marker {
+            writeln!(
+                out,
+                " {:>width$} | {:>width$} | {}",
+                "",
+                "",
+                marker,
+                width = max_digits
+            )
+            .unwrap();
+        }
     }
 }
 
-pub fn print_diff(diff: Diff, extra_lines: usize) {
+/// Appends changed text wrapped in `open` and `close`, if the palette marks changes.
+/// Each line is wrapped separately, so that markers don't span line breaks
+fn push_changed_words(
+    out: &mut String,
+    text: &str,
+    style: ContentStyle,
+    (open, close): (&str, &str),
+    palette: &Palette,
+) {
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            out.push('\n');
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if palette.intra_line_markers {
+            write!(out, "{}", style.apply(format!("{}{}{}", open, line, close))).unwrap();
+        } else {
+            write!(out, "{}", style.apply(line)).unwrap();
+        }
+    }
+}
+
+/// Writes a run of removed and added lines as one text, with the changed words marked
+fn write_word_change(out: &mut String, removed: &[String], added: &[String], palette: &Palette) {
+    let join = |lines: &[String]| lines.iter().map(|line| format!("{}\n", line)).collect();
+    let (old, new): (String, String) = (join(removed), join(added));
+    let (old_words, new_words) = (words(&old), words(&new));
+
+    let mut removed_words = String::new();
+    let mut added_words = String::new();
+    let flush = |out: &mut String, removed_words: &mut String, added_words: &mut String| {
+        push_changed_words(out, removed_words, palette.removed, ("[-", "-]"), palette);
+        push_changed_words(out, added_words, palette.added, ("{+", "+}"), palette);
+        removed_words.clear();
+        added_words.clear();
+    };
+    for word in diff::slice(&old_words, &new_words) {
+        match word {
+            diff::Result::Left(w) => removed_words.push_str(w),
+            diff::Result::Right(w) => added_words.push_str(w),
+            diff::Result::Both(w, _) => {
+                flush(out, &mut removed_words, &mut added_words);
+                out.push_str(w);
+            }
+        }
+    }
+    flush(out, &mut removed_words, &mut added_words);
+}
+
+fn write_word_hunk(out: &mut String, hunk: Hunk, palette: &Palette) {
+    let mut lines = hunk.lines.into_iter().peekable();
+    while let Some(line) = lines.next() {
+        let (mut removed, mut added) = (Vec::new(), Vec::new());
+        match line {
+            diff::Result::Both(l, _) => {
+                writeln!(out, "{}", l).unwrap();
+                continue;
+            }
+            diff::Result::Left(l) => removed.push(l),
+            diff::Result::Right(r) => added.push(r),
+        }
+        while let Some(diff::Result::Left(_)) = lines.peek() {
+            if let Some(diff::Result::Left(l)) = lines.next() {
+                removed.push(l);
+            }
+        }
+        while let Some(diff::Result::Right(_)) = lines.peek() {
+            if let Some(diff::Result::Right(r)) = lines.next() {
+                added.push(r);
+            }
+        }
+        write_word_change(out, &removed, &added, palette);
+    }
+}
+
+/// Renders the hunks of a diff as running text with the changed words marked, like
+/// `git diff --word-diff`. Hunks are separated by empty lines
+pub fn format_word_diff(diff: Diff, extra_lines: usize, palette: &Palette) -> String {
+    let mut out = String::new();
+    for (index, hunk) in hunkify_diff(diff, extra_lines).into_iter().enumerate() {
+        if index > 0 {
+            out.push('\n');
+        }
+        write_word_hunk(&mut out, hunk, palette);
+    }
+    out
+}
+
+/// Renders the hunks of a diff, separated by empty lines
+pub fn format_diff(diff: Diff, extra_lines: usize, palette: &Palette) -> String {
     let mut diff = hunkify_diff(diff, extra_lines);
 
     let last_hunk = diff.pop().expect("at least one hunk");
-    let max_possible_line = max(last_hunk.0, last_hunk.1) + last_hunk.2.len();
+    let max_possible_line = max(last_hunk.old_start, last_hunk.new_start) + last_hunk.lines.len();
     let max_possible_digits = max_possible_line.to_string().len(); // yes I could log10, whatever
 
+    let mut out = String::new();
     for hunk in diff {
-        print_hunk(hunk.0, hunk.1, hunk.2, max_possible_digits);
-        println!();
+        write_hunk(&mut out, hunk, max_possible_digits, palette);
+        out.push('\n');
     }
 
-    print_hunk(last_hunk.0, last_hunk.1, last_hunk.2, max_possible_digits);
+    write_hunk(&mut out, last_hunk, max_possible_digits, palette);
+    out
+}
+
+pub fn print_diff(diff: Diff, extra_lines: usize) {
+    let diff = format_diff(diff, extra_lines, &Palette::current());
+    progress::suspend(|| print!("{}", diff));
+}
+
+/// The differences of one target file
+pub struct FileDiff {
+    pub target: PathBuf,
+    pub target_exists: bool,
+    pub diff: Diff,
+}
+
+/// A diff that turns `old` - or a missing file if it's None - into `new`, in the format of
+/// `git diff` so that `git apply` can apply it from the root directory. A missing file is
+/// created executable if `executable` is set. `target` has to be absolute.
+/// None if nothing changes
+pub fn to_unified_diff(
+    target: &Path,
+    old: Option<&str>,
+    new: &str,
+    executable: bool,
+    algorithm: DiffAlgorithm,
+    extra_lines: usize,
+) -> Option<String> {
+    // Lines keep their newline, so that a missing newline at the end is a change as well
+    let old_lines = old
+        .unwrap_or_default()
+        .split_inclusive('\n')
+        .collect::<Vec<_>>();
+    let new_lines = new.split_inclusive('\n').collect::<Vec<_>>();
+    let diff = algorithm.diff_slices(&old_lines, &new_lines);
+    if old.is_some() && !diff_nonempty(&diff) {
+        return None;
+    }
+
+    let path = patch_path(target);
+    let mut out = format!("diff --git a/{0} b/{0}\n", path);
+    match old {
+        Some(_) => writeln!(out, "--- a/{}", path).unwrap(),
+        None => writeln!(
+            out,
+            "new file mode {}\n--- /dev/null",
+            if executable { "100755" } else { "100644" }
+        )
+        .unwrap(),
+    }
+    writeln!(out, "+++ b/{}", path).unwrap();
+
+    for hunk in hunkify_diff(diff, extra_lines) {
+        let old_count = hunk
+            .lines
+            .iter()
+            .filter(|line| !matches!(line, diff::Result::Right(_)))
+            .count();
+        let new_count = hunk
+            .lines
+            .iter()
+            .filter(|line| !matches!(line, diff::Result::Left(_)))
+            .count();
+        // An empty range starts before the line it would be inserted at
+        let range = |start: usize, count| match count {
+            0 => format!("{},0", start - 1),
+            _ => format!("{},{}", start, count),
+        };
+        writeln!(
+            out,
+            "@@ -{} +{} @@",
+            range(hunk.old_start, old_count),
+            range(hunk.new_start, new_count)
+        )
+        .unwrap();
+
+        for line in hunk.lines {
+            let (prefix, line) = match line {
+                diff::Result::Left(line) => ('-', line),
+                diff::Result::Both(line, _) => (' ', line),
+                diff::Result::Right(line) => ('+', line),
+            };
+            out.push(prefix);
+            out.push_str(&line);
+            if !line.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    Some(out)
+}
+
+/// The absolute target's path in a patch: relative to the root directory, with `/` as
+/// separator and without `.` or `..`, which `git apply` rejects
+fn patch_path(target: &Path) -> String {
+    let mut components = Vec::new();
+    for component in target.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                components.pop();
+            }
+            Component::Normal(name) => components.push(name.to_string_lossy()),
+        }
+    }
+    components.join("/")
+}
+
+/// The line naming the target above its diff
+pub fn format_file_header(target: &Path, target_exists: bool, palette: &Palette) -> String {
+    format_header(
+        target,
+        if target_exists { "changed" } else { "new" },
+        palette,
+    )
+}
+
+/// The line naming a target that already is what a deploy would make it, but that the cache
+/// doesn't know about, so a deploy would still rewrite it
+pub fn format_unmanaged_header(target: &Path, palette: &Palette) -> String {
+    format_header(
+        target,
+        "in sync but not in the cache, run `dotter sync-cache`",
+        palette,
+    )
+}
+
+/// The line naming a target that isn't the kind of file a deploy would make it
+pub fn format_type_change_header(target: &Path, change: &TypeChange, palette: &Palette) -> String {
+    format_header(target, &change.to_string(), palette)
+}
+
+fn format_header(target: &Path, label: &str, palette: &Palette) -> String {
+    let header = format!("━━━ {} ({}) ━━━", target.display(), label);
+    format!("{}\n", palette.header.apply(header))
+}
+
+/// Renders the diffs of several files, each under a header naming the target,
+/// separated by empty lines. With `word_diff`, changes are shown inline
+pub fn format_file_diffs(
+    files: Vec<FileDiff>,
+    extra_lines: usize,
+    word_diff: bool,
+    palette: &Palette,
+) -> String {
+    let format = if word_diff {
+        format_word_diff
+    } else {
+        format_diff
+    };
+    let mut out = String::new();
+    for (index, file) in files.into_iter().enumerate() {
+        if index > 0 {
+            out.push('\n');
+        }
+        out.push_str(&format_file_header(
+            &file.target,
+            file.target_exists,
+            palette,
+        ));
+        if diff_nonempty(&file.diff) {
+            out.push_str(&format(file.diff, extra_lines, palette));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn owned_lines(left: &str, right: &str) -> Diff {
+        diff::lines(left, right)
+            .into_iter()
+            .map(to_owned_diff_result)
+            .collect()
+    }
+
+    #[test]
+    fn diff_stat_table() {
+        let changed = owned_lines("a\nb\nc\n", "a\nB\nc\nd\n");
+        let created = vec![
+            diff::Result::Right("one".into()),
+            diff::Result::Right("two".into()),
+        ];
+        assert_eq!(diff_stats(&changed), (2, 1));
+        assert_eq!(diff_stats(&created), (2, 0));
+
+        let table = format_diff_stat(&[
+            (PathBuf::from("/home/user/.bashrc"), 2, 1),
+            (PathBuf::from("/home/user/.config/app/config.toml"), 12, 0),
+        ]);
+        assert_eq!(
+            table,
+            " /home/user/.bashrc                 |  +2 -1
+ /home/user/.config/app/config.toml | +12 -0
+ 2 files changed, 14 insertions(+), 1 deletion(-)
+"
+        );
+    }
+
+    #[test]
+    fn hunks() {
+        let diff = owned_lines(
+            "1\n2\n3\n4\n5\n6\n7\n8\n",
+            "1\nTWO\n3\n4\n5\n6\n7\n8\nnine\n",
+        );
+        let hunks = hunkify_diff(diff, 1);
+
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].old_start, hunks[0].new_start), (1, 1));
+        assert_eq!(
+            hunks[0].lines,
+            vec![
+                diff::Result::Both("1".into(), "1".into()),
+                diff::Result::Left("2".into()),
+                diff::Result::Right("TWO".into()),
+                diff::Result::Both("3".into(), "3".into()),
+            ]
+        );
+        assert_eq!((hunks[1].old_start, hunks[1].new_start), (8, 8));
+        assert_eq!(
+            hunks[1],
+            Hunk::from((
+                8,
+                8,
+                vec![
+                    diff::Result::Both("8".into(), "8".into()),
+                    diff::Result::Right("nine".into()),
+                    diff::Result::Both("".into(), "".into()),
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn large_files_are_not_diffed() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "line\n".repeat(100_000)).unwrap();
+
+        let mut target: TemplateTarget = dir.path().join("target").into();
+        target.max_diff_size = Some(1024);
+
+        let handlebars = Handlebars::new();
+        let error = format_template_update(
+            &source,
+            &target,
+            &handlebars,
+            &Variables::new(),
+            3,
+            &Palette::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "file too large to diff (0.5 MB)");
+
+        // The limit is only for showing diffs, like when a deploy checks the target
+        let diff =
+            generate_template_diff(&source, &target, &handlebars, &Variables::new(), true).unwrap();
+        assert_eq!(diff_stats(&diff), (100_000, 0));
+
+        target.max_diff_size = None;
+        assert!(format_template_update(
+            &source,
+            &target,
+            &handlebars,
+            &Variables::new(),
+            3,
+            &Palette::default(),
+        )
+        .unwrap()
+        .is_some());
+    }
+
+    #[test]
+    fn gutter_is_styled_apart_from_content() {
+        let diff = owned_lines("same\nold\n", "same\nnew\n");
+        let output = format_diff(diff, 3, &Palette::default());
+
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                " \u{1b}[38;5;8m1\u{1b}[39m | \u{1b}[38;5;8m1\u{1b}[39m | same",
+                " \u{1b}[38;5;9m\u{1b}[2m2\u{1b}[0m |   | \u{1b}[38;5;9mold\u{1b}[39m",
+                "   | \u{1b}[38;5;10m\u{1b}[2m2\u{1b}[0m | \u{1b}[38;5;10mnew\u{1b}[39m",
+                " \u{1b}[38;5;8m3\u{1b}[39m | \u{1b}[38;5;8m3\u{1b}[39m | ",
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_file_diffs() {
+        let files = vec![
+            FileDiff {
+                target: "/home/user/.bashrc".into(),
+                target_exists: true,
+                diff: owned_lines("a\nb\n", "a\nc\n"),
+            },
+            FileDiff {
+                target: "/home/user/.vimrc".into(),
+                target_exists: false,
+                diff: vec![diff::Result::Right("set number".into())],
+            },
+        ];
+
+        assert_eq!(
+            format_file_diffs(files, 3, false, &Palette::plain()),
+            "━━━ /home/user/.bashrc (changed) ━━━
+ 1 | 1 | a
+ 2 |   | b
+   | 2 | c
+ 3 | 3 | \n\n━━━ /home/user/.vimrc (new) ━━━
+   | 1 | set number
+"
+        );
+    }
+
+    #[test]
+    fn template_update_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target: TemplateTarget = dir.path().join("target").into();
+        fs::write(&source, "theme = {{theme}}\nsize = 12\n").unwrap();
+        fs::write(&target.target, "theme = light\nsize = 12\n").unwrap();
+
+        let mut variables = Variables::new();
+        variables.insert("theme".into(), "dark".into());
+        let handlebars = Handlebars::new();
+        let output = format_template_update(
+            &source,
+            &target,
+            &handlebars,
+            &variables,
+            3,
+            &Palette::plain(),
+        )
+        .unwrap();
+        assert_eq!(
+            output.unwrap(),
+            format!(
+                "━━━ {} (changed) ━━━
+ 1 |   | theme = light
+   |   |         ^^^^^
+   | 1 | theme = dark
+   |   |         ^^^^
+ 2 | 2 | size = 12
+ 3 | 3 | \n",
+                target.target.display()
+            )
+        );
+
+        fs::write(&target.target, "theme = dark\nsize = 12\n").unwrap();
+        let output = format_template_update(
+            &source,
+            &target,
+            &handlebars,
+            &variables,
+            3,
+            &Palette::plain(),
+        )
+        .unwrap();
+        assert_eq!(output, None);
+    }
+
+    #[test]
+    fn intra_line_markers_align_with_changes() {
+        let diff = owned_lines(
+            "name = \"dotter\"\n\tcolor = blue # theme\nunrelated\n",
+            "name = \"dotter\"\n\tcolor = green # theme\ncompletely different\n",
+        );
+        let output = format_diff(diff, 0, &Palette::plain());
+
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec![
+                " 2 |   | \tcolor = blue # theme",
+                "   |   | \t        ^^^^",
+                " 3 |   | unrelated",
+                "   | 2 | \tcolor = green # theme",
+                "   |   | \t        ^^^^^",
+                "   | 3 | completely different",
+            ]
+        );
+
+        // The carets are under the changed characters
+        let removed = "\tcolor = blue # theme";
+        let marker = "\t        ^^^^";
+        assert_eq!(&removed[marker.find('^').unwrap()..marker.len()], "blue");
+
+        // Colored output shows the changes with colors instead
+        let diff = owned_lines("color = blue\n", "color = green\n");
+        assert!(!format_diff(diff, 0, &Palette::default()).contains('^'));
+    }
+
+    #[test]
+    fn word_diff() {
+        let diff = owned_lines(
+            "The quick brown fox\njumps over\nthe lazy dog.\n",
+            "The quick red fox\njumps over\nthe sleepy dog!\nFin\n",
+        );
+        assert_eq!(
+            format_word_diff(diff, 1, &Palette::plain()),
+            "The quick [-brown-]{+red+} fox\n\
+             jumps over\n\
+             the [-lazy-]{+sleepy+} dog[-.-]{+!+}\n\
+             {+Fin+}\n\
+             \n"
+        );
+
+        // Colored output shows the changes with colors instead
+        let diff = owned_lines("color = blue\n", "color = green\n");
+        let output = format_word_diff(diff, 0, &Palette::default());
+        assert!(!output.contains("[-") && !output.contains("{+"));
+        assert!(output.contains(&Palette::default().added.apply("green").to_string()));
+    }
+
+    #[test]
+    fn ignored_lines_dont_form_hunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("config");
+        let mut target = TemplateTarget::from(dir.path().join("target"));
+        std::fs::write(&source, "# Generated at {{now}}\nkey = value\n").unwrap();
+        std::fs::write(
+            &target.target,
+            "# Generated at 2024-01-01 12:00\nkey = value\n",
+        )
+        .unwrap();
+        let mut variables = Variables::new();
+        variables.insert("now".into(), "2024-06-30 08:15".into());
+
+        let diff = |target: &TemplateTarget| {
+            generate_template_diff(&source, target, &Handlebars::new(), &variables, true).unwrap()
+        };
+        assert!(diff_nonempty(&diff(&target)));
+
+        target.diff_ignore_lines = vec!["^# Generated at .*$".into()];
+        let ignored = diff(&target);
+        assert!(!diff_nonempty(&ignored));
+        assert_eq!(
+            ignored[0],
+            diff::Result::Both(
+                "# Generated at 2024-01-01 12:00".into(),
+                "# Generated at 2024-06-30 08:15".into()
+            )
+        );
+
+        // Other changes are still found
+        std::fs::write(&source, "# Generated at {{now}}\nkey = other\n").unwrap();
+        assert!(diff_nonempty(&diff(&target)));
+
+        // Matching lines without a counterpart keep the line numbers of their side
+        let line = |side: &str, text: &str| match side {
+            "-" => diff::Result::Left(text.to_string()),
+            "+" => diff::Result::Right(text.to_string()),
+            _ => diff::Result::Both(text.to_string(), text.to_string()),
+        };
+        let patterns = ["^# at".to_string()];
+        assert_eq!(
+            ignore_lines(
+                vec![
+                    line("-", "# at 1"),
+                    line("-", "x"),
+                    line("+", "# at 2"),
+                    line("+", "y"),
+                    line(" ", "z"),
+                    line("-", "# at 3"),
+                ],
+                &patterns
+            )
+            .unwrap(),
+            vec![
+                diff::Result::Both("# at 1".into(), "# at 2".into()),
+                line("-", "x"),
+                line("+", "y"),
+                line(" ", "z"),
+                line(" ", "# at 3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_level_variable_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("colors");
+        std::fs::write(&source, "theme={{theme}} font={{font}}\n").unwrap();
+        let files: std::collections::BTreeMap<String, TemplateTarget> = toml::from_str(&format!(
+            "editor = {{ target = {:?}, variables = {{ theme = \"light\" }} }}\n\
+             terminal = {{ target = {:?} }}\n",
+            dir.path().join("editor"),
+            dir.path().join("terminal")
+        ))
+        .unwrap();
+
+        let mut variables = Variables::new();
+        variables.insert("theme".into(), "dark".into());
+        variables.insert("font".into(), "mono".into());
+        let expected = [
+            ("editor", "theme=light font=mono\n"),
+            ("terminal", "theme=dark font=mono\n"),
+        ];
+        for (name, contents) in expected {
+            let target = &files[name];
+            std::fs::write(&target.target, contents).unwrap();
+            let diff =
+                generate_template_diff(&source, target, &Handlebars::new(), &variables, true)
+                    .unwrap();
+            assert!(!diff_nonempty(&diff), "{} was rendered differently", name);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn external_diff_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target: TemplateTarget = dir.path().join("target.json").into();
+        let stub = dir.path().join("stub.sh");
+        let seen = dir.path().join("seen");
+        std::fs::write(&source, "{\"theme\": \"{{theme}}\"}\n").unwrap();
+        std::fs::write(&target.target, "{\"theme\": \"light\"}\n").unwrap();
+        // Records the paths and contents it was called with, and fails like `cmp` on changes
+        std::fs::write(
+            &stub,
+            format!(
+                "echo \"$1 $(basename \"$2\")\" > {seen:?}\ncat \"$2\" >> {seen:?}\ncmp -s \"$1\" \"$2\"\n",
+                seen = seen
+            ),
+        )
+        .unwrap();
+        let command = format!("sh {}", stub.display());
+
+        let handlebars = Handlebars::new();
+        let mut variables = Variables::new();
+        variables.insert("theme".into(), "light".into());
+        assert!(!run_diff_command(&command, &source, &target, &handlebars, &variables).unwrap());
+
+        variables.insert("theme".into(), "dark".into());
+        assert!(run_diff_command(&command, &source, &target, &handlebars, &variables).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&seen).unwrap(),
+            format!(
+                "{} dotter-{}-target.json\n{{\"theme\": \"dark\"}}\n",
+                target.target.display(),
+                std::process::id()
+            )
+        );
+
+        // Other exit codes are errors, like for `diff`
+        let broken = dir.path().join("broken.sh");
+        std::fs::write(&broken, "exit 2\n").unwrap();
+        let command = format!("sh {}", broken.display());
+        let error =
+            run_diff_command(&command, &source, &target, &handlebars, &variables).unwrap_err();
+        assert_eq!(error.to_string(), "diff command exited with code 2");
+    }
+
+    #[test]
+    fn encoded_templates_diff_as_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let mut target: TemplateTarget = dir.path().join("target").into();
+        target.encoding = Some(crate::encoding::Encoding::Base64);
+        std::fs::write(&source, "AP8=").unwrap();
+        let handlebars = Handlebars::new();
+
+        let diff =
+            generate_template_diff(&source, &target, &handlebars, &Variables::new(), true).unwrap();
+        assert_eq!(
+            diff,
+            vec![diff::Result::Right(
+                "binary data, 2 bytes, sha256 06eb7d6a69ee19e5".into()
+            )]
+        );
+
+        std::fs::write(&target.target, [0x00, 0xff]).unwrap();
+        let diff =
+            generate_template_diff(&source, &target, &handlebars, &Variables::new(), true).unwrap();
+        assert!(!diff_nonempty(&diff));
+
+        std::fs::write(&target.target, [0x00]).unwrap();
+        let diff =
+            generate_template_diff(&source, &target, &handlebars, &Variables::new(), true).unwrap();
+        assert_eq!(diff_stats(&diff), (1, 1));
+    }
+
+    #[test]
+    fn latin1_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let mut target: TemplateTarget = dir.path().join("target").into();
+        target.encoding = Some(crate::encoding::Encoding::Latin1);
+        std::fs::write(&source, "name = {{name}}\n").unwrap();
+        let handlebars = Handlebars::new();
+        let mut variables = Variables::new();
+        variables.insert("name".into(), "Jos\u{e9}".into());
+
+        crate::actions::perform_template_deploy(
+            &source,
+            &dir.path().join("cache/source"),
+            &target,
+            &mut crate::filesystem::RealFilesystem::new(true, false),
+            &handlebars,
+            &variables,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read(&target.target).unwrap(),
+            b"name = Jos\xe9\n".to_vec()
+        );
+
+        // The target is diffed as text
+        let diff = generate_template_diff(&source, &target, &handlebars, &variables, true).unwrap();
+        assert!(!diff_nonempty(&diff));
+        variables.insert("name".into(), "Ren\u{e9}e".into());
+        let diff = generate_template_diff(&source, &target, &handlebars, &variables, true).unwrap();
+        assert_eq!(
+            diff,
+            vec![
+                diff::Result::Left("name = Jos\u{e9}".into()),
+                diff::Result::Right("name = Ren\u{e9}e".into()),
+                diff::Result::Both("".into(), "".into()),
+            ]
+        );
+
+        variables.insert("name".into(), "\u{17d}aneta".into());
+        let error =
+            generate_template_diff(&source, &target, &handlebars, &variables, true).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "character '\u{17d}' at byte 7 can't be represented in Latin-1"
+        );
+    }
+
+    #[test]
+    fn diff_algorithms() {
+        let old = "fn d()\n{\n    return;\n}\n\nfn c()\n{\n    y += 1;\n}\n";
+        let new = "fn c()\n{\n    y += 1;\n}\n\nfn d()\n{\n    return;\n}\n";
+        let changes = |algorithm: DiffAlgorithm| {
+            hunkify_diff(algorithm.diff_lines(old, new), 0)
+                .into_iter()
+                .map(|hunk| hunk.lines)
+                .collect::<Vec<_>>()
+        };
+
+        // Matching the braces and empty lines splits the moved function into 4 hunks
+        assert_eq!(DiffAlgorithm::default(), DiffAlgorithm::Lcs);
+        assert_eq!(changes(DiffAlgorithm::Lcs).len(), 4);
+        assert_eq!(changes(DiffAlgorithm::Myers).len(), 4);
+
+        // Patience keeps it together
+        let moved = ["fn c()", "{", "    y += 1;", "}", ""];
+        assert_eq!(
+            changes(DiffAlgorithm::Patience),
+            vec![
+                moved
+                    .iter()
+                    .map(|l| diff::Result::Right(l.to_string()))
+                    .collect::<Vec<_>>(),
+                moved
+                    .iter()
+                    .map(|l| diff::Result::Left(l.to_string()))
+                    .collect::<Vec<_>>(),
+            ]
+        );
+
+        // Every algorithm describes the same change
+        for algorithm in [
+            DiffAlgorithm::Lcs,
+            DiffAlgorithm::Myers,
+            DiffAlgorithm::Patience,
+        ] {
+            let diff = algorithm.diff_lines(old, new);
+            let side = |right: bool| {
+                diff.iter()
+                    .filter_map(|line| match line {
+                        diff::Result::Both(l, _) => Some(l.as_str()),
+                        diff::Result::Left(l) if !right => Some(l.as_str()),
+                        diff::Result::Right(l) if right => Some(l.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            assert_eq!(side(false), old, "{:?}", algorithm);
+            assert_eq!(side(true), new, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn patch_paths_are_relative_to_the_root() {
+        let root = std::path::absolute("/").unwrap();
+        assert_eq!(
+            patch_path(&root.join("home/./user/../other/.bashrc")),
+            "home/other/.bashrc"
+        );
+    }
 }
@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+
+/// How a rendered template is turned into its target's contents: binary data that's kept as
+/// text in the source, or a text encoding other than UTF-8
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Standard base64, with or without padding. Whitespace is ignored
+    Base64,
+    /// ISO-8859-1, for tools that don't understand UTF-8
+    Latin1,
+    /// UTF-8, the same as no encoding
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// UTF-16 in little-endian byte order, without a byte order mark
+    #[serde(rename = "utf-16le")]
+    Utf16Le,
+}
+
+impl Encoding {
+    /// Whether targets in this encoding are text, which can be diffed line by line
+    pub fn is_text(self) -> bool {
+        self != Encoding::Base64
+    }
+}
+
+/// Turns a rendered template into the contents of its target
+pub fn encode(rendered: String, encoding: Option<Encoding>) -> Result<Vec<u8>> {
+    match encoding {
+        Some(Encoding::Base64) => decode_base64(&rendered).context("decode base64"),
+        Some(Encoding::Latin1) => encode_latin1(&rendered),
+        Some(Encoding::Utf16Le) => Ok(rendered.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+        Some(Encoding::Utf8) | None => Ok(rendered.into_bytes()),
+    }
+}
+
+/// Turns the contents of a target in a text encoding back into text
+pub fn read_text(contents: Vec<u8>, encoding: Option<Encoding>) -> Result<String> {
+    match encoding {
+        Some(Encoding::Base64) => anyhow::bail!("base64 encoded targets aren't text"),
+        Some(Encoding::Latin1) => Ok(contents.into_iter().map(char::from).collect()),
+        Some(Encoding::Utf16Le) => {
+            anyhow::ensure!(
+                contents.len().is_multiple_of(2),
+                "UTF-16 data has an odd number of bytes"
+            );
+            let units = contents
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+            char::decode_utf16(units)
+                .collect::<Result<_, _>>()
+                .context("decode UTF-16")
+        }
+        Some(Encoding::Utf8) | None => String::from_utf8(contents).context("decode UTF-8"),
+    }
+}
+
+/// Encodes text as ISO-8859-1, which only has the first 256 code points
+fn encode_latin1(text: &str) -> Result<Vec<u8>> {
+    text.char_indices()
+        .map(|(position, c)| {
+            u8::try_from(c).map_err(|_| {
+                anyhow::anyhow!(
+                    "character {:?} at byte {} can't be represented in Latin-1",
+                    c,
+                    position
+                )
+            })
+        })
+        .collect()
+}
+
+/// Decodes standard base64 (`A-Z`, `a-z`, `0-9`, `+`, `/`), ignoring whitespace
+pub fn decode_base64(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    let mut padding = 0;
+
+    for (position, c) in text.char_indices() {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            '=' => {
+                padding += 1;
+                continue;
+            }
+            _ if c.is_whitespace() => continue,
+            _ => anyhow::bail!("invalid base64 character {:?} at byte {}", c, position),
+        };
+        anyhow::ensure!(padding == 0, "base64 data continues after padding");
+
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    // Left over bits are only allowed if they're the zero filler of the last byte
+    anyhow::ensure!(
+        bits < 6 && buffer == 0 && padding <= 2,
+        "truncated base64 data"
+    );
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm8").unwrap(), b"fo");
+        assert_eq!(decode_base64("Zm9v\nYmFy\n").unwrap(), b"foobar");
+        assert_eq!(
+            decode_base64("AP+AfwE=").unwrap(),
+            vec![0x00, 0xff, 0x80, 0x7f, 0x01]
+        );
+
+        assert!(decode_base64("Zm9v!").is_err());
+        assert!(decode_base64("Z").is_err());
+        assert!(decode_base64("Zg==Zg").is_err());
+    }
+
+    #[test]
+    fn text_encodings() {
+        let text = "caf\u{e9} \u{1f600}".to_string();
+        let utf16 = encode(text.clone(), Some(Encoding::Utf16Le)).unwrap();
+        assert_eq!(&utf16[..8], b"c\0a\0f\0\xe9\0");
+        assert_eq!(read_text(utf16, Some(Encoding::Utf16Le)).unwrap(), text);
+
+        let latin1 = encode("caf\u{e9}".into(), Some(Encoding::Latin1)).unwrap();
+        assert_eq!(latin1, b"caf\xe9");
+        assert_eq!(
+            read_text(latin1, Some(Encoding::Latin1)).unwrap(),
+            "caf\u{e9}"
+        );
+
+        let error = encode(text, Some(Encoding::Latin1)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "character '\u{1f600}' at byte 6 can't be represented in Latin-1"
+        );
+    }
+}
@@ -3,14 +3,15 @@ use anyhow::{Context, Result};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
 use std::io::{self, ErrorKind, Read};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 #[cfg(unix)]
 use std::process::Command;
 
-use crate::config::UnixUser;
+use crate::config::{FileMode, UnixUser};
+use crate::progress;
 
 // === Serialize/deserialize files ===
 
@@ -27,10 +28,44 @@ where
     }
     .context("open file")?;
     f.read_to_string(&mut buf).context("read file")?;
-    let data = toml::from_str::<T>(&buf).context("deserialize file contents")?;
+    let data = parse_toml::<T>(&buf).context("deserialize file contents")?;
     Ok(Some(data))
 }
 
+/// Parses TOML like `toml::from_str`, but errors at a known position also show the column and
+/// the line they're on
+pub fn parse_toml<T: DeserializeOwned>(contents: &str) -> Result<T> {
+    toml::from_str(contents).map_err(|e| {
+        let position = e
+            .line_col()
+            .and_then(|(line, column)| error_excerpt(contents, line, column));
+        let error = anyhow::Error::new(e);
+        match position {
+            Some(position) => error.context(position),
+            None => error,
+        }
+    })
+}
+
+/// Like `at line 3, column 8:` and the line with a caret under the column, indented to line up
+/// with the causes of a printed error. `line` and `column` are 0-based
+fn error_excerpt(contents: &str, line: usize, column: usize) -> Option<String> {
+    let text = contents.lines().nth(line)?;
+    let number = (line + 1).to_string();
+    let caret_offset = text
+        .get(..column)
+        .map_or(column, |before| before.chars().count());
+    Some(format!(
+        "at line {}, column {}:\n        {} | {}\n        {} | {}^",
+        line + 1,
+        column + 1,
+        number,
+        text,
+        " ".repeat(number.len()),
+        " ".repeat(caret_offset)
+    ))
+}
+
 pub fn save_file<T>(filename: &Path, data: T) -> Result<()>
 where
     T: Serialize,
@@ -55,8 +90,11 @@ pub trait Filesystem {
     /// Read contents of file into a string
     fn read_to_string(&mut self, path: &Path) -> Result<String>;
 
-    /// Write string to file, without elevating privileges
-    fn write(&mut self, path: &Path, content: String) -> Result<()>;
+    /// Read contents of file
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Write contents to file, without elevating privileges
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()>;
 
     /// Delete parents of target file if they're empty
     fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()>;
@@ -65,8 +103,15 @@ pub trait Filesystem {
     fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()>;
 
     /// Create directory (and its parents) owned by the selected user,
-    /// elevating privileges as needed
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// elevating privileges as needed.
+    /// If mode is set, it is applied to the directories that had to be created,
+    /// otherwise they get `0777` with the umask applied.
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        mode: Option<FileMode>,
+    ) -> Result<()>;
 
     /// Copy readable file to target existing location.
     /// Target file will be owned by the selected user. Privileges elevated as needed.
@@ -76,11 +121,17 @@ pub trait Filesystem {
     /// If owner.is_none, ensures file is owned by the current user (elevating privileges if needed)
     fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()>;
 
-    /// Copy file mode, elevating privileges as needed. (Does not change owner)
-    fn copy_permissions(
+    /// Set the mode of a file written from source, elevating privileges as needed.
+    /// (Does not change owner)
+    /// An explicit mode is used as is. Otherwise the target gets the source's mode with the
+    /// umask applied, like a freshly created file would. With `executable_from_source`, only
+    /// the source's executable bits are taken over.
+    fn set_mode(
         &mut self,
         source: &Path,
         target: &Path,
+        mode: Option<FileMode>,
+        executable_from_source: bool,
         owner: &Option<UnixUser>,
     ) -> Result<()>;
 }
@@ -90,12 +141,23 @@ pub trait Filesystem {
 #[cfg(windows)]
 pub struct RealFilesystem {
     noconfirm: bool,
+    relative_symlinks: bool,
 }
 
 #[cfg(windows)]
 impl RealFilesystem {
-    pub fn new(noconfirm: bool) -> RealFilesystem {
-        RealFilesystem { noconfirm }
+    pub fn new(noconfirm: bool, relative_symlinks: bool) -> RealFilesystem {
+        RealFilesystem {
+            noconfirm,
+            relative_symlinks,
+        }
+    }
+
+    pub fn with_privileged_writes(self, privileged_writes: PrivilegedWrites) -> RealFilesystem {
+        if !privileged_writes.targets.is_empty() {
+            warn!("Ignoring `privileged_write`, privilege helpers aren't supported on Windows");
+        }
+        self
     }
 }
 
@@ -107,7 +169,7 @@ impl Filesystem for RealFilesystem {
         let link_state = get_file_state(link).context("get link state")?;
         trace!("Link state: {:#?}", link_state);
 
-        compare_symlink(source, source_state, link_state)
+        compare_symlink(source, link, source_state, link_state)
     }
 
     fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
@@ -132,7 +194,11 @@ impl Filesystem for RealFilesystem {
         fs::read_to_string(path).context("read from file")
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).context("read from file")
+    }
+
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
         fs::write(path, content).context("write to file")
     }
 
@@ -168,22 +234,51 @@ impl Filesystem for RealFilesystem {
             );
         }
         let real_source_path = real_path(target).context("get real path of source file")?;
-        if real_source_path.is_dir() {
-            fs::symlink_dir(real_source_path, link)
+        let contents = symlink_contents(link, target, self.relative_symlinks)?;
+        let result = if real_source_path.is_dir() {
+            fs::symlink_dir(contents, link)
         } else {
-            fs::symlink_file(real_source_path, link)
+            fs::symlink_file(contents, link)
+        };
+        match result {
+            // os error 1314: A required privilege is not held by the client.
+            // Directories are only linked without that privilege with `windows_symlink_fallback`
+            // set to junction, and junctions don't need it.
+            Err(e) if e.raw_os_error() == Some(1314) && real_source_path.is_dir() => {
+                warn!(
+                    "No permission to create symlink {:?} -> {:?}, creating a junction instead",
+                    link, target
+                );
+                junction::create(&real_source_path, link).context("create junction")
+            }
+            result => result
+                .map_err(|e| permission_denied_context(e, link))
+                .context("create symlink"),
         }
-        .context("create symlink")
     }
 
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        mode: Option<FileMode>,
+    ) -> Result<()> {
         if let Some(owner) = owner {
             warn!(
                 "Ignoring `owner`={:?} when creating directory {:?}",
                 owner, path
             );
         }
-        std::fs::create_dir_all(path).context("create directories")
+        if let Some(mode) = mode {
+            warn!(
+                "Ignoring `dir_mode`={:?} when creating directory {:?}",
+                String::from(mode),
+                path
+            );
+        }
+        std::fs::create_dir_all(path)
+            .map_err(|e| permission_denied_context(e, path))
+            .context("create directories")
     }
 
     fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
@@ -193,7 +288,9 @@ impl Filesystem for RealFilesystem {
                 owner, source, target
             );
         }
-        std::fs::copy(source, target).context("copy file")?;
+        std::fs::copy(source, target)
+            .map_err(|e| permission_denied_context(e, target))
+            .context("copy file")?;
         Ok(())
     }
 
@@ -204,10 +301,12 @@ impl Filesystem for RealFilesystem {
         Ok(())
     }
 
-    fn copy_permissions(
+    fn set_mode(
         &mut self,
         source: &Path,
         target: &Path,
+        mode: Option<FileMode>,
+        executable_from_source: bool,
         owner: &Option<UnixUser>,
     ) -> Result<()> {
         if let Some(owner) = owner {
@@ -216,6 +315,19 @@ impl Filesystem for RealFilesystem {
                 owner, source, target
             );
         }
+        if let Some(mode) = mode {
+            warn!(
+                "Ignoring `mode`={:?} of file {:?}",
+                String::from(mode),
+                target
+            );
+        }
+        if executable_from_source {
+            warn!(
+                "Ignoring `executable_from_source` of file {:?}, executable bits don't exist on Windows",
+                target
+            );
+        }
         std::fs::set_permissions(
             target,
             source
@@ -227,24 +339,48 @@ impl Filesystem for RealFilesystem {
     }
 }
 
+/// Targets that may be written through a privilege helper like `sudo` when writing them as the
+/// current user is denied
+#[derive(Debug, Clone, Default)]
+pub struct PrivilegedWrites {
+    /// The helper command, split on whitespace
+    pub helper: String,
+    pub targets: BTreeSet<PathBuf>,
+}
+
 // == Unix Filesystem ==
 
 #[cfg(unix)]
 pub struct RealFilesystem {
     noconfirm: bool,
     sudo_occurred: bool,
+    relative_symlinks: bool,
+    privileged_writes: PrivilegedWrites,
 }
 
 #[cfg(unix)]
 impl RealFilesystem {
-    pub fn new(noconfirm: bool) -> RealFilesystem {
+    pub fn new(noconfirm: bool, relative_symlinks: bool) -> RealFilesystem {
         RealFilesystem {
             sudo_occurred: false,
             noconfirm,
+            relative_symlinks,
+            privileged_writes: PrivilegedWrites::default(),
         }
     }
 
+    pub fn with_privileged_writes(mut self, privileged_writes: PrivilegedWrites) -> RealFilesystem {
+        self.privileged_writes = privileged_writes;
+        self
+    }
+
     fn sudo(&mut self, goal: impl AsRef<str>) -> Command {
+        self.elevate(goal, &["sudo"])
+    }
+
+    /// Command that runs the rest of its arguments as root through `helper`,
+    /// warning the first time
+    fn elevate(&mut self, goal: impl AsRef<str>, helper: &[&str]) -> Command {
         if !self.sudo_occurred {
             warn!("Elevating permissions ({})", goal.as_ref());
             if !log_enabled!(log::Level::Debug) {
@@ -254,7 +390,114 @@ impl RealFilesystem {
         } else {
             debug!("Elevating permissions ({})", goal.as_ref());
         }
-        Command::new("sudo")
+        let mut command = Command::new(helper[0]);
+        command.args(&helper[1..]);
+        command
+    }
+
+    /// The privilege helper split into its arguments, if `target` may be written with it
+    fn privilege_helper(&self, target: &Path) -> Option<Vec<String>> {
+        let helper = self
+            .privileged_writes
+            .helper
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        (self.privileged_writes.targets.contains(target) && !helper.is_empty()).then_some(helper)
+    }
+
+    /// Handles the result of copying `source` to `target` as the current user.
+    /// Only if that was denied, and only for privileged targets, the copy is done again through
+    /// the privilege helper. It copies to a temporary file next to the target and renames it
+    /// over the target, so that the target is replaced atomically like a direct write.
+    /// The temporary file gets the mode and owner of an existing target before it's renamed,
+    /// or the mode of a new file, so that the target is never briefly unreadable and keeps
+    /// its owner
+    fn copy_or_elevate(
+        &mut self,
+        direct: io::Result<u64>,
+        source: &Path,
+        target: &Path,
+    ) -> Result<()> {
+        let error = match direct {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        let helper = match self.privilege_helper(target) {
+            Some(helper) if error.kind() == ErrorKind::PermissionDenied => helper,
+            _ => {
+                return Err(permission_denied_context(error, target)).context("copy file");
+            }
+        };
+
+        let (mode, owner) = match target.metadata() {
+            Ok(metadata) => {
+                use std::os::unix::fs::{MetadataExt, PermissionsExt};
+                (
+                    metadata.permissions().mode() & 0o7777,
+                    format!("{}:{}", metadata.uid(), metadata.gid()),
+                )
+            }
+            Err(_) => (0o666 & !umask(), String::new()),
+        };
+
+        let helper = helper.iter().map(String::as_str).collect::<Vec<_>>();
+        let success = self
+            .elevate(
+                format!("copying {:?} -> {:?} with {:?}", source, target, helper),
+                &helper,
+            )
+            .arg("sh")
+            .arg("-c")
+            .arg(PRIVILEGED_COPY_SCRIPT)
+            .arg("dotter")
+            .arg(source)
+            .arg(target)
+            .arg(format!("{:o}", mode))
+            .arg(owner)
+            .spawn()
+            .context("spawn privilege helper")?
+            .wait()
+            .context("wait for privilege helper")?
+            .success();
+
+        anyhow::ensure!(success, "privileged copy with {:?} failed", helper);
+        Ok(())
+    }
+
+    /// Like `copy_or_elevate`, for setting the mode of a target that was written as root
+    fn set_mode_or_elevate(&mut self, target: &Path, mode: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let error = match std::fs::set_permissions(target, fs::Permissions::from_mode(mode)) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let helper = match self.privilege_helper(target) {
+            Some(helper) if error.kind() == ErrorKind::PermissionDenied => helper,
+            _ => return Err(error).context("set target permissions"),
+        };
+
+        let helper = helper.iter().map(String::as_str).collect::<Vec<_>>();
+        let success = self
+            .elevate(
+                format!(
+                    "setting mode of {:?} to {:o} with {:?}",
+                    target, mode, helper
+                ),
+                &helper,
+            )
+            .arg("chmod")
+            .arg(format!("{:o}", mode))
+            .arg(target)
+            .spawn()
+            .context("spawn privilege helper")?
+            .wait()
+            .context("wait for privilege helper")?
+            .success();
+
+        anyhow::ensure!(success, "privileged chmod with {:?} failed", helper);
+        Ok(())
     }
 
     fn is_owned_by_user(&self, path: &Path) -> Result<bool> {
@@ -263,6 +506,43 @@ impl RealFilesystem {
         let process_uid = unsafe { libc::geteuid() };
         Ok(file_uid == process_uid)
     }
+
+    /// Sets the mode of freshly created directories, as their owner if one is selected
+    fn set_dir_mode(
+        &mut self,
+        dirs: &[&Path],
+        mode: FileMode,
+        owner: &Option<UnixUser>,
+    ) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(owner) = owner {
+            let success = self
+                .sudo(format!(
+                    "Setting mode of {:?} to {:o} as user {:?}...",
+                    dirs, mode.0, owner
+                ))
+                .arg("-u")
+                .arg(owner.as_sudo_arg())
+                .arg("chmod")
+                .arg(format!("{:o}", mode.0))
+                .args(dirs)
+                .spawn()
+                .context("spawn sudo chmod")?
+                .wait()
+                .context("wait for sudo chmod")?
+                .success();
+
+            anyhow::ensure!(success, "sudo chmod failed");
+        } else {
+            for dir in dirs {
+                debug!("Setting mode of {:?} to {:o}...", dir, mode.0);
+                fs::set_permissions(dir, fs::Permissions::from_mode(mode.0))
+                    .context(format!("set mode of {:?}", dir))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(unix)]
@@ -271,7 +551,7 @@ impl Filesystem for RealFilesystem {
         let source_state = get_file_state(source).context("get source state")?;
         let link_state = get_file_state(link).context("get link state")?;
 
-        compare_symlink(source, source_state, link_state)
+        compare_symlink(source, link, source_state, link_state)
     }
 
     fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
@@ -313,7 +593,11 @@ impl Filesystem for RealFilesystem {
         fs::read_to_string(path).context("read from file")
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).context("read from file")
+    }
+
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
         fs::write(path, content).context("write to file")
     }
 
@@ -370,7 +654,7 @@ impl Filesystem for RealFilesystem {
                 .arg(owner.as_sudo_arg())
                 .arg("ln")
                 .arg("-s")
-                .arg(real_path(target).context("get real path of source file")?)
+                .arg(symlink_contents(link, target, self.relative_symlinks)?)
                 .arg(link)
                 .spawn()
                 .context("spawn sudo ln")?
@@ -385,15 +669,23 @@ impl Filesystem for RealFilesystem {
                 link, target
             );
             fs::symlink(
-                real_path(target).context("get real path of source file")?,
+                symlink_contents(link, target, self.relative_symlinks)?,
                 link,
             )
+            .map_err(|e| permission_denied_context(e, link))
             .context("create symlink")?;
         }
         Ok(())
     }
 
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        mode: Option<FileMode>,
+    ) -> Result<()> {
+        let created: Vec<&Path> = path.ancestors().take_while(|p| !p.exists()).collect();
+
         if let Some(owner) = owner {
             let success = self
                 .sudo(format!(
@@ -414,7 +706,14 @@ impl Filesystem for RealFilesystem {
             anyhow::ensure!(success, "sudo mkdir failed");
         } else {
             debug!("Creating directory {:?} as current user...", path);
-            std::fs::create_dir_all(path).context("create directories")?;
+            std::fs::create_dir_all(path)
+                .map_err(|e| permission_denied_context(e, path))
+                .context("create directories")?;
+        }
+
+        if let (Some(mode), false) = (mode, created.is_empty()) {
+            self.set_dir_mode(&created, mode, owner)
+                .context("set mode of created directories")?;
         }
         Ok(())
     }
@@ -453,7 +752,8 @@ impl Filesystem for RealFilesystem {
             anyhow::ensure!(success, "sudo tee failed");
         } else {
             debug!("Copying {:?} -> {:?} as current user", source, target);
-            std::fs::copy(source, target).context("copy file")?;
+            let direct = std::fs::copy(source, target);
+            self.copy_or_elevate(direct, source, target)?;
         }
 
         Ok(())
@@ -489,21 +789,31 @@ impl Filesystem for RealFilesystem {
         Ok(())
     }
 
-    fn copy_permissions(
+    fn set_mode(
         &mut self,
         source: &Path,
         target: &Path,
+        mode: Option<FileMode>,
+        executable_from_source: bool,
         owner: &Option<UnixUser>,
     ) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source_mode = source
+            .metadata()
+            .context("get source metadata")?
+            .permissions()
+            .mode();
+        let mode = target_mode(source_mode, mode, executable_from_source, umask());
+
         if let Some(owner) = owner {
             let success = self
                 .sudo(format!(
-                    "Copying permissions {:?} -> {:?} as user {:?}",
-                    source, target, owner
+                    "Setting mode of {:?} to {:o} as user {:?}",
+                    target, mode, owner
                 ))
                 .arg("chmod")
-                .arg("--reference")
-                .arg(source)
+                .arg(format!("{:o}", mode))
                 .arg(target)
                 .spawn()
                 .context("spawn sudo chmod command")?
@@ -513,23 +823,112 @@ impl Filesystem for RealFilesystem {
 
             anyhow::ensure!(success, "sudo chmod failed");
         } else {
-            debug!(
-                "Copying permissions {:?} -> {:?} as current user",
-                source, target
-            );
-            std::fs::set_permissions(
-                target,
-                source
-                    .metadata()
-                    .context("get source metadata")?
-                    .permissions(),
-            )
-            .context("set target permissions")?;
+            debug!("Setting mode of {:?} to {:o} as current user", target, mode);
+            self.set_mode_or_elevate(target, mode)?;
         }
         Ok(())
     }
 }
 
+/// Copies `$1` to a temporary file in the directory of `$2`, gives it the mode `$3` and, unless
+/// it's empty, the owner `$4`, then renames it to `$2`.
+/// The temporary file is removed if anything fails, and the target is left as it was
+#[cfg(unix)]
+const PRIVILEGED_COPY_SCRIPT: &str = r#"set -e
+tmp=$(mktemp "$2.dotter.XXXXXX")
+trap 'rm -f "$tmp"' EXIT
+cat "$1" > "$tmp"
+chmod "$3" "$tmp"
+if [ -n "$4" ]; then chown "$4" "$tmp"; fi
+mv -f "$tmp" "$2"
+"#;
+
+/// The mode a file written from a source with `source_mode` gets: an explicit mode overrides
+/// everything, otherwise the umask is applied to the source's mode, or only its executable bits
+/// on top of a new file's default of 0666
+#[cfg(unix)]
+fn target_mode(
+    source_mode: u32,
+    mode: Option<FileMode>,
+    executable_from_source: bool,
+    umask: u32,
+) -> u32 {
+    match mode {
+        Some(mode) => mode.0,
+        None if executable_from_source => (0o666 | (source_mode & 0o111)) & !umask,
+        None => source_mode & 0o7777 & !umask,
+    }
+}
+
+/// The mode `set_mode` gives the target of a template from `source`, for recording it ahead
+/// of time. None on Windows, where files don't have modes
+#[cfg(unix)]
+pub fn template_mode(
+    source: &Path,
+    mode: Option<FileMode>,
+    executable_from_source: bool,
+) -> Result<Option<FileMode>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let source_mode = source
+        .metadata()
+        .context("get source metadata")?
+        .permissions()
+        .mode();
+    Ok(Some(FileMode(target_mode(
+        source_mode,
+        mode,
+        executable_from_source,
+        umask(),
+    ))))
+}
+
+#[cfg(windows)]
+pub fn template_mode(
+    _source: &Path,
+    _mode: Option<FileMode>,
+    _executable_from_source: bool,
+) -> Result<Option<FileMode>> {
+    Ok(None)
+}
+
+/// The umask of the process, read once by `read_umask`
+#[cfg(unix)]
+static UMASK: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+
+/// Reads the umask of the process. Linux reports it in /proc, elsewhere it can only be read by
+/// setting it, so it's set right back. That changes it for every thread in between, so this is
+/// called once at startup, before other threads create files
+#[cfg(unix)]
+pub fn read_umask() {
+    UMASK.get_or_init(current_umask);
+}
+
+#[cfg(windows)]
+pub fn read_umask() {}
+
+#[cfg(unix)]
+fn umask() -> u32 {
+    *UMASK.get_or_init(current_umask)
+}
+
+#[cfg(unix)]
+fn current_umask() -> u32 {
+    let reported = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Umask:"))
+                .and_then(|umask| u32::from_str_radix(umask.trim(), 8).ok())
+        });
+    reported.unwrap_or_else(|| unsafe {
+        let umask = libc::umask(0o022);
+        libc::umask(umask);
+        umask as u32
+    })
+}
+
 // == Dry run Filesystem ==
 pub struct DryRunFilesystem {
     file_states: BTreeMap<PathBuf, FileState>,
@@ -537,8 +936,7 @@ pub struct DryRunFilesystem {
 
 #[derive(Debug, Clone, PartialEq)]
 enum FileState {
-    /// None if file is invalid UTF-8
-    File(Option<String>),
+    File(Vec<u8>),
     SymbolicLink(PathBuf),
     Directory,
     Missing,
@@ -578,7 +976,7 @@ impl Filesystem for DryRunFilesystem {
             state
         };
 
-        compare_symlink(source, source_state, link_state)
+        compare_symlink(source, link, source_state, link_state)
     }
 
     fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
@@ -611,15 +1009,29 @@ impl Filesystem for DryRunFilesystem {
     fn read_to_string(&mut self, path: &Path) -> Result<String> {
         debug!("Reading contents of file {:?}", path);
         match self.get_state(path).context("get file state")? {
-            FileState::File(s) => Ok(s.context("invalid utf-8 in template source")?),
+            FileState::File(s) => {
+                Ok(String::from_utf8(s).context("invalid utf-8 in template source")?)
+            }
             _ => anyhow::bail!("writing to non-file"),
         }
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
-        debug!("Writing contents {:?} to file {:?}", content, path);
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        debug!("Reading contents of file {:?}", path);
+        match self.get_state(path).context("get file state")? {
+            FileState::File(s) => Ok(s),
+            _ => anyhow::bail!("reading non-file"),
+        }
+    }
+
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        debug!(
+            "Writing contents {:?} to file {:?}",
+            String::from_utf8_lossy(&content),
+            path
+        );
         self.file_states
-            .insert(path.into(), FileState::File(Some(content)));
+            .insert(path.into(), FileState::File(content));
         Ok(())
     }
 
@@ -641,8 +1053,18 @@ impl Filesystem for DryRunFilesystem {
         Ok(())
     }
 
-    fn create_dir_all(&mut self, mut path: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        debug!("Creating directory {:?} (owned by {:?})", path, owner);
+    fn create_dir_all(
+        &mut self,
+        mut path: &Path,
+        owner: &Option<UnixUser>,
+        mode: Option<FileMode>,
+    ) -> Result<()> {
+        debug!(
+            "Creating directory {:?} (owned by {:?}, mode {:?})",
+            path,
+            owner,
+            mode.map(String::from)
+        );
         self.file_states.insert(path.into(), FileState::Directory);
         while path.parent().is_some() {
             path = path.parent().unwrap();
@@ -681,15 +1103,21 @@ impl Filesystem for DryRunFilesystem {
         Ok(())
     }
 
-    fn copy_permissions(
+    fn set_mode(
         &mut self,
         source: &Path,
         target: &Path,
+        mode: Option<FileMode>,
+        executable_from_source: bool,
         owner: &Option<UnixUser>,
     ) -> Result<()> {
         debug!(
-            "Copying permissions on files {:?} -> {:?} (target owned by {:?})",
-            source, target, owner
+            "Setting mode of {:?} from {:?} (mode {:?}, executable from source: {}, target owned by {:?})",
+            target,
+            source,
+            mode.map(String::from),
+            executable_from_source,
+            owner
         );
         Ok(())
     }
@@ -706,10 +1134,12 @@ fn get_file_state(path: &Path) -> Result<FileState> {
         return Ok(FileState::Directory);
     }
 
-    match fs::read_to_string(path) {
-        Ok(f) => Ok(FileState::File(Some(f))),
-        Err(e) if e.kind() == ErrorKind::InvalidData => Ok(FileState::File(None)),
-        Err(e) if e.kind() == ErrorKind::NotFound => Ok(FileState::Missing),
+    match fs::read(path) {
+        Ok(f) => Ok(FileState::File(f)),
+        // A path below a file doesn't exist either
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+            Ok(FileState::Missing)
+        }
         Err(e) => Err(e).context("read contents of file that isn't symbolic or directory")?,
     }
 }
@@ -741,13 +1171,16 @@ impl std::fmt::Display for SymlinkComparison {
 
 fn compare_symlink(
     source_path: &Path,
+    link_path: &Path,
     source_state: FileState,
     link_state: FileState,
 ) -> Result<SymlinkComparison> {
     Ok(match (source_state, link_state) {
         (FileState::Missing, FileState::SymbolicLink(_)) => SymlinkComparison::OnlyTargetExists,
         (_, FileState::SymbolicLink(t)) => {
-            if t == real_path(source_path).context("get real path of source")? {
+            if resolve_symlink(link_path, &t)
+                == real_path(source_path).context("get real path of source")?
+            {
                 SymlinkComparison::Identical
             } else {
                 SymlinkComparison::Changed
@@ -800,28 +1233,159 @@ fn compare_template(target_state: FileState, cache_state: FileState) -> Template
     }
 }
 
-/// === Utility functions ===
+// === Utility functions ===
 
 pub fn real_path(path: &Path) -> Result<PathBuf, io::Error> {
     let path = std::fs::canonicalize(path)?;
     Ok(platform_dunce(&path))
 }
 
-pub fn ask_boolean(prompt: &str) -> bool {
-    let mut buf = String::from("a"); // enter the loop at least once
-    while !(buf.to_lowercase().starts_with('y')
-        || buf.to_lowercase().starts_with('n')
-        || buf.is_empty())
-    {
-        eprintln!("{}", prompt);
-        buf.clear();
-        io::stdin()
-            .read_line(&mut buf)
-            .expect("Failed to read line from stdin");
+/// The absolute path that `path` refers to with `..` and symlinks resolved, even if it doesn't
+/// exist yet: the part that exists is resolved by the OS, the rest can't contain symlinks
+pub fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let absolute = std::env::current_dir()
+        .context("get current directory")?
+        .join(path);
+    let mut existing = absolute.components().collect::<Vec<_>>();
+    let mut missing = Vec::new();
+    let mut resolved = loop {
+        let candidate = existing.iter().collect::<PathBuf>();
+        if let Ok(real) = real_path(&candidate) {
+            break real;
+        }
+        missing.push(existing.pop().context("find an existing ancestor")?);
+    };
+    for component in missing.into_iter().rev() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Like `resolve_path`, but a symlink at the target itself isn't followed, since it's the link
+/// that gets replaced
+pub fn resolve_target_path(target: &Path) -> Result<PathBuf> {
+    match (target.parent(), target.file_name()) {
+        (Some(parent), Some(name)) => Ok(resolve_path(parent)?.join(name)),
+        _ => resolve_path(target),
     }
+}
 
-    // If empty defaults to no
-    buf.to_lowercase().starts_with('y')
+/// What a symlink at `link` to `source` should contain: the real path of the source, or with
+/// `relative` the path to it from the link's real parent directory, which has to exist
+pub fn symlink_contents(link: &Path, source: &Path, relative: bool) -> Result<PathBuf> {
+    let source = real_path(source).context("get real path of source file")?;
+    if !relative {
+        return Ok(source);
+    }
+    let parent = link.parent().context("get parent of link")?;
+    let parent = real_path(parent).context("get real path of link's parent")?;
+    Ok(relative_path(&parent, &source))
+}
+
+/// The path to `to` from the directory `from`, both absolute.
+/// If they don't share a root (like paths on different drives on Windows), `to` itself
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return to.iter().collect();
+    }
+
+    let mut path: PathBuf = from[common..].iter().map(|_| "..").collect();
+    path.extend(&to[common..]);
+    path
+}
+
+/// Where a symlink at `link` containing `contents` points to, with relative contents resolved
+/// from the link's directory. Resolved as far as possible, so that it can be compared to the
+/// real path of a source
+pub fn resolve_symlink(link: &Path, contents: &Path) -> PathBuf {
+    let path = match link.parent() {
+        Some(parent) => parent.join(contents),
+        None => contents.into(),
+    };
+    real_path(&path).unwrap_or(path)
+}
+
+/// Turns a permission error that occurred while writing to `path` into an error that explains
+/// which directory couldn't be written to and how to resolve it. Other errors are passed through.
+fn permission_denied_context(error: io::Error, path: &Path) -> anyhow::Error {
+    if error.kind() != ErrorKind::PermissionDenied {
+        return error.into();
+    }
+
+    let parent = path.parent().unwrap_or(path);
+    anyhow::Error::new(error).context(format!(
+        "no permission to write {:?}: the parent directory {:?} is {}.
+Check the ownership of that directory, or set the `owner` field of the file so that Dotter uses sudo to write it",
+        path,
+        parent,
+        describe_owner(parent)
+    ))
+}
+
+#[cfg(unix)]
+fn describe_owner(path: &Path) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let uid = match path.metadata() {
+        Ok(metadata) => metadata.uid(),
+        Err(_) => return "not accessible".into(),
+    };
+
+    // Safety: getpwuid returns either null or a pointer to a valid static passwd entry
+    let name = unsafe {
+        let passwd = libc::getpwuid(uid);
+        if passwd.is_null() {
+            None
+        } else {
+            Some(
+                std::ffi::CStr::from_ptr((*passwd).pw_name)
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    };
+
+    match name {
+        Some(name) => format!("owned by {} (uid {})", name, uid),
+        None => format!("owned by uid {}", uid),
+    }
+}
+
+#[cfg(windows)]
+fn describe_owner(path: &Path) -> String {
+    match path.metadata() {
+        Ok(metadata) if metadata.permissions().readonly() => "read-only".into(),
+        Ok(_) => "not writable by the current user".into(),
+        Err(_) => "not accessible".into(),
+    }
+}
+
+pub fn ask_boolean(prompt: &str) -> bool {
+    progress::suspend(|| {
+        let mut buf = String::from("a"); // enter the loop at least once
+        while !(buf.to_lowercase().starts_with('y')
+            || buf.to_lowercase().starts_with('n')
+            || buf.is_empty())
+        {
+            eprintln!("{}", prompt);
+            buf.clear();
+            io::stdin()
+                .read_line(&mut buf)
+                .expect("Failed to read line from stdin");
+        }
+
+        // If empty defaults to no
+        buf.to_lowercase().starts_with('y')
+    })
 }
 
 pub fn is_template(source: &Path) -> Result<bool> {
@@ -908,7 +1472,7 @@ mod test {
     #[test]
     fn simple_create_dir_all() {
         let mut fs = DryRunFilesystem::new();
-        fs.create_dir_all(&PathBuf::from("/home/user/.config"), &None)
+        fs.create_dir_all(&PathBuf::from("/home/user/.config"), &None, None)
             .unwrap();
         assert_eq!(
             fs.get_state(&PathBuf::from("/home")).unwrap(),
@@ -941,7 +1505,7 @@ mod test {
             TemplateComparison::BothMissing
         );
 
-        fs.create_dir_all(&PathBuf::from("target_dir"), &None)
+        fs.create_dir_all(&PathBuf::from("target_dir"), &None, None)
             .unwrap();
 
         // perform_template_deploy
@@ -952,9 +1516,9 @@ mod test {
         let rendered = String::from("John");
 
         // cache
-        fs.create_dir_all(&PathBuf::from("cache_dir"), &None)
+        fs.create_dir_all(&PathBuf::from("cache_dir"), &None, None)
             .unwrap();
-        fs.write(&PathBuf::from("cache_dir/cache"), rendered)
+        fs.write(&PathBuf::from("cache_dir/cache"), rendered.into_bytes())
             .unwrap();
 
         // target
@@ -964,9 +1528,11 @@ mod test {
             &None,
         )
         .unwrap();
-        fs.copy_permissions(
+        fs.set_mode(
             &PathBuf::from("source"),
             &PathBuf::from("target_dir/target"),
+            None,
+            false,
             &None,
         )
         .unwrap();
@@ -974,7 +1540,7 @@ mod test {
         // Verify all actions
         assert_eq!(
             fs.file_states.get(&PathBuf::from("source")),
-            Some(&FileState::File(Some("{{name}}".into())))
+            Some(&FileState::File("{{name}}".into()))
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("cache_dir")),
@@ -982,7 +1548,7 @@ mod test {
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("cache_dir/cache")),
-            Some(&FileState::File(Some("John".into())))
+            Some(&FileState::File("John".into()))
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("target_dir")),
@@ -990,7 +1556,7 @@ mod test {
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("target_dir/target")),
-            Some(&FileState::File(Some("John".into())))
+            Some(&FileState::File("John".into()))
         );
     }
 
@@ -1014,4 +1580,259 @@ mod test {
         fs.copy_file(&PathBuf::from("link"), &PathBuf::from("link2"), &None)
             .unwrap_err();
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn permission_denied_on_target_write() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let readonly = dir.path().join("readonly");
+        fs::write(&source, "hello").unwrap();
+        fs::create_dir(&readonly).unwrap();
+        fs::set_permissions(&readonly, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let target = readonly.join("target");
+        let result = RealFilesystem::new(true, false).copy_file(&source, &target, &None);
+
+        if unsafe { libc::geteuid() } == 0 {
+            // Root ignores directory permissions, so the write can't fail here
+            assert!(result.is_ok());
+            return;
+        }
+
+        let message = format!("{:#}", result.unwrap_err());
+        assert!(message.contains("no permission to write"), "{}", message);
+        assert!(message.contains(&format!("{:?}", target)), "{}", message);
+        assert!(message.contains("owned by"), "{}", message);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_dir_all_sets_mode_of_new_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("a");
+        fs::create_dir(&existing).unwrap();
+        fs::set_permissions(&existing, fs::Permissions::from_mode(0o755)).unwrap();
+
+        RealFilesystem::new(true, false)
+            .create_dir_all(&existing.join("b/c"), &None, Some(FileMode(0o700)))
+            .unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&existing), 0o755);
+        assert_eq!(mode(&existing.join("b")), 0o700);
+        assert_eq!(mode(&existing.join("b/c")), 0o700);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modes_respect_umask_unless_explicit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o775)).unwrap();
+
+        let mut fs = RealFilesystem::new(true, false);
+        fs.create_dir_all(&dir.path().join("default"), &None, None)
+            .unwrap();
+        fs.create_dir_all(&dir.path().join("explicit"), &None, Some(FileMode(0o705)))
+            .unwrap();
+        for name in ["default/file", "explicit/file"] {
+            fs.copy_file(&source, &dir.path().join(name), &None)
+                .unwrap();
+        }
+        fs.set_mode(
+            &source,
+            &dir.path().join("default/file"),
+            None,
+            false,
+            &None,
+        )
+        .unwrap();
+        fs.set_mode(
+            &source,
+            &dir.path().join("explicit/file"),
+            Some(FileMode(0o606)),
+            false,
+            &None,
+        )
+        .unwrap();
+
+        let mode = |path: &str| {
+            fs::metadata(dir.path().join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        // The process umask is left alone, since the other tests run in parallel
+        assert_eq!(mode("default"), 0o777 & !umask());
+        assert_eq!(mode("default/file"), 0o775 & !umask());
+        assert_eq!(target_mode(0o775, None, false, 0o027), 0o750);
+        assert_eq!(mode("explicit"), 0o705);
+        assert_eq!(mode("explicit/file"), 0o606);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn executable_bits_from_source() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = RealFilesystem::new(true, false);
+        let mode_of = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        for (name, source_mode) in [("script", 0o700), ("config", 0o600)] {
+            let source = dir.path().join(name);
+            let target = dir.path().join(format!("{}.out", name));
+            fs::write(&source, "").unwrap();
+            fs::set_permissions(&source, fs::Permissions::from_mode(source_mode)).unwrap();
+            fs.copy_file(&source, &target, &None).unwrap();
+            fs.set_mode(&source, &target, None, true, &None).unwrap();
+
+            assert_eq!(mode_of(&target) & 0o666, 0o666 & !umask());
+            assert_eq!(mode_of(&target) & 0o100 != 0, source_mode & 0o100 != 0);
+        }
+
+        assert_eq!(target_mode(0o700, None, true, 0o022), 0o744);
+        assert_eq!(target_mode(0o640, None, true, 0o022), 0o644);
+        assert_eq!(
+            target_mode(0o755, Some(FileMode(0o600)), true, 0o022),
+            0o600
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn absolute_and_relative_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("dotfiles/vimrc");
+        let links = dir.path().join("home/.config/vim");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::create_dir_all(&links).unwrap();
+        fs::write(&source, "set number").unwrap();
+
+        let absolute = links.join("absolute");
+        let relative = links.join("relative");
+        RealFilesystem::new(true, false)
+            .make_symlink(&absolute, &source, &None)
+            .unwrap();
+        RealFilesystem::new(true, true)
+            .make_symlink(&relative, &source, &None)
+            .unwrap();
+
+        assert_eq!(
+            fs::read_link(&absolute).unwrap(),
+            real_path(&source).unwrap()
+        );
+        assert_eq!(
+            fs::read_link(&relative).unwrap(),
+            PathBuf::from("../../../dotfiles/vimrc")
+        );
+        // Both are recognized as pointing at the source, regardless of the mode
+        for link in [&absolute, &relative] {
+            assert_eq!(
+                RealFilesystem::new(true, false)
+                    .compare_symlink(&source, link)
+                    .unwrap(),
+                SymlinkComparison::Identical
+            );
+        }
+    }
+
+    #[test]
+    fn base64_template_deploys_decoded_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("icon.png.b64");
+        fs::write(&source, "iVBORw0KGgoA\n{{suffix}}\n").unwrap();
+        let mut target = crate::config::TemplateTarget::from(dir.path().join("icon.png"));
+        target.encoding = Some(crate::encoding::Encoding::Base64);
+        let mut variables = crate::config::Variables::new();
+        variables.insert("suffix".into(), "AP8".into());
+
+        crate::actions::perform_template_deploy(
+            &source,
+            &dir.path().join("cache/icon.png.b64"),
+            &target,
+            &mut RealFilesystem::new(true, false),
+            &handlebars::Handlebars::new(),
+            &variables,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read(&target.target).unwrap(),
+            vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0xff]
+        );
+    }
+
+    #[test]
+    fn permission_denied_context_passes_other_errors() {
+        let error = permission_denied_context(
+            io::Error::new(ErrorKind::NotFound, "missing"),
+            Path::new("some/target"),
+        );
+        assert_eq!(error.to_string(), "missing");
+
+        let error = permission_denied_context(
+            io::Error::new(ErrorKind::PermissionDenied, "denied"),
+            Path::new("some/target"),
+        );
+        assert!(error.to_string().contains("\"some\""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn privilege_helper_is_only_used_when_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        // Records the command it runs, as the current user
+        let helper = dir.path().join("helper");
+        let calls = dir.path().join("calls");
+        fs::write(
+            &helper,
+            format!("#!/bin/sh\necho \"$1 $2\" >> {:?}\nexec \"$@\"\n", calls),
+        )
+        .unwrap();
+        fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        let other = dir.path().join("other");
+        fs::write(&source, "new contents").unwrap();
+        fs::write(&target, "old contents").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        let mut fs = RealFilesystem::new(true, false).with_privileged_writes(PrivilegedWrites {
+            helper: helper.to_str().unwrap().into(),
+            targets: maplit::btreeset! { target.clone() },
+        });
+        let denied = || Err(io::Error::from(ErrorKind::PermissionDenied));
+
+        // A direct write that worked, or that failed for another reason, is left alone
+        fs.copy_or_elevate(Ok(0), &source, &target).unwrap();
+        assert!(fs
+            .copy_or_elevate(Err(io::Error::from(ErrorKind::NotFound)), &source, &target)
+            .is_err());
+        // Only privileged targets are elevated
+        assert!(fs.copy_or_elevate(denied(), &source, &other).is_err());
+        assert!(!calls.exists());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old contents");
+
+        fs.copy_or_elevate(denied(), &source, &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new contents");
+        assert_eq!(fs::read_to_string(&calls).unwrap(), "sh -c\n");
+        // The temporary file was renamed over the target, with the target's mode
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+    }
 }
@@ -0,0 +1,208 @@
+use anyhow::{Context, Result};
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::GitSource;
+
+static SYNC_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Makes `sync` use existing checkouts as they are instead of fetching, for commands that
+/// mustn't write anything
+pub(crate) fn disable_sync() {
+    SYNC_ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Clones the repository into `checkout`, or fetches it if it was cloned before,
+/// then checks out the requested revision.
+/// If fetching fails (for example when offline), the previous checkout is used.
+pub(crate) fn sync(source: &GitSource, checkout: &Path) -> Result<()> {
+    // It would be taken as an option of `git checkout`
+    if let Some(rev) = source.rev.as_ref().filter(|rev| rev.starts_with('-')) {
+        anyhow::bail!("revision {:?} starts with `-`", rev);
+    }
+    if !SYNC_ENABLED.load(Ordering::Relaxed) {
+        anyhow::ensure!(
+            checkout.join(".git").exists(),
+            "{:?} wasn't checked out yet, run a deploy first",
+            checkout
+        );
+        debug!(
+            "Using the existing checkout in {:?} without fetching",
+            checkout
+        );
+        return Ok(());
+    }
+
+    if checkout.join(".git").exists() {
+        debug!("Fetching {:?} into {:?}", source.git, checkout);
+        if let Err(e) = git(Some(checkout), &["fetch", "--quiet", "origin"]) {
+            warn!(
+                "Failed to fetch {:?}, using the existing checkout in {:?}: {:#}",
+                source.git, checkout, e
+            );
+        }
+    } else {
+        debug!("Cloning {:?} into {:?}", source.git, checkout);
+        if let Some(parent) = checkout.parent() {
+            std::fs::create_dir_all(parent).context("create sources directory")?;
+        }
+        git(
+            None,
+            &[
+                "clone",
+                "--quiet",
+                "--no-checkout",
+                "--",
+                &source.git,
+                &checkout.to_string_lossy(),
+            ],
+        )
+        .context("clone repository")?;
+    }
+
+    let rev = match &source.rev {
+        // Prefer the fetched branch over a stale local branch of the same name
+        Some(rev)
+            if git(
+                Some(checkout),
+                &[
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &format!("refs/remotes/origin/{}", rev),
+                ],
+            )
+            .is_ok() =>
+        {
+            format!("origin/{}", rev)
+        }
+        Some(rev) => rev.clone(),
+        None => "origin/HEAD".into(),
+    };
+    git(
+        Some(checkout),
+        &["checkout", "--quiet", "--force", "--detach", &rev],
+    )
+    .with_context(|| format!("check out revision {:?}", rev))?;
+
+    Ok(())
+}
+
+fn git(dir: Option<&Path>, args: &[&str]) -> Result<()> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+    let output = command.args(args).output().context("run git")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "git {} failed: {}",
+        args[0],
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn sync_from_local_bare_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = dir.path().join("remote.git");
+        let work = dir.path().join("work");
+        let checkout = dir.path().join("sources/shared");
+
+        std::fs::create_dir(&work).unwrap();
+        run(dir.path(), &["init", "--quiet", "--bare", "remote.git"]);
+        run(&work, &["init", "--quiet"]);
+        run(&work, &["checkout", "--quiet", "-b", "main"]);
+        let commit = |message: &str| {
+            std::fs::write(work.join("vimrc"), message).unwrap();
+            run(&work, &["add", "vimrc"]);
+            run(
+                &work,
+                &[
+                    "-c",
+                    "user.name=test",
+                    "-c",
+                    "user.email=test@example.com",
+                    "commit",
+                    "--quiet",
+                    "-m",
+                    message,
+                ],
+            );
+            run(
+                &work,
+                &["push", "--quiet", remote.to_str().unwrap(), "main"],
+            );
+        };
+        commit("first");
+
+        let source = GitSource {
+            git: remote.to_string_lossy().into(),
+            rev: Some("main".into()),
+        };
+        sync(&source, &checkout).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("vimrc")).unwrap(),
+            "first"
+        );
+
+        commit("second");
+        sync(&source, &checkout).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("vimrc")).unwrap(),
+            "second"
+        );
+
+        // Offline: the cached checkout is still usable
+        std::fs::remove_dir_all(&remote).unwrap();
+        sync(&source, &checkout).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("vimrc")).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn options_are_not_taken_from_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkout = dir.path().join("sources/shared");
+        let pwned = dir.path().join("pwned");
+
+        let source = GitSource {
+            git: format!("--upload-pack=touch {}", pwned.display()),
+            rev: None,
+        };
+        assert!(sync(&source, &checkout).is_err());
+        assert!(!pwned.exists());
+
+        let source = GitSource {
+            git: dir.path().to_string_lossy().into(),
+            rev: Some("--orphan=main".into()),
+        };
+        let error = sync(&source, &checkout).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "revision \"--orphan=main\" starts with `-`"
+        );
+        assert!(!checkout.exists());
+    }
+}
@@ -1,26 +1,74 @@
 use anyhow::{Context as AnyhowContext, Result};
 
-use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    TemplateError,
+};
+use serde::Serialize;
 use toml::value::{Table, Value};
 
-use std::collections::{BTreeMap, BTreeSet};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
 #[cfg(feature = "scripting")]
 use crate::config::Helpers;
-use crate::config::{Configuration, Files, Variables};
+use crate::config::{
+    self, Configuration, FileTarget, Files, LayeredVariables, Partial, Partials, Settings,
+    SourceSpan, TemplateTarget, Variables,
+};
+
+/// The time the `now` helper renders instead of the current time
+static PINNED_NOW: OnceLock<OffsetDateTime> = OnceLock::new();
+
+/// Pins the time of the `now` helper to `now` in RFC 3339, or to the SOURCE_DATE_EPOCH
+/// environment variable if it's None. Without either, `now` is the current time
+pub fn pin_now(now: Option<&str>) -> Result<()> {
+    let pinned = match (now, std::env::var("SOURCE_DATE_EPOCH")) {
+        (Some(now), _) => OffsetDateTime::parse(now, &Rfc3339)
+            .with_context(|| format!("parse {:?} as an RFC 3339 time", now))?,
+        (None, Ok(epoch)) => epoch
+            .trim()
+            .parse()
+            .ok()
+            .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds).ok())
+            .with_context(|| format!("parse SOURCE_DATE_EPOCH {:?} as seconds", epoch))?,
+        (None, Err(_)) => return Ok(()),
+    };
+    debug!("Pinning the time of `now` to {}", pinned);
+    // Only set once per run
+    let _ = PINNED_NOW.set(pinned);
+    Ok(())
+}
 
 pub fn create_new_handlebars<'b>(config: &mut Configuration) -> Result<Handlebars<'b>> {
     debug!("Creating Handlebars instance...");
     let mut handlebars = Handlebars::new();
     handlebars.register_escape_fn(|s| s.to_string()); // Disable html-escaping
-    handlebars.set_strict_mode(true); // Report missing variables as errors
-    register_rust_helpers(&mut handlebars);
+    handlebars.set_strict_mode(config.settings.strict); // Report missing variables as errors
+    register_rust_helpers(&mut handlebars, &config.settings);
+    register_external_helpers(&mut handlebars, &config.settings)
+        .context("register external helpers")?;
 
     #[cfg(feature = "scripting")]
     register_script_helpers(&mut handlebars, &config.helpers);
 
+    register_partials(&mut handlebars, &config.partials).context("register partials")?;
+
+    // Before `dotter.files` lists the sources
+    render_sources(
+        &handlebars,
+        &config.variables,
+        &mut config.files,
+        &mut config.file_packages,
+    )
+    .context("render variables in sources")?;
     add_dotter_variable(&mut config.variables, &config.files, &config.packages);
     filter_files_condition(&handlebars, &config.variables, &mut config.files)
         .context("filter files based on `if` field")?;
@@ -47,7 +95,7 @@ fn filter_files_condition(
                 Some((source, target))
             })
         })
-        .collect::<Result<BTreeSet<Option<(PathBuf, _)>>>>()?
+        .collect::<Result<Vec<Option<(PathBuf, _)>>>>()?
         .into_iter()
         .flatten()
         .collect();
@@ -55,6 +103,260 @@ fn filter_files_condition(
     Ok(())
 }
 
+/// Renders the variables in the sources of symbolic and automatic files, like
+/// `nvim-{{nvim_version}}`, so that their targets link to or are rendered from the rendered
+/// path. The package of a rendered source stays the same
+fn render_sources(
+    handlebars: &Handlebars,
+    variables: &Variables,
+    files: &mut Files,
+    file_packages: &mut BTreeMap<PathBuf, String>,
+) -> Result<()> {
+    let templated = files
+        .iter()
+        .filter(|(source, target)| {
+            matches!(target, FileTarget::Automatic(_) | FileTarget::Symbolic(_))
+                && source.to_string_lossy().contains("{{")
+        })
+        .map(|(source, _)| source.clone())
+        .collect::<Vec<_>>();
+    for source in templated {
+        let rendered = PathBuf::from(
+            handlebars
+                .render_template(&source.to_string_lossy(), variables)
+                .with_context(|| format!("render source {:?}", source))?,
+        );
+        anyhow::ensure!(
+            !files.contains_key(&rendered),
+            "source {:?} renders to {:?}, which is configured already",
+            source,
+            rendered
+        );
+        let target = files.remove(&source).expect("source is in the files");
+        if let Some(package) = config::file_package(file_packages, &source).cloned() {
+            file_packages.remove(&source);
+            file_packages.insert(rendered.clone(), package);
+        }
+        files.insert(rendered, target);
+    }
+    Ok(())
+}
+
+/// The last output of Handlebars for a template, so that rendering it again, like for
+/// `deploy --diff` and then for the deploy itself, is skipped if the template is the same.
+/// It's kept with the template's target, which lives as long as one command, since partials,
+/// helpers and included files aren't part of the key. Nothing is cached until `set_variables`
+/// is given the hash of the variables, so that they're hashed once per command
+#[derive(Clone, Default)]
+pub struct RenderCache(Arc<Mutex<CachedRender>>);
+
+#[derive(Default)]
+struct CachedRender {
+    variables_hash: Option<String>,
+    /// The hash of the source and its contents, and the output
+    last: Option<(String, String)>,
+}
+
+impl RenderCache {
+    /// The hash that `set_variables` takes. None if the variables can't be hashed, which
+    /// disables the cache
+    pub fn hash_variables(variables: &Variables) -> Option<String> {
+        serde_json::to_string(variables)
+            .ok()
+            .map(|json| crate::plan::sha256(json.as_bytes()))
+    }
+
+    /// Sets the hash of the variables the template is rendered with from now on, which drops
+    /// the last render if they changed
+    pub fn set_variables(&self, variables_hash: Option<&str>) {
+        let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if cached.variables_hash.as_deref() != variables_hash {
+            *cached = CachedRender {
+                variables_hash: variables_hash.map(Into::into),
+                last: None,
+            };
+        }
+    }
+
+    fn get_or_render(
+        &self,
+        source: &Path,
+        contents: &str,
+        render: impl FnOnce() -> Result<String>,
+    ) -> Result<String> {
+        if self
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .variables_hash
+            .is_none()
+        {
+            return render();
+        }
+        let key = match serde_json::to_string(&(source, contents)) {
+            Ok(key) => crate::plan::sha256(key.as_bytes()),
+            Err(_) => return render(),
+        };
+        if let Some((cached_key, rendered)) = &self.0.lock().unwrap_or_else(|e| e.into_inner()).last
+        {
+            if *cached_key == key {
+                trace!("Using cached render of {:?}", source);
+                return Ok(rendered.clone());
+            }
+        }
+        // Errors aren't cached, they're reported every time
+        let rendered = render()?;
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).last = Some((key, rendered.clone()));
+        Ok(rendered)
+    }
+}
+
+impl std::fmt::Debug for RenderCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("RenderCache")
+    }
+}
+
+/// Caches aren't part of a target's configuration
+impl PartialEq for RenderCache {
+    fn eq(&self, _: &RenderCache) -> bool {
+        true
+    }
+}
+
+/// Renders the contents of the template file `source` with its actions applied.
+/// If rendering fails at a line that moved because of the actions, the error says which line
+/// of the source it is. The post-render actions, like the formatter, are cached with the
+/// render, except for a merge, which depends on the target's current contents
+pub fn render_target(
+    handlebars: &Handlebars<'static>,
+    source: &Path,
+    target: &TemplateTarget,
+    contents: String,
+    variables: &Variables,
+) -> Result<String> {
+    let (contents, span) = target.apply_actions_mapped(contents);
+    let mut overlay = target.variables.clone();
+    add_file_metadata(&mut overlay, source, &target.target);
+    let variables = LayeredVariables {
+        base: variables,
+        overlay: &overlay,
+    };
+    let rendered = target.render_cache.get_or_render(source, &contents, || {
+        let rendered =
+            render_with_timeout(handlebars, &contents, &variables, target.render_timeout)
+                .map_err(|e| add_render_context(e, &contents, &span))?;
+        match target.merge {
+            Some(_) => Ok(rendered),
+            None => target.apply_post_render_actions(rendered),
+        }
+    })?;
+    match target.merge {
+        Some(_) => target.apply_post_render_actions(rendered),
+        None => Ok(rendered),
+    }
+}
+
+/// Says which line of the source a render error is at, if the actions moved it
+fn add_render_context(e: anyhow::Error, contents: &str, span: &SourceSpan) -> anyhow::Error {
+    let context = match error_line(&e) {
+        Some(line) => match span.source_line(contents, line) {
+            Some(source_line) if source_line == line => "render template".into(),
+            Some(source_line) => format!(
+                "render template (line {} after prepend, append and banner is line {} of the source)",
+                line, source_line
+            ),
+            None => format!(
+                "render template (line {} was added by prepend, append or banner)",
+                line
+            ),
+        },
+        None => "render template".into(),
+    };
+    e.context(context)
+}
+
+/// Adds `dotter.target_path`, `dotter.source_path` and `dotter.file_name` (of the target) of the
+/// file that's rendered
+fn add_file_metadata(variables: &mut Variables, source: &Path, target: &Path) {
+    let file_name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let dotter = variables
+        .entry(String::from("dotter"))
+        .or_insert_with(|| Table::new().into());
+    if let Value::Table(dotter) = dotter {
+        for (name, value) in [
+            ("target_path", target.to_string_lossy().into_owned()),
+            ("source_path", source.to_string_lossy().into_owned()),
+            ("file_name", file_name),
+        ] {
+            dotter.insert(name.to_string(), Value::String(value));
+        }
+    }
+}
+
+/// The line a render or parse error happened at
+fn error_line(error: &anyhow::Error) -> Option<usize> {
+    let error = error.downcast_ref::<RenderError>()?;
+    error.line_no.or_else(|| {
+        std::error::Error::source(error)?
+            .downcast_ref::<TemplateError>()?
+            .line_no
+    })
+}
+
+/// Renders a template, giving up after `timeout` so that a runaway template can't hang Dotter.
+/// Handlebars can't be interrupted, so the render is stopped by failing the first write of
+/// output after the timeout. Nothing else runs after that, but a template that loops without
+/// writing anything isn't stopped
+pub fn render_with_timeout(
+    handlebars: &Handlebars<'static>,
+    template: &str,
+    variables: &impl Serialize,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(handlebars.render_template(template, variables)?),
+    };
+
+    let mut output = DeadlineWriter {
+        output: Vec::new(),
+        deadline: Instant::now() + timeout,
+        timed_out: false,
+    };
+    let rendered = handlebars.render_template_to_write(template, variables, &mut output);
+    if output.timed_out {
+        anyhow::bail!("render timed out after {:.1}s", timeout.as_secs_f64());
+    }
+    rendered?;
+    String::from_utf8(output.output).context("decode rendered template as UTF-8")
+}
+
+/// Collects the output of a render, failing every write after `deadline`
+struct DeadlineWriter {
+    output: Vec<u8>,
+    deadline: Instant,
+    timed_out: bool,
+}
+
+impl std::io::Write for DeadlineWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if Instant::now() > self.deadline {
+            self.timed_out = true;
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 fn eval_condition(handlebars: &Handlebars, variables: &Variables, condition: &str) -> Result<bool> {
     // extra { for format!()
     let condition = format!("{{{{#if {} }}}}true{{{{/if}}}}", condition);
@@ -99,33 +401,96 @@ fn math_helper(
     Ok(())
 }
 
-fn include_template_helper(
-    h: &Helper<'_, '_>,
-    handlebars: &Handlebars<'_>,
-    ctx: &Context,
-    _: &mut RenderContext<'_, '_>,
-    out: &mut dyn Output,
-) -> HelperResult {
-    let mut params = h.params().iter();
-    let path = params
-        .next()
-        .ok_or_else(|| RenderError::new("include_template: No path given"))?
-        .render();
-    if params.next().is_some() {
-        return Err(RenderError::new(
-            "include_template: More than one parameter given",
-        ));
+/// `{{include_template "path"}}` renders another file with the current context.
+/// Included files are kept in memory as long as the Handlebars instance, so that a snippet that
+/// many templates include is only read once. They're keyed by their canonical path and
+/// modification time, so a file that changed is read again
+#[derive(Default)]
+struct IncludeTemplateHelper {
+    files: Mutex<BTreeMap<PathBuf, (SystemTime, Arc<str>)>>,
+}
+
+impl IncludeTemplateHelper {
+    fn read(&self, path: &Path) -> std::io::Result<Arc<str>> {
+        let path = std::fs::canonicalize(path)?;
+        let modified = std::fs::metadata(&path)?.modified()?;
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        match files.get(&path) {
+            Some((cached, contents)) if *cached == modified => Ok(contents.clone()),
+            _ => {
+                trace!("Reading included file {:?}", path);
+                let contents = Arc::<str>::from(std::fs::read_to_string(&path)?);
+                files.insert(path, (modified, contents.clone()));
+                Ok(contents)
+            }
+        }
     }
+}
 
-    let included_file = std::fs::read_to_string(path)
-        .map_err(|e| RenderError::from_error("include_template", e))?;
-    let rendered_file = handlebars
-        .render_template_with_context(&included_file, ctx)
-        .map_err(|e| RenderError::from_error("include_template", e))?;
+impl HelperDef for IncludeTemplateHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        handlebars: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let mut params = h.params().iter();
+        let path = params
+            .next()
+            .ok_or_else(|| RenderError::new("include_template: No path given"))?
+            .render();
+        if params.next().is_some() {
+            return Err(RenderError::new(
+                "include_template: More than one parameter given",
+            ));
+        }
 
-    out.write(&rendered_file)?;
+        let included_file = self
+            .read(Path::new(&path))
+            .map_err(|e| RenderError::from_error("include_template", e))?;
+        let rendered_file = handlebars
+            .render_template_with_context(&included_file, ctx)
+            .map_err(|e| RenderError::from_error("include_template", e))?;
 
-    Ok(())
+        out.write(&rendered_file)?;
+
+        Ok(())
+    }
+}
+
+/// `{{now}}` renders the time in RFC 3339, `{{now "[year]-[month]-[day]"}}` in the given format
+/// (see the `time` crate's format descriptions)
+struct NowHelper {
+    pinned: Option<OffsetDateTime>,
+}
+
+impl HelperDef for NowHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let now = self.pinned.unwrap_or_else(OffsetDateTime::now_utc);
+        let formatted = match h.params().as_slice() {
+            [] => now.format(&Rfc3339),
+            [format] => {
+                let format = format.render();
+                let description = time::format_description::parse(&format).map_err(|e| {
+                    RenderError::new(format!("now: Invalid format {:?}: {}", format, e))
+                })?;
+                now.format(&description)
+            }
+            _ => return Err(RenderError::new("now: More than one parameter given")),
+        }
+        .map_err(|e| RenderError::from_error("now", e))?;
+        out.write(&formatted)?;
+        Ok(())
+    }
 }
 
 fn is_executable_helper(
@@ -156,67 +521,107 @@ fn is_executable_helper(
     Ok(())
 }
 
-fn command_success_helper(
-    h: &Helper<'_, '_>,
-    _: &Handlebars<'_>,
-    _: &Context,
-    _: &mut RenderContext<'_, '_>,
-    out: &mut dyn Output,
-) -> HelperResult {
-    let mut params = h.params().iter();
-    let command = params
-        .next()
-        .ok_or_else(|| RenderError::new("command_success: No executable name given"))?
-        .render();
-    if params.next().is_some() {
-        return Err(RenderError::new(
-            "command_success: More than one parameter given",
-        ));
-    }
+/// Renders "true" if a shell command exits successfully
+struct CommandSuccessHelper {
+    shell: Vec<String>,
+}
 
-    let status = os_shell()
-        .arg(&command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()?
-        .success();
-    if status {
-        out.write("true")?;
+impl HelperDef for CommandSuccessHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let mut params = h.params().iter();
+        let command = params
+            .next()
+            .ok_or_else(|| RenderError::new("command_success: No executable name given"))?
+            .render();
+        if params.next().is_some() {
+            return Err(RenderError::new(
+                "command_success: More than one parameter given",
+            ));
+        }
+
+        let status = crate::hooks::shell_command(&self.shell, &command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?
+            .success();
+        if status {
+            out.write("true")?;
+        }
+        // writing anything other than an empty string is considered truthy
+
+        Ok(())
     }
-    // writing anything other than an empty string is considered truthy
+}
 
-    Ok(())
+/// Renders the stdout of a shell command, running it again up to `retries` times
+/// while it exits with an error
+struct CommandOutputHelper {
+    shell: Vec<String>,
+    retries: u32,
+    retry_delay: Duration,
 }
 
-fn command_output_helper(
-    h: &Helper<'_, '_>,
-    _: &Handlebars<'_>,
-    _: &Context,
-    _: &mut RenderContext<'_, '_>,
-    out: &mut dyn Output,
-) -> HelperResult {
-    let mut params = h.params().iter();
-    let command = params
-        .next()
-        .ok_or_else(|| RenderError::new("command_success: No executable name given"))?
-        .render();
-    if params.next().is_some() {
-        return Err(RenderError::new(
-            "command_success: More than one parameter given",
-        ));
-    }
+impl HelperDef for CommandOutputHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let mut params = h.params().iter();
+        let command = params
+            .next()
+            .ok_or_else(|| RenderError::new("command_success: No executable name given"))?
+            .render();
+        if params.next().is_some() {
+            return Err(RenderError::new(
+                "command_success: More than one parameter given",
+            ));
+        }
 
-    let output = os_shell()
-        .arg(&command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        // .stderr(Stdio::piped()) - probably not wanted
-        .output()?;
-    out.write(&String::from_utf8_lossy(&output.stdout))?;
-    // writing anything other than an empty string is considered truthy
+        let mut last_output = None;
+        let result = crate::hooks::with_retries(
+            self.retries,
+            self.retry_delay,
+            &format!("run command {:?}", command),
+            || {
+                let output = crate::hooks::shell_command(&self.shell, &command)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    // .stderr(Stdio::piped()) - probably not wanted
+                    .output()?;
+                let status = output.status;
+                last_output = Some(output);
+                anyhow::ensure!(status.success(), "command failed with {}", status);
+                Ok(())
+            },
+        );
+        // Once the retries are exhausted, a failed command's output is rendered like before.
+        // There's only no output if the shell couldn't be started
+        let output = match (result, last_output) {
+            (_, Some(output)) => output,
+            (result, None) => {
+                return Err(RenderError::new(format!(
+                    "command_output: {:#}",
+                    result.expect_err("there's output if the command ran")
+                )))
+            }
+        };
+        out.write(&String::from_utf8_lossy(&output.stdout))?;
+        // writing anything other than an empty string is considered truthy
 
-    Ok(())
+        Ok(())
+    }
 }
 
 #[cfg(windows)]
@@ -247,28 +652,126 @@ fn is_executable(name: &str) -> Result<bool, std::io::Error> {
         .map(|s| s.success())
 }
 
-#[cfg(windows)]
-fn os_shell() -> Command {
-    let mut cmd = Command::new("cmd");
-    cmd.arg("/C");
-    cmd
+fn register_rust_helpers(handlebars: &mut Handlebars<'_>, settings: &Settings) {
+    handlebars_misc_helpers::register(handlebars);
+    handlebars.register_helper("math", Box::new(math_helper));
+
+    handlebars.register_helper(
+        "include_template",
+        Box::new(IncludeTemplateHelper::default()),
+    );
+    handlebars.register_helper("is_executable", Box::new(is_executable_helper));
+    let shell = crate::hooks::shell(settings.shell.as_deref());
+    handlebars.register_helper(
+        "command_success",
+        Box::new(CommandSuccessHelper {
+            shell: shell.clone(),
+        }),
+    );
+    handlebars.register_helper(
+        "command_output",
+        Box::new(CommandOutputHelper {
+            shell,
+            retries: settings.commands.retries,
+            retry_delay: Duration::from_millis(settings.commands.retry_delay_ms),
+        }),
+    );
+    handlebars.register_helper(
+        "now",
+        Box::new(NowHelper {
+            pinned: PINNED_NOW.get().copied(),
+        }),
+    );
 }
 
-#[cfg(unix)]
-fn os_shell() -> Command {
-    let mut cmd = Command::new("sh");
-    cmd.arg("-c");
-    cmd
+fn register_external_helpers(handlebars: &mut Handlebars<'_>, settings: &Settings) -> Result<()> {
+    let dir = match &settings.helpers_dir {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    anyhow::ensure!(
+        settings.external_helpers,
+        "`helpers_dir` is set, but `external_helpers` isn't enabled in the settings"
+    );
+
+    debug!("Registering external helpers from {:?}...", dir);
+    for entry in std::fs::read_dir(dir).with_context(|| format!("read directory {:?}", dir))? {
+        let path = entry.context("read directory entry")?.path();
+        let name = match path.file_stem().and_then(|name| name.to_str()) {
+            Some(name) if path.is_file() && !name.starts_with('.') => name.to_string(),
+            _ => continue,
+        };
+        debug!("Registering helper {:?} from {:?}", name, path);
+        handlebars.register_helper(&name, Box::new(ExternalHelper { path }));
+    }
+    Ok(())
 }
 
-fn register_rust_helpers(handlebars: &mut Handlebars<'_>) {
-    handlebars_misc_helpers::register(handlebars);
-    handlebars.register_helper("math", Box::new(math_helper));
+/// A helper that runs an executable from `helpers_dir`
+struct ExternalHelper {
+    path: PathBuf,
+}
 
-    handlebars.register_helper("include_template", Box::new(include_template_helper));
-    handlebars.register_helper("is_executable", Box::new(is_executable_helper));
-    handlebars.register_helper("command_success", Box::new(command_success_helper));
-    handlebars.register_helper("command_output", Box::new(command_output_helper));
+impl HelperDef for ExternalHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let hash: serde_json::Map<String, serde_json::Value> = h
+            .hash()
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.value().clone()))
+            .collect();
+        let output = run_external_helper(
+            &self.path,
+            h.params().iter().map(|param| param.render()),
+            &serde_json::Value::Object(hash).to_string(),
+        )
+        .map_err(|e| RenderError::new(format!("{}: {:#}", h.name(), e)))?;
+        out.write(&output)?;
+        Ok(())
+    }
+}
+
+/// Returns the executable's stdout without the final newline
+fn run_external_helper(
+    path: &Path,
+    args: impl Iterator<Item = String>,
+    stdin: &str,
+) -> Result<String> {
+    let mut child = Command::new(path)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("run {:?}", path))?;
+    child
+        .stdin
+        .take()
+        .context("open stdin")?
+        .write_all(stdin.as_bytes())
+        .context("write to stdin")?;
+    let output = child.wait_with_output().context("wait for helper")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "{:?} failed: {}",
+        path,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    let mut stdout = String::from_utf8(output.stdout).context("decode output as UTF-8")?;
+    if stdout.ends_with('\n') {
+        stdout.pop();
+        if stdout.ends_with('\r') {
+            stdout.pop();
+        }
+    }
+    Ok(stdout)
 }
 
 #[cfg(feature = "scripting")]
@@ -284,6 +787,21 @@ fn register_script_helpers(handlebars: &mut Handlebars<'_>, helpers: &Helpers) {
     }
 }
 
+fn register_partials(handlebars: &mut Handlebars<'_>, partials: &Partials) -> Result<()> {
+    debug!("Registering partials...");
+    for (partial_name, partial) in partials {
+        let content = match partial {
+            Partial::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("read partial {:?} from file {:?}", partial_name, path))?,
+            Partial::Inline { content } => content.clone(),
+        };
+        handlebars
+            .register_partial(partial_name, content)
+            .with_context(|| format!("parse partial {:?}", partial_name))?;
+    }
+    Ok(())
+}
+
 fn files_as_toml(files: &Files) -> Value {
     Value::Table(
         files
@@ -343,67 +861,496 @@ fn add_dotter_variable(
 mod test {
     use super::*;
 
+    use crate::config::Settings;
+
     #[test]
     fn eval_condition_simple() {
         let mut config = Configuration {
             files: Files::new(),
+            file_packages: BTreeMap::new(),
+            package_stages: BTreeMap::new(),
             variables: maplit::btreemap! { "foo".into() => 2.into() },
             helpers: Helpers::new(),
             packages: maplit::btreemap! { "default".into() => true, "disabled".into() => false },
+            settings: Settings::default(),
+            partials: Partials::new(),
             recurse: true,
+            owned_directories: BTreeMap::new(),
         };
         let handlebars = create_new_handlebars(&mut config).unwrap();
 
-        assert_eq!(
-            eval_condition(&handlebars, &config.variables, "foo").unwrap(),
-            true
+        assert!(eval_condition(&handlebars, &config.variables, "foo").unwrap());
+        assert!(!eval_condition(&handlebars, &config.variables, "bar").unwrap());
+        assert!(eval_condition(&handlebars, &config.variables, "dotter.packages.default").unwrap());
+        assert!(
+            !eval_condition(&handlebars, &config.variables, "dotter.packages.nonexist").unwrap()
         );
+        assert!(!eval_condition(
+            &handlebars,
+            &config.variables,
+            "(and true dotter.packages.disabled)"
+        )
+        .unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_sources_are_rendered() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("nvim-0.9")).unwrap();
+        std::fs::create_dir(dir.path().join("nvim-0.10")).unwrap();
+        let link = dir.path().join("nvim");
+        std::os::unix::fs::symlink(dir.path().join("nvim-0.9"), &link).unwrap();
+
+        let source = dir.path().join("nvim-{{nvim_version}}");
+        let mut config = Configuration {
+            files: maplit::btreemap! {
+                source.clone() => FileTarget::Symbolic(link.clone().into()),
+            },
+            file_packages: maplit::btreemap! { source => "nvim".into() },
+            package_stages: BTreeMap::new(),
+            variables: maplit::btreemap! { "nvim_version".into() => "0.10".into() },
+            helpers: Helpers::new(),
+            packages: BTreeMap::new(),
+            settings: Settings::default(),
+            partials: Partials::new(),
+            recurse: true,
+            owned_directories: BTreeMap::new(),
+        };
+        let handlebars = create_new_handlebars(&mut config).unwrap();
+        let rendered = dir.path().join("nvim-0.10");
+        assert_eq!(config.files.keys().collect::<Vec<_>>(), vec![&rendered]);
         assert_eq!(
-            eval_condition(&handlebars, &config.variables, "bar").unwrap(),
-            false
+            config.file_packages,
+            maplit::btreemap! { rendered.clone() => "nvim".to_string() }
         );
-        assert_eq!(
-            eval_condition(&handlebars, &config.variables, "dotter.packages.default").unwrap(),
-            true
+
+        // The link still points to the old version
+        let symlinks = maplit::btreemap! { rendered => link.into() };
+        let report = crate::audit::AuditReport::new(
+            &symlinks,
+            &BTreeMap::new(),
+            &Default::default(),
+            &handlebars,
+            &config.variables,
+        )
+        .unwrap();
+        assert_eq!(report.entries[0].status, crate::audit::AuditStatus::Changed);
+    }
+
+    #[test]
+    fn eval_condition_helpers() {
+        let mut config = Configuration {
+            files: Files::new(),
+            file_packages: BTreeMap::new(),
+            package_stages: BTreeMap::new(),
+            variables: Variables::new(),
+            helpers: Helpers::new(),
+            packages: BTreeMap::new(),
+            settings: Settings::default(),
+            partials: Partials::new(),
+            recurse: true,
+            owned_directories: BTreeMap::new(),
+        };
+        let handlebars = create_new_handlebars(&mut config).unwrap();
+
+        assert!(!eval_condition(
+            &handlebars,
+            &config.variables,
+            "(is_executable \"no_such_executable_please\")"
+        )
+        .unwrap());
+        assert!(
+            eval_condition(&handlebars, &config.variables, "(eq (math \"5+5\") \"10\")").unwrap()
         );
+    }
+
+    #[test]
+    fn strict_mode_missing_variable() {
+        let mut config = Configuration {
+            files: Files::new(),
+            file_packages: BTreeMap::new(),
+            package_stages: BTreeMap::new(),
+            variables: Variables::new(),
+            helpers: Helpers::new(),
+            packages: BTreeMap::new(),
+            settings: Settings::default(),
+            partials: Partials::new(),
+            recurse: true,
+            owned_directories: BTreeMap::new(),
+        };
+        let handlebars = create_new_handlebars(&mut config).unwrap();
+        let error = handlebars
+            .render_template("color={{theme.color}}", &config.variables)
+            .unwrap_err();
+        assert!(error.to_string().contains("theme.color"));
+
+        config.settings.strict = false;
+        let handlebars = create_new_handlebars(&mut config).unwrap();
         assert_eq!(
-            eval_condition(&handlebars, &config.variables, "dotter.packages.nonexist").unwrap(),
-            false
+            handlebars
+                .render_template("color={{theme.color}}", &config.variables)
+                .unwrap(),
+            "color="
         );
+    }
+
+    #[test]
+    fn partials() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = dir.path().join("header.hbs");
+        std::fs::write(&header, "# {{title}}\n").unwrap();
+
+        let mut config = Configuration {
+            files: Files::new(),
+            file_packages: BTreeMap::new(),
+            package_stages: BTreeMap::new(),
+            variables: maplit::btreemap! { "title".into() => "Hello".into() },
+            helpers: Helpers::new(),
+            packages: BTreeMap::new(),
+            settings: Settings::default(),
+            partials: maplit::btreemap! {
+                "header".into() => Partial::File(header),
+                "footer".into() => Partial::Inline { content: "# end".into() },
+            },
+            recurse: true,
+            owned_directories: BTreeMap::new(),
+        };
+        let handlebars = create_new_handlebars(&mut config).unwrap();
         assert_eq!(
-            eval_condition(
-                &handlebars,
-                &config.variables,
-                "(and true dotter.packages.disabled)"
-            )
-            .unwrap(),
-            false
+            handlebars
+                .render_template("{{> header}}body\n{{> footer}}", &config.variables)
+                .unwrap(),
+            "# Hello\nbody\n# end"
         );
+
+        config.partials = maplit::btreemap! {
+            "missing".into() => Partial::File(dir.path().join("missing.hbs")),
+        };
+        let error = format!("{:#}", create_new_handlebars(&mut config).unwrap_err());
+        assert!(error.contains("\"missing\""), "{}", error);
+        assert!(error.contains("missing.hbs"), "{}", error);
     }
 
     #[test]
-    fn eval_condition_helpers() {
+    #[cfg(unix)]
+    fn external_helpers() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let helper = dir.path().join("shout.sh");
+        std::fs::write(
+            &helper,
+            "#!/bin/sh\nprintf '%s %s ' \"$1\" \"$2\" | tr a-z A-Z\ncat\necho\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
         let mut config = Configuration {
             files: Files::new(),
-            variables: Variables::new(),
+            file_packages: BTreeMap::new(),
+            package_stages: BTreeMap::new(),
+            variables: maplit::btreemap! { "name".into() => "there".into() },
             helpers: Helpers::new(),
             packages: BTreeMap::new(),
+            settings: Settings {
+                helpers_dir: Some(dir.path().into()),
+                ..Settings::default()
+            },
+            partials: Partials::new(),
             recurse: true,
+            owned_directories: BTreeMap::new(),
         };
-        let handlebars = create_new_handlebars(&mut config).unwrap();
+        let error = create_new_handlebars(&mut config).unwrap_err();
+        assert!(format!("{:#}", error).contains("`external_helpers` isn't enabled"));
 
+        config.settings.external_helpers = true;
+        let handlebars = create_new_handlebars(&mut config).unwrap();
         assert_eq!(
-            eval_condition(
+            handlebars
+                .render_template("[{{shout \"hi\" name volume=11}}]", &config.variables)
+                .unwrap(),
+            "[HI THERE {\"volume\":11}]"
+        );
+    }
+
+    #[test]
+    fn pinned_now_renders_identically() {
+        let render = |pinned: &str| {
+            let mut handlebars = Handlebars::new();
+            handlebars.register_helper(
+                "now",
+                Box::new(NowHelper {
+                    pinned: Some(OffsetDateTime::parse(pinned, &Rfc3339).unwrap()),
+                }),
+            );
+            handlebars
+                .render_template("{{now}} {{now \"[year]-[month]-[day]\"}}", &())
+                .unwrap()
+        };
+
+        let first = render("2024-05-01T12:00:00Z");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(render("2024-05-01T12:00:00Z"), first);
+        assert_eq!(first, "2024-05-01T12:00:00Z 2024-05-01");
+        assert_ne!(render("2024-05-02T12:00:00Z"), first);
+    }
+
+    #[test]
+    fn render_timeout() {
+        let handlebars = Handlebars::new();
+        let mut variables = Variables::new();
+        variables.insert(
+            "items".into(),
+            Value::Array((0..2000).map(Value::Integer).collect()),
+        );
+
+        let rendered = render_with_timeout(
+            &handlebars,
+            "{{#each items}}{{#if @first}}{{this}}{{/if}}{{/each}}",
+            &variables,
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap();
+        assert_eq!(rendered, "0");
+
+        let error = render_with_timeout(
+            &handlebars,
+            "{{#each items}}{{#each ../items}}{{#each ../../items}}.{{/each}}{{/each}}{{/each}}",
+            &variables,
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "render timed out after 0.1s");
+    }
+
+    #[test]
+    fn included_files_are_read_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let snippet = dir.path().join("snippet");
+        std::fs::write(&snippet, "color = {{color}}").unwrap();
+        let modified = std::fs::metadata(&snippet).unwrap().modified().unwrap();
+        let set_modified = |time| {
+            std::fs::File::options()
+                .write(true)
+                .open(&snippet)
+                .unwrap()
+                .set_modified(time)
+                .unwrap()
+        };
+
+        let mut handlebars = Handlebars::new();
+        register_rust_helpers(&mut handlebars, &Settings::default());
+        let template = format!("{{{{include_template {:?}}}}}", snippet);
+        let mut variables = Variables::new();
+        variables.insert("color".into(), "red".into());
+        let render = || handlebars.render_template(&template, &variables).unwrap();
+        assert_eq!(render(), "color = red");
+
+        // A change that keeps the modification time isn't seen, since the file isn't read again
+        std::fs::write(&snippet, "changed").unwrap();
+        set_modified(modified);
+        assert_eq!(render(), "color = red");
+
+        set_modified(modified + Duration::from_secs(1));
+        assert_eq!(render(), "changed");
+    }
+
+    #[test]
+    fn repeated_renders_are_cached() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static RENDERS: AtomicUsize = AtomicUsize::new(0);
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper(
+            "count",
+            Box::new(
+                |_: &Helper<'_, '_>,
+                 _: &Handlebars<'_>,
+                 _: &Context,
+                 _: &mut RenderContext<'_, '_>,
+                 _: &mut dyn Output|
+                 -> HelperResult {
+                    RENDERS.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+            ),
+        );
+        let target: TemplateTarget = "/home/user/.config/app".into();
+        let mut variables = Variables::new();
+        variables.insert("theme".into(), "dark".into());
+        let render = |contents: &str, variables: &Variables| {
+            target
+                .render_cache
+                .set_variables(RenderCache::hash_variables(variables).as_deref());
+            // A clone, like the targets that are compared and then deployed
+            render_target(
                 &handlebars,
-                &config.variables,
-                "(is_executable \"no_such_executable_please\")"
+                Path::new("app"),
+                &target.clone(),
+                contents.into(),
+                variables,
             )
-            .unwrap(),
-            false
+            .unwrap()
+        };
+
+        assert_eq!(render("{{count}}{{theme}}", &variables), "dark");
+        assert_eq!(render("{{count}}{{theme}}", &variables), "dark");
+        assert_eq!(RENDERS.load(Ordering::Relaxed), 1);
+
+        variables.insert("theme".into(), "light".into());
+        assert_eq!(render("{{count}}{{theme}}", &variables), "light");
+        assert_eq!(
+            render("{{count}}theme = {{theme}}", &variables),
+            "theme = light"
+        );
+        assert_eq!(RENDERS.load(Ordering::Relaxed), 3);
+
+        // The formatter's output is cached too
+        #[cfg(unix)]
+        {
+            let dir = tempfile::tempdir().unwrap();
+            let log = dir.path().join("formatted");
+            let target = TemplateTarget {
+                format_command: Some(format!("tee -a {}", log.display())),
+                ..target.clone()
+            };
+            target
+                .render_cache
+                .set_variables(RenderCache::hash_variables(&variables).as_deref());
+            for _ in 0..2 {
+                let rendered = render_target(
+                    &handlebars,
+                    Path::new("app"),
+                    &target.clone(),
+                    "{{theme}}".into(),
+                    &variables,
+                )
+                .unwrap();
+                assert_eq!(rendered, "light");
+            }
+            assert_eq!(std::fs::read_to_string(&log).unwrap(), "light");
+        }
+    }
+
+    #[test]
+    fn tables_are_iterated_in_key_order() {
+        let variables: Variables = toml::from_str(
+            r#"
+            [colors]
+            red = "ff0000"
+            green = "00ff00"
+            blue = "0000ff"
+            "#,
+        )
+        .unwrap();
+        let template = "{{#each colors}}{{@key}}={{this}} {{/each}}";
+
+        // Always sorted by key, whatever the order in the configuration or of the insertions
+        let expected = "blue=0000ff green=00ff00 red=ff0000 ";
+        let handlebars = Handlebars::new();
+        for _ in 0..10 {
+            let rendered = render_with_timeout(&handlebars, template, &variables, None).unwrap();
+            assert_eq!(rendered, expected);
+        }
+    }
+
+    #[test]
+    fn file_metadata_is_available_when_rendering() {
+        let handlebars = Handlebars::new();
+        let target: TemplateTarget = "/home/user/.config/app/config.toml".into();
+        let source =
+            "# {{dotter.file_name}} at {{dotter.target_path}}, from {{dotter.source_path}}\n";
+
+        let rendered = render_target(
+            &handlebars,
+            Path::new("app/config.toml"),
+            &target,
+            source.into(),
+            &Variables::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            rendered,
+            "# config.toml at /home/user/.config/app/config.toml, from app/config.toml\n"
+        );
+
+        // They're merged into the configuration's `dotter` variables, and the file's own
+        // variables over the configuration's
+        let mut variables = Variables::new();
+        variables.insert("name".into(), "alice".into());
+        variables.insert(
+            "dotter".into(),
+            toml::from_str::<Value>("[packages]\napp = true\n").unwrap(),
+        );
+        let mut target = target;
+        target.variables.insert("name".into(), "bob".into());
+        let rendered = render_target(
+            &handlebars,
+            Path::new("app/config.toml"),
+            &target,
+            "{{name}} {{dotter.file_name}} {{dotter.packages.app}}".into(),
+            &variables,
+        )
+        .unwrap();
+        assert_eq!(rendered, "bob config.toml true");
+        assert_eq!(variables.get("name"), Some(&"alice".into()));
+    }
+
+    #[test]
+    fn render_errors_point_at_the_source_line() {
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        let mut target: TemplateTarget = "~/.local/bin/script.sh".into();
+        target.prepend = Some("# prepended\n# twice\n".into());
+        target.resolve_banner(&Settings {
+            banner: true,
+            ..Settings::default()
+        });
+
+        let source = "#!/bin/sh\necho {{greeting}}\necho {{missing}}\n";
+        let mut variables = Variables::new();
+        variables.insert("greeting".into(), "hi".into());
+        let error = render_target(
+            &handlebars,
+            Path::new("script.sh"),
+            &target,
+            source.into(),
+            &variables,
+        )
+        .unwrap_err();
+        // The banner goes after the shebang, which now is the first prepended line
+        assert_eq!(
+            error.to_string(),
+            "render template (line 6 after prepend, append and banner is line 3 of the source)"
         );
+
+        let source = "{{#if greeting}}\n{{/each}}\n{{/if}}\n";
+        let error = render_target(
+            &handlebars,
+            Path::new("script.sh"),
+            &target,
+            source.into(),
+            &variables,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "render template (line 6 after prepend, append and banner is line 3 of the source)"
+        );
+
+        target.prepend = Some("{{missing}}\n".into());
+        let error = render_target(
+            &handlebars,
+            Path::new("script.sh"),
+            &target,
+            "ok\n".into(),
+            &variables,
+        )
+        .unwrap_err();
         assert_eq!(
-            eval_condition(&handlebars, &config.variables, "(eq (math \"5+5\") \"10\")").unwrap(),
-            true
+            error.to_string(),
+            "render template (line 2 was added by prepend, append or banner)"
         );
     }
 }
@@ -1,23 +1,36 @@
 use anyhow::{Context, Result};
 use handlebars::Handlebars;
 
+use std::io::Write;
 use std::path::Path;
-use std::process::Child;
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{HookFailure, Settings};
+use crate::display_error;
 
 pub(crate) fn run_hook(
     location: &Path,
     cache_dir: &Path,
-    handlebars: &Handlebars,
+    handlebars: &Handlebars<'static>,
     variables: &crate::config::Variables,
+    settings: &Settings,
 ) -> Result<()> {
     if !location.exists() {
         debug!("Hook file at {:?} missing", location);
         return Ok(());
     }
 
+    // Every hook gets its own file to run, so that hooks of parallel runs don't replace each other
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
     let mut script_file = cache_dir.join(location);
-    let mut target = std::env::temp_dir().join("dotter_temp");
+    let mut target = std::env::temp_dir().join(format!(
+        "dotter-hook-{}-{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ));
     if cfg!(windows) {
         script_file.set_extension("bat");
         target.set_extension("bat");
@@ -28,39 +41,307 @@ pub(crate) fn run_hook(
         location,
         &script_file,
         &target.clone().into(),
-        &mut crate::filesystem::RealFilesystem::new(false),
+        &mut crate::filesystem::RealFilesystem::new(false, false),
         handlebars,
         variables,
     )
     .context("deploy script")?;
 
-    debug!("Running script file");
-    let mut child = run_script_file(&target)?;
+    let shell = shell(
+        settings
+            .hooks
+            .shell
+            .as_deref()
+            .or(settings.shell.as_deref()),
+    );
+    let result = with_retries(
+        settings.hooks.retries,
+        Duration::from_millis(settings.hooks.retry_delay_ms),
+        "run hook",
+        || {
+            debug!("Running script file");
+            let output = run_script_file(&target, &shell)?;
+
+            // Stderr is captured to be included in the error, show it either way
+            std::io::stderr()
+                .write_all(&output.stderr)
+                .context("forward stderr of hook")?;
 
-    anyhow::ensure!(
-        child.wait().context("wait for child shell")?.success(),
-        "subshell returned error"
+            if !output.status.success() {
+                let status = match output.status.code() {
+                    Some(code) => format!("exited with code {}", code),
+                    None => "was terminated by a signal".into(),
+                };
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if stderr.trim().is_empty() {
+                    anyhow::bail!("hook {}", status);
+                }
+                anyhow::bail!("hook {}: {}", status, stderr.trim());
+            }
+
+            Ok(())
+        },
     );
+    if let Err(e) = std::fs::remove_file(&target) {
+        debug!("Failed to remove script file {:?}: {}", target, e);
+    }
+    result
+}
 
-    Ok(())
+/// The shell that runs commands, split on whitespace: `configured`, otherwise `$SHELL`,
+/// otherwise `sh` (`cmd` on Windows)
+pub(crate) fn shell(configured: Option<&str>) -> Vec<String> {
+    let split = |shell: &str| {
+        shell
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    if let Some(shell) = configured.map(split).filter(|shell| !shell.is_empty()) {
+        return shell;
+    }
+    if cfg!(unix) {
+        if let Some(shell) = std::env::var("SHELL")
+            .ok()
+            .map(|shell| split(&shell))
+            .filter(|shell| !shell.is_empty())
+        {
+            return shell;
+        }
+        vec!["sh".into()]
+    } else {
+        vec!["cmd".into()]
+    }
 }
 
+/// A command that runs `command` in `shell`, which is passed with `-c`, or `/C` to cmd and
+/// `-Command` to PowerShell
+pub(crate) fn shell_command(shell: &[String], command: &str) -> Command {
+    let program = Path::new(&shell[0])
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let flag = match program.as_str() {
+        "cmd" => "/C",
+        "powershell" | "pwsh" => "-Command",
+        _ => "-c",
+    };
+    let mut result = Command::new(&shell[0]);
+    result.args(&shell[1..]).arg(flag).arg(command);
+    result
+}
+
+/// Calls `attempt` until it succeeds, at most `retries` more times after it first failed.
+/// Waits `delay` before the first retry, and twice as long before each further one.
+/// Returns the error of the last attempt if none succeeded
+pub(crate) fn with_retries<T>(
+    retries: u32,
+    delay: Duration,
+    description: &str,
+    mut attempt: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut delay = delay;
+    for retry in 1..=retries {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                debug!(
+                    "Attempt {} of {} to {} failed, retrying in {:?}: {:#}",
+                    retry,
+                    retries + 1,
+                    description,
+                    delay,
+                    e
+                );
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+        }
+    }
+    attempt()
+}
+
+/// Handles the result of a hook according to the `hooks.on_failure` setting.
+/// Returns true if an error was printed that should make Dotter exit with an error.
+pub(crate) fn handle_failure(
+    result: Result<()>,
+    on_failure: HookFailure,
+    description: &'static str,
+) -> Result<bool> {
+    let error = match result {
+        Ok(()) => return Ok(false),
+        Err(e) => e.context(description),
+    };
+
+    match on_failure {
+        HookFailure::Abort => Err(error),
+        HookFailure::Warn => {
+            display_error(error);
+            Ok(true)
+        }
+        HookFailure::Continue => {
+            warn!("Failed to {:#}", error);
+            Ok(false)
+        }
+    }
+}
+
+/// Runs the script directly if it's executable, so that its shebang applies, otherwise in `shell`
 #[cfg(unix)]
-fn run_script_file(script: &Path) -> Result<Child> {
+fn run_script_file(script: &Path, shell: &[String]) -> Result<Output> {
     use std::os::unix::fs::PermissionsExt;
 
     let permissions = script.metadata()?.permissions();
     if !script.is_dir() && permissions.mode() & 0o111 != 0 {
-        Command::new(script).spawn().context("spawn script file")
+        with_captured_stderr(Command::new(script)).context("run script file")
     } else {
-        Command::new("sh")
-            .arg(script)
-            .spawn()
-            .context("spawn shell")
+        let mut command = Command::new(&shell[0]);
+        command.args(&shell[1..]).arg(script);
+        with_captured_stderr(command).context(format!("run shell {:?}", shell[0]))
     }
 }
 
 #[cfg(windows)]
-fn run_script_file(script: &Path) -> Result<Child> {
-    Command::new(script).spawn().context("spawn batch file")
+fn run_script_file(script: &Path, _shell: &[String]) -> Result<Output> {
+    with_captured_stderr(Command::new(script)).context("run batch file")
+}
+
+fn with_captured_stderr(mut command: Command) -> std::io::Result<Output> {
+    command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .output()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::HookSettings;
+
+    fn run_failing_hook() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = dir.path().join("hook.sh");
+        std::fs::write(&hook, "echo 'something broke' >&2\nexit 3\n").unwrap();
+        run_hook(
+            &hook,
+            &dir.path().join("cache"),
+            &Handlebars::new(),
+            &Default::default(),
+            &Settings::default(),
+        )
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn failing_hook_reports_exit_code_and_stderr() {
+        let error = run_failing_hook().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "hook exited with code 3: something broke"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn hook_failure_modes() {
+        let error = handle_failure(run_failing_hook(), HookFailure::Abort, "run hook").unwrap_err();
+        assert_eq!(error.to_string(), "run hook");
+        assert!(handle_failure(run_failing_hook(), HookFailure::Warn, "run hook").unwrap());
+        assert!(!handle_failure(run_failing_hook(), HookFailure::Continue, "run hook").unwrap());
+
+        for mode in [HookFailure::Abort, HookFailure::Warn, HookFailure::Continue] {
+            assert!(!handle_failure(Ok(()), mode, "run hook").unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn flaky_hook_is_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = dir.path().join("hook.sh");
+        let marker = dir.path().join("failed once");
+        // Fails the first time it runs, and succeeds afterwards
+        std::fs::write(
+            &hook,
+            format!("if [ ! -e {0:?} ]; then touch {0:?}; exit 1; fi\n", marker),
+        )
+        .unwrap();
+        let run = |retries| {
+            run_hook(
+                &hook,
+                &dir.path().join("cache"),
+                &Handlebars::new(),
+                &Default::default(),
+                &Settings {
+                    hooks: HookSettings {
+                        retries,
+                        retry_delay_ms: 1,
+                        ..HookSettings::default()
+                    },
+                    ..Settings::default()
+                },
+            )
+        };
+
+        assert_eq!(run(0).unwrap_err().to_string(), "hook exited with code 1");
+        std::fs::remove_file(&marker).unwrap();
+        run(2).unwrap();
+        assert!(marker.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn hook_runs_in_configured_shell() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let hook = dir.path().join("hook.sh");
+        std::fs::write(&hook, "true\n").unwrap();
+        // Shells that only record that they ran
+        let log = dir.path().join("log");
+        let fake_shell = |name: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(
+                &path,
+                format!("#!/bin/sh\necho {} >> \"{}\"\n", name, log.display()),
+            )
+            .unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let (general, hooks) = (fake_shell("general"), fake_shell("hooks"));
+        let run = |hooks_shell: Option<&String>| {
+            run_hook(
+                &hook,
+                &dir.path().join("cache"),
+                &Handlebars::new(),
+                &Default::default(),
+                &Settings {
+                    shell: Some(general.clone()),
+                    hooks: HookSettings {
+                        shell: hooks_shell.cloned(),
+                        ..HookSettings::default()
+                    },
+                    ..Settings::default()
+                },
+            )
+            .unwrap()
+        };
+
+        run(Some(&hooks));
+        run(None);
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "hooks\ngeneral\n");
+
+        assert_eq!(
+            shell(Some("zsh  -f")),
+            vec!["zsh".to_string(), "-f".to_string()]
+        );
+        let command = shell_command(&shell(Some("pwsh -NoProfile")), "ls");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec!["-NoProfile", "-Command", "ls"]
+        );
+    }
 }
@@ -43,6 +43,8 @@ pub fn init(opt: Options) -> Result<()> {
         config::Cache {
             symlinks: BTreeMap::default(),
             templates: BTreeMap::default(),
+            last_deploy: None,
+            history: BTreeMap::new(),
         },
     )
     .context("save empty cache file")?;
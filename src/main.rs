@@ -3,13 +3,24 @@ extern crate log;
 
 mod actions;
 mod args;
+mod audit;
+mod comments;
 mod config;
 mod deploy;
+mod diagnostics;
 mod difference;
+mod encoding;
 mod filesystem;
+mod git;
 mod handlebars_helpers;
 mod hooks;
 mod init;
+mod merge;
+mod notify;
+mod parallel;
+mod plan;
+mod progress;
+mod variables;
 #[cfg(feature = "watch")]
 mod watch;
 
@@ -21,14 +32,15 @@ use clap::CommandFactory;
 use clap_complete::{generate, generate_to};
 
 fn main() {
-    match run() {
-        Ok(success) if success => std::process::exit(0),
-        Ok(_) => std::process::exit(1),
-        Err(e) => {
-            display_error(e);
-            std::process::exit(1);
-        }
+    let success = run().unwrap_or_else(|e| {
+        display_error(e);
+        false
+    });
+    // On stderr, like the warnings themselves, to keep machine-readable output intact
+    if let Some(summary) = diagnostics::WARNINGS.take_summary() {
+        eprintln!("\n{}", summary);
     }
+    std::process::exit(if success { 0 } else { 1 });
 }
 
 pub(crate) fn display_error(error: anyhow::Error) {
@@ -51,18 +63,19 @@ fn run() -> Result<bool> {
 
     use simplelog::LevelFilter;
 
-    simplelog::TermLogger::init(
-        if opt.quiet {
-            LevelFilter::Error
-        } else {
-            match opt.verbosity {
-                0 => LevelFilter::Warn,
-                1 => LevelFilter::Info,
-                2 => LevelFilter::Debug,
-                3 => LevelFilter::Trace,
-                _ => unreachable!(),
-            }
-        },
+    let level = if opt.quiet {
+        LevelFilter::Error
+    } else {
+        match opt.verbosity {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            3 => LevelFilter::Trace,
+            _ => unreachable!(),
+        }
+    };
+    let logger = simplelog::TermLogger::new(
+        level,
         simplelog::ConfigBuilder::new()
             .set_time_level(LevelFilter::Off)
             .set_location_level(LevelFilter::Debug)
@@ -72,11 +85,33 @@ fn run() -> Result<bool> {
             .add_filter_allow("dotter".into())
             .build(),
         simplelog::TerminalMode::Mixed,
-        simplelog::ColorChoice::Auto,
-    )
+        if opt.no_color {
+            simplelog::ColorChoice::Never
+        } else {
+            simplelog::ColorChoice::Auto
+        },
+    );
+    // Log messages have to hide the progress bar while they're printed, and warnings are
+    // repeated at the end
+    log::set_boxed_logger(Box::new(diagnostics::CollectingLogger {
+        inner: Box::new(progress::SuspendingLogger(logger)),
+        warnings: &diagnostics::WARNINGS,
+        allow_targets: &["dotter"],
+    }))
     .unwrap();
+    log::set_max_level(level);
 
     trace!("Loaded options: {:#?}", opt);
+    anyhow::ensure!(
+        opt.stdin_inputs() <= 1,
+        "read stdin for more than one of `--global-config -`, `--config -`, --patch and --vars-stdin"
+    );
+
+    if opt.no_color {
+        difference::disable_colors();
+    }
+    handlebars_helpers::pin_now(opt.now.as_deref()).context("pin the time of `now`")?;
+    filesystem::read_umask();
 
     if std::env::var("USER").unwrap_or_default() == "root" {
         warn!("It is not recommended to run Dotter as root, since the cache files and all files not marked with an `owner` field will default to being owned by root.
@@ -85,9 +120,20 @@ Otherwise, run `dotter undeploy` as root, remove cache.toml and cache/ folders,
     }
 
     match opt.action.clone().unwrap_or_default() {
-        args::Action::Deploy => {
+        args::Action::Deploy(args::DeployOptions {
+            format: args::DeployFormat::Patch,
+            out,
+            ..
+        }) => {
+            debug!("Writing patch...");
+            if deploy::write_patch(&opt, out.as_deref()).context("write patch")? {
+                // An error occurred
+                return Ok(false);
+            }
+        }
+        args::Action::Deploy(args::DeployOptions { review, .. }) => {
             debug!("Deploying...");
-            if deploy::deploy(&opt).context("deploy")? {
+            if deploy::deploy(&opt, review).context("deploy")? {
                 // An error occurred
                 return Ok(false);
             }
@@ -99,12 +145,94 @@ Otherwise, run `dotter undeploy` as root, remove cache.toml and cache/ folders,
                 return Ok(false);
             }
         }
+        args::Action::Diff(diff_opt) => {
+            debug!("Diffing...");
+            if deploy::diff(&opt, &diff_opt).context("diff")? {
+                // Differences were found or an error occurred
+                return Ok(false);
+            }
+        }
+        args::Action::Status => {
+            debug!("Reading last deploy...");
+            deploy::status(&opt).context("show status")?;
+        }
+        args::Action::Log { target } => {
+            debug!("Reading history of {:?}...", target);
+            deploy::log(&opt, &target).context("show history")?;
+        }
+        args::Action::Audit => {
+            debug!("Auditing targets...");
+            git::disable_sync();
+            if deploy::audit(&opt).context("audit targets")? {
+                // A target isn't in sync
+                return Ok(false);
+            }
+        }
+        args::Action::SyncCache => {
+            debug!("Syncing cache...");
+            if deploy::sync_cache(&opt).context("sync cache")? {
+                // An error occurred
+                return Ok(false);
+            }
+        }
+        args::Action::Check => {
+            debug!("Checking templates...");
+            if deploy::check(&opt).context("check templates")? {
+                // A template failed to parse
+                return Ok(false);
+            }
+        }
+        args::Action::Dedup => {
+            debug!("Finding duplicate templates...");
+            if deploy::dedup(&opt).context("find duplicate templates")? {
+                // A template failed to render
+                return Ok(false);
+            }
+        }
+        args::Action::Variables(variables_opt) => {
+            debug!("Listing variables...");
+            deploy::variables(&opt, &variables_opt).context("list variables")?;
+        }
+        args::Action::List { format } => {
+            debug!("Listing targets...");
+            git::disable_sync();
+            deploy::list(&opt, format).context("list targets")?;
+        }
+        args::Action::Config { format } => {
+            debug!("Printing effective configuration...");
+            git::disable_sync();
+            deploy::print_config(&opt, format).context("print effective configuration")?;
+        }
+        args::Action::Plan { output } => {
+            debug!("Planning deployment...");
+            if deploy::plan(&opt, &output).context("plan deployment")? {
+                // An error occurred
+                return Ok(false);
+            }
+        }
+        args::Action::ApplyPlan { plan } => {
+            debug!("Applying plan...");
+            if deploy::apply_plan(&opt, &plan).context("apply plan")? {
+                // An error occurred
+                return Ok(false);
+            }
+        }
         args::Action::Init => {
             debug!("Initializing repo...");
             init::init(opt).context("initalize directory")?;
         }
         #[cfg(feature = "watch")]
         args::Action::Watch => {
+            anyhow::ensure!(
+                !opt.global_configs()
+                    .iter()
+                    .any(|c| c == std::path::Path::new("-")),
+                "watch can't reload a global configuration read from stdin"
+            );
+            anyhow::ensure!(
+                !opt.vars_stdin,
+                "watch can't reload variables read from stdin"
+            );
             debug!("Watching...");
             tokio::runtime::Runtime::new()
                 .expect("create a tokio runtime")
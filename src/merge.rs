@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Format of a target that the rendered template is merged into, instead of replacing it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeFormat {
+    Json,
+    Toml,
+}
+
+/// Deep-merges the rendered template over the current contents of `target`: objects (tables) are
+/// merged key by key, and everything else is replaced by the rendered value, with a warning if
+/// that changes it. The result is serialized anew, so the target's formatting and comments
+/// aren't kept. A missing or empty target is merged into like an empty object
+pub fn merge_into_target(format: MergeFormat, rendered: &str, target: &Path) -> Result<String> {
+    let current = match fs::read_to_string(target) {
+        Ok(current) => current,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context("read target to merge into"),
+    };
+
+    match format {
+        MergeFormat::Json => {
+            let rendered = serde_json::from_str(rendered).context("parse rendered template")?;
+            let mut merged = if current.trim().is_empty() {
+                serde_json::Value::Object(Default::default())
+            } else {
+                serde_json::from_str(&current).context("parse target")?
+            };
+            merge_json(&mut merged, rendered, target, "");
+            let mut merged = serde_json::to_string_pretty(&merged).context("serialize merge")?;
+            merged.push('\n');
+            Ok(merged)
+        }
+        MergeFormat::Toml => {
+            let rendered = rendered
+                .parse::<toml::Value>()
+                .context("parse rendered template")?;
+            let mut merged = current.parse::<toml::Value>().context("parse target")?;
+            merge_toml(&mut merged, rendered, target, "");
+            toml::to_string_pretty(&merged).context("serialize merge")
+        }
+    }
+}
+
+fn merge_json(
+    current: &mut serde_json::Value,
+    rendered: serde_json::Value,
+    target: &Path,
+    key: &str,
+) {
+    match (current, rendered) {
+        (serde_json::Value::Object(current), serde_json::Value::Object(rendered)) => {
+            for (name, value) in rendered {
+                let key = child_key(key, &name);
+                match current.get_mut(&name) {
+                    Some(existing) => merge_json(existing, value, target, &key),
+                    None => {
+                        current.insert(name, value);
+                    }
+                }
+            }
+        }
+        (current, rendered) => {
+            if *current != rendered {
+                warn_conflict(target, key, &current.to_string(), &rendered.to_string());
+            }
+            *current = rendered;
+        }
+    }
+}
+
+fn merge_toml(current: &mut toml::Value, rendered: toml::Value, target: &Path, key: &str) {
+    match (current, rendered) {
+        (toml::Value::Table(current), toml::Value::Table(rendered)) => {
+            for (name, value) in rendered {
+                let key = child_key(key, &name);
+                match current.get_mut(&name) {
+                    Some(existing) => merge_toml(existing, value, target, &key),
+                    None => {
+                        current.insert(name, value);
+                    }
+                }
+            }
+        }
+        (current, rendered) => {
+            if *current != rendered {
+                warn_conflict(target, key, &current.to_string(), &rendered.to_string());
+            }
+            *current = rendered;
+        }
+    }
+}
+
+fn child_key(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.into()
+    } else {
+        format!("{}.{}", parent, name)
+    }
+}
+
+fn warn_conflict(target: &Path, key: &str, current: &str, rendered: &str) {
+    warn!(
+        "Merging into {:?}: `{}` is {} in the target, replacing it with {}",
+        target, key, current, rendered
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rendered_json_is_merged_into_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("settings.json");
+        fs::write(
+            &target,
+            r#"{"editor": {"fontSize": 12, "tabSize": 8}, "user.added": true}"#,
+        )
+        .unwrap();
+
+        let merged = merge_into_target(
+            MergeFormat::Json,
+            r#"{"editor": {"tabSize": 4, "wordWrap": "on"}}"#,
+            &target,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&merged).unwrap(),
+            serde_json::json!({
+                "editor": {"fontSize": 12, "tabSize": 4, "wordWrap": "on"},
+                "user.added": true,
+            })
+        );
+
+        // Without a target, the fragment is all there is
+        let merged = merge_into_target(
+            MergeFormat::Toml,
+            "[editor]\ntab_size = 4\n",
+            &dir.path().join("missing.toml"),
+        )
+        .unwrap();
+        assert_eq!(merged, "[editor]\ntab_size = 4\n");
+    }
+}
@@ -0,0 +1,226 @@
+use serde::Serialize;
+
+use std::path::Path;
+
+#[cfg(unix)]
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// How a single action of a deploy ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    /// The target was left alone because it wasn't what Dotter expected, see --force
+    Skipped,
+    Failed,
+}
+
+/// A line that's written to the socket of `--notify-socket`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Action {
+        action: &'a str,
+        source: &'a Path,
+        target: &'a Path,
+        outcome: Outcome,
+    },
+    Summary {
+        ok: usize,
+        skipped: usize,
+        failed: usize,
+        error: bool,
+    },
+}
+
+/// Writes the events of a deploy as JSON lines to a Unix domain socket, so that tools watching
+/// the deploy don't have to parse its output.
+/// Does nothing if no socket was given, or if it can't be connected to or written to.
+/// If it's dropped before [`Notifier::summary`], e.g. because the deploy returned early, it
+/// sends a summary with `error: true`, so that watchers always see the end of the stream
+#[derive(Default)]
+pub struct Notifier {
+    #[cfg(unix)]
+    stream: Option<UnixStream>,
+    ok: usize,
+    skipped: usize,
+    failed: usize,
+    summarized: bool,
+}
+
+impl Notifier {
+    pub fn connect(socket: Option<&Path>) -> Notifier {
+        let mut notifier = Notifier::default();
+        let socket = match socket {
+            Some(socket) => socket,
+            None => return notifier,
+        };
+
+        #[cfg(unix)]
+        match UnixStream::connect(socket) {
+            Ok(stream) => {
+                // A watcher that stopped reading shouldn't hold up the deploy
+                if let Err(e) = stream.set_write_timeout(Some(std::time::Duration::from_secs(1))) {
+                    debug!("Couldn't set write timeout of notify socket: {}", e);
+                }
+                notifier.stream = Some(stream);
+            }
+            Err(e) => debug!(
+                "Couldn't connect to notify socket {:?}, deploying without events: {}",
+                socket, e
+            ),
+        }
+        #[cfg(not(unix))]
+        warn!(
+            "Notify sockets are only supported on Unix, not writing events to {:?}",
+            socket
+        );
+
+        notifier
+    }
+
+    pub fn action(&mut self, action: &str, source: &Path, target: &Path, outcome: Outcome) {
+        match outcome {
+            Outcome::Ok => self.ok += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+        self.send(&Event::Action {
+            action,
+            source,
+            target,
+            outcome,
+        });
+    }
+
+    /// Ends the stream with the counts of the actions so far
+    pub fn summary(&mut self, error: bool) {
+        self.summarized = true;
+        self.send(&Event::Summary {
+            ok: self.ok,
+            skipped: self.skipped,
+            failed: self.failed,
+            error,
+        });
+    }
+
+    #[cfg(unix)]
+    fn send(&mut self, event: &Event) {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut line = serde_json::to_string(event).expect("events are serializable");
+        line.push('\n');
+        if let Err(e) = stream.write_all(line.as_bytes()) {
+            debug!(
+                "Couldn't write to notify socket, not sending more events: {}",
+                e
+            );
+            self.stream = None;
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn send(&mut self, _event: &Event) {}
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        if !self.summarized {
+            self.summary(true);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn events_are_written_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("dotter.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let mut notifier = Notifier::connect(Some(&socket));
+        notifier.action(
+            "create_symlink",
+            Path::new("vimrc"),
+            Path::new("/home/user/.vimrc"),
+            Outcome::Ok,
+        );
+        notifier.action(
+            "update_template",
+            Path::new("bashrc"),
+            Path::new("/home/user/.bashrc"),
+            Outcome::Skipped,
+        );
+        notifier.summary(true);
+        drop(notifier);
+
+        let (stream, _) = listener.accept().unwrap();
+        let lines = BufReader::new(stream)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({
+                    "event": "action",
+                    "action": "create_symlink",
+                    "source": "vimrc",
+                    "target": "/home/user/.vimrc",
+                    "outcome": "ok",
+                }),
+                serde_json::json!({
+                    "event": "action",
+                    "action": "update_template",
+                    "source": "bashrc",
+                    "target": "/home/user/.bashrc",
+                    "outcome": "skipped",
+                }),
+                serde_json::json!({
+                    "event": "summary",
+                    "ok": 1,
+                    "skipped": 1,
+                    "failed": 0,
+                    "error": true,
+                }),
+            ]
+        );
+
+        // Dropping the notifier early still ends the stream
+        let mut notifier = Notifier::connect(Some(&socket));
+        notifier.action(
+            "create_symlink",
+            Path::new("vimrc"),
+            Path::new("/home/user/.vimrc"),
+            Outcome::Ok,
+        );
+        drop(notifier);
+        let (stream, _) = listener.accept().unwrap();
+        let last = BufReader::new(stream).lines().last().unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&last).unwrap(),
+            serde_json::json!({
+                "event": "summary",
+                "ok": 1,
+                "skipped": 0,
+                "failed": 0,
+                "error": true,
+            })
+        );
+
+        // Without a listener, the events go nowhere
+        let mut notifier = Notifier::connect(Some(&dir.path().join("missing.sock")));
+        notifier.action("delete_symlink", &socket, &socket, Outcome::Failed);
+        notifier.summary(true);
+    }
+}
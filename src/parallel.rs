@@ -0,0 +1,59 @@
+use std::num::NonZeroUsize;
+use std::thread;
+
+/// Calls `f` on every item, on up to `jobs` threads, or one per CPU if it's None.
+/// The results are in the order of `items` no matter which thread finishes first,
+/// so that output built from them is deterministic
+pub fn map<T: Sync, R: Send>(
+    items: &[T],
+    jobs: Option<NonZeroUsize>,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let jobs = jobs
+        .or_else(|| thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get);
+    map_with_jobs(items, jobs, f)
+}
+
+fn map_with_jobs<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let f = &f;
+    thread::scope(|scope| {
+        // Every thread gets a contiguous chunk, so joining them in order keeps the items' order
+        let threads = items
+            .chunks(items.len().div_ceil(jobs))
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .flat_map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn results_keep_the_order_of_the_items() {
+        let items = (0..20).collect::<Vec<u64>>();
+        // Earlier items take longer, so later threads finish first
+        let results = map_with_jobs(&items, 4, |&item| {
+            thread::sleep(Duration::from_millis(20 - item));
+            item * 2
+        });
+        assert_eq!(results, (0..20).map(|item| item * 2).collect::<Vec<_>>());
+        assert_eq!(map_with_jobs(&items, 1, |&item| item), items);
+        assert!(map(&[] as &[u64], None, |&item| item).is_empty());
+    }
+}
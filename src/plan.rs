@@ -0,0 +1,538 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Cache, FileMode, SymbolicTarget, TemplateTarget, UnixUser, Variables};
+use crate::deploy::snapshot_path;
+use crate::display_error;
+use crate::encoding::{self, Encoding};
+use crate::filesystem::{self, Filesystem};
+use crate::handlebars_helpers::render_target;
+
+/// The changes a deploy would make, with templates already rendered.
+/// Each action remembers the state its target was in when the plan was made,
+/// so that applying it later doesn't overwrite changes made in the meantime.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeployPlan {
+    pub actions: Vec<PlannedAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlannedAction {
+    /// Write rendered contents to the target
+    Write {
+        target: PathBuf,
+        /// The source as the configuration names it, which the cache records the target under
+        source: PathBuf,
+        contents: String,
+        /// Encoding of the contents, encoded when writing them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+        /// Mode of the target, resolved when the plan was made
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<FileMode>,
+        owner: Option<UnixUser>,
+        dir_mode: Option<FileMode>,
+        expected: TargetState,
+    },
+    /// Make the target a symlink to the source
+    Symlink {
+        target: PathBuf,
+        /// The source as the configuration names it, which the cache records the target under
+        managed_as: PathBuf,
+        /// Real path of the source, which the symlink points to
+        source: PathBuf,
+        owner: Option<UnixUser>,
+        dir_mode: Option<FileMode>,
+        expected: TargetState,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TargetState {
+    Missing,
+    File { sha256: String },
+    Symlink { points_to: PathBuf },
+}
+
+impl TargetState {
+    pub fn of(path: &Path) -> Result<TargetState> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(TargetState::Missing),
+            Err(e) => return Err(e).context("read metadata"),
+        };
+
+        if metadata.file_type().is_symlink() {
+            let contents = fs::read_link(path).context("read symlink")?;
+            Ok(TargetState::Symlink {
+                points_to: filesystem::resolve_symlink(path, &contents),
+            })
+        } else if metadata.is_file() {
+            Ok(TargetState::File {
+                sha256: sha256(&fs::read(path).context("read file")?),
+            })
+        } else {
+            anyhow::bail!("target is a directory")
+        }
+    }
+}
+
+pub(crate) fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Why a deploy would leave out the target of the template `source`, which is in the state
+/// `current`: it exists but isn't managed by Dotter according to `cache`, or it was changed
+/// since the last deploy. None if Dotter may overwrite it
+pub(crate) fn template_target_problem(
+    source: &Path,
+    target: &TemplateTarget,
+    current: &TargetState,
+    cache: &Cache,
+    cache_directory: &Path,
+) -> Option<&'static str> {
+    if *current == TargetState::Missing {
+        None
+    } else if cache.templates.get(source) != Some(&target.target) {
+        Some("target exists and isn't managed by Dotter")
+    } else {
+        // The target is what the last deploy wrote if it matches its snapshot
+        let snapshot = fs::read(snapshot_path(cache_directory, source)).ok();
+        let deployed = snapshot.map(|snapshot| TargetState::File {
+            sha256: sha256(&snapshot),
+        });
+        (deployed.as_ref() != Some(current)).then_some("target was changed since the last deploy")
+    }
+}
+
+impl DeployPlan {
+    /// Renders the templates and records what has to change for every target
+    /// that isn't already up to date.
+    /// Like a deploy, targets that aren't managed by Dotter according to `cache`, or that were
+    /// changed since the last deploy, are left out unless `force` is set. The returned bool is
+    /// true if one was left out
+    pub fn new(
+        symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
+        templates: &BTreeMap<PathBuf, TemplateTarget>,
+        cache: &Cache,
+        cache_directory: &Path,
+        force: bool,
+        handlebars: &Handlebars<'static>,
+        variables: &Variables,
+    ) -> Result<(DeployPlan, bool)> {
+        let mut actions = Vec::new();
+        let mut skipped = false;
+
+        for (source, target) in symlinks {
+            || -> Result<()> {
+                let real_source =
+                    filesystem::real_path(source).context("get real path of source")?;
+                let expected = TargetState::of(&target.target).context("get state of target")?;
+                if expected
+                    == (TargetState::Symlink {
+                        points_to: real_source.clone(),
+                    })
+                {
+                    return Ok(());
+                }
+                if expected != TargetState::Missing && !force {
+                    error!(
+                        "Planning symlink {:?} -> {:?} but target exists and isn't a symlink to the source. Skipping.",
+                        source, target.target
+                    );
+                    skipped = true;
+                    return Ok(());
+                }
+                actions.push(PlannedAction::Symlink {
+                    target: target.target.clone(),
+                    managed_as: source.clone(),
+                    source: real_source,
+                    owner: target.owner.clone(),
+                    dir_mode: target.dir_mode,
+                    expected,
+                });
+                Ok(())
+            }()
+            .with_context(|| format!("plan symlink {:?} -> {:?}", source, target.target))?;
+        }
+
+        for (source, target) in templates {
+            || -> Result<()> {
+                let contents = fs::read_to_string(source).context("read template source file")?;
+                let rendered = render_target(handlebars, source, target, contents, variables)?;
+                let encoded = encoding::encode(rendered.clone(), target.encoding)?;
+
+                let expected = TargetState::of(&target.target).context("get state of target")?;
+                if expected
+                    == (TargetState::File {
+                        sha256: sha256(&encoded),
+                    })
+                {
+                    return Ok(());
+                }
+                if !force {
+                    if let Some(problem) =
+                        template_target_problem(source, target, &expected, cache, cache_directory)
+                    {
+                        error!(
+                            "Planning template {:?} -> {:?} but {}. Skipping.",
+                            source, target.target, problem
+                        );
+                        skipped = true;
+                        return Ok(());
+                    }
+                }
+                actions.push(PlannedAction::Write {
+                    target: target.target.clone(),
+                    source: source.clone(),
+                    contents: rendered,
+                    encoding: target.encoding,
+                    mode: filesystem::template_mode(
+                        source,
+                        target.mode,
+                        target.executable_from_source,
+                    )?,
+                    owner: target.owner.clone(),
+                    dir_mode: target.dir_mode,
+                    expected,
+                });
+                Ok(())
+            }()
+            .with_context(|| format!("plan template {:?} -> {:?}", source, target.target))?;
+        }
+
+        Ok((DeployPlan { actions }, skipped))
+    }
+
+    /// Applies every action whose target is still in the expected state, and records the
+    /// targets in `cache` like a deploy. Rendered contents are written to their snapshot in
+    /// `cache_directory` before being copied to their target.
+    /// Returns true if an error was printed
+    pub fn apply(
+        &self,
+        fs: &mut dyn Filesystem,
+        cache: &mut Cache,
+        cache_directory: &Path,
+    ) -> bool {
+        let mut error_occurred = false;
+        for action in &self.actions {
+            match action.apply(fs, cache_directory) {
+                Ok(()) => match action {
+                    PlannedAction::Write { target, source, .. } => {
+                        cache.templates.insert(source.clone(), target.clone());
+                    }
+                    PlannedAction::Symlink {
+                        target, managed_as, ..
+                    } => {
+                        cache.symlinks.insert(managed_as.clone(), target.clone());
+                    }
+                },
+                Err(e) => {
+                    display_error(e.context(format!("apply plan for {:?}", action.target())));
+                    error_occurred = true;
+                }
+            }
+        }
+        error_occurred
+    }
+}
+
+impl PlannedAction {
+    fn target(&self) -> &Path {
+        match self {
+            PlannedAction::Write { target, .. } | PlannedAction::Symlink { target, .. } => target,
+        }
+    }
+
+    fn apply(&self, fs: &mut dyn Filesystem, cache_directory: &Path) -> Result<()> {
+        let (target, owner, dir_mode, expected) = match self {
+            PlannedAction::Write {
+                target,
+                owner,
+                dir_mode,
+                expected,
+                ..
+            }
+            | PlannedAction::Symlink {
+                target,
+                owner,
+                dir_mode,
+                expected,
+                ..
+            } => (target, owner, *dir_mode, expected),
+        };
+
+        let current = TargetState::of(target).context("get state of target")?;
+        // Encoded contents are only needed for writes
+        let (desired, encoded) = match self {
+            PlannedAction::Write {
+                contents, encoding, ..
+            } => {
+                let encoded = encoding::encode(contents.clone(), *encoding)?;
+                (
+                    TargetState::File {
+                        sha256: sha256(&encoded),
+                    },
+                    encoded,
+                )
+            }
+            PlannedAction::Symlink { source, .. } => (
+                TargetState::Symlink {
+                    points_to: source.clone(),
+                },
+                Vec::new(),
+            ),
+        };
+        if current == desired {
+            debug!("{:?} is already up to date", target);
+            return Ok(());
+        }
+        anyhow::ensure!(
+            current == *expected,
+            "target was changed since the plan was made, refusing to overwrite it"
+        );
+
+        if current != TargetState::Missing {
+            fs.remove_file(target).context("remove previous target")?;
+        }
+        fs.create_dir_all(
+            target.parent().context("get parent of target file")?,
+            owner,
+            dir_mode,
+        )
+        .context("create parent for target file")?;
+
+        match self {
+            PlannedAction::Write { source, mode, .. } => {
+                info!("{} file {:?}", "[+]".green(), target);
+                let snapshot = snapshot_path(cache_directory, source);
+                fs.create_dir_all(
+                    snapshot.parent().context("get parent of snapshot")?,
+                    &None,
+                    None,
+                )
+                .context("create snapshot directory")?;
+                fs.write(&snapshot, encoded)
+                    .context("write rendered contents to snapshot")?;
+                fs.copy_file(&snapshot, target, owner)
+                    .context("copy snapshot to target")?;
+                if let Some(mode) = mode {
+                    // With an explicit mode, the source's mode isn't read
+                    fs.set_mode(&snapshot, target, Some(*mode), false, owner)
+                        .context("set mode of target")?;
+                }
+            }
+            PlannedAction::Symlink { source, .. } => {
+                info!("{} symlink {:?} -> {:?}", "[+]".green(), source, target);
+                fs.make_symlink(target, source, owner)
+                    .context("create symlink")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::filesystem::RealFilesystem;
+
+    fn new_plan(
+        symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
+        templates: &BTreeMap<PathBuf, TemplateTarget>,
+        cache: &Cache,
+        cache_directory: &Path,
+        force: bool,
+        variables: &Variables,
+    ) -> (DeployPlan, bool) {
+        DeployPlan::new(
+            symlinks,
+            templates,
+            cache,
+            cache_directory,
+            force,
+            &Handlebars::new(),
+            variables,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn plan_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_directory = dir.path().join("cache");
+        let template = dir.path().join("template");
+        let plain = dir.path().join("plain");
+        fs::write(&template, "theme = {{theme}}\n").unwrap();
+        fs::write(&plain, "unchanged").unwrap();
+
+        let symlinks = maplit::btreemap! {
+            plain.clone() => SymbolicTarget::from(dir.path().join("out/plain")),
+        };
+        let mut template_target = TemplateTarget::from(dir.path().join("out/template"));
+        template_target.mode = Some(FileMode(0o640));
+        let templates = maplit::btreemap! { template.clone() => template_target };
+        let mut variables = Variables::new();
+        variables.insert("theme".into(), "dark".into());
+
+        let mut cache = Cache::default();
+        let (plan, skipped) = new_plan(
+            &symlinks,
+            &templates,
+            &cache,
+            &cache_directory,
+            false,
+            &variables,
+        );
+        assert!(!skipped);
+        assert_eq!(plan.actions.len(), 2);
+
+        let json = serde_json::to_string(&plan).unwrap();
+        let loaded: DeployPlan = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, plan);
+
+        let mut fs = RealFilesystem::new(true, false);
+        assert!(!loaded.apply(&mut fs, &mut cache, &cache_directory));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("out/template")).unwrap(),
+            "theme = dark\n"
+        );
+        assert_eq!(
+            fs::read_link(dir.path().join("out/plain")).unwrap(),
+            filesystem::real_path(&plain).unwrap()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join("out/template"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o7777, 0o640);
+        }
+
+        // The targets are managed now, like after a deploy
+        assert_eq!(cache.symlinks[&plain], dir.path().join("out/plain"));
+        assert_eq!(cache.templates[&template], dir.path().join("out/template"));
+        assert_eq!(
+            fs::read_to_string(snapshot_path(&cache_directory, &template)).unwrap(),
+            "theme = dark\n"
+        );
+
+        // Applying again is a no-op, and a new plan is empty
+        assert!(!loaded.apply(&mut fs, &mut cache, &cache_directory));
+        let (plan, _) = new_plan(
+            &symlinks,
+            &templates,
+            &cache,
+            &cache_directory,
+            false,
+            &variables,
+        );
+        assert!(plan.actions.is_empty());
+
+        // A managed template is updated, unless it was changed since it was deployed
+        variables.insert("theme".into(), "light".into());
+        let (plan, skipped) = new_plan(
+            &symlinks,
+            &templates,
+            &cache,
+            &cache_directory,
+            false,
+            &variables,
+        );
+        assert!(!skipped);
+        assert_eq!(plan.actions.len(), 1);
+        fs::write(dir.path().join("out/template"), "edited\n").unwrap();
+        let (plan, skipped) = new_plan(
+            &symlinks,
+            &templates,
+            &cache,
+            &cache_directory,
+            false,
+            &variables,
+        );
+        assert!(skipped);
+        assert!(plan.actions.is_empty());
+    }
+
+    #[test]
+    fn plan_leaves_out_unmanaged_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("template");
+        let plain = dir.path().join("plain");
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        fs::write(&template, "new contents\n").unwrap();
+        fs::write(&plain, "").unwrap();
+        fs::write(&target, "not from dotter\n").unwrap();
+        fs::write(&link, "not a symlink\n").unwrap();
+
+        let symlinks = maplit::btreemap! { plain => SymbolicTarget::from(link) };
+        let templates = maplit::btreemap! { template => TemplateTarget::from(target) };
+        let cache_directory = dir.path().join("cache");
+        let plan = |force| {
+            new_plan(
+                &symlinks,
+                &templates,
+                &Cache::default(),
+                &cache_directory,
+                force,
+                &Variables::new(),
+            )
+        };
+
+        let (unforced, skipped) = plan(false);
+        assert!(skipped);
+        assert!(unforced.actions.is_empty());
+
+        let (forced, skipped) = plan(true);
+        assert!(!skipped);
+        assert_eq!(forced.actions.len(), 2);
+    }
+
+    #[test]
+    fn plan_refuses_drifted_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_directory = dir.path().join("cache");
+        let template = dir.path().join("template");
+        let target = dir.path().join("target");
+        fs::write(&template, "new contents\n").unwrap();
+
+        let templates = maplit::btreemap! { template => TemplateTarget::from(target.clone()) };
+        let mut cache = Cache::default();
+        let (plan, _) = new_plan(
+            &BTreeMap::new(),
+            &templates,
+            &cache,
+            &cache_directory,
+            false,
+            &Variables::new(),
+        );
+
+        fs::write(&target, "edited after planning\n").unwrap();
+
+        let mut fs = RealFilesystem::new(true, false);
+        assert!(plan.apply(&mut fs, &mut cache, &cache_directory));
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "edited after planning\n"
+        );
+        assert!(cache.templates.is_empty());
+    }
+}
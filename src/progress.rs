@@ -0,0 +1,154 @@
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use std::cell::Cell;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::args::Options;
+
+/// The progress bar that's currently shown, so that output can be printed around it
+static ACTIVE: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+thread_local! {
+    /// Whether this thread is already inside `suspend`, which can't be nested
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether to show a progress bar: only on a terminal, and not with `--quiet`
+pub fn enabled(opt: &Options, is_terminal: bool) -> bool {
+    !opt.quiet && is_terminal
+}
+
+/// Width of the terminal that stdout is connected to, or None if it isn't a terminal or its size
+/// can't be determined, like in some CI environments. Output that depends on the terminal falls
+/// back to its non-interactive form then
+pub fn terminal_width() -> Option<usize> {
+    width_of(io::stdout().is_terminal(), crossterm::terminal::size())
+}
+
+fn width_of(is_terminal: bool, size: io::Result<(u16, u16)>) -> Option<usize> {
+    match size {
+        Ok((columns, _)) if is_terminal && columns > 0 => Some(columns.into()),
+        _ => None,
+    }
+}
+
+/// Hides the progress bar, if one is shown, while `f` prints something.
+/// Otherwise the next redraw of the bar would overwrite the output
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    let bar = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    match bar {
+        Some(bar) if !SUSPENDED.with(Cell::get) => {
+            SUSPENDED.with(|suspended| suspended.set(true));
+            let result = bar.suspend(f);
+            SUSPENDED.with(|suspended| suspended.set(false));
+            result
+        }
+        _ => f(),
+    }
+}
+
+/// Output that goes through `suspend`
+pub struct SuspendingLogger(pub Box<dyn log::Log>);
+
+impl log::Log for SuspendingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.0.enabled(record.metadata()) {
+            suspend(|| self.0.log(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// A progress bar over the files of a deploy or diff, drawn on stdout and removed when dropped
+pub struct Progress {
+    bar: ProgressBar,
+    visible: bool,
+}
+
+impl Progress {
+    /// A progress bar if stdout is a terminal of a known size, otherwise a hidden one
+    pub fn new(opt: &Options, total: usize) -> Progress {
+        Progress::with_visibility(total, enabled(opt, terminal_width().is_some()))
+    }
+
+    #[cfg(test)]
+    pub fn hidden() -> Progress {
+        Progress::with_visibility(0, false)
+    }
+
+    fn with_visibility(total: usize, visible: bool) -> Progress {
+        if !visible {
+            return Progress {
+                bar: ProgressBar::hidden(),
+                visible,
+            };
+        }
+        let bar = ProgressBar::with_draw_target(Some(total as u64), ProgressDrawTarget::stdout());
+        bar.set_style(
+            ProgressStyle::with_template("{pos}/{len} {wide_msg}")
+                .expect("progress bar template is valid"),
+        );
+        *ACTIVE.lock().unwrap_or_else(|e| e.into_inner()) = Some(bar.clone());
+        Progress { bar, visible }
+    }
+
+    /// Advances to the next file
+    pub fn step(&self, file: &Path) {
+        self.bar.set_message(file.display().to_string());
+        self.bar.inc(1);
+    }
+
+    #[cfg(test)]
+    fn is_hidden(&self) -> bool {
+        self.bar.is_hidden()
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.visible {
+            *ACTIVE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        self.bar.finish_and_clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn disabled_when_quiet_or_not_a_terminal() {
+        let opt = Options::default();
+        assert!(enabled(&opt, true));
+        assert!(!enabled(&opt, false));
+        assert!(!enabled(
+            &Options {
+                quiet: true,
+                ..Options::default()
+            },
+            true
+        ));
+
+        let progress = Progress::with_visibility(3, false);
+        assert!(progress.is_hidden());
+        assert_eq!(suspend(|| 1 + 1), 2);
+    }
+
+    #[test]
+    fn no_width_without_a_sized_terminal() {
+        assert_eq!(width_of(true, Ok((120, 40))), Some(120));
+        assert_eq!(width_of(false, Ok((120, 40))), None);
+        assert_eq!(width_of(true, Ok((0, 0))), None);
+        assert_eq!(width_of(true, Err(io::Error::other("no tty"))), None);
+    }
+}
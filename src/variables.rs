@@ -0,0 +1,153 @@
+use handlebars::template::{HelperTemplate, Parameter, TemplateElement};
+use handlebars::{Path, Template};
+use toml::Value;
+
+use std::collections::BTreeSet;
+
+use crate::config::Variables;
+
+/// Collects the variable paths a template references, like `theme.color`.
+/// Paths are collected syntactically: variables that are only accessed dynamically
+/// (for example through `lookup` or a script helper) aren't found, and paths inside
+/// `#each`/`#with` blocks that are relative to the block's item are reported as if they were
+/// top-level.
+pub fn referenced_variables(template: &Template, referenced: &mut BTreeSet<String>) {
+    for element in &template.elements {
+        match element {
+            TemplateElement::Expression(helper)
+            | TemplateElement::HtmlExpression(helper)
+            | TemplateElement::HelperBlock(helper) => helper_references(helper, referenced),
+            TemplateElement::DecoratorExpression(decorator)
+            | TemplateElement::DecoratorBlock(decorator)
+            | TemplateElement::PartialExpression(decorator)
+            | TemplateElement::PartialBlock(decorator) => {
+                for param in decorator.params.iter().chain(decorator.hash.values()) {
+                    parameter_references(param, referenced);
+                }
+                if let Some(template) = &decorator.template {
+                    referenced_variables(template, referenced);
+                }
+            }
+            TemplateElement::RawString(_) | TemplateElement::Comment(_) => {}
+        }
+    }
+}
+
+fn helper_references(helper: &HelperTemplate, referenced: &mut BTreeSet<String>) {
+    match &helper.name {
+        // `{{name}}` without parameters may be a variable rather than a helper
+        Parameter::Name(name) if helper.params.is_empty() && helper.hash.is_empty() => {
+            add_path(name, referenced)
+        }
+        Parameter::Name(_) => {}
+        name => parameter_references(name, referenced),
+    }
+    for param in helper.params.iter().chain(helper.hash.values()) {
+        parameter_references(param, referenced);
+    }
+    for template in helper.template.iter().chain(&helper.inverse) {
+        referenced_variables(template, referenced);
+    }
+}
+
+fn parameter_references(param: &Parameter, referenced: &mut BTreeSet<String>) {
+    match param {
+        Parameter::Path(Path::Relative((_, raw))) => add_path(raw, referenced),
+        Parameter::Path(Path::Local(_)) | Parameter::Literal(_) | Parameter::Name(_) => {}
+        Parameter::Subexpression(subexpression) => {
+            if let TemplateElement::Expression(helper) = subexpression.element.as_ref() {
+                helper_references(helper, referenced);
+            }
+        }
+    }
+}
+
+fn add_path(raw: &str, referenced: &mut BTreeSet<String>) {
+    let mut path = raw.trim_start_matches("@root.");
+    while let Some(rest) = path
+        .strip_prefix("../")
+        .or_else(|| path.strip_prefix("./"))
+        .or_else(|| path.strip_prefix("this."))
+    {
+        path = rest;
+    }
+    if path.is_empty() || path == "this" || path.starts_with('@') {
+        return;
+    }
+    referenced.insert(path.replace('/', "."));
+}
+
+/// Returns the dotted paths of all variables that aren't referenced, directly or through one of
+/// their parents or children. Paths in `ignore` (and everything below them) are never reported.
+pub fn unused_variables(
+    variables: &Variables,
+    referenced: &BTreeSet<String>,
+    ignore: &[String],
+) -> Vec<String> {
+    let mut leaves = Vec::new();
+    for (name, value) in variables {
+        collect_leaves(name.clone(), value, &mut leaves);
+    }
+
+    leaves
+        .into_iter()
+        .filter(|leaf| {
+            !referenced
+                .iter()
+                .chain(ignore)
+                .any(|path| is_prefix(path, leaf) || is_prefix(leaf, path))
+        })
+        .collect()
+}
+
+fn collect_leaves(path: String, value: &Value, leaves: &mut Vec<String>) {
+    match value {
+        Value::Table(table) if !table.is_empty() => {
+            for (name, value) in table {
+                collect_leaves(format!("{}.{}", path, name), value, leaves);
+            }
+        }
+        _ => leaves.push(path),
+    }
+}
+
+/// Whether `prefix` is `path` or one of its parents
+fn is_prefix(prefix: &str, path: &str) -> bool {
+    path == prefix || (path.starts_with(prefix) && path[prefix.len()..].starts_with('.'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn find_unused_variables() {
+        let template = Template::compile(
+            "{{theme.color}} {{#if font}}{{font}}{{/if}} {{#each ../plugins}}{{this}}{{/each}}",
+        )
+        .unwrap();
+        let mut referenced = BTreeSet::new();
+        referenced_variables(&template, &mut referenced);
+        assert!(referenced.contains("theme.color"));
+        assert!(referenced.contains("font"));
+        assert!(referenced.contains("plugins"));
+
+        let variables: Variables = toml::from_str(
+            r#"
+            font = "mono"
+            unused = 1
+            plugins = ["a", "b"]
+            ignored = true
+            [theme]
+            color = "blue"
+            size = 12
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            unused_variables(&variables, &referenced, &["ignored".into()]),
+            vec!["theme.size".to_string(), "unused".to_string()]
+        );
+    }
+}
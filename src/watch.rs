@@ -1,5 +1,8 @@
+use std::collections::BTreeMap;
 use std::convert::Infallible;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{Context, Result};
 use log::Level;
@@ -13,6 +16,11 @@ use watchexec::Watchexec;
 use super::display_error;
 use crate::args::Options;
 use crate::deploy;
+use crate::diagnostics;
+
+/// Editors that save atomically move a new file over the original, which can show up as
+/// removing the file and creating it again. Deploying in between would find the source missing
+const REPLACEMENT_GRACE_PERIOD: Duration = Duration::from_millis(500);
 
 pub(crate) async fn watch(opt: Options) -> Result<()> {
     let mut init = InitConfig::default();
@@ -47,6 +55,13 @@ pub(crate) async fn watch(opt: Options) -> Result<()> {
                 pat: Pattern::Glob(opt.cache_file.to_string_lossy().into()),
                 negate: false,
             },
+            Filter {
+                in_path: None,
+                on: Matcher::Path,
+                op: Op::NotGlob,
+                pat: Pattern::Glob(format!("{}/", opt.sources_directory.display())),
+                negate: false,
+            },
             Filter {
                 in_path: None,
                 on: Matcher::Path,
@@ -65,18 +80,37 @@ pub(crate) async fn watch(opt: Options) -> Result<()> {
         .await?;
     runtime.filterer(Arc::new(filter));
 
+    let redeployer = Arc::new(Mutex::new(Redeployer::default()));
     runtime.on_action(move |action: Action| {
         let opt = opt.clone();
+        let redeployer = redeployer.clone();
         async move {
             if action.events.iter().any(|e| e.signals().next().is_some()) {
                 action.outcome(Outcome::Exit);
                 return Ok(());
             }
 
-            println!("[Dotter] Deploying...");
-            if let Err(e) = deploy::deploy(&opt) {
-                display_error(e);
-            }
+            let paths = action
+                .events
+                .iter()
+                .flat_map(|event| event.paths())
+                .map(|(path, _)| path.to_path_buf())
+                .collect();
+            redeployer.lock().unwrap_or_else(|e| e.into_inner()).handle(
+                paths,
+                REPLACEMENT_GRACE_PERIOD,
+                || {
+                    println!("[Dotter] Deploying...");
+                    // Redeploys happen on every change, so they aren't confirmed
+                    if let Err(e) = deploy::deploy_with_confirmation(&opt, None, None) {
+                        display_error(e);
+                    }
+                    // Every redeploy summarizes its own warnings
+                    if let Some(summary) = diagnostics::WARNINGS.take_summary() {
+                        eprintln!("\n{}", summary);
+                    }
+                },
+            );
 
             action.outcome(Outcome::if_running(Outcome::DoNothing, Outcome::Start));
 
@@ -88,3 +122,141 @@ pub(crate) async fn watch(opt: Options) -> Result<()> {
     we.main().await.context("run watchexec main loop")??;
     Ok(())
 }
+
+/// Decides which events are redeployed. The paths of the last redeploy are kept with their
+/// modification times, so that the events of a change that was deployed already, like the
+/// creation that follows the removal of an atomic save, don't deploy it again
+#[derive(Default)]
+struct Redeployer {
+    deployed: BTreeMap<PathBuf, Option<SystemTime>>,
+}
+
+impl Redeployer {
+    /// Runs `deploy` for the paths of an action's events, unless they're all unchanged since
+    /// the last redeploy. Returns whether it ran
+    fn handle(
+        &mut self,
+        paths: Vec<PathBuf>,
+        grace_period: Duration,
+        deploy: impl FnOnce(),
+    ) -> bool {
+        let modified = |path: &PathBuf| path.metadata().and_then(|m| m.modified()).ok();
+        if !paths.is_empty()
+            && paths
+                .iter()
+                .all(|path| self.deployed.get(path) == Some(&modified(path)))
+        {
+            log::debug!("{:?} were deployed already", paths);
+            return false;
+        }
+
+        for path in wait_for_replacements(paths.clone(), grace_period) {
+            log::debug!("{:?} was removed", path);
+        }
+        self.deployed = paths
+            .into_iter()
+            .map(|path| {
+                let modified = modified(&path);
+                (path, modified)
+            })
+            .collect();
+        deploy();
+        true
+    }
+}
+
+/// Waits for the paths that don't exist to be created again, for at most `grace_period`.
+/// Returns the ones that are still missing, which were really removed
+fn wait_for_replacements(paths: Vec<PathBuf>, grace_period: Duration) -> Vec<PathBuf> {
+    let deadline = Instant::now() + grace_period;
+    let mut missing = paths;
+    missing.retain(|path| !path.exists());
+    while !missing.is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+        missing.retain(|path| !path.exists());
+    }
+    missing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn atomic_save_is_not_a_removal() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = dir.path().join("vimrc");
+        let removed = dir.path().join("bashrc");
+        let unchanged = dir.path().join("zshrc");
+        std::fs::write(&unchanged, "").unwrap();
+
+        // The editor moves its temporary file over the source a moment after the event
+        let temporary = dir.path().join(".vimrc.swp");
+        std::fs::write(&temporary, "set number\n").unwrap();
+        let editor = {
+            let saved = saved.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                std::fs::rename(temporary, saved).unwrap();
+            })
+        };
+
+        let missing = wait_for_replacements(
+            vec![saved.clone(), removed.clone(), unchanged],
+            REPLACEMENT_GRACE_PERIOD,
+        );
+        editor.join().unwrap();
+        assert_eq!(missing, vec![removed]);
+        assert_eq!(std::fs::read_to_string(saved).unwrap(), "set number\n");
+    }
+
+    #[test]
+    fn atomic_save_is_deployed_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        let target = dir.path().join("home/.vimrc");
+        std::fs::write(&source, "set nonumber\n").unwrap();
+        let opt = crate::deploy::test::test_options(
+            dir.path(),
+            &format!(
+                "[vim.files]\n{:?} = {{ target = {:?}, type = \"template\" }}\n",
+                source, target
+            ),
+            &["vim"],
+        );
+        assert!(!deploy::deploy(&opt, false).unwrap());
+
+        let mut redeployer = Redeployer::default();
+        let mut deploys = 0;
+        let mut redeploy = |redeployer: &mut Redeployer| {
+            redeployer.handle(vec![source.clone()], REPLACEMENT_GRACE_PERIOD, || {
+                deploys += 1;
+                assert!(!deploy::deploy(&opt, false).unwrap());
+            })
+        };
+
+        // The removal is reported before the editor moved its temporary file over the source
+        std::fs::remove_file(&source).unwrap();
+        let temporary = dir.path().join(".vimrc.swp");
+        std::fs::write(&temporary, "set number\n").unwrap();
+        let editor = {
+            let source = source.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                std::fs::rename(temporary, source).unwrap();
+            })
+        };
+        assert!(redeploy(&mut redeployer));
+        editor.join().unwrap();
+
+        // Then the creation, which was deployed already
+        assert!(!redeploy(&mut redeployer));
+        assert_eq!(deploys, 1);
+
+        // The target was updated instead of removed as the target of a missing source
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "set number\n");
+        let cache: crate::config::Cache =
+            toml::from_str(&std::fs::read_to_string(&opt.cache_file).unwrap()).unwrap();
+        assert_eq!(cache.templates.get(&source), Some(&target));
+    }
+}